@@ -1,18 +1,16 @@
 use std::collections::{HashMap, VecDeque};
-use std::iter;
-
-use log::trace;
 
 use crate::elf::LoadElfInfo;
+use crate::trace::{TraceEvent, Tracer};
 
 pub struct CallStack<'a> {
     symbol_map: &'a HashMap<u64, String>,
     call_stack: VecDeque<(u64, String)>,
-    pub ftrace: bool,
+    ftrace: Tracer,
 }
 
 impl<'a> CallStack<'a> {
-    pub fn new(symbol_map: &HashMap<u64, String>, ftrace: bool) -> CallStack {
+    pub fn new(symbol_map: &HashMap<u64, String>, ftrace: Tracer) -> CallStack {
         CallStack {
             symbol_map,
             call_stack: VecDeque::new(),
@@ -20,20 +18,24 @@ impl<'a> CallStack<'a> {
         }
     }
 
-    pub fn from_elf_info(info: &LoadElfInfo, ftrace: bool) -> CallStack {
+    pub fn from_elf_info(info: &LoadElfInfo, ftrace: Tracer) -> CallStack {
         CallStack::new(info.symbol_map(), ftrace)
     }
 
+    pub fn symbol_map(&self) -> &'a HashMap<u64, String> {
+        self.symbol_map
+    }
+
     pub fn call(&mut self, pc: u64, target_pc: u64) {
         if let Some(func_name) = self.symbol_map.get(&target_pc) {
             let len = self.call_stack.len();
-            if self.ftrace {
-                trace!(
-                    "{:x}:{} call [{func_name}@{:#x}]",
+            if self.ftrace.enabled() {
+                self.ftrace.record(&TraceEvent::Call {
                     pc,
-                    iter::repeat(' ').take(len).collect::<String>(),
-                    target_pc
-                );
+                    target: target_pc,
+                    depth: len,
+                    name: func_name,
+                });
             }
             self.call_stack.push_back((pc, func_name.clone()));
         }
@@ -42,12 +44,12 @@ impl<'a> CallStack<'a> {
     pub fn ret(&mut self, pc: u64) {
         if let Some((_, func_name)) = self.call_stack.pop_back() {
             let len = self.call_stack.len();
-            if self.ftrace {
-                trace!(
-                    "{:x}:{} ret [{func_name}]",
+            if self.ftrace.enabled() {
+                self.ftrace.record(&TraceEvent::Ret {
                     pc,
-                    iter::repeat(' ').take(len).collect::<String>()
-                );
+                    depth: len,
+                    name: &func_name,
+                });
             }
         }
     }
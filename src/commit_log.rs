@@ -0,0 +1,181 @@
+//! Commit log in the format of Spike's `--log-commits`.
+//!
+//! Every retired instruction produces one line:
+//! `core   0: 3 0x<pc> (0x<inst>)` followed by the register write
+//! ` x<rd> 0x<value>` (writes to x0 are omitted) and the memory access,
+//! ` mem 0x<addr>` for loads or ` mem 0x<addr> 0x<value>` for stores.
+
+use std::{
+    fmt::{self, Display},
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use log::error;
+
+/// Privilege level reported for every instruction, we only run in M-mode.
+const PRIV_MACHINE: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemAccess {
+    Read {
+        addr: u64,
+    },
+    /// `size` in bytes
+    Write {
+        addr: u64,
+        size: u8,
+        value: u64,
+    },
+}
+
+/// Architectural effects of one retired instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetireInfo {
+    pub pc: u64,
+    pub raw_inst: u32,
+    /// (rd, value), [`None`] if no register or x0 is written
+    pub reg_write: Option<(u8, u64)>,
+    pub mem: Option<MemAccess>,
+}
+
+impl RetireInfo {
+    /// Derive the effects of a retired instruction from its encoding.
+    /// `src1` and `src2` are the source operands read before execution and
+    /// `rd_val` the value of `rd` after execution.
+    pub fn from_exec(pc: u64, raw_inst: u32, src1: u64, src2: u64, rd_val: u64) -> RetireInfo {
+        const LOAD: u32 = 0b0000011;
+        const STORE: u32 = 0b0100011;
+        const BRANCH: u32 = 0b1100011;
+        const SYSTEM: u32 = 0b1110011;
+
+        let opcode = raw_inst & 0x7f;
+        let rd = ((raw_inst >> 7) & 0x1f) as u8;
+        let size = 1u8 << ((raw_inst >> 12) & 0b11);
+
+        let reg_write = match opcode {
+            STORE | BRANCH => None,
+            // only the CSR instructions of SYSTEM write rd
+            SYSTEM if (raw_inst >> 12) & 0b111 == 0 => None,
+            _ if rd == 0 => None,
+            _ => Some((rd, rd_val)),
+        };
+        let mem = match opcode {
+            LOAD => {
+                let imm = (raw_inst as i32 >> 20) as u64;
+                Some(MemAccess::Read {
+                    addr: src1.wrapping_add(imm),
+                })
+            }
+            STORE => {
+                let imm = ((raw_inst as i32 >> 25) << 5) as u64 | ((raw_inst >> 7) & 0x1f) as u64;
+                let value = match size {
+                    8 => src2,
+                    _ => src2 & ((1u64 << (size as u32 * 8)) - 1),
+                };
+                Some(MemAccess::Write {
+                    addr: src1.wrapping_add(imm),
+                    size,
+                    value,
+                })
+            }
+            _ => None,
+        };
+
+        RetireInfo {
+            pc,
+            raw_inst,
+            reg_write,
+            mem,
+        }
+    }
+}
+
+impl Display for RetireInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "core {:3}: {} 0x{:016x} (0x{:08x})",
+            0, PRIV_MACHINE, self.pc, self.raw_inst
+        )?;
+        if let Some((rd, value)) = self.reg_write {
+            write!(f, " x{:<2} 0x{:016x}", rd, value)?;
+        }
+        match self.mem {
+            Some(MemAccess::Read { addr }) => write!(f, " mem 0x{:016x}", addr),
+            Some(MemAccess::Write { addr, size, value }) => write!(
+                f,
+                " mem 0x{:016x} 0x{:0width$x}",
+                addr,
+                value,
+                width = size as usize * 2
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writer of the commit log.
+pub struct CommitLog {
+    out: Option<Box<dyn Write>>,
+}
+
+impl CommitLog {
+    pub fn new(out: Box<dyn Write>) -> CommitLog {
+        CommitLog { out: Some(out) }
+    }
+
+    pub fn create(path: &str) -> io::Result<CommitLog> {
+        Ok(CommitLog::new(Box::new(BufWriter::new(File::create(
+            path,
+        )?))))
+    }
+
+    /// Append one retired instruction. The log is turned off after the first
+    /// write error.
+    pub fn commit(&mut self, info: &RetireInfo) {
+        if let Some(out) = self.out.as_mut() {
+            if let Err(e) = writeln!(out, "{}", info) {
+                error!("Fail to write commit log: {e}, commit log disabled");
+                self.out = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spike_format() {
+        // addi sp,sp,-16
+        let info = RetireInfo::from_exec(0x80000014, 0xff010113, 0x80009000, 0, 0x80008ff0);
+        assert_eq!(
+            info.to_string(),
+            "core   0: 3 0x0000000080000014 (0xff010113) x2  0x0000000080008ff0"
+        );
+
+        // ld ra,8(sp)
+        let info = RetireInfo::from_exec(0x8000002c, 0x00813083, 0x80008ff0, 0, 0x80000010);
+        assert_eq!(
+            info.to_string(),
+            "core   0: 3 0x000000008000002c (0x00813083) x1  0x0000000080000010 mem 0x0000000080008ff8"
+        );
+
+        // sw a0,-4(s0)
+        let info =
+            RetireInfo::from_exec(0x80000040, 0xfea42e23, 0x80009000, 0xffffffff_00000005, 0);
+        assert_eq!(
+            info.to_string(),
+            "core   0: 3 0x0000000080000040 (0xfea42e23) mem 0x0000000080008ffc 0x00000005"
+        );
+
+        // beq zero,zero,0
+        let info = RetireInfo::from_exec(0x80000044, 0x00000063, 0, 0, 0);
+        assert_eq!(
+            info.to_string(),
+            "core   0: 3 0x0000000080000044 (0x00000063)"
+        );
+    }
+}
@@ -0,0 +1,552 @@
+//! Compliance runs of riscv-tests and riscv-arch-test programs.
+//!
+//! A test reports its result through the HTIF `tohost` word (see
+//! [`crate::core::htif`]): exit code 0 is a pass and any other code is the
+//! number of the failed test case. A test of riscv-arch-test also leaves a
+//! signature, the memory between `begin_signature` and `end_signature`,
+//! which must match the reference signature of the test. Every test is run
+//! on every CPU model, and the results are reported as a table of tests by
+//! models.
+//!
+//! The tests of the A, F, D and C extensions and of the riscv-tests `v`
+//! environment, which needs virtual memory, are not run but reported as
+//! skipped, as the CPU models implement none of them.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use log::{error, info};
+
+use crate::{
+    core::vm::VirtualMemory,
+    elf::LoadElfInfo,
+    error::{Error, Result},
+    stats::{StatValue, Stats},
+    sweep, CPUMode,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct ComplianceArgs {
+    /// Test programs, or directories whose ELF files are the tests
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+
+    /// CPU modes, separated by commas, by default every mode
+    #[arg(short, long, value_delimiter = ',')]
+    cpu_mode: Vec<CPUMode>,
+
+    /// Write the signature of every run to this directory, as
+    /// `<test>.<cpu mode>.signature`.
+    #[arg(long)]
+    signature_dir: Option<PathBuf>,
+
+    /// Compare the signatures with the reference signatures
+    /// `<test>.reference_output` in this directory.
+    #[arg(long, requires = "signature_dir")]
+    reference_dir: Option<PathBuf>,
+
+    /// Steps after which a test that has not ended fails
+    #[arg(long, default_value_t = 10_000_000)]
+    max_steps: i32,
+
+    /// Number of runs in parallel, by default the number of CPUs
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Write the table as markdown to this file.
+    #[arg(long)]
+    markdown: Option<String>,
+
+    /// Options given to every run, after `--`. The pipelined CPUs forward
+    /// data and predict branches not taken unless set here.
+    #[arg(last = true)]
+    options: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Verdict {
+    Pass,
+    /// Number of the failed test case
+    Fail(u64),
+    SignatureMismatch,
+    /// The test did not end in --max-steps
+    Timeout,
+    /// The run failed before the test ended
+    Error,
+    /// The test needs something the CPU models do not implement
+    Skipped(&'static str),
+}
+
+impl Verdict {
+    fn text(&self) -> String {
+        match self {
+            Verdict::Pass => "PASS".to_string(),
+            Verdict::Fail(case) => format!("FAIL ({case})"),
+            Verdict::SignatureMismatch => "SIGNATURE MISMATCH".to_string(),
+            Verdict::Timeout => "TIMEOUT".to_string(),
+            Verdict::Error => "ERROR".to_string(),
+            Verdict::Skipped(_) => "SKIPPED".to_string(),
+        }
+    }
+}
+
+/// A test program and its name, the file name without extension.
+struct Test {
+    path: PathBuf,
+    name: String,
+}
+
+/// Test programs of the inputs, the ELF files of a directory in name order.
+fn tests(inputs: &[PathBuf]) -> Result<Vec<Test>> {
+    let mut tests = Vec::new();
+    for input in inputs {
+        let paths = if input.is_dir() {
+            let mut paths = Vec::new();
+            for entry in fs::read_dir(input)? {
+                let path = entry?.path();
+                if path.is_file() && is_elf(&path)? {
+                    paths.push(path);
+                }
+            }
+            paths.sort();
+            paths
+        } else {
+            vec![input.clone()]
+        };
+        tests.extend(paths.into_iter().map(|path| {
+            Test {
+                name: path
+                    .file_stem()
+                    .map_or(String::new(), |s| s.to_string_lossy().to_string()),
+                path,
+            }
+        }));
+    }
+    Ok(tests)
+}
+
+/// Extensions the CPU models do not implement, by the letter of the
+/// riscv-tests suites (`rv64ua`) and the riscv-arch-test directories
+/// (`rv64i_m/A`).
+const UNIMPLEMENTED: [(&str, &str); 4] = [
+    ("a", "no A extension"),
+    ("f", "no F extension"),
+    ("d", "no D extension"),
+    ("c", "no C extension"),
+];
+
+impl Test {
+    /// Why the test is not run, from the suite and environment of a
+    /// riscv-tests name (`rv64uf-p-fadd`) or the directories of a
+    /// riscv-arch-test path.
+    fn skip_reason(&self) -> Option<&'static str> {
+        let unimplemented = |ext: &str| {
+            UNIMPLEMENTED
+                .iter()
+                .find(|(letter, _)| ext.eq_ignore_ascii_case(letter))
+                .map(|(_, reason)| *reason)
+        };
+        let mut parts = self.name.split('-');
+        if let (Some(suite), Some(env)) = (parts.next(), parts.next()) {
+            if let Some(ext) = suite.strip_prefix("rv64u") {
+                if let Some(reason) = unimplemented(ext) {
+                    return Some(reason);
+                }
+                if env == "v" {
+                    return Some("no virtual memory");
+                }
+            }
+        }
+        let dirs: Vec<String> = self
+            .path
+            .parent()?
+            .iter()
+            .map(|dir| dir.to_string_lossy().to_string())
+            .collect();
+        dirs.windows(2)
+            .filter(|pair| pair[0].starts_with("rv64") && pair[0].ends_with("_m"))
+            .find_map(|pair| unimplemented(&pair[1]))
+    }
+}
+
+fn is_elf(path: &Path) -> Result<bool> {
+    let data = fs::read(path)?;
+    Ok(data.starts_with(b"\x7fELF"))
+}
+
+fn name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map_or(String::new(), |v| v.get_name().to_string())
+}
+
+impl ComplianceArgs {
+    fn cpu_modes(&self) -> Vec<CPUMode> {
+        if self.cpu_mode.is_empty() {
+            CPUMode::value_variants().to_vec()
+        } else {
+            self.cpu_mode.clone()
+        }
+    }
+
+    fn signature_path(&self, test: &Test, cpu_mode: &CPUMode) -> Option<PathBuf> {
+        let dir = self.signature_dir.as_ref()?;
+        Some(dir.join(format!("{}.{}.signature", test.name, name(cpu_mode))))
+    }
+
+    fn command_line(&self, test: &Test, cpu_mode: &CPUMode) -> Vec<String> {
+        let mut argv = vec![
+            "riscv-emulator".to_string(),
+            "-i".to_string(),
+            test.path.to_string_lossy().to_string(),
+            "-c".to_string(),
+            name(cpu_mode),
+            "--data-hazard-policy".to_string(),
+            "data-forward".to_string(),
+            "--control-policy".to_string(),
+            "always-not-taken".to_string(),
+            "--max-steps".to_string(),
+            self.max_steps.to_string(),
+        ];
+        if let Some(path) = self.signature_path(test, cpu_mode) {
+            argv.extend([
+                "--signature".to_string(),
+                path.to_string_lossy().to_string(),
+            ]);
+        }
+        argv.extend(self.options.iter().cloned());
+        argv
+    }
+
+    fn verdict(&self, test: &Test, cpu_mode: &CPUMode, stats: Option<&Stats>) -> Verdict {
+        let Some(stats) = stats else {
+            return Verdict::Error;
+        };
+        match stats.get("exit_code") {
+            Some(StatValue::Int(0)) => {}
+            Some(StatValue::Int(case)) => return Verdict::Fail(*case),
+            _ => return Verdict::Timeout,
+        }
+        let (Some(dir), Some(signature)) =
+            (&self.reference_dir, self.signature_path(test, cpu_mode))
+        else {
+            return Verdict::Pass;
+        };
+        let reference = dir.join(format!("{}.reference_output", test.name));
+        match (
+            fs::read_to_string(&reference),
+            fs::read_to_string(&signature),
+        ) {
+            (Ok(reference), Ok(signature)) if same_signature(&reference, &signature) => {
+                Verdict::Pass
+            }
+            (Ok(_), Ok(_)) => Verdict::SignatureMismatch,
+            (Err(e), _) => {
+                error!("Fail to read reference signature {reference:?}: {e}");
+                Verdict::Error
+            }
+            (_, Err(e)) => {
+                error!("Fail to read signature {signature:?}: {e}");
+                Verdict::Error
+            }
+        }
+    }
+}
+
+/// Whether two signatures hold the same words, ignoring case and blank lines.
+fn same_signature(reference: &str, signature: &str) -> bool {
+    let words = |text: &str| {
+        text.lines()
+            .map(|line| line.trim().to_ascii_lowercase())
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+    };
+    words(reference) == words(signature)
+}
+
+/// Write the signature of the program in `vm` to `path`, one little-endian
+/// 32-bit word per line.
+pub fn write_signature(vm: &VirtualMemory, info: &LoadElfInfo, path: &Path) -> Result<()> {
+    let (Some(begin), Some(end)) = (
+        info.symbol_addr("begin_signature"),
+        info.symbol_addr("end_signature"),
+    ) else {
+        return Err(Error::InvalidElf(
+            "no begin_signature and end_signature symbols".to_string(),
+        ));
+    };
+    if end < begin {
+        return Err(Error::InvalidElf(format!(
+            "end_signature {end:#x} is before begin_signature {begin:#x}"
+        )));
+    }
+    let Some(bytes) = vm.read_bytes(begin as usize, (end - begin) as usize) else {
+        return Err(Error::InvalidElf(format!(
+            "signature {begin:#x}..{end:#x} is outside the memory"
+        )));
+    };
+    fs::write(path, signature_text(bytes))?;
+    Ok(())
+}
+
+fn signature_text(bytes: &[u8]) -> String {
+    let mut text = String::new();
+    for chunk in bytes.chunks(4) {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        let _ = writeln!(text, "{:08x}", u32::from_le_bytes(word));
+    }
+    text
+}
+
+/// Run every test on every CPU model and print the table, returning whether
+/// every test that is not skipped passed.
+pub fn run_tests(args: &ComplianceArgs) -> Result<bool> {
+    let tests = tests(&args.inputs)?;
+    let cpu_modes = args.cpu_modes();
+    if let Some(dir) = &args.signature_dir {
+        fs::create_dir_all(dir)?;
+    }
+
+    let workers = sweep::workers(args.jobs, tests.len() * cpu_modes.len());
+    info!(
+        "{} tests on {} CPU models, on {workers} threads",
+        tests.len(),
+        cpu_modes.len()
+    );
+    let verdicts = verdicts(args, &tests, &cpu_modes, workers);
+
+    let markdown = to_markdown(&tests, &cpu_modes, &verdicts);
+    for line in markdown.lines() {
+        info!("{line}");
+    }
+    if let Some(path) = &args.markdown {
+        fs::write(path, &markdown)?;
+        info!("Compliance table written to {path}");
+    }
+    Ok(verdicts
+        .iter()
+        .flatten()
+        .all(|v| matches!(v, Verdict::Pass | Verdict::Skipped(_))))
+}
+
+/// Verdicts of every test (rows) on every CPU model (columns).
+fn verdicts(
+    args: &ComplianceArgs,
+    tests: &[Test],
+    cpu_modes: &[CPUMode],
+    workers: usize,
+) -> Vec<Vec<Verdict>> {
+    let mut command_lines = Vec::new();
+    for test in tests.iter().filter(|test| test.skip_reason().is_none()) {
+        for cpu_mode in cpu_modes {
+            command_lines.push(args.command_line(test, cpu_mode));
+        }
+    }
+    let mut stats = sweep::run_all(&command_lines, workers).into_iter();
+
+    let mut verdicts = Vec::new();
+    for test in tests {
+        if let Some(reason) = test.skip_reason() {
+            info!("Skip {}: {reason}", test.name);
+            verdicts.push(vec![Verdict::Skipped(reason); cpu_modes.len()]);
+            continue;
+        }
+        let row: Vec<Verdict> = cpu_modes
+            .iter()
+            .map(|cpu_mode| {
+                let stats = stats.next().flatten();
+                args.verdict(test, cpu_mode, stats.as_ref())
+            })
+            .collect();
+        verdicts.push(row);
+    }
+    verdicts
+}
+
+/// Table of the verdicts of every test on every CPU model, the number of
+/// passed tests of each model and the number of skipped tests.
+fn to_markdown(tests: &[Test], cpu_modes: &[CPUMode], verdicts: &[Vec<Verdict>]) -> String {
+    let mut table = String::new();
+    let header: Vec<String> = cpu_modes.iter().map(name).collect();
+    let _ = writeln!(table, "| test | {} |", header.join(" | "));
+    let _ = writeln!(table, "|{}", "---|".repeat(cpu_modes.len() + 1));
+    for (test, row) in std::iter::zip(tests, verdicts) {
+        let cells: Vec<String> = row.iter().map(Verdict::text).collect();
+        let _ = writeln!(table, "| {} | {} |", test.name, cells.join(" | "));
+    }
+    let passed: Vec<String> = (0..cpu_modes.len())
+        .map(|i| {
+            let n = verdicts
+                .iter()
+                .filter(|row| row[i] == Verdict::Pass)
+                .count();
+            format!("{n}/{}", tests.len())
+        })
+        .collect();
+    let _ = writeln!(table, "| passed | {} |", passed.join(" | "));
+    let skipped = verdicts
+        .iter()
+        .filter(|row| matches!(row.first(), Some(Verdict::Skipped(_))))
+        .count();
+    if skipped > 0 {
+        let cells = vec![skipped.to_string(); cpu_modes.len()];
+        let _ = writeln!(table, "| skipped | {} |", cells.join(" | "));
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+    use crate::Cli;
+
+    #[test]
+    fn runs_and_table() {
+        let cli = Cli::try_parse_from([
+            "riscv-emulator",
+            "compliance",
+            "-c",
+            "single,pipeline",
+            "--signature-dir",
+            "sig",
+            "test/riscv-tests/smoke/smoke",
+            "--",
+            "--control-policy",
+            "dynamic-predict",
+        ])
+        .unwrap();
+        let Some(crate::Command::Compliance(args)) = cli.command else {
+            panic!("not a compliance run");
+        };
+        let mut tests = tests(&args.inputs).unwrap();
+        assert_eq!(tests[0].name, "smoke");
+        assert_eq!(
+            args.command_line(&tests[0], &CPUMode::Pipeline)[9..],
+            [
+                "--max-steps",
+                "10000000",
+                "--signature",
+                "sig/smoke.pipeline.signature",
+                "--control-policy",
+                "dynamic-predict"
+            ]
+        );
+
+        let verdicts = vec![vec![Verdict::Pass, Verdict::Fail(3)]];
+        assert_eq!(
+            to_markdown(&tests, &args.cpu_modes(), &verdicts),
+            "| test | single | pipeline |\n\
+             |---|---|---|\n\
+             | smoke | PASS | FAIL (3) |\n\
+             | passed | 1/1 | 0/1 |\n"
+        );
+        let skipped = Test {
+            path: PathBuf::from("rv64uf-p-fadd"),
+            name: "rv64uf-p-fadd".to_string(),
+        };
+        let verdicts = vec![
+            vec![Verdict::Pass, Verdict::Fail(3)],
+            vec![Verdict::Skipped("no F extension"); 2],
+        ];
+        assert_eq!(
+            to_markdown(&[tests.remove(0), skipped], &args.cpu_modes(), &verdicts),
+            "| test | single | pipeline |\n\
+             |---|---|---|\n\
+             | smoke | PASS | FAIL (3) |\n\
+             | rv64uf-p-fadd | SKIPPED | SKIPPED |\n\
+             | passed | 1/2 | 0/2 |\n\
+             | skipped | 1 | 1 |\n"
+        );
+
+        // every mode by default, and a reference directory needs signatures
+        let cli = Cli::try_parse_from(["riscv-emulator", "compliance", "a.elf"]).unwrap();
+        let Some(crate::Command::Compliance(args)) = cli.command else {
+            panic!("not a compliance run");
+        };
+        assert_eq!(args.cpu_modes().len(), 5);
+        assert!(Cli::try_parse_from([
+            "riscv-emulator",
+            "compliance",
+            "--reference-dir",
+            "ref",
+            "a.elf"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn smoke_tests_end_through_tohost() {
+        let dir = std::env::temp_dir().join(format!("compliance-{}", std::process::id()));
+        let cli = Cli::try_parse_from([
+            "riscv-emulator",
+            "compliance",
+            "--signature-dir",
+            dir.to_str().unwrap(),
+            "test/riscv-tests/smoke",
+        ])
+        .unwrap();
+        let Some(crate::Command::Compliance(args)) = cli.command else {
+            panic!("not a compliance run");
+        };
+        let tests = tests(&args.inputs).unwrap();
+        let names: Vec<&str> = tests.iter().map(|test| test.name.as_str()).collect();
+        assert_eq!(names, ["smoke", "smoke-fail", "smoke-p"]);
+
+        fs::create_dir_all(&dir).unwrap();
+        let cpu_modes = args.cpu_modes();
+        let verdicts = verdicts(&args, &tests, &cpu_modes, 2);
+        let signature = fs::read_to_string(dir.join("smoke.pipeline.signature"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(verdicts[0], vec![Verdict::Pass; cpu_modes.len()]);
+        assert_eq!(verdicts[1], vec![Verdict::Fail(2); cpu_modes.len()]);
+        // machine-mode startup, user mode and ecall of the p environment
+        assert_eq!(verdicts[2], vec![Verdict::Pass; cpu_modes.len()]);
+        // the test stored 7 * 7 over the first word
+        assert_eq!(
+            signature.unwrap(),
+            "00000031\n00000001\n00000000\n00000000\n"
+        );
+    }
+
+    #[test]
+    fn skips_unimplemented_extensions() {
+        let reason = |path: &str| {
+            let path = PathBuf::from(path);
+            Test {
+                name: path.file_stem().unwrap().to_string_lossy().to_string(),
+                path,
+            }
+            .skip_reason()
+        };
+        assert_eq!(reason("build/rv64ui-p-add"), None);
+        assert_eq!(reason("build/rv64um-p-div"), None);
+        assert_eq!(reason("build/rv64ua-p-amoadd_w"), Some("no A extension"));
+        assert_eq!(reason("build/rv64ud-p-fadd"), Some("no D extension"));
+        assert_eq!(reason("build/rv64uc-p-rvc"), Some("no C extension"));
+        assert_eq!(reason("build/rv64ui-v-add"), Some("no virtual memory"));
+        assert_eq!(reason("work/rv64i_m/I/src/add-01.S/dut/my.elf"), None);
+        assert_eq!(
+            reason("work/rv64i_m/F/src/fadd_b1-01.S/dut/my.elf"),
+            Some("no F extension")
+        );
+        assert_eq!(reason("test/riscv-tests/smoke/smoke-p"), None);
+    }
+
+    #[test]
+    fn signature() {
+        assert_eq!(
+            signature_text(&[0x78, 0x56, 0x34, 0x12, 0xef, 0xbe, 0xad, 0xde, 1]),
+            "12345678\ndeadbeef\n00000001\n"
+        );
+        assert!(same_signature("DEADBEEF\n00000001\n", "deadbeef\n00000001"));
+        assert!(!same_signature("deadbeef\n", "deadbeef\n00000000\n"));
+    }
+}
@@ -0,0 +1,388 @@
+//! Options of a run read from a TOML configuration file.
+//!
+//! The file holds the same options as the command line. A key is the long
+//! name of an option, with `_` or `-`, and the keys may be grouped into
+//! sections: a key in a section is the option named after the section and
+//! the key if there is one, like `mode` in `[cpu]` for `--cpu-mode`, and the
+//! option named after the key otherwise. A table whose name is an option
+//! taking `key=value` lists, like `[fu_latency]` or `[icache]`, is that list.
+//! See `config/cpu.toml`.
+//!
+//! The options of the file are put before the command line, so that the
+//! options given on the command line override the file, and a flag set by
+//! the file is cleared by `--no-<flag>`. Before a subcommand, `--config` is
+//! handed to every run of the subcommand, after its `--`.
+
+use std::{fs, path::Path};
+
+use clap::{Arg, CommandFactory};
+
+use crate::{
+    error::{Error, Result},
+    Cli,
+};
+
+/// Insert the options of the file given by `--config` into `argv` and
+/// apply the `--no-<flag>` overrides.
+pub fn expand_args(argv: Vec<String>) -> Result<Vec<String>> {
+    let command = Cli::command();
+    let mut path = None;
+    // the arguments without `--config`
+    let mut rest = Vec::new();
+    let mut iter = argv.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            rest.push(arg.clone());
+            rest.extend(iter.cloned());
+            break;
+        } else if arg == "--config" {
+            path = iter.next().cloned();
+        } else if let Some(p) = arg.strip_prefix("--config=") {
+            path = Some(p.to_string());
+        } else {
+            rest.push(arg.clone());
+        }
+    }
+
+    let subcommand = rest
+        .first()
+        .is_some_and(|arg| command.find_subcommand(arg).is_some());
+    if subcommand {
+        let Some(path) = path else {
+            return Ok(argv);
+        };
+        // every run of the subcommand reads the file
+        let at = match rest.iter().position(|arg| arg == "--") {
+            Some(i) => i + 1,
+            None => {
+                rest.push("--".to_string());
+                rest.len()
+            }
+        };
+        rest.splice(at..at, ["--config".to_string(), path]);
+        let mut expanded = argv[..1].to_vec();
+        expanded.extend(rest);
+        return Ok(expanded);
+    }
+
+    let mut expanded = argv[..1].to_vec();
+    if let Some(path) = path {
+        let text = fs::read_to_string(&path)?;
+        let options = options_from_toml(&text)
+            .map_err(|e| Error::Config(format!("{}: {e}", Path::new(&path).display())))?;
+        expanded.extend(options);
+    }
+    expanded.extend(argv.into_iter().skip(1));
+    Ok(clear_flags(&command, expanded))
+}
+
+/// Drop the flags given before a `--no-<flag>`, and the `--no-<flag>`.
+fn clear_flags(command: &clap::Command, argv: Vec<String>) -> Vec<String> {
+    let mut args: Vec<String> = Vec::with_capacity(argv.len());
+    let mut iter = argv.into_iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            args.push(arg);
+            args.extend(iter);
+            break;
+        }
+        let flag = arg
+            .strip_prefix("--no-")
+            .and_then(|name| find(command, name))
+            .filter(|flag| !flag.get_action().takes_values());
+        let Some(flag) = flag else {
+            args.push(arg);
+            continue;
+        };
+        args = without_flag(command, flag, args);
+    }
+    args
+}
+
+/// Drop `flag` from `args`, only where it stands as a flag and not as the
+/// value of an option, also from a group of short flags like `-dv`.
+fn without_flag(command: &clap::Command, flag: &Arg, args: Vec<String>) -> Vec<String> {
+    let takes_value = |arg: Option<&Arg>| arg.is_some_and(|arg| arg.get_action().takes_values());
+    let mut kept = Vec::with_capacity(args.len());
+    // the previous argument is an option waiting for its value
+    let mut value = false;
+    for arg in args {
+        if value {
+            value = false;
+            kept.push(arg);
+        } else if let Some(name) = arg.strip_prefix("--") {
+            if name.contains('=') {
+                kept.push(arg);
+                continue;
+            }
+            if flag.get_long() == Some(name) {
+                continue;
+            }
+            value = takes_value(find(command, name));
+            kept.push(arg);
+        } else if let Some(group) = arg.strip_prefix('-').filter(|group| !group.is_empty()) {
+            let mut rest = String::from("-");
+            for (i, c) in group.char_indices() {
+                if flag.get_short() == Some(c) {
+                    continue;
+                }
+                rest.push(c);
+                let short = command
+                    .get_arguments()
+                    .find(|arg| arg.get_short() == Some(c));
+                if takes_value(short) {
+                    // the rest of the group is the value, or the next argument
+                    let attached = &group[i + c.len_utf8()..];
+                    rest.push_str(attached);
+                    value = attached.is_empty();
+                    break;
+                }
+            }
+            if rest != "-" {
+                kept.push(rest);
+            }
+        } else {
+            kept.push(arg);
+        }
+    }
+    kept
+}
+
+/// Command line options of a configuration file.
+pub fn options_from_toml(text: &str) -> std::result::Result<Vec<String>, String> {
+    let table: toml::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+    let command = Cli::command();
+    let mut options = Vec::new();
+    for (key, value) in &table {
+        match (value, find(&command, key)) {
+            (toml::Value::Table(section), None) => {
+                for (k, v) in section {
+                    let grouped = format!("{key}-{k}");
+                    let arg = find(&command, &grouped)
+                        .or_else(|| find(&command, k))
+                        .ok_or_else(|| format!("unknown key `{k}` in [{key}]"))?;
+                    push_option(&mut options, arg, v).map_err(|e| format!("[{key}] {e}"))?;
+                }
+            }
+            (_, Some(arg)) => push_option(&mut options, arg, value)?,
+            (_, None) => return Err(format!("unknown key `{key}`")),
+        }
+    }
+    Ok(options)
+}
+
+fn find<'c>(command: &'c clap::Command, key: &str) -> Option<&'c Arg> {
+    let long = key.replace('_', "-");
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(long.as_str()))
+}
+
+fn push_option(
+    options: &mut Vec<String>,
+    arg: &Arg,
+    value: &toml::Value,
+) -> std::result::Result<(), String> {
+    let long = arg.get_long().unwrap_or_default();
+    if long == "config" {
+        return Err("a configuration file cannot include another".to_string());
+    }
+    if !arg.get_action().takes_values() {
+        // a flag is set by `true`, and cleared by `--no-<flag>` after it
+        match value {
+            toml::Value::Boolean(true) => options.push(format!("--{long}")),
+            toml::Value::Boolean(false) => {}
+            _ => return Err(format!("`{long}` must be true or false")),
+        }
+        return Ok(());
+    }
+    let value = match value {
+        toml::Value::String(s) => s.clone(),
+        toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+            value.to_string()
+        }
+        // `key=value` list
+        toml::Value::Table(table) => table
+            .iter()
+            .map(|(k, v)| match v {
+                toml::Value::String(s) => format!("{k}={s}"),
+                v => format!("{k}={v}"),
+            })
+            .collect::<Vec<_>>()
+            .join(","),
+        _ => return Err(format!("unsupported value of `{long}`")),
+    };
+    options.push(format!("--{long}"));
+    options.push(value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+    use crate::multi_stage::cpu::{ControlPolicy, DataHazardPolicy};
+
+    #[test]
+    fn file_options() {
+        let options = options_from_toml(
+            r#"
+            input = "a.elf"
+            [cpu]
+            mode = "pipeline"
+            data_hazard_policy = "naive-stall"
+            control-policy = "always-not-taken"
+            ras_depth = 8
+            [fu_latency]
+            mul = 3
+            div-pipelined = true
+            [trace]
+            itrace = true
+            mtrace = false
+            format = "json"
+            [memory]
+            stack_size = "64K"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            options,
+            // keys are sorted
+            [
+                "--control-policy",
+                "always-not-taken",
+                "--data-hazard-policy",
+                "naive-stall",
+                "--cpu-mode",
+                "pipeline",
+                "--ras-depth",
+                "8",
+                "--fu-latency",
+                "div-pipelined=true,mul=3",
+                "--input",
+                "a.elf",
+                "--stack-size",
+                "64K",
+                "--trace-format",
+                "json",
+                "--itrace"
+            ]
+        );
+
+        // the command line overrides the file
+        let mut argv = vec!["riscv-emulator".to_string()];
+        argv.extend(options);
+        argv.extend(["--data-hazard-policy", "data-forward", "--itrace"].map(String::from));
+        let args = Cli::try_parse_from(argv).unwrap().run.unwrap();
+        assert_eq!(args.data_hazard_policy, Some(DataHazardPolicy::DataForward));
+        assert_eq!(args.control_policy, Some(ControlPolicy::AlwaysNotTaken));
+        assert_eq!(args.stack_size, 64 * 1024);
+        assert!(args.itrace);
+
+        assert!(options_from_toml("[cpu]\nfoo = 1").is_err());
+        assert!(options_from_toml("itrace = 1").is_err());
+        assert!(options_from_toml("config = \"a.toml\"").is_err());
+    }
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn command_line_overrides() {
+        // a flag of the file is cleared by a later --no-<flag>
+        let mut options = argv(&["riscv-emulator"]);
+        options.extend(options_from_toml("difftest = true\n[trace]\nitrace = true").unwrap());
+        options.extend(argv(&["-i", "a.elf", "-c", "single", "--no-difftest"]));
+        let expanded = clear_flags(&Cli::command(), options);
+        assert_eq!(
+            expanded,
+            argv(&["riscv-emulator", "--itrace", "-i", "a.elf", "-c", "single"])
+        );
+        let args = Cli::try_parse_from(expanded).unwrap().run.unwrap();
+        assert!(!args.difftest);
+        assert!(args.itrace);
+
+        // and set again by a flag after it
+        assert_eq!(
+            expand_args(argv(&["riscv-emulator", "-d", "--no-debug", "--debug"])).unwrap(),
+            argv(&["riscv-emulator", "--debug"])
+        );
+        // values of options and grouped short flags
+        assert_eq!(
+            clear_flags(
+                &Cli::command(),
+                argv(&[
+                    "riscv-emulator",
+                    "--input",
+                    "-d",
+                    "-d",
+                    "-dc",
+                    "single",
+                    "-id",
+                    "--no-debug"
+                ])
+            ),
+            argv(&["riscv-emulator", "--input", "-d", "-c", "single", "-id"])
+        );
+        assert_eq!(
+            clear_flags(
+                &Cli::command(),
+                argv(&[
+                    "riscv-emulator",
+                    "-i",
+                    "--debug",
+                    "--input=-d",
+                    "--no-debug"
+                ])
+            ),
+            argv(&["riscv-emulator", "-i", "--debug", "--input=-d"])
+        );
+        // options taking a value are left to the parser
+        assert_eq!(
+            expand_args(argv(&["riscv-emulator", "--no-input"])).unwrap(),
+            argv(&["riscv-emulator", "--no-input"])
+        );
+
+        // the runs of a subcommand read the file
+        assert_eq!(
+            expand_args(argv(&[
+                "riscv-emulator",
+                "--config",
+                "a.toml",
+                "sweep",
+                "a.elf"
+            ]))
+            .unwrap(),
+            argv(&[
+                "riscv-emulator",
+                "sweep",
+                "a.elf",
+                "--",
+                "--config",
+                "a.toml"
+            ])
+        );
+        assert_eq!(
+            expand_args(argv(&[
+                "riscv-emulator",
+                "--config=a.toml",
+                "compliance",
+                "t",
+                "--",
+                "--difftest"
+            ]))
+            .unwrap(),
+            argv(&[
+                "riscv-emulator",
+                "compliance",
+                "t",
+                "--",
+                "--config",
+                "a.toml",
+                "--difftest"
+            ])
+        );
+    }
+}
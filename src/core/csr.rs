@@ -0,0 +1,353 @@
+//! Machine-mode control and status registers and traps.
+//!
+//! The CPU models implement the machine and user privilege modes with the
+//! CSRs the `p` environment of riscv-tests sets up, and no interrupts, no
+//! supervisor mode and no virtual memory:
+//!
+//! + `mstatus` (writable `MIE`, `MPIE` and `MPP`), `misa` (RV64IMU, writes
+//!   ignored), `mie` (the machine interrupt enables), `mip` (zero), `mtvec`
+//!   (direct and vectored modes, exceptions go to the base), `mscratch`,
+//!   `mepc`, `mcause` and `mtval`;
+//! + `mvendorid`, `marchid`, `mimpid`, `mhartid` and `mconfigptr`, which
+//!   read zero, and the PMP registers, which read zero and ignore writes.
+//!
+//! Any other CSR, an access from user mode to a machine CSR, a write to a
+//! read-only CSR, `mret` from user mode and `sret` raise an illegal
+//! instruction exception. `ecall` raises an environment call from the
+//! current mode. A trap saves the PC in `mepc`, the cause in `mcause` and
+//! the faulting instruction in `mtval`, enters machine mode and continues
+//! at `mtvec`. A program that never set `mtvec` has no trap handler, so a
+//! trap ends its run with an error instead. `wfi`, `fence` and `fence.i`
+//! are no-ops here, the pipelined CPUs refetch after `fence.i`.
+
+use crate::{
+    core::insts::{funct3, imm_I, rs1, Inst64},
+    error::{Error, Result},
+};
+
+const MSTATUS: u16 = 0x300;
+const MISA: u16 = 0x301;
+const MIE: u16 = 0x304;
+const MTVEC: u16 = 0x305;
+const MSCRATCH: u16 = 0x340;
+const MEPC: u16 = 0x341;
+const MCAUSE: u16 = 0x342;
+const MTVAL: u16 = 0x343;
+const MIP: u16 = 0x344;
+const PMPCFG0: u16 = 0x3a0;
+const PMPCFG15: u16 = 0x3af;
+const PMPADDR0: u16 = 0x3b0;
+const PMPADDR63: u16 = 0x3ef;
+const MVENDORID: u16 = 0xf11;
+const MCONFIGPTR: u16 = 0xf15;
+
+const MSTATUS_MIE: u64 = 1 << 3;
+const MSTATUS_MPIE: u64 = 1 << 7;
+const MSTATUS_MPP_SHIFT: u64 = 11;
+const MSTATUS_MPP: u64 = 0b11 << MSTATUS_MPP_SHIFT;
+/// `UXL`, user mode is 64-bit
+const MSTATUS_UXL_64: u64 = 2 << 32;
+
+/// RV64 with the I, M and U extensions
+const MISA_VALUE: u64 = (2 << 62) | (1 << 8) | (1 << 12) | (1 << 20);
+/// `MSIE`, `MTIE` and `MEIE`
+const MIE_MASK: u64 = (1 << 3) | (1 << 7) | (1 << 11);
+
+const CAUSE_ILLEGAL_INSTRUCTION: u64 = 2;
+const CAUSE_USER_ECALL: u64 = 8;
+const CAUSE_MACHINE_ECALL: u64 = 11;
+
+/// Privilege mode of the hart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Privilege {
+    User = 0,
+    Machine = 3,
+}
+
+/// Result of a SYSTEM or MISC-MEM instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemOutcome {
+    /// Value written to `rd`, none if nothing is written or it trapped
+    pub rd_val: Option<u64>,
+    /// Address of the next instruction
+    pub next_pc: u64,
+    /// Whether it raised an exception
+    pub trapped: bool,
+}
+
+/// The CSRs and privilege mode of a hart.
+#[derive(Debug, Clone)]
+pub struct CsrFile {
+    privilege: Privilege,
+    mstatus: u64,
+    mie: u64,
+    mtvec: u64,
+    mscratch: u64,
+    mepc: u64,
+    mcause: u64,
+    mtval: u64,
+}
+
+impl Default for CsrFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Whether `op` runs through [`CsrFile::exec`]. The pipelined CPUs flush
+/// and refetch behind these instructions, as any of them may trap.
+pub fn serializes(op: Inst64) -> bool {
+    use Inst64::*;
+    matches!(
+        op,
+        csrrw | csrrs | csrrc | csrrwi | csrrsi | csrrci | ecall | mret | sret | wfi | fence_i
+    )
+}
+
+impl CsrFile {
+    /// A hart out of reset, in machine mode.
+    pub fn new() -> Self {
+        Self {
+            privilege: Privilege::Machine,
+            mstatus: MSTATUS_UXL_64,
+            mie: 0,
+            mtvec: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+        }
+    }
+
+    /// Run the instruction `op` (see [`serializes`]), encoded as `raw` at
+    /// `pc`, with `src1` the value of its `rs1` register.
+    pub fn exec(&mut self, op: Inst64, pc: u64, raw: u32, src1: u64) -> Result<SystemOutcome> {
+        use Inst64::*;
+        let next = |rd_val| SystemOutcome {
+            rd_val,
+            next_pc: pc.wrapping_add(4),
+            trapped: false,
+        };
+        match op {
+            csrrw | csrrs | csrrc | csrrwi | csrrsi | csrrci => {
+                let addr = imm_I(raw) as u16;
+                // the immediate forms take rs1 as a 5-bit unsigned value
+                let src = if funct3(raw) & 0b100 != 0 {
+                    rs1(raw) as u64
+                } else {
+                    src1
+                };
+                match self.csr_op(op, addr, src, rs1(raw) != 0) {
+                    Some(old) => Ok(next(Some(old))),
+                    None => self.trap(pc, CAUSE_ILLEGAL_INSTRUCTION, raw as u64),
+                }
+            }
+            ecall => {
+                let cause = match self.privilege {
+                    Privilege::User => CAUSE_USER_ECALL,
+                    Privilege::Machine => CAUSE_MACHINE_ECALL,
+                };
+                self.trap(pc, cause, 0)
+            }
+            mret if self.privilege == Privilege::Machine => Ok(SystemOutcome {
+                rd_val: None,
+                next_pc: self.mret(),
+                trapped: false,
+            }),
+            mret | sret => self.trap(pc, CAUSE_ILLEGAL_INSTRUCTION, raw as u64),
+            wfi | fence | fence_i => Ok(next(None)),
+            _ => unreachable!("{op:?} is not a SYSTEM instruction"),
+        }
+    }
+
+    /// Read, modify and write the CSR `addr`, returning its old value, or
+    /// none if the access is illegal. `csrrs` and `csrrc` do not write with
+    /// `x0` or a zero immediate as source, as `write` is false then.
+    fn csr_op(&mut self, op: Inst64, addr: u16, src: u64, write: bool) -> Option<u64> {
+        use Inst64::*;
+        // bits 9..8 are the lowest privilege of the CSR
+        if (addr >> 8) & 0b11 > self.privilege as u16 {
+            return None;
+        }
+        let old = self.read(addr)?;
+        let new = match op {
+            csrrw | csrrwi => src,
+            csrrs | csrrsi if write => old | src,
+            csrrc | csrrci if write => old & !src,
+            _ => return Some(old),
+        };
+        // bits 11..10 are 0b11 for read-only CSRs
+        if addr >> 10 == 0b11 {
+            return None;
+        }
+        self.write(addr, new);
+        Some(old)
+    }
+
+    fn read(&self, addr: u16) -> Option<u64> {
+        let value = match addr {
+            MSTATUS => self.mstatus,
+            MISA => MISA_VALUE,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => 0,
+            // only the even pmpcfg registers exist in RV64
+            PMPCFG0..=PMPCFG15 if addr.is_multiple_of(2) => 0,
+            PMPADDR0..=PMPADDR63 => 0,
+            MVENDORID..=MCONFIGPTR => 0,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    fn write(&mut self, addr: u16, value: u64) {
+        match addr {
+            MSTATUS => {
+                // MPP holds a mode the hart has
+                let mpp = match (value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT {
+                    3 => MSTATUS_MPP,
+                    _ => 0,
+                };
+                self.mstatus = (value & (MSTATUS_MIE | MSTATUS_MPIE)) | mpp | MSTATUS_UXL_64;
+            }
+            MIE => self.mie = value & MIE_MASK,
+            // modes above vectored are reserved
+            MTVEC => {
+                self.mtvec = if value & 0b11 > 1 {
+                    value & !0b11
+                } else {
+                    value
+                }
+            }
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !0b11,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            _ => {}
+        }
+    }
+
+    /// Take the exception `cause` of the instruction at `pc`.
+    fn trap(&mut self, pc: u64, cause: u64, tval: u64) -> Result<SystemOutcome> {
+        if self.mtvec == 0 {
+            return Err(Error::Execute(format!(
+                "exception {cause} at {pc:#x} without a trap handler in mtvec"
+            )));
+        }
+        self.mepc = pc;
+        self.mcause = cause;
+        self.mtval = tval;
+        let mpie = if self.mstatus & MSTATUS_MIE != 0 {
+            MSTATUS_MPIE
+        } else {
+            0
+        };
+        let mpp = (self.privilege as u64) << MSTATUS_MPP_SHIFT;
+        self.mstatus = (self.mstatus & !(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)) | mpie | mpp;
+        self.privilege = Privilege::Machine;
+        Ok(SystemOutcome {
+            rd_val: None,
+            next_pc: self.mtvec & !0b11,
+            trapped: true,
+        })
+    }
+
+    /// Return from a trap to `mepc`, in the mode saved in `MPP`.
+    fn mret(&mut self) -> u64 {
+        self.privilege = match (self.mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT {
+            3 => Privilege::Machine,
+            _ => Privilege::User,
+        };
+        let mie = if self.mstatus & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        // MPP is left at the least privileged mode
+        self.mstatus = (self.mstatus & !(MSTATUS_MIE | MSTATUS_MPP)) | mie | MSTATUS_MPIE;
+        self.mepc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC: u64 = 0x8000_0000;
+
+    /// `csr<op> rd, csr, rs1` with funct3 `funct3`
+    fn csr_inst(funct3: u32, csr: u32, rs1: u32, rd: u32) -> u32 {
+        (csr << 20) | (rs1 << 15) | (funct3 << 12) | (rd << 7) | 0b1110011
+    }
+
+    #[test]
+    fn reads_and_writes() {
+        let mut csr = CsrFile::new();
+        // csrrw a0, mscratch, a1
+        let raw = csr_inst(0b001, MSCRATCH as u32, 11, 10);
+        let outcome = csr.exec(Inst64::csrrw, PC, raw, 42).unwrap();
+        assert_eq!(outcome.rd_val, Some(0));
+        assert_eq!(outcome.next_pc, PC + 4);
+        // csrrsi a0, mscratch, 1
+        let raw = csr_inst(0b110, MSCRATCH as u32, 1, 10);
+        let outcome = csr.exec(Inst64::csrrsi, PC, raw, 0).unwrap();
+        assert_eq!(outcome.rd_val, Some(42));
+        assert_eq!(csr.mscratch, 43);
+        // csrr a0, misa reads a read-only CSR, csrw misa ignores the write
+        let raw = csr_inst(0b010, MISA as u32, 0, 10);
+        assert_eq!(
+            csr.exec(Inst64::csrrs, PC, raw, 0).unwrap().rd_val,
+            Some(MISA_VALUE)
+        );
+        // csrr a0, mhartid
+        let raw = csr_inst(0b010, 0xf14, 0, 10);
+        assert_eq!(csr.exec(Inst64::csrrs, PC, raw, 0).unwrap().rd_val, Some(0));
+    }
+
+    #[test]
+    fn traps_to_mtvec() {
+        let mut csr = CsrFile::new();
+        // without a trap handler the run fails
+        assert!(csr.exec(Inst64::ecall, PC, 0x73, 0).is_err());
+
+        csr.mtvec = 0x8000_0100;
+        // csrw satp, zero: no supervisor mode
+        let raw = csr_inst(0b001, 0x180, 0, 0);
+        let outcome = csr.exec(Inst64::csrrw, PC, raw, 0).unwrap();
+        let trap = SystemOutcome {
+            rd_val: None,
+            next_pc: 0x8000_0100,
+            trapped: true,
+        };
+        assert_eq!(outcome, trap);
+        assert_eq!((csr.mepc, csr.mcause, csr.mtval), (PC, 2, raw as u64));
+        // csrw mhartid, a0: read-only
+        let raw = csr_inst(0b001, 0xf14, 10, 0);
+        assert_eq!(
+            csr.exec(Inst64::csrrw, PC, raw, 1).unwrap().next_pc,
+            0x8000_0100
+        );
+
+        // mret to user mode, where ecall and machine CSRs trap
+        csr.mepc = PC + 0x40;
+        csr.mstatus = MSTATUS_UXL_64;
+        let outcome = csr.exec(Inst64::mret, PC, 0x30200073, 0).unwrap();
+        assert_eq!(outcome.next_pc, PC + 0x40);
+        assert_eq!(csr.privilege, Privilege::User);
+        let raw = csr_inst(0b010, MSCRATCH as u32, 0, 10);
+        let outcome = csr.exec(Inst64::csrrs, PC + 0x40, raw, 0).unwrap();
+        assert!(outcome.trapped);
+        assert_eq!(outcome.rd_val, None);
+        assert_eq!(csr.mcause, CAUSE_ILLEGAL_INSTRUCTION);
+        assert_eq!(csr.privilege, Privilege::Machine);
+        assert_eq!(csr.mstatus & MSTATUS_MPP, 0);
+
+        csr.exec(Inst64::mret, PC, 0x30200073, 0).unwrap();
+        csr.exec(Inst64::ecall, PC + 0x44, 0x73, 0).unwrap();
+        assert_eq!((csr.mepc, csr.mcause), (PC + 0x44, CAUSE_USER_ECALL));
+    }
+}
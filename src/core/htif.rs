@@ -0,0 +1,627 @@
+//! Host-target interface (HTIF) of riscv-tests, riscv-pk and Spike.
+//!
+//! A program talks to the host by writing a command to the `tohost` word:
+//! the device in bits 63..56, the command in bits 55..48 and the payload in
+//! bits 47..0. The host takes the command by clearing `tohost` and answers
+//! in the `fromhost` word with the device and command of the request.
+//!
+//! + Device 0 is the syscall proxy. A payload with the lowest bit set ends
+//!   the program with exit code `payload >> 1`, which is how riscv-tests
+//!   report pass (code 0) and fail (the number of the failed test). Any
+//!   other payload is the address of eight words holding a syscall number
+//!   and its arguments, and the return value replaces the number.
+//! + Device 1 is the console: command 1 writes the character in the
+//!   payload and is not answered, command 0 reads one from stdin, blocks
+//!   until there is one and answers it.
+//!
+//! The syscalls are the file syscalls riscv-pk forwards to the host, run on
+//! host files: `openat` (relative to the working directory, the path
+//! length includes the terminating nul), `close`, `lseek`, `read`, `write`,
+//! `pread64`, `pwrite64` and `fstat`, plus `exit`. Descriptors 0, 1 and 2
+//! are stdin, stdout and stderr. The other syscalls return `ENOSYS`, which
+//! includes the ones riscv-pk runs itself, like `brk` and `mmap`.
+//!
+//! Memory given by the program is bounds-checked, a syscall on a buffer
+//! outside the memory fails with `EFAULT`.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    ffi::OsStr,
+    fs::{File, Metadata, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::{
+            ffi::OsStrExt,
+            fs::{FileExt, MetadataExt, OpenOptionsExt},
+        },
+    },
+    path::Path,
+    rc::Rc,
+};
+
+use log::warn;
+
+use super::vm::VirtualMemory;
+use crate::{
+    commit_log::{MemAccess, RetireInfo},
+    elf::LoadElfInfo,
+};
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+
+const CONSOLE_READ: u64 = 0;
+const CONSOLE_WRITE: u64 = 1;
+
+const PAYLOAD_MASK: u64 = (1 << 48) - 1;
+
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_PREAD: u64 = 67;
+const SYS_PWRITE: u64 = 68;
+const SYS_FSTAT: u64 = 80;
+const SYS_EXIT: u64 = 93;
+const SYS_EXIT_GROUP: u64 = 94;
+
+const EIO: i64 = 5;
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOSYS: i64 = 38;
+
+const AT_FDCWD: i64 = -100;
+
+const O_ACCMODE: u64 = 0o3;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+/// Size of `struct stat` of the RISC-V Linux ABI.
+const STAT_SIZE: usize = 128;
+
+/// Effects of a syscall on the program, replayed by a following HTIF
+/// instead of running the syscall on the host again.
+#[derive(Debug, Clone, Default)]
+struct SyscallEffect {
+    ret: i64,
+    // bytes written to the memory of the program, and where
+    data: Option<(u64, Vec<u8>)>,
+}
+
+impl SyscallEffect {
+    fn ret(ret: i64) -> SyscallEffect {
+        SyscallEffect { ret, data: None }
+    }
+}
+
+#[derive(Debug)]
+pub struct Htif {
+    tohost: u64,
+    fromhost: Option<u64>,
+    exit_code: Option<u64>,
+    // host files opened by the program, by descriptor
+    files: HashMap<u64, File>,
+    // console input read so far, shared with a following HTIF which
+    // replays it
+    console_input: Rc<RefCell<VecDeque<u8>>>,
+    // effects of the syscalls run so far, shared with a following HTIF
+    // which replays them
+    syscall_effects: Rc<RefCell<VecDeque<SyscallEffect>>>,
+    // console output is dropped
+    quiet: bool,
+    // follows another HTIF, for the reference CPU of difftest: its console
+    // input is the one the other HTIF read and its syscalls take the effects
+    // of the ones the other HTIF ran
+    following: bool,
+}
+
+impl Htif {
+    /// The interface of a program with a `tohost` symbol, answering in
+    /// `fromhost` if the program has one.
+    pub fn from_elf_info(info: &LoadElfInfo) -> Option<Htif> {
+        let tohost = info.symbol_addr("tohost")?;
+        Some(Htif {
+            tohost,
+            fromhost: info.symbol_addr("fromhost"),
+            exit_code: None,
+            files: HashMap::new(),
+            console_input: Rc::default(),
+            syscall_effects: Rc::default(),
+            quiet: false,
+            following: false,
+        })
+    }
+
+    /// Follow the console and syscalls of `leader`, see
+    /// [`VirtualMemory::follow_htif`].
+    pub fn follow(&mut self, leader: &Htif) {
+        self.quiet = true;
+        self.following = true;
+        self.console_input = Rc::clone(&leader.console_input);
+        self.syscall_effects = Rc::clone(&leader.syscall_effects);
+    }
+
+    /// Whether a write to `addr` hands a command to the host.
+    pub fn is_tohost(&self, addr: u64) -> bool {
+        addr == self.tohost
+    }
+
+    /// Handle the command in `tohost`, after the program wrote it.
+    pub fn command(&mut self, vm: &mut VirtualMemory) {
+        let command: u64 = vm.host_mread(self.tohost as usize);
+        if command == 0 {
+            return;
+        }
+        vm.host_mwrite(self.tohost as usize, 0u64);
+
+        let device = command >> 56;
+        let cmd = (command >> 48) & 0xff;
+        let payload = command & PAYLOAD_MASK;
+        match (device, cmd) {
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => self.exit_code = Some(payload >> 1),
+            (DEVICE_SYSCALL, 0) => {
+                self.syscall(vm, payload);
+                self.respond(vm, command, 1);
+            }
+            // like Spike, a console write is not answered: riscv-pk asserts
+            // that no write answer shows up in `fromhost`
+            (DEVICE_CONSOLE, CONSOLE_WRITE) => self.output(1, &[payload as u8]),
+            (DEVICE_CONSOLE, CONSOLE_READ) => match self.input() {
+                Some(ch) => self.respond(vm, command, 0x100 | ch as u64),
+                None => warn!("HTIF console read at the end of the input"),
+            },
+            _ => warn!("Unsupported HTIF command {command:#x}: device {device}, command {cmd}"),
+        }
+    }
+
+    /// Read one byte of the console, blocking until there is one.
+    fn input(&self) -> Option<u8> {
+        let mut input = self.console_input.borrow_mut();
+        if self.following {
+            return input.pop_front();
+        }
+        let mut ch = [0];
+        match io::stdin().read(&mut ch) {
+            Ok(1) => {
+                // kept for the following HTIF only
+                if Rc::strong_count(&self.console_input) > 1 {
+                    input.push_back(ch[0]);
+                }
+                Some(ch[0])
+            }
+            _ => None,
+        }
+    }
+
+    /// Run the syscall in the eight words at `magic_mem`.
+    fn syscall(&mut self, vm: &mut VirtualMemory, magic_mem: u64) {
+        let args: Option<Vec<u64>> = (0..8)
+            .map(|i| vm.host_try_mread(magic_mem as usize + i * 8))
+            .collect();
+        let Some(args) = args else {
+            warn!("HTIF syscall at {magic_mem:#x} outside the memory");
+            return;
+        };
+        let effect = match args[0] {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.exit_code = Some(args[1]);
+                SyscallEffect::default()
+            }
+            n if self.following => self
+                .syscall_effects
+                .borrow_mut()
+                .pop_front()
+                .unwrap_or_else(|| {
+                    warn!("HTIF syscall {n} not run by the followed HTIF");
+                    SyscallEffect::ret(-ENOSYS)
+                }),
+            n => {
+                let effect = self.host_syscall(vm, n, &args[1..]);
+                // kept for the following HTIF only
+                if Rc::strong_count(&self.syscall_effects) > 1 {
+                    self.syscall_effects.borrow_mut().push_back(effect.clone());
+                }
+                effect
+            }
+        };
+        if let Some((addr, bytes)) = &effect.data {
+            vm.host_write_bytes(*addr as usize, bytes);
+        }
+        vm.host_mwrite(magic_mem as usize, effect.ret as u64);
+    }
+
+    /// Run syscall `n` with `args` on the host.
+    fn host_syscall(&mut self, vm: &VirtualMemory, n: u64, args: &[u64]) -> SyscallEffect {
+        let buf = args[1] as usize;
+        let len = args[2] as usize;
+        match n {
+            SYS_OPENAT => {
+                let Some(path) = vm.read_bytes(buf, len) else {
+                    return SyscallEffect::ret(-EFAULT);
+                };
+                let path = Path::new(OsStr::from_bytes(
+                    path.split(|&b| b == 0).next().unwrap_or_default(),
+                ));
+                if args[0] as i64 != AT_FDCWD && !path.is_absolute() {
+                    return SyscallEffect::ret(-EBADF);
+                }
+                let ret = open_options(args[3], args[4] as u32)
+                    .open(path)
+                    .map(|file| {
+                        let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+                        self.files.insert(fd, file);
+                        fd as i64
+                    });
+                SyscallEffect::ret(ret.unwrap_or_else(|e| -errno(&e)))
+            }
+            SYS_CLOSE => SyscallEffect::ret(match self.files.remove(&args[0]) {
+                Some(_) => 0,
+                // the console stays open for the host
+                None if args[0] <= 2 => 0,
+                None => -EBADF,
+            }),
+            SYS_LSEEK => {
+                let Some(file) = self.files.get_mut(&args[0]) else {
+                    return SyscallEffect::ret(-EBADF);
+                };
+                let pos = match args[2] {
+                    0 => SeekFrom::Start(args[1]),
+                    1 => SeekFrom::Current(args[1] as i64),
+                    2 => SeekFrom::End(args[1] as i64),
+                    _ => return SyscallEffect::ret(-EINVAL),
+                };
+                SyscallEffect::ret(file.seek(pos).map_or_else(|e| -errno(&e), |pos| pos as i64))
+            }
+            SYS_READ | SYS_PREAD => {
+                if vm.read_bytes(buf, len).is_none() {
+                    return SyscallEffect::ret(-EFAULT);
+                }
+                let mut bytes = vec![0; len];
+                let read = match (args[0], self.files.get_mut(&args[0])) {
+                    (_, Some(file)) if n == SYS_PREAD => file.read_at(&mut bytes, args[3]),
+                    (_, Some(file)) => file.read(&mut bytes),
+                    (0, None) if n == SYS_READ => io::stdin().read(&mut bytes),
+                    _ => return SyscallEffect::ret(-EBADF),
+                };
+                match read {
+                    Ok(read) => {
+                        bytes.truncate(read);
+                        SyscallEffect {
+                            ret: read as i64,
+                            data: Some((args[1], bytes)),
+                        }
+                    }
+                    Err(e) => SyscallEffect::ret(-errno(&e)),
+                }
+            }
+            SYS_WRITE | SYS_PWRITE => {
+                let Some(bytes) = vm.read_bytes(buf, len) else {
+                    return SyscallEffect::ret(-EFAULT);
+                };
+                let written = match (args[0], self.files.get_mut(&args[0])) {
+                    (_, Some(file)) if n == SYS_PWRITE => file.write_at(bytes, args[3]),
+                    (_, Some(file)) => file.write(bytes),
+                    (fd @ (1 | 2), None) if n == SYS_WRITE => {
+                        self.output(fd, bytes);
+                        Ok(len)
+                    }
+                    _ => return SyscallEffect::ret(-EBADF),
+                };
+                SyscallEffect::ret(written.map_or_else(|e| -errno(&e), |n| n as i64))
+            }
+            SYS_FSTAT => {
+                if vm.read_bytes(buf, STAT_SIZE).is_none() {
+                    return SyscallEffect::ret(-EFAULT);
+                }
+                let metadata = match (args[0], self.files.get(&args[0])) {
+                    (_, Some(file)) => file.metadata(),
+                    (0, None) => console_metadata(io::stdin().as_fd()),
+                    (1, None) => console_metadata(io::stdout().as_fd()),
+                    (2, None) => console_metadata(io::stderr().as_fd()),
+                    _ => return SyscallEffect::ret(-EBADF),
+                };
+                match metadata {
+                    Ok(metadata) => SyscallEffect {
+                        ret: 0,
+                        data: Some((buf as u64, stat(&metadata))),
+                    },
+                    Err(e) => SyscallEffect::ret(-errno(&e)),
+                }
+            }
+            n => {
+                warn!("Unsupported syscall {n} through HTIF");
+                SyscallEffect::ret(-ENOSYS)
+            }
+        }
+    }
+
+    fn respond(&self, vm: &mut VirtualMemory, command: u64, response: u64) {
+        if let Some(fromhost) = self.fromhost {
+            let value = (command & !PAYLOAD_MASK) | (response & PAYLOAD_MASK);
+            vm.host_mwrite(fromhost as usize, value);
+        }
+    }
+
+    fn output(&self, fd: u64, bytes: &[u8]) {
+        if self.quiet {
+            return;
+        }
+        let _ = if fd == 2 {
+            io::stderr().write_all(bytes)
+        } else {
+            let mut stdout = io::stdout();
+            stdout.write_all(bytes).and_then(|_| stdout.flush())
+        };
+    }
+
+    /// Exit code of the program if `retired` wrote the exit command.
+    pub fn exit_code(&self, retired: Option<&RetireInfo>) -> Option<u64> {
+        match retired?.mem {
+            Some(MemAccess::Write { addr, .. }) if addr == self.tohost => self.exit_code,
+            _ => None,
+        }
+    }
+
+    /// Exit code written to `tohost`, if the program has exited.
+    pub fn exited(&self) -> Option<u64> {
+        self.exit_code
+    }
+}
+
+/// Options of `open` for the `openat` flags of the RISC-V Linux ABI.
+fn open_options(flags: u64, mode: u32) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    match flags & O_ACCMODE {
+        O_WRONLY => options.write(true),
+        O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    options
+        .append(flags & O_APPEND != 0)
+        .truncate(flags & O_TRUNC != 0)
+        .mode(mode);
+    if flags & O_CREAT != 0 {
+        if flags & O_EXCL != 0 {
+            options.create_new(true);
+        } else {
+            options.create(true);
+        }
+    }
+    options
+}
+
+fn console_metadata(fd: BorrowedFd) -> io::Result<Metadata> {
+    File::from(fd.try_clone_to_owned()?).metadata()
+}
+
+/// `struct stat` of the RISC-V Linux ABI.
+fn stat(metadata: &Metadata) -> Vec<u8> {
+    let mut stat = Vec::with_capacity(STAT_SIZE);
+    stat.extend(metadata.dev().to_le_bytes());
+    stat.extend(metadata.ino().to_le_bytes());
+    stat.extend(metadata.mode().to_le_bytes());
+    stat.extend((metadata.nlink() as u32).to_le_bytes());
+    stat.extend(metadata.uid().to_le_bytes());
+    stat.extend(metadata.gid().to_le_bytes());
+    stat.extend(metadata.rdev().to_le_bytes());
+    stat.extend(0u64.to_le_bytes());
+    stat.extend(metadata.size().to_le_bytes());
+    stat.extend((metadata.blksize() as u32).to_le_bytes());
+    stat.extend(0u32.to_le_bytes());
+    stat.extend(metadata.blocks().to_le_bytes());
+    for (secs, nsecs) in [
+        (metadata.atime(), metadata.atime_nsec()),
+        (metadata.mtime(), metadata.mtime_nsec()),
+        (metadata.ctime(), metadata.ctime_nsec()),
+    ] {
+        stat.extend(secs.to_le_bytes());
+        stat.extend(nsecs.to_le_bytes());
+    }
+    stat.resize(STAT_SIZE, 0);
+    stat
+}
+
+/// Error number of `e` for the program, the host numbers are the ones of
+/// the RISC-V Linux ABI.
+fn errno(e: &io::Error) -> i64 {
+    e.raw_os_error().map_or(EIO, i64::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trace::Tracer;
+
+    const TOHOST: u64 = 0x100;
+    const FROMHOST: u64 = 0x140;
+
+    fn send(htif: &mut Htif, vm: &mut VirtualMemory, command: u64) {
+        vm.host_mwrite(TOHOST as usize, command);
+        htif.command(vm);
+        assert_eq!(vm.host_mread::<u64>(TOHOST as usize), 0);
+    }
+
+    fn htif(quiet: bool) -> Htif {
+        Htif {
+            tohost: TOHOST,
+            fromhost: Some(FROMHOST),
+            exit_code: None,
+            files: HashMap::new(),
+            console_input: Rc::default(),
+            syscall_effects: Rc::default(),
+            quiet,
+            following: false,
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let mut vm = VirtualMemory::new(0x1000, Tracer::disabled());
+        let mut htif = htif(true);
+
+        // console write, not answered
+        send(&mut htif, &mut vm, 1 << 56 | 1 << 48 | b'a' as u64);
+        assert_eq!(vm.host_mread::<u64>(FROMHOST as usize), 0);
+
+        // write(1, buf, 5) through the syscall proxy
+        for (i, arg) in [SYS_WRITE, 1, 0x200, 5].into_iter().enumerate() {
+            vm.host_mwrite(0x400 + i * 8, arg);
+        }
+        send(&mut htif, &mut vm, 0x400);
+        assert_eq!(vm.host_mread::<u64>(0x400), 5);
+        assert_eq!(vm.host_mread::<u64>(FROMHOST as usize), 1);
+        vm.host_mwrite(0x400, 1234u64);
+        send(&mut htif, &mut vm, 0x400);
+        assert_eq!(vm.host_mread::<u64>(0x400), -ENOSYS as u64);
+        assert_eq!(htif.exited(), None);
+
+        // exit(7)
+        vm.host_mwrite(0x400, SYS_EXIT);
+        vm.host_mwrite(0x408, 7u64);
+        send(&mut htif, &mut vm, 0x400);
+        assert_eq!(htif.exited(), Some(7));
+
+        // riscv-tests: test 3 failed
+        send(&mut htif, &mut vm, 3 << 1 | 1);
+        assert_eq!(htif.exited(), Some(3));
+
+        let store = |addr| RetireInfo {
+            pc: 0x80000000,
+            raw_inst: 0,
+            reg_write: None,
+            mem: Some(MemAccess::Write {
+                addr,
+                size: 4,
+                value: 0,
+            }),
+        };
+        assert_eq!(htif.exit_code(Some(&store(TOHOST))), Some(3));
+        assert_eq!(htif.exit_code(Some(&store(TOHOST + 4))), None);
+        assert_eq!(htif.exit_code(None), None);
+    }
+
+    /// Run the syscall `args` through the eight words at 0x400.
+    fn syscall(htif: &mut Htif, vm: &mut VirtualMemory, args: &[u64]) -> i64 {
+        for (i, arg) in args.iter().enumerate() {
+            vm.host_mwrite(0x400 + i * 8, *arg);
+        }
+        send(htif, vm, 0x400);
+        vm.host_mread::<u64>(0x400) as i64
+    }
+
+    #[test]
+    fn file_syscalls() {
+        let path = std::env::temp_dir().join(format!("htif-{}", std::process::id()));
+        let mut vm = VirtualMemory::new(0x1000, Tracer::disabled());
+        let mut htif = htif(false);
+        let mut reference = self::htif(false);
+        reference.follow(&htif);
+
+        // openat(AT_FDCWD, path, len, O_RDWR | O_CREAT | O_TRUNC, 0644)
+        let name = path.as_os_str().as_bytes();
+        for (i, b) in name.iter().chain([&0]).enumerate() {
+            vm.host_mwrite(0x600 + i, *b);
+        }
+        let open = [
+            SYS_OPENAT,
+            AT_FDCWD as u64,
+            0x600,
+            name.len() as u64 + 1,
+            O_RDWR | O_CREAT | O_TRUNC,
+            0o644,
+        ];
+        let fd = syscall(&mut htif, &mut vm, &open);
+        assert_eq!(fd, 3);
+        for (i, b) in b"hello".iter().enumerate() {
+            vm.host_mwrite(0x200 + i, *b);
+        }
+        assert_eq!(syscall(&mut htif, &mut vm, &[SYS_WRITE, 3, 0x200, 5]), 5);
+        assert_eq!(syscall(&mut htif, &mut vm, &[SYS_LSEEK, 3, 1, 0]), 1);
+        assert_eq!(syscall(&mut htif, &mut vm, &[SYS_READ, 3, 0x300, 8]), 4);
+        assert_eq!(vm.read_bytes(0x300, 4), Some(&b"ello"[..]));
+        assert_eq!(syscall(&mut htif, &mut vm, &[SYS_PREAD, 3, 0x300, 3, 2]), 3);
+        assert_eq!(vm.read_bytes(0x300, 3), Some(&b"llo"[..]));
+        assert_eq!(syscall(&mut htif, &mut vm, &[SYS_FSTAT, 3, 0x800]), 0);
+        // st_size
+        assert_eq!(vm.host_mread::<u64>(0x830), 5);
+        assert_eq!(syscall(&mut htif, &mut vm, &[SYS_CLOSE, 3]), 0);
+        assert_eq!(syscall(&mut htif, &mut vm, &[SYS_CLOSE, 3]), -EBADF);
+        assert_eq!(
+            syscall(&mut htif, &mut vm, &[SYS_READ, 3, 0x300, 1]),
+            -EBADF
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // the reference replays the effects without touching the file
+        let mut ref_vm = VirtualMemory::new(0x1000, Tracer::disabled());
+        assert_eq!(syscall(&mut reference, &mut ref_vm, &open), 3);
+        assert_eq!(
+            syscall(&mut reference, &mut ref_vm, &[SYS_WRITE, 3, 0x200, 5]),
+            5
+        );
+        assert_eq!(
+            syscall(&mut reference, &mut ref_vm, &[SYS_LSEEK, 3, 1, 0]),
+            1
+        );
+        assert_eq!(
+            syscall(&mut reference, &mut ref_vm, &[SYS_READ, 3, 0x300, 8]),
+            4
+        );
+        assert_eq!(ref_vm.read_bytes(0x300, 4), Some(&b"ello"[..]));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn guest_memory_is_checked() {
+        let mut vm = VirtualMemory::new(0x1000, Tracer::disabled());
+        let mut htif = htif(true);
+
+        // write(1, buf, len) past the end of the memory, or wrapping around
+        for (buf, len) in [(0xff0, 0x100), (u64::MAX, 2)] {
+            for (i, arg) in [SYS_WRITE, 1, buf, len].into_iter().enumerate() {
+                vm.host_mwrite(0x400 + i * 8, arg);
+            }
+            send(&mut htif, &mut vm, 0x400);
+            assert_eq!(vm.host_mread::<u64>(0x400), -EFAULT as u64);
+        }
+
+        // a syscall block partly or entirely outside the memory is ignored
+        for magic_mem in [0xfe0, 0xffff_0000_0000] {
+            vm.host_mwrite(FROMHOST as usize, 0u64);
+            send(&mut htif, &mut vm, magic_mem);
+            assert_eq!(vm.host_mread::<u64>(FROMHOST as usize), 1);
+        }
+        assert_eq!(htif.exited(), None);
+    }
+
+    #[test]
+    fn replay_console_input() {
+        let dut = htif(false);
+        let mut reference = htif(false);
+        reference.follow(&dut);
+        // as read from stdin by the HTIF of the CPU under test
+        dut.console_input.borrow_mut().extend(b"hi");
+
+        let mut vm = VirtualMemory::new(0x1000, Tracer::disabled());
+        let read = 1 << 56;
+        for ch in [b'h', b'i'] {
+            send(&mut reference, &mut vm, read);
+            assert_eq!(
+                vm.host_mread::<u64>(FROMHOST as usize),
+                read | 0x100 | ch as u64
+            );
+        }
+        // nothing left to replay, no answer
+        vm.host_mwrite(FROMHOST as usize, 0u64);
+        send(&mut reference, &mut vm, read);
+        assert_eq!(vm.host_mread::<u64>(FROMHOST as usize), 0);
+    }
+}
@@ -352,6 +352,28 @@ macro_rules! pinst {
             crate::core::reg::REGNAME[$t2 as usize],
         )
     };
+    // CSR
+    ($pc:ident, $inst:tt, $rd:ident, $csr:ident=>csr, $rs1:ident) => {
+        format!(
+            "{:8x}:\t{}\t{},{:#x},{}",
+            $pc,
+            stringify!($inst),
+            crate::core::reg::REGNAME[$rd as usize],
+            $csr,
+            crate::core::reg::REGNAME[$rs1 as usize]
+        )
+    };
+    // CSR with an immediate in rs1
+    ($pc:ident, $inst:tt, $rd:ident, $csr:ident=>csr, $zimm:ident=>zimm) => {
+        format!(
+            "{:8x}:\t{}\t{},{:#x},{}",
+            $pc,
+            stringify!($inst),
+            crate::core::reg::REGNAME[$rd as usize],
+            $csr,
+            $zimm
+        )
+    };
 }
 
 pub struct ExecInternal {
@@ -472,6 +494,91 @@ pub fn get_high_64_bit(x: u128) -> u64 {
     ((x & MASK) >> 64) as u64
 }
 
+/// Assembly of a decoded instruction located at `pc`.
+pub fn format_inst(pc: u64, alu_op: Inst64, rd: u8, rs1: u8, rs2: u8, imm: u64) -> String {
+    use Inst64::*;
+    let msg = match alu_op {
+        noop => pinst!(pc, noop),
+        add => pinst!(pc, add, rd, rs1, rs2),
+        addi => pinst!(pc, addi, rd, rs1, imm=>imm),
+        addiw => pinst!(pc, addiw, rd, rs1, imm=>imm),
+        addw => pinst!(pc, addw, rd, rs1, rs2),
+        and => pinst!(pc, and, rd, rs1, rs2),
+        andi => pinst!(pc, andi, rd, rs1, imm=>imm),
+        auipc => pinst!(pc, auipc, rd, imm=>imm),
+        beq => pinst!(pc, beq, rs1, rs2, imm=>offset),
+        bge => pinst!(pc, bge, rs1, rs2, imm=>offset),
+        bgeu => pinst!(pc, bgeu, rs1, rs2, imm=>offset),
+        blt => pinst!(pc, blt, rs1, rs2, imm=>offset),
+        bltu => pinst!(pc, bltu, rs1, rs2, imm=>offset),
+        bne => pinst!(pc, bne, rs1, rs2, imm=>offset),
+        csrrc => pinst!(pc, csrrc, rd, imm=>csr, rs1),
+        csrrci => pinst!(pc, csrrci, rd, imm=>csr, rs1=>zimm),
+        csrrs => pinst!(pc, csrrs, rd, imm=>csr, rs1),
+        csrrsi => pinst!(pc, csrrsi, rd, imm=>csr, rs1=>zimm),
+        csrrw => pinst!(pc, csrrw, rd, imm=>csr, rs1),
+        csrrwi => pinst!(pc, csrrwi, rd, imm=>csr, rs1=>zimm),
+        div => pinst!(pc, div, rd, rs1, rs2),
+        divu => pinst!(pc, divu, rd, rs1, rs2),
+        divuw => pinst!(pc, divuw, rd, rs1, rs2),
+        divw => pinst!(pc, divw, rd, rs1, rs2),
+        ebreak => pinst!(pc, ebreak),
+        ecall => pinst!(pc, ecall),
+        fence => pinst!(pc, fence),
+        fence_i => format!("{:8x}:\tfence.i", pc),
+        jal => pinst!(pc, jal, rd, imm=>offset),
+        jalr => pinst!(pc, jalr, rd, imm(rs1)),
+        lb => pinst!(pc, lb, rd, imm(rs1)),
+        lbu => pinst!(pc, lbu, rd, imm(rs1)),
+        ld => pinst!(pc, ld, rd, imm(rs1)),
+        lh => pinst!(pc, lh, rd, imm(rs1)),
+        lhu => pinst!(pc, lhu, rd, imm(rs1)),
+        lui => pinst!(pc, lui, rd, imm=>imm),
+        lw => pinst!(pc, lw, rd, imm(rs1)),
+        lwu => pinst!(pc, lwu, rd, imm(rs1)),
+        mret => pinst!(pc, mret),
+        mul => pinst!(pc, mul, rd, rs1, rs2),
+        mulh => pinst!(pc, mulh, rd, rs1, rs2),
+        mulhsu => pinst!(pc, mulhsu, rd, rs1, rs2),
+        mulhu => pinst!(pc, mulhu, rd, rs1, rs2),
+        mulw => pinst!(pc, mulw, rd, rs1, rs2),
+        or => pinst!(pc, or, rd, rs1, rs2),
+        ori => pinst!(pc, ori, rd, rs1, imm=>imm),
+        rem => pinst!(pc, rem, rd, rs1, rs2),
+        remu => pinst!(pc, remu, rd, rs1, rs2),
+        remuw => pinst!(pc, remuw, rd, rs1, rs2),
+        remw => pinst!(pc, remw, rd, rs1, rs2),
+        sb => pinst!(pc, sb, rs2, imm(rs1)),
+        sd => pinst!(pc, sd, rs2, imm(rs1)),
+        sh => pinst!(pc, sh, rs2, imm(rs1)),
+        sll => pinst!(pc, sll, rd, rs1, rs2),
+        slli => pinst!(pc, slli, rd, rs1, imm=>imm),
+        slliw => pinst!(pc, slliw, rd, rs1, imm=>imm),
+        sllw => pinst!(pc, sllw, rd, rs1, rs2),
+        slt => pinst!(pc, slt, rd, rs1, rs2),
+        slti => pinst!(pc, slti, rd, rs1, imm=>imm),
+        sltiu => pinst!(pc, sltiu, rd, rs1, imm=>imm),
+        sltu => pinst!(pc, sltu, rd, rs1, rs2),
+        sra => pinst!(pc, sra, rd, rs1, rs2),
+        srai => pinst!(pc, srai, rd, rs1, imm=>imm),
+        sraiw => pinst!(pc, sraiw, rd, rs1, imm=>imm),
+        sraw => pinst!(pc, sraw, rd, rs1, rs2),
+        sret => pinst!(pc, sret),
+        srl => pinst!(pc, srl, rd, rs1, rs2),
+        srli => pinst!(pc, srli, rd, rs1, imm=>imm),
+        srliw => pinst!(pc, srliw, rd, rs1, imm=>imm),
+        srlw => pinst!(pc, srlw, rd, rs1, rs2),
+        sub => pinst!(pc, sub, rd, rs1, rs2),
+        subw => pinst!(pc, subw, rd, rs1, rs2),
+        sw => pinst!(pc, sw, rs2, imm(rs1)),
+        wfi => pinst!(pc, wfi),
+        xor => pinst!(pc, xor, rd, rs1, rs2),
+        xori => pinst!(pc, xori, rd, rs1, imm=>imm),
+        _ => format!("Unknown inst {:?}", alu_op),
+    };
+    msg
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,4 +1,6 @@
+pub mod csr;
+pub mod htif;
 pub mod insts;
 pub mod reg;
-pub mod vm;
 pub mod utils;
+pub mod vm;
@@ -1,34 +1,54 @@
 use std::{
-    fmt::Display,
+    mem::size_of,
     ptr::{read_volatile, write_volatile},
 };
 
-use log::trace;
-
+use super::htif::Htif;
 use crate::{
+    commit_log::RetireInfo,
     elf::LoadElfInfo,
     error::{Error, Result},
+    trace::{TraceEvent, Tracer},
 };
 
 const PROTECT_SIZE: usize = 1 * 1024 * 1024; // 1 MiB, for separation of stack
 const STACK_SIZE: usize = 8 * 1024 * 1024; // 8 MiB, for the stack
 
+/// Sizes of the regions after the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+    /// Gap between the program and the stack
+    pub protect_size: usize,
+    pub stack_size: usize,
+}
+
+impl Default for MemoryLayout {
+    fn default() -> Self {
+        Self {
+            protect_size: PROTECT_SIZE,
+            stack_size: STACK_SIZE,
+        }
+    }
+}
+
 /// For now, we view virtual memory as a continuous bytes array.
 #[derive(Debug)]
 pub struct VirtualMemory {
     ld_start: usize, // vaddr where the code starts
     mm: Vec<u8>,
-    mtrace: bool,
+    mtrace: Tracer,
+    htif: Option<Htif>,
 }
 
 impl VirtualMemory {
-    pub fn new(size: usize, mtrace: bool) -> VirtualMemory {
+    pub fn new(size: usize, mtrace: Tracer) -> VirtualMemory {
         let mut mm = Vec::with_capacity(size);
         mm.resize(size, 0);
         VirtualMemory {
             ld_start: 0, // default to 0, but usually not what the case is.
             mm,
             mtrace,
+            htif: None,
         }
     }
 
@@ -37,13 +57,18 @@ impl VirtualMemory {
         self.mm.clear();
     }
 
-    pub fn from_elf_info(info: &LoadElfInfo, mtrace: bool) -> VirtualMemory {
+    pub fn from_elf_info(
+        info: &LoadElfInfo,
+        layout: MemoryLayout,
+        mtrace: Tracer,
+    ) -> VirtualMemory {
         let prog_size = (info.max_vaddr() - info.min_vaddr()) as usize;
 
-        let tot_size = prog_size + PROTECT_SIZE + STACK_SIZE;
+        let tot_size = prog_size + layout.protect_size + layout.stack_size;
 
         let mut vm = VirtualMemory::new(tot_size, mtrace);
         vm.ld_start = info.min_vaddr();
+        vm.htif = Htif::from_elf_info(info);
         // debug!("vm.ld_start = {:#x}", vm.ld_start);
 
         for (vm_range, file_range) in std::iter::zip(info.vm_ranges(), info.file_ranges()) {
@@ -76,11 +101,15 @@ impl VirtualMemory {
 
     /// Read a value from a virtual memory address.
     #[inline(always)]
-    pub fn mread<T: Sized + Display>(&self, vaddr: usize) -> T {
+    pub fn mread<T: Sized + Copy + Into<u64>>(&self, vaddr: usize) -> T {
         // self.host_read(vaddr - self.ld_start)
         let ret = self._mread::<T>(vaddr);
-        if self.mtrace {
-            trace!("mread {:#x}\t{}", vaddr, ret);
+        if self.mtrace.enabled() {
+            self.mtrace.record(&TraceEvent::MemRead {
+                addr: vaddr as u64,
+                size: size_of::<T>() as u8,
+                value: ret.into(),
+            });
         }
         ret
     }
@@ -101,12 +130,76 @@ impl VirtualMemory {
 
     /// Write a value into a virtual memory address.
     #[inline(always)]
-    pub fn mwrite<T: Sized + Display>(&mut self, vaddr: usize, value: T) {
+    pub fn mwrite<T: Sized + Copy + Into<u64>>(&mut self, vaddr: usize, value: T) {
         // self.host_write(vaddr - self.ld_start, value);
-        if self.mtrace {
-            trace!("mwrite {:#x}\t{}", vaddr, value);
+        if self.mtrace.enabled() {
+            self.mtrace.record(&TraceEvent::MemWrite {
+                addr: vaddr as u64,
+                size: size_of::<T>() as u8,
+                value: value.into(),
+            });
         }
         self._mwrite::<T>(vaddr, value);
+        if let Some(mut htif) = self.htif.take_if(|htif| htif.is_tohost(vaddr as u64)) {
+            htif.command(self);
+            self.htif = Some(htif);
+        }
+    }
+
+    /// Read a value from a virtual memory address as the host, without
+    /// tracing.
+    pub(super) fn host_mread<T: Sized>(&self, vaddr: usize) -> T {
+        self._mread(vaddr)
+    }
+
+    /// [`Self::host_mread`] of an address given by the program, [`None`]
+    /// if the value is not all in the memory.
+    pub(super) fn host_try_mread<T: Sized>(&self, vaddr: usize) -> Option<T> {
+        let pos = vaddr.checked_sub(self.ld_start)?;
+        (pos.checked_add(size_of::<T>())? <= self.mm.len()).then(|| self.host_read(pos))
+    }
+
+    /// Write a value into a virtual memory address as the host, without
+    /// tracing or handing a command to HTIF.
+    pub(super) fn host_mwrite<T: Sized>(&mut self, vaddr: usize, value: T) {
+        self._mwrite(vaddr, value);
+    }
+
+    /// Write bytes given by the host to an address given by the program,
+    /// [`None`] if the bytes are not all in the memory.
+    pub(super) fn host_write_bytes(&mut self, vaddr: usize, bytes: &[u8]) -> Option<()> {
+        let start = vaddr.checked_sub(self.ld_start)?;
+        self.mm
+            .get_mut(start..start.checked_add(bytes.len())?)?
+            .copy_from_slice(bytes);
+        Some(())
+    }
+
+    /// Drop the console output of HTIF, replay the console input and the
+    /// syscall effects of the HTIF of `dut`, for the reference CPU of
+    /// difftest.
+    pub fn follow_htif(&mut self, dut: &VirtualMemory) {
+        if let (Some(htif), Some(dut)) = (self.htif.as_mut(), dut.htif.as_ref()) {
+            htif.follow(dut);
+        }
+    }
+
+    /// Exit code of the program if `retired` wrote the HTIF exit command,
+    /// at which point the CPU stops.
+    pub fn htif_exit(&self, retired: Option<&RetireInfo>) -> Option<u64> {
+        self.htif.as_ref()?.exit_code(retired)
+    }
+
+    /// Exit code the program wrote to `tohost`, if any.
+    pub fn htif_exit_code(&self) -> Option<u64> {
+        self.htif.as_ref()?.exited()
+    }
+
+    /// Read `len` bytes from a virtual memory address, without tracing.
+    /// [`None`] if the bytes are not all in the memory.
+    pub fn read_bytes(&self, vaddr: usize, len: usize) -> Option<&[u8]> {
+        let start = vaddr.checked_sub(self.ld_start)?;
+        self.mm.get(start..start.checked_add(len)?)
     }
 
     /// Fetch instruction from memory.
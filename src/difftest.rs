@@ -0,0 +1,291 @@
+//! Lock-step differential testing.
+//!
+//! The single-cycle CPU is the golden reference. The CPU under test is
+//! clocked until it retires instructions, then the reference executes them
+//! one by one and the architectural state of both is compared: the retired
+//! PC and instruction, the register write, the memory access (including the
+//! stored value) and, once the reference caught up, the whole register file.
+//! The run stops at the first divergence.
+
+use log::{error, info};
+
+use crate::{
+    commit_log::RetireInfo,
+    core::reg::REGNAME,
+    error::{Error, Result},
+    multi_stage::{
+        cpu::{MultistageCPU, CPU as PipelineCPU},
+        dual_issue::DualIssueCPU,
+        ooo::OooCPU,
+    },
+    single_cycle::cpu::CPU as SingleCycleCPU,
+};
+
+/// A CPU model taking part in difftest.
+pub trait DifftestCPU {
+    /// Advance by one step (one instruction or one clock).
+    fn step(&mut self) -> Result<()>;
+    fn running(&self) -> bool;
+    fn read_reg(&self, idx: u8) -> u64;
+    fn take_retired(&mut self) -> Option<RetireInfo>;
+}
+
+macro_rules! impl_difftest_cpu {
+    ($cpu:ty) => {
+        impl<'a> DifftestCPU for $cpu {
+            fn step(&mut self) -> Result<()> {
+                self.cpu_exec(Some(1))
+            }
+
+            fn running(&self) -> bool {
+                self.running()
+            }
+
+            fn read_reg(&self, idx: u8) -> u64 {
+                self.read_reg(idx)
+            }
+
+            fn take_retired(&mut self) -> Option<RetireInfo> {
+                self.take_retired()
+            }
+        }
+    };
+}
+
+impl_difftest_cpu!(SingleCycleCPU<'a>);
+impl_difftest_cpu!(MultistageCPU<'a>);
+impl_difftest_cpu!(PipelineCPU<'a>);
+impl_difftest_cpu!(DualIssueCPU<'a>);
+impl_difftest_cpu!(OooCPU<'a>);
+
+/// Run `dut` against `reference` until the program ends or they diverge, or
+/// for at most `steps` steps of `dut`.
+pub fn run(
+    reference: &mut dyn DifftestCPU,
+    dut: &mut dyn DifftestCPU,
+    steps: Option<i32>,
+) -> Result<()> {
+    let mut retired = 0u64;
+
+    for step in 0.. {
+        if steps.is_some_and(|n| step >= n) {
+            info!("Difftest stopped after {step} steps, {retired} instructions compared");
+            return Ok(());
+        }
+        dut.step()?;
+        // a superscalar CPU under test retires several instructions a step,
+        // the register files match once the reference caught up
+        let dut_infos = std::iter::from_fn(|| dut.take_retired()).collect::<Vec<_>>();
+        for (i, dut_info) in dut_infos.iter().enumerate() {
+            retired += 1;
+            reference.step()?;
+            let ref_info = reference.take_retired().ok_or_else(|| {
+                Error::Difftest(format!(
+                    "reference retired nothing at instruction {retired}"
+                ))
+            })?;
+            let regs = i + 1 == dut_infos.len();
+            compare(retired, reference, &ref_info, dut, dut_info, regs)?;
+        }
+        if !dut.running() {
+            break;
+        }
+    }
+
+    if reference.running() {
+        error!("Difftest: CPU under test halted but reference is still running");
+        return Err(Error::Difftest(format!(
+            "early halt after {retired} instructions"
+        )));
+    }
+    info!("Difftest passed, {retired} instructions compared");
+    Ok(())
+}
+
+fn compare(
+    retired: u64,
+    reference: &dyn DifftestCPU,
+    ref_info: &RetireInfo,
+    dut: &dyn DifftestCPU,
+    dut_info: &RetireInfo,
+    regs: bool,
+) -> Result<()> {
+    let mut diffs = Vec::new();
+    if ref_info.pc != dut_info.pc {
+        diffs.push(format!(
+            "pc differs: REF {:#x}, DUT {:#x}",
+            ref_info.pc, dut_info.pc
+        ));
+    }
+    if ref_info.raw_inst != dut_info.raw_inst {
+        diffs.push(format!(
+            "instruction differs: REF {:#010x}, DUT {:#010x}",
+            ref_info.raw_inst, dut_info.raw_inst
+        ));
+    }
+    if ref_info.reg_write != dut_info.reg_write {
+        diffs.push(format!(
+            "register write differs: REF {:x?}, DUT {:x?}",
+            ref_info.reg_write, dut_info.reg_write
+        ));
+    }
+    if ref_info.mem != dut_info.mem {
+        diffs.push(format!(
+            "memory access differs: REF {:x?}, DUT {:x?}",
+            ref_info.mem, dut_info.mem
+        ));
+    }
+    for i in (0..32).filter(|&i| regs && reference.read_reg(i) != dut.read_reg(i)) {
+        diffs.push(format!(
+            "{:>4}(x{:<2}): REF {:#018x}, DUT {:#018x}",
+            REGNAME[i as usize],
+            i,
+            reference.read_reg(i),
+            dut.read_reg(i)
+        ));
+    }
+
+    if diffs.is_empty() {
+        return Ok(());
+    }
+
+    error!("Difftest: divergence at retired instruction {retired}");
+    error!("  REF: {ref_info}");
+    error!("  DUT: {dut_info}");
+    for diff in &diffs {
+        error!("  {diff}");
+    }
+    Err(Error::Difftest(format!(
+        "divergence at pc {:#x} after {retired} instructions: {}",
+        dut_info.pc,
+        diffs.join("; ")
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::commit_log::MemAccess;
+
+    /// Replays a stream of retired instructions, one per step.
+    struct Replay {
+        retired: Vec<RetireInfo>,
+        next: usize,
+        regs: [u64; 32],
+        last: Option<RetireInfo>,
+    }
+
+    impl Replay {
+        fn new(retired: Vec<RetireInfo>) -> Replay {
+            Replay {
+                retired,
+                next: 0,
+                regs: [0; 32],
+                last: None,
+            }
+        }
+    }
+
+    impl DifftestCPU for Replay {
+        fn step(&mut self) -> Result<()> {
+            let info = self.retired[self.next];
+            if let Some((rd, value)) = info.reg_write {
+                self.regs[rd as usize] = value;
+            }
+            self.last = Some(info);
+            self.next += 1;
+            Ok(())
+        }
+
+        fn running(&self) -> bool {
+            self.next < self.retired.len()
+        }
+
+        fn read_reg(&self, idx: u8) -> u64 {
+            self.regs[idx as usize]
+        }
+
+        fn take_retired(&mut self) -> Option<RetireInfo> {
+            self.last.take()
+        }
+    }
+
+    fn program() -> Vec<RetireInfo> {
+        vec![
+            // addi a0, zero, 5
+            RetireInfo {
+                pc: 0x80000000,
+                raw_inst: 0x00500513,
+                reg_write: Some((10, 5)),
+                mem: None,
+            },
+            // sd a0, 8(sp)
+            RetireInfo {
+                pc: 0x80000004,
+                raw_inst: 0x00a13423,
+                reg_write: None,
+                mem: Some(MemAccess::Write {
+                    addr: 0x1008,
+                    size: 8,
+                    value: 5,
+                }),
+            },
+            // ebreak
+            RetireInfo {
+                pc: 0x80000008,
+                raw_inst: 0x00100073,
+                reg_write: None,
+                mem: None,
+            },
+        ]
+    }
+
+    /// Difftest of `program` against the program changed by `diverge`.
+    fn difftest(diverge: impl FnOnce(&mut Vec<RetireInfo>)) -> Result<()> {
+        let mut dut_program = program();
+        diverge(&mut dut_program);
+        let mut reference = Replay::new(program());
+        let mut dut = Replay::new(dut_program);
+        run(&mut reference, &mut dut, None)
+    }
+
+    fn divergence(result: Result<()>) -> String {
+        match result {
+            Err(Error::Difftest(msg)) => msg,
+            result => panic!("expect a divergence, got {result:?}"),
+        }
+    }
+
+    #[test]
+    fn report_divergences() {
+        difftest(|_| {}).unwrap();
+
+        let msg = divergence(difftest(|p| p[1].pc = 0x80000008));
+        assert!(msg.contains("after 2 instructions"), "{msg}");
+        assert!(
+            msg.contains("pc differs: REF 0x80000004, DUT 0x80000008"),
+            "{msg}"
+        );
+
+        let msg = divergence(difftest(|p| p[0].reg_write = Some((10, 6))));
+        assert!(msg.contains("after 1 instructions"), "{msg}");
+        assert!(msg.contains("register write differs"), "{msg}");
+        assert!(msg.contains("a0(x10)"), "{msg}");
+        assert!(!msg.contains("pc differs"), "{msg}");
+
+        let msg = divergence(difftest(|p| {
+            p[1].mem = Some(MemAccess::Write {
+                addr: 0x1010,
+                size: 8,
+                value: 5,
+            })
+        }));
+        assert!(msg.contains("memory access differs"), "{msg}");
+        assert!(!msg.contains("register write differs"), "{msg}");
+
+        let msg = divergence(difftest(|p| {
+            p.pop();
+        }));
+        assert!(msg.contains("early halt after 2 instructions"), "{msg}");
+    }
+}
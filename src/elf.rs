@@ -14,6 +14,7 @@ pub struct LoadElfInfo {
     min_vaddr: usize,
     max_vaddr: usize,
     symbol_map: HashMap<u64, String>,
+    symbol_addrs: HashMap<String, u64>,
 }
 
 impl LoadElfInfo {
@@ -48,6 +49,12 @@ impl LoadElfInfo {
     pub fn symbol_map(&self) -> &HashMap<u64, String> {
         &self.symbol_map
     }
+
+    /// Address of the symbol `name`. Unlike [`LoadElfInfo::symbol_map`], this
+    /// also finds symbols sharing their address with another one.
+    pub fn symbol_addr(&self, name: &str) -> Option<u64> {
+        self.symbol_addrs.get(name).copied()
+    }
 }
 
 pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
@@ -113,11 +120,13 @@ pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
 
     // Symbol table
     let mut symbol_map = HashMap::new();
+    let mut symbol_addrs = HashMap::new();
     for sym in elf.syms.iter() {
         if let Some(name) = elf.strtab.get_at(sym.st_name) {
             // maybe we could add elf-trace?
             // info!("Symbol: {}, address: {:#x}", name, sym.st_value);
             symbol_map.insert(sym.st_value, name.to_string());
+            symbol_addrs.insert(name.to_string(), sym.st_value);
         }
     }
 
@@ -190,6 +199,7 @@ pub fn read_elf(path: &PathBuf) -> Result<LoadElfInfo> {
         min_vaddr,
         max_vaddr,
         symbol_map,
+        symbol_addrs,
     };
     Ok(info)
 }
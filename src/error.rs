@@ -18,13 +18,15 @@ pub enum Error {
     Execute(String),
     #[error("{0}")]
     Exception(#[from] Exception),
+    #[error("Difftest failed: {0}")]
+    Difftest(String),
+    #[error("Invalid configuration: {0}")]
+    Config(String),
 }
 
 /// CPU raised exceptions
 #[derive(Debug, thiserror::Error)]
 pub enum Exception {
-    #[error("DividedByZero")]
-    DividedByZero,
     #[error("IllegalInstruction")]
     IllegalInstruction,
 }
@@ -0,0 +1,121 @@
+//! Ring buffer of the last retired instructions.
+//!
+//! Every CPU keeps one and pushes each retired instruction into it. The
+//! buffer is printed when the program hits a BAD TRAP, and when it is dropped
+//! while unwinding from a panic (which is how `main` reports execution errors).
+
+use std::thread;
+
+use log::error;
+
+use crate::{
+    commit_log::{MemAccess, RetireInfo},
+    core::reg::REGNAME,
+    multi_stage::debug::disasm,
+};
+
+pub struct InstRingBuffer {
+    buf: Vec<RetireInfo>,
+    capacity: usize,
+    // index of the slot the next instruction goes into
+    next: usize,
+}
+
+impl InstRingBuffer {
+    /// A buffer of the last `capacity` instructions, 0 disables it.
+    pub fn new(capacity: usize) -> InstRingBuffer {
+        InstRingBuffer {
+            buf: Vec::with_capacity(capacity),
+            capacity,
+            next: 0,
+        }
+    }
+
+    #[inline(always)]
+    pub fn push(&mut self, info: RetireInfo) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.buf.len() < self.capacity {
+            self.buf.push(info);
+        } else {
+            self.buf[self.next] = info;
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    /// Retired instructions from the oldest to the newest.
+    pub fn iter(&self) -> impl Iterator<Item = &RetireInfo> {
+        // until the buffer is full, `next` is its length and `tail` is empty
+        let (head, tail) = self.buf.split_at(self.next);
+        tail.iter().chain(head.iter())
+    }
+
+    pub fn dump(&self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        error!("Last {} retired instructions:", self.buf.len());
+        let last = self.buf.len() - 1;
+        for (i, info) in self.iter().enumerate() {
+            let mut line = format!(
+                "{} {}",
+                if i == last { "-->" } else { "   " },
+                disasm(info.pc, info.raw_inst)
+            );
+            if let Some((rd, value)) = info.reg_write {
+                line += &format!("\t{} = {:#x}", REGNAME[rd as usize], value);
+            }
+            match info.mem {
+                Some(MemAccess::Read { addr }) => line += &format!("\tmread {:#x}", addr),
+                Some(MemAccess::Write { addr, value, .. }) => {
+                    line += &format!("\tmwrite {:#x} <- {:#x}", addr, value)
+                }
+                None => {}
+            }
+            error!("{line}");
+        }
+    }
+}
+
+impl Drop for InstRingBuffer {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.dump();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn inst(pc: u64) -> RetireInfo {
+        RetireInfo {
+            pc,
+            raw_inst: 0x00000013,
+            reg_write: None,
+            mem: None,
+        }
+    }
+
+    #[test]
+    fn keeps_last_entries_in_order() {
+        let mut rb = InstRingBuffer::new(3);
+        for pc in [0, 4] {
+            rb.push(inst(pc));
+        }
+        let pcs: Vec<u64> = rb.iter().map(|i| i.pc).collect();
+        assert_eq!(pcs, vec![0, 4]);
+
+        for pc in [8, 12, 16] {
+            rb.push(inst(pc));
+        }
+        let pcs: Vec<u64> = rb.iter().map(|i| i.pc).collect();
+        assert_eq!(pcs, vec![8, 12, 16]);
+
+        let mut disabled = InstRingBuffer::new(0);
+        disabled.push(inst(0));
+        assert_eq!(disabled.iter().count(), 0);
+    }
+}
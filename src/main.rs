@@ -1,22 +1,74 @@
 use callstack::CallStack;
-use clap::{Parser, ValueEnum};
-use core::vm::VirtualMemory;
+use clap::{Parser, Subcommand, ValueEnum};
+use commit_log::CommitLog;
+use core::vm::{MemoryLayout, VirtualMemory};
 use elf::read_elf;
-use log::info;
+use log::{error, info};
+use multi_stage::branch_predict::TableConfig;
+use multi_stage::cache::{parse_size, CacheConfig};
 use multi_stage::cpu::{ControlPolicy, DataHazardPolicy, PredictPolicy};
+use multi_stage::diagram::CycleWindow;
+use multi_stage::func_unit::FuConfig;
+use multi_stage::global_predict::PredictorConfig;
+use multi_stage::memory::{MemoryConfig, MemoryHierarchy};
+use multi_stage::ooo::OooConfig;
+use multi_stage::stage_latency::StageLatency;
+use profile::{ProfileFiles, Profiler};
+use stats::Stats;
 use std::path;
+use trace::{TraceFormat, Tracer};
 
 mod callstack;
+mod commit_log;
+mod compliance;
+mod config;
 mod core;
+mod difftest;
 mod elf;
 mod error;
+mod iringbuf;
 mod logger;
 mod multi_stage;
+mod profile;
 mod single_cycle;
+mod stats;
+mod sweep;
+mod trace;
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about)]
+#[command(
+    version,
+    about,
+    long_about,
+    args_conflicts_with_subcommands = true,
+    args_override_self = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run every combination of programs, CPU modes and policies in parallel
+    /// and compare them in a table.
+    Sweep(sweep::SweepArgs),
+    /// Run riscv-tests or riscv-arch-test programs on CPU models and report
+    /// whether each test passes on each model.
+    Compliance(compliance::ComplianceArgs),
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
+    /// Read options from this TOML file, see `config/cpu.toml`. Options given
+    /// on the command line override the file, `--no-<flag>` clears a flag set
+    /// by the file.
+    #[arg(long)]
+    config: Option<path::PathBuf>,
+
     /// Path to the program to be loaded
     #[arg(short, long)]
     input: String,
@@ -41,6 +93,22 @@ struct Args {
     #[arg(long)]
     ftrace: bool,
 
+    /// Write itrace to this file instead of the log. Implies --itrace.
+    #[arg(long)]
+    itrace_file: Option<String>,
+
+    /// Write mtrace to this file instead of the log. Implies --mtrace.
+    #[arg(long)]
+    mtrace_file: Option<String>,
+
+    /// Write ftrace to this file instead of the log. Implies --ftrace.
+    #[arg(long)]
+    ftrace_file: Option<String>,
+
+    /// Format of trace files.
+    #[arg(long, default_value = "text")]
+    trace_format: TraceFormat,
+
     /// Data hazard policy
     #[arg(long)]
     data_hazard_policy: Option<DataHazardPolicy>,
@@ -53,6 +121,65 @@ struct Args {
     #[arg(long)]
     predict_policy: Option<PredictPolicy>,
 
+    /// Make the BHT of dynamic prediction a finite table, configured as
+    /// `entries=1024,assoc=1,tag=0,repl=lru|fifo|random` (`index=<bits>` may
+    /// replace `entries`, `tag=0` is untagged). Unbounded if not given.
+    #[arg(long)]
+    bht: Option<TableConfig>,
+
+    /// Make the BTB of dynamic prediction a finite table, configured like --bht.
+    #[arg(long)]
+    btb: Option<TableConfig>,
+
+    /// Sizes of the global history predictors, configured as
+    /// `history=12,entries=4096,tables=4,tag=8` (history length in bits, entries
+    /// per table, number of TAGE tagged tables and their tag width).
+    #[arg(long, default_value = "")]
+    predictor: PredictorConfig,
+
+    /// Depth of the return address stack of dynamic prediction
+    #[arg(long, default_value_t = 16)]
+    ras_depth: usize,
+
+    /// Number of branches with the most mispredictions listed at the end of a
+    /// pipeline run, 0 to only print the MPKI
+    #[arg(long, default_value_t = 10)]
+    branch_report: usize,
+
+    /// Latencies of the functional units of the pipeline CPU, configured as
+    /// `mul=1,mul-pipelined=true,div=1,div-pipelined=false,load=2`
+    /// (cycles until a dependent instruction can execute, 1 is like `add`).
+    #[arg(long, default_value = "")]
+    fu_latency: FuConfig,
+
+    /// Depth of the pipeline CPU, configured as `fetch=1,exec=1,mem=1,branch=1`
+    /// (stages of fetch, execute and memory, and the execute stage resolving
+    /// branches, by default the last one).
+    #[arg(long, default_value = "")]
+    stage_latency: StageLatency,
+
+    /// Sizes of the out-of-order CPU, configured as
+    /// `rob=64,issue=2,rs=16,lsq=16` (entries of the reorder buffer,
+    /// instructions fetched, dispatched, issued and committed per cycle,
+    /// entries of the reservation stations and of the load/store queue).
+    #[arg(long, default_value = "")]
+    ooo: OooConfig,
+
+    /// Enable the L1 I-cache of the pipeline CPU, configured as
+    /// `size=16K,assoc=4,line=64,repl=lru|fifo|random,write=back|through,alloc=true,penalty=20`.
+    /// Omitted parameters take the values above.
+    #[arg(long)]
+    icache: Option<CacheConfig>,
+
+    /// Enable the L1 D-cache of the pipeline CPU, configured like --icache.
+    #[arg(long)]
+    dcache: Option<CacheConfig>,
+
+    /// Memory hierarchy of the pipeline CPU (L1s, L2, L3, MSHRs and DRAM)
+    /// as a TOML file, see `config/memory.toml`. Replaces --icache and --dcache.
+    #[arg(long, conflicts_with_all = ["icache", "dcache"])]
+    memory_config: Option<path::PathBuf>,
+
     // Pre-execution pipeline register info
     #[arg(long)]
     pre_pipeline_info: bool,
@@ -72,6 +199,71 @@ struct Args {
     // Data hazard info
     #[arg(long)]
     data_hazard_info: bool,
+
+    /// Write per-instruction profile as annotated disassembly to this file.
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Write per-instruction profile in callgrind format to this file.
+    #[arg(long)]
+    callgrind: Option<String>,
+
+    /// Write a commit log in the format of Spike's --log-commits to this file.
+    #[arg(long)]
+    commit_log: Option<String>,
+
+    /// Write a pipeline log in the Kanata format of the Konata viewer to this
+    /// file, only for the pipeline CPU.
+    #[arg(long)]
+    kanata: Option<String>,
+
+    /// Write a pipeline diagram of the cycles in --diagram-cycles to this file,
+    /// as HTML if it ends with .html and as a text table otherwise, only for
+    /// the pipeline CPU.
+    #[arg(long)]
+    pipeline_diagram: Option<String>,
+
+    /// Cycles shown in the pipeline diagram, as <first>-<last>.
+    #[arg(long, default_value_t = CycleWindow::default())]
+    diagram_cycles: CycleWindow,
+
+    /// Dump the pipeline registers and control signals as a Value Change Dump
+    /// to this file, one timestep per clock, only for the pipeline CPU.
+    #[arg(long)]
+    vcd: Option<String>,
+
+    /// Write the configuration and statistics of the run to this file, as CSV
+    /// if it ends with .csv and as JSON otherwise.
+    #[arg(long)]
+    stats: Option<String>,
+
+    /// Size of the gap separating the program from the stack, `K`/`M` suffix
+    /// allowed.
+    #[arg(long, value_parser = parse_size, default_value_t = MemoryLayout::default().protect_size)]
+    protect_size: usize,
+
+    /// Size of the stack, `K`/`M` suffix allowed.
+    #[arg(long, value_parser = parse_size, default_value_t = MemoryLayout::default().stack_size)]
+    stack_size: usize,
+
+    /// Check every retired instruction against the single-cycle CPU.
+    #[arg(long)]
+    difftest: bool,
+
+    /// Number of last retired instructions printed on failure, 0 to disable.
+    #[arg(long, default_value_t = 16)]
+    iringbuf_size: usize,
+
+    /// Stop the program if it has not ended after this many steps
+    /// (instructions of the single-cycle CPU, clocks of the others).
+    #[arg(long, value_parser = clap::value_parser!(i32).range(1..))]
+    max_steps: Option<i32>,
+
+    /// Write the memory between the `begin_signature` and `end_signature`
+    /// symbols to this file at the end of the run, one 32-bit word in hex per
+    /// line, as the signature of riscv-arch-test.
+    #[arg(long)]
+    signature: Option<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, ValueEnum)]
@@ -79,6 +271,8 @@ enum CPUMode {
     Single,
     Multi,
     Pipeline,
+    DualIssue,
+    OutOfOrder,
 }
 
 fn main() {
@@ -86,20 +280,50 @@ fn main() {
     //     .expect("Fail to load logger configuration");
     logger::init();
 
-    let args = Args::parse();
+    let argv =
+        config::expand_args(std::env::args().collect()).expect("Fail to load configuration file");
+    let cli = Cli::parse_from(argv);
+    match (cli.command, cli.run) {
+        (Some(Command::Sweep(sweep)), _) => {
+            let all_good = sweep::sweep(&sweep).expect("Sweep failed");
+            if !all_good {
+                std::process::exit(1);
+            }
+        }
+        (Some(Command::Compliance(compliance)), _) => {
+            let all_passed = compliance::run_tests(&compliance).expect("Compliance run failed");
+            if !all_passed {
+                std::process::exit(1);
+            }
+        }
+        (None, Some(args)) => {
+            run(&args);
+        }
+        (None, None) => unreachable!("the arguments of a run are required without a subcommand"),
+    }
+}
+
+/// Run one program, returning the configuration and statistics of the run.
+fn run(args: &Args) -> Stats {
     let file_path = path::PathBuf::from(&args.input);
     let enable_debug_mode = args.debug;
-    let itrace = args.itrace;
-    let mtrace = args.mtrace;
-    let ftrace = args.ftrace;
-    let cpu_mode = args.cpu_mode;
+    let itrace = Tracer::open(args.itrace, args.itrace_file.as_deref(), args.trace_format)
+        .expect("Fail to open itrace file");
+    let mtrace = Tracer::open(args.mtrace, args.mtrace_file.as_deref(), args.trace_format)
+        .expect("Fail to open mtrace file");
+    let ftrace = Tracer::open(args.ftrace, args.ftrace_file.as_deref(), args.trace_format)
+        .expect("Fail to open ftrace file");
+    let cpu_mode = args.cpu_mode.clone();
     let data_hazard_policy = if cpu_mode == CPUMode::Pipeline {
         args.data_hazard_policy
             .expect("Must give data hazard policy if pipeline CPU is used")
     } else {
         DataHazardPolicy::NaiveStall /* Useless */
     };
-    let control_policy = if cpu_mode == CPUMode::Pipeline {
+    let control_policy = if matches!(
+        cpu_mode,
+        CPUMode::Pipeline | CPUMode::DualIssue | CPUMode::OutOfOrder
+    ) {
         args.control_policy
             .expect("Must give control hazard policy if pipeline CPU is used")
     } else {
@@ -114,71 +338,351 @@ fn main() {
         None
     };
 
-    let pre_pipeline_info = args.pre_pipeline_info;
-    let pipeline_info = args.pipeline_info;
-    let post_pipeline_info = args.post_pipeline_info;
-    let control_hazard_info = args.control_hazard_info;
-    let data_hazard_info = args.data_hazard_info;
     info!("Loading file: {file_path:?}");
 
     // Parse ELF file
     let elf_info = read_elf(&file_path).expect("Fail to load ELF");
 
     // Load the file into virtual memory
-    let mut vm = VirtualMemory::from_elf_info(&elf_info, mtrace);
+    let layout = MemoryLayout {
+        protect_size: args.protect_size,
+        stack_size: args.stack_size,
+    };
+    let mut vm = VirtualMemory::from_elf_info(&elf_info, layout, mtrace);
 
     // Create call stack for the running process on the CPU
     let mut callstack = CallStack::from_elf_info(&elf_info, ftrace);
 
+    // Create profiler if any profile output is requested, its files are
+    // written when it is dropped, after the CPU even if the run panics
+    let mut profile = if args.profile.is_some() || args.callgrind.is_some() {
+        Some(ProfileFiles::new(
+            Profiler::from_elf_info(&elf_info),
+            args.profile.clone(),
+            args.callgrind.clone(),
+            &args.input,
+        ))
+    } else {
+        None
+    };
+    let mut profiler = profile.as_mut().map(|profile| &mut profile.profiler);
+
+    let commit_log = args
+        .commit_log
+        .as_deref()
+        .map(|path| CommitLog::create(path).expect("Fail to open commit log file"));
+
+    // Reference CPU for difftest, with its own memory and call stack
+    let mut ref_vm = args.difftest.then(|| {
+        let mut ref_vm = VirtualMemory::from_elf_info(&elf_info, layout, Tracer::disabled());
+        // the CPU under test prints the console output and reads its input
+        ref_vm.follow_htif(&vm);
+        ref_vm
+    });
+    let mut ref_callstack = args
+        .difftest
+        .then(|| CallStack::from_elf_info(&elf_info, Tracer::disabled()));
+    let mut reference = match (ref_vm.as_mut(), ref_callstack.as_mut()) {
+        (Some(vm), Some(callstack)) => {
+            let mut cpu =
+                single_cycle::cpu::CPU::new(vm, callstack, Tracer::disabled(), None, None, 0);
+            cpu.init_elfinfo_64(&elf_info);
+            Some(cpu)
+        }
+        _ => None,
+    };
+    if reference.is_some() && enable_debug_mode {
+        panic!("Difftest is not available in debug mode");
+    }
+
+    let mut stats = config_stats(args);
+    // a0 when the program ended, [`None`] if it did not end in --max-steps
+    let mut exit_code = None;
+
     match cpu_mode {
         CPUMode::Single => {
             use single_cycle::{cpu::CPU, debug::REDB};
-            let mut cpu = CPU::new(&mut vm, &mut callstack, itrace);
+            let mut cpu = CPU::new(
+                &mut vm,
+                &mut callstack,
+                itrace,
+                profiler.as_deref_mut(),
+                commit_log,
+                args.iringbuf_size,
+            );
 
             cpu.init_elfinfo_64(&elf_info);
 
-            if !enable_debug_mode {
-                cpu.cpu_exec(None).expect("Failed to execute the program");
-            } else {
+            if enable_debug_mode {
                 let mut redb = REDB::new(&mut cpu);
                 redb.run();
+            } else {
+                if let Some(reference) = reference.as_mut() {
+                    difftest::run(reference, &mut cpu, args.max_steps).expect("Difftest failed");
+                } else {
+                    cpu.cpu_exec(args.max_steps)
+                        .expect("Failed to execute the program");
+                }
+                cpu.add_stats(&mut stats);
+                exit_code = (!cpu.running()).then(|| cpu.read_reg(10));
             }
         }
         CPUMode::Multi => {
             use multi_stage::cpu::MultistageCPU;
-            let mut cpu = MultistageCPU::new(&mut vm, &mut callstack, itrace);
+            let mut cpu = MultistageCPU::new(
+                &mut vm,
+                &mut callstack,
+                itrace,
+                profiler.as_deref_mut(),
+                commit_log,
+                args.iringbuf_size,
+            );
             cpu.init_elfinfo_64(&elf_info);
-            cpu.cpu_exec(None).expect("Failed to execute the program");
+            if let Some(reference) = reference.as_mut() {
+                difftest::run(reference, &mut cpu, args.max_steps).expect("Difftest failed");
+            } else {
+                cpu.cpu_exec(args.max_steps)
+                    .expect("Failed to execute the program");
+            }
             cpu.print_info();
+            cpu.add_stats(&mut stats);
+            exit_code = (!cpu.running()).then(|| cpu.read_reg(10));
         }
         CPUMode::Pipeline => {
-            use multi_stage::{cpu::CPU, debug::REDB};
+            use multi_stage::{
+                config::CoreConfig,
+                cpu::{PipelineConfig, PipelineInfo, PipelineLogs, CPU},
+                debug::REDB,
+                diagram::PipelineDiagram,
+                kanata::KanataLog,
+                vcd::VcdDump,
+            };
+            let memory = if let Some(path) = args.memory_config.as_deref() {
+                let config = MemoryConfig::load(path).expect("Fail to load memory configuration");
+                Some(MemoryHierarchy::from_config(config))
+            } else if args.icache.is_some() || args.dcache.is_some() {
+                Some(MemoryHierarchy::from_l1(
+                    args.icache.clone(),
+                    args.dcache.clone(),
+                ))
+            } else {
+                None
+            };
+            let kanata = args
+                .kanata
+                .as_deref()
+                .map(|path| KanataLog::create(path).expect("Fail to open Kanata log file"));
+            let diagram = args.pipeline_diagram.as_deref().map(|path| {
+                PipelineDiagram::create(path, args.diagram_cycles)
+                    .expect("Fail to open pipeline diagram file")
+            });
+            let vcd = args
+                .vcd
+                .as_deref()
+                .map(|path| VcdDump::create(path).expect("Fail to open VCD file"));
+            let config = PipelineConfig {
+                core: CoreConfig {
+                    control_policy,
+                    predict_policy,
+                    bht: args.bht.clone(),
+                    btb: args.btb.clone(),
+                    predictor: args.predictor.clone(),
+                    ras_depth: args.ras_depth,
+                    fu_latency: args.fu_latency.clone(),
+                },
+                data_hazard_policy,
+                stage_latency: args.stage_latency.clone(),
+                memory,
+                branch_report: args.branch_report,
+                info: PipelineInfo {
+                    pre_pipeline: args.pre_pipeline_info,
+                    pipeline: args.pipeline_info,
+                    post_pipeline: args.post_pipeline_info,
+                    control_hazard: args.control_hazard_info,
+                    data_hazard: args.data_hazard_info,
+                },
+            };
+            let logs = PipelineLogs {
+                commit_log,
+                kanata,
+                diagram,
+                vcd,
+            };
             let mut cpu = CPU::new(
                 &mut vm,
                 &mut callstack,
-                data_hazard_policy,
-                control_policy,
-                predict_policy,
-                pre_pipeline_info,
-                pipeline_info,
-                post_pipeline_info,
-                control_hazard_info,
-                data_hazard_info,
+                itrace,
+                config,
+                profiler.as_deref_mut(),
+                logs,
+                args.iringbuf_size,
             );
 
             cpu.init_elfinfo_64(&elf_info);
 
-            if !enable_debug_mode {
-                cpu.cpu_exec(None).expect("Failed to execute the program");
-                cpu.print_info();
-            } else {
+            if enable_debug_mode {
                 let mut redb = REDB::new(&mut cpu);
                 redb.run();
+            } else {
+                if let Some(reference) = reference.as_mut() {
+                    difftest::run(reference, &mut cpu, args.max_steps).expect("Difftest failed");
+                } else {
+                    cpu.cpu_exec(args.max_steps)
+                        .expect("Failed to execute the program");
+                }
+                cpu.print_info();
+                cpu.add_stats(&mut stats);
+                exit_code = (!cpu.running()).then(|| cpu.read_reg(10));
             }
         }
+        CPUMode::DualIssue => {
+            use multi_stage::{config::CoreConfig, dual_issue::DualIssueCPU};
+            let config = CoreConfig {
+                control_policy,
+                predict_policy,
+                bht: args.bht.clone(),
+                btb: args.btb.clone(),
+                predictor: args.predictor.clone(),
+                ras_depth: args.ras_depth,
+                fu_latency: args.fu_latency.clone(),
+            };
+            let mut cpu = DualIssueCPU::new(
+                &mut vm,
+                &mut callstack,
+                itrace,
+                config,
+                profiler.as_deref_mut(),
+                commit_log,
+                args.iringbuf_size,
+            );
+            cpu.init_elfinfo_64(&elf_info);
+            if let Some(reference) = reference.as_mut() {
+                difftest::run(reference, &mut cpu, args.max_steps).expect("Difftest failed");
+            } else {
+                cpu.cpu_exec(args.max_steps)
+                    .expect("Failed to execute the program");
+            }
+            cpu.print_info();
+            cpu.add_stats(&mut stats);
+            exit_code = (!cpu.running()).then(|| cpu.read_reg(10));
+        }
+        CPUMode::OutOfOrder => {
+            use multi_stage::{
+                config::CoreConfig,
+                ooo::{OooCPU, OooCpuConfig},
+            };
+            let config = OooCpuConfig {
+                core: CoreConfig {
+                    control_policy,
+                    predict_policy,
+                    bht: args.bht.clone(),
+                    btb: args.btb.clone(),
+                    predictor: args.predictor.clone(),
+                    ras_depth: args.ras_depth,
+                    fu_latency: args.fu_latency.clone(),
+                },
+                sizes: args.ooo.clone(),
+            };
+            let mut cpu = OooCPU::new(
+                &mut vm,
+                &mut callstack,
+                itrace,
+                config,
+                profiler,
+                commit_log,
+                args.iringbuf_size,
+            );
+            cpu.init_elfinfo_64(&elf_info);
+            if let Some(reference) = reference.as_mut() {
+                difftest::run(reference, &mut cpu, args.max_steps).expect("Difftest failed");
+            } else {
+                cpu.cpu_exec(args.max_steps)
+                    .expect("Failed to execute the program");
+            }
+            cpu.print_info();
+            cpu.add_stats(&mut stats);
+            exit_code = (!cpu.running()).then(|| cpu.read_reg(10));
+        }
+    }
+
+    match exit_code {
+        // riscv-tests report their result through HTIF instead of a0
+        Some(a0) => stats.set("exit_code", vm.htif_exit_code().unwrap_or(a0)),
+        None if !enable_debug_mode => {
+            error!(
+                "The program did not end in {} steps",
+                args.max_steps.unwrap_or_default()
+            )
+        }
+        None => {}
+    }
+
+    if let Some(path) = &args.signature {
+        compliance::write_signature(&vm, &elf_info, path::Path::new(path))
+            .expect("Fail to write signature");
+        info!("Signature written to {path}");
+    }
+
+    if let Some(path) = &args.stats {
+        stats.write(path).expect("Fail to write statistics");
+        info!("Statistics written to {path}");
     }
 
     // Atomatically drop all resources
+    stats
+}
+
+/// Configuration of the run, the first section of the statistics.
+fn config_stats(args: &Args) -> Stats {
+    fn name<T: ValueEnum>(value: Option<&T>) -> String {
+        value
+            .and_then(|v| v.to_possible_value())
+            .map_or("none".to_string(), |v| v.get_name().to_string())
+    }
+    fn debug<T: std::fmt::Debug>(value: Option<&T>) -> String {
+        value.map_or("none".to_string(), |v| format!("{v:?}"))
+    }
+
+    let mut stats = Stats::new();
+    stats.section("config", |s| {
+        s.set(
+            "command_line",
+            std::env::args().collect::<Vec<_>>().join(" "),
+        );
+        s.set(
+            "config",
+            args.config
+                .as_ref()
+                .map_or("none".to_string(), |path| path.display().to_string()),
+        );
+        s.set("input", args.input.as_str());
+        s.set("cpu_mode", name(Some(&args.cpu_mode)));
+        s.set("data_hazard_policy", name(args.data_hazard_policy.as_ref()));
+        s.set("control_policy", name(args.control_policy.as_ref()));
+        s.set("predict_policy", name(args.predict_policy.as_ref()));
+        s.set("bht", debug(args.bht.as_ref()));
+        s.set("btb", debug(args.btb.as_ref()));
+        s.set("predictor", args.predictor.to_string());
+        s.set("ras_depth", args.ras_depth);
+        s.set("fu_latency", args.fu_latency.to_string());
+        s.set("stage_latency", args.stage_latency.to_string());
+        s.set("ooo", args.ooo.to_string());
+        s.set("icache", debug(args.icache.as_ref()));
+        s.set("dcache", debug(args.dcache.as_ref()));
+        s.set("memory_config", debug(args.memory_config.as_ref()));
+        s.set("protect_size", args.protect_size);
+        s.set("stack_size", args.stack_size);
+        s.set("itrace", args.itrace || args.itrace_file.is_some());
+        s.set("mtrace", args.mtrace || args.mtrace_file.is_some());
+        s.set("ftrace", args.ftrace || args.ftrace_file.is_some());
+        s.set("trace_format", name(Some(&args.trace_format)));
+        s.set("difftest", args.difftest);
+        s.set("branch_report", args.branch_report);
+        s.set("iringbuf_size", args.iringbuf_size);
+        s.set("max_steps", debug(args.max_steps.as_ref()));
+        s.set("signature", args.signature.as_deref().unwrap_or("none"));
+    });
+    stats
 }
 
 #[macro_export]
@@ -1,61 +1,365 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, str::FromStr};
 
-use super::cpu::PredictPolicy;
+use log::info;
+
+use crate::stats::Stats;
+
+use super::{
+    cache::ReplacementPolicy,
+    cpu::PredictPolicy,
+    global_predict::{GlobalPredictor, PredictorConfig},
+};
+
+/// Geometry of a finite prediction table, e.g.
+/// `entries=1024,assoc=4,tag=8,repl=lru`. Tables are indexed by `pc >> 2`;
+/// `index=<bits>` may be given instead of `entries`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableConfig {
+    pub entries: usize,
+    pub assoc: usize,
+    /// Width of the partial tag, 0 for an untagged (aliasing) table
+    pub tag_bits: u32,
+    pub replacement: ReplacementPolicy,
+}
+
+impl Default for TableConfig {
+    fn default() -> Self {
+        Self {
+            entries: 1024,
+            assoc: 1,
+            tag_bits: 0,
+            replacement: ReplacementPolicy::Lru,
+        }
+    }
+}
+
+impl TableConfig {
+    pub fn num_sets(&self) -> usize {
+        self.entries / self.assoc
+    }
+
+    pub fn index_bits(&self) -> u32 {
+        self.num_sets().trailing_zeros()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.entries == 0 || self.assoc == 0 {
+            return Err("entries and assoc must be positive".into());
+        }
+        if !self.entries.is_multiple_of(self.assoc) || !self.num_sets().is_power_of_two() {
+            return Err(format!(
+                "entries {} is not a power of 2 multiple of assoc {}",
+                self.entries, self.assoc
+            ));
+        }
+        if self.tag_bits > 64 {
+            return Err(format!("tag width {} exceeds 64 bits", self.tag_bits));
+        }
+        if self.tag_bits == 0 && self.assoc != 1 {
+            return Err("an untagged table must be direct-mapped (assoc=1)".into());
+        }
+        Ok(())
+    }
+}
+
+/// `key=value` pairs separated by commas, omitted keys take their default
+/// value (`entries=1024,assoc=1,tag=0,repl=lru`).
+impl FromStr for TableConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = TableConfig::default();
+        let mut index_bits = None;
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expect key=value, got `{item}`"))?;
+            let invalid = |e: &dyn std::fmt::Display| format!("invalid {key} `{value}`: {e}");
+            match key {
+                "entries" => config.entries = value.parse().map_err(|e| invalid(&e))?,
+                "index" => index_bits = Some(value.parse::<u32>().map_err(|e| invalid(&e))?),
+                "assoc" => config.assoc = value.parse().map_err(|e| invalid(&e))?,
+                "tag" => config.tag_bits = value.parse().map_err(|e| invalid(&e))?,
+                "repl" => config.replacement = value.parse().map_err(|e| invalid(&e))?,
+                _ => return Err(format!("unknown table parameter `{key}`")),
+            }
+        }
+        if let Some(bits) = index_bits {
+            if bits >= usize::BITS {
+                return Err(format!("too many index bits: {bits}"));
+            }
+            config.entries = config.assoc << bits;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TableEntry<T> {
+    valid: bool,
+    tag: u64,
+    // last access time for LRU, fill time for FIFO
+    stamp: u64,
+    value: T,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TableStatistics {
+    pub lookups: u64,
+    pub hits: u64,
+    pub evictions: u64,
+}
+
+enum Storage<T> {
+    /// Unbounded, keyed by the full PC
+    Exact(HashMap<u64, T>),
+    Sets {
+        config: TableConfig,
+        sets: Vec<Vec<TableEntry<T>>>,
+    },
+}
+
+/// Prediction table indexed by PC.
+struct Table<T> {
+    storage: Storage<T>,
+    // access counter used as timestamp
+    tick: u64,
+    // xorshift state for random replacement
+    rng: u64,
+    stats: TableStatistics,
+}
+
+impl<T: Copy + Default> Table<T> {
+    fn new(config: Option<TableConfig>) -> Self {
+        let storage = match config {
+            None => Storage::Exact(HashMap::new()),
+            Some(config) => Storage::Sets {
+                sets: vec![vec![TableEntry::default(); config.assoc]; config.num_sets()],
+                config,
+            },
+        };
+        Self {
+            storage,
+            tick: 0,
+            rng: 0x2545_f491_4f6c_dd1d,
+            stats: TableStatistics::default(),
+        }
+    }
+
+    fn is_exact(&self) -> bool {
+        matches!(self.storage, Storage::Exact(_))
+    }
+
+    /// Set index and partial tag of `pc`.
+    fn locate(config: &TableConfig, pc: u64) -> (usize, u64) {
+        let key = pc >> 2;
+        let index = (key & (config.num_sets() as u64 - 1)) as usize;
+        let tag = match config.tag_bits {
+            0 => 0,
+            64.. => key >> config.index_bits(),
+            bits => (key >> config.index_bits()) & ((1 << bits) - 1),
+        };
+        (index, tag)
+    }
+
+    /// Read the entry matching `pc` without counting an access.
+    fn peek(&self, pc: u64) -> Option<T> {
+        match &self.storage {
+            Storage::Exact(map) => map.get(&pc).copied(),
+            Storage::Sets { config, sets } => {
+                let (index, tag) = Self::locate(config, pc);
+                sets[index]
+                    .iter()
+                    .find(|e| e.valid && e.tag == tag)
+                    .map(|e| e.value)
+            }
+        }
+    }
+
+    /// Read the entry matching `pc`, which may belong to another PC sharing
+    /// its index and partial tag.
+    fn lookup(&mut self, pc: u64) -> Option<T> {
+        self.tick += 1;
+        self.stats.lookups += 1;
+        let value = match &mut self.storage {
+            Storage::Exact(map) => map.get(&pc).copied(),
+            Storage::Sets { config, sets } => {
+                let (index, tag) = Self::locate(config, pc);
+                let lru = config.replacement == ReplacementPolicy::Lru;
+                sets[index]
+                    .iter_mut()
+                    .find(|e| e.valid && e.tag == tag)
+                    .map(|e| {
+                        if lru {
+                            e.stamp = self.tick;
+                        }
+                        e.value
+                    })
+            }
+        };
+        if value.is_some() {
+            self.stats.hits += 1;
+        }
+        value
+    }
+
+    /// Replace the entry matching `pc` with `f(old value)`, allocating one if
+    /// there is none.
+    fn update(&mut self, pc: u64, f: impl FnOnce(Option<T>) -> T) {
+        self.tick += 1;
+        let tick = self.tick;
+        match &mut self.storage {
+            Storage::Exact(map) => {
+                let value = f(map.get(&pc).copied());
+                map.insert(pc, value);
+            }
+            Storage::Sets { config, sets } => {
+                let (index, tag) = Self::locate(config, pc);
+                let set = &mut sets[index];
+                if let Some(entry) = set.iter_mut().find(|e| e.valid && e.tag == tag) {
+                    entry.value = f(Some(entry.value));
+                    if config.replacement == ReplacementPolicy::Lru {
+                        entry.stamp = tick;
+                    }
+                    return;
+                }
+                let way = match set.iter().position(|e| !e.valid) {
+                    Some(way) => way,
+                    None => {
+                        self.stats.evictions += 1;
+                        match config.replacement {
+                            ReplacementPolicy::Lru | ReplacementPolicy::Fifo => set
+                                .iter()
+                                .enumerate()
+                                .min_by_key(|(_, e)| e.stamp)
+                                .map(|(way, _)| way)
+                                .unwrap(),
+                            ReplacementPolicy::Random => {
+                                self.rng ^= self.rng << 13;
+                                self.rng ^= self.rng >> 7;
+                                self.rng ^= self.rng << 17;
+                                (self.rng % set.len() as u64) as usize
+                            }
+                        }
+                    }
+                };
+                set[way] = TableEntry {
+                    valid: true,
+                    tag,
+                    stamp: tick,
+                    value: f(None),
+                };
+            }
+        }
+    }
+
+    fn print_info(&self, name: &str) {
+        let stats = &self.stats;
+        info!(
+            "{name} lookups: {}, hits: {}, evictions: {}",
+            stats.lookups, stats.hits, stats.evictions
+        );
+    }
+
+    fn add_stats(&self, stats: &mut Stats) {
+        stats.set("lookups", self.stats.lookups);
+        stats.set("hits", self.stats.hits);
+        stats.set("evictions", self.stats.evictions);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PredictStatistics {
+    pub branches: u64,
+    pub correct: u64,
+}
 
 /// Branch history table
 pub struct BHT {
-    inner: HashMap<u64, u8>, // pc -> taken
+    inner: Table<u8>, // pc -> taken
     predict_policy: PredictPolicy,
+    // predictor using the global history, if any
+    global: Option<GlobalPredictor>,
+    // global history register, newest outcome in bit 0
+    history: u64,
+    stats: PredictStatistics,
 }
 
 /// Branch target buffer
 pub struct BTB {
-    inner: HashMap<u64, u64>, // pc -> branch target address
+    inner: Table<u64>, // pc -> branch target address
 }
 
-/// Return address stack
+/// Return address stack, a circular buffer: a push onto a full stack
+/// overwrites the oldest entry
 #[derive(Debug)]
 pub struct RAS {
     inner: Vec<u64>,
+    // index of the top entry
+    tos: usize,
+    // valid entries, at most the depth
+    count: usize,
+    stats: RasStatistics,
+}
+
+/// Top of the RAS after an instruction was fetched, to repair the RAS when
+/// younger instructions are flushed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RasCheckpoint {
+    tos: usize,
+    top: u64,
+    count: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RasStatistics {
+    pub pushes: u64,
+    pub pops: u64,
+    pub overflows: u64,
+    pub underflows: u64,
+    pub hits: u64,
+    pub mispredictions: u64,
+    pub repairs: u64,
 }
 
 impl BHT {
-    pub fn new(predict_policy: PredictPolicy) -> Self {
+    /// The per-PC table is unbounded and keyed by the full PC if `config` is
+    /// [`None`].
+    pub fn new(
+        predict_policy: PredictPolicy,
+        config: Option<TableConfig>,
+        predictor_config: &PredictorConfig,
+    ) -> Self {
         Self {
-            inner: HashMap::new(),
+            inner: Table::new(config),
             predict_policy,
+            global: GlobalPredictor::new(predict_policy, predictor_config),
+            history: 0,
+            stats: PredictStatistics::default(),
         }
     }
 
-    pub fn init_pc_predict(&mut self, pc: u64) -> u8 {
-        let init_predict = match self.predict_policy {
+    fn init_predict(&self) -> u8 {
+        match self.predict_policy {
             PredictPolicy::OneBitPredict => {
                 0 // Initially not taken
             }
-            PredictPolicy::TwoBitsPredict => {
+            _ => {
                 0b01 // Initially not taken but in an unstable FSM state
             }
-        };
-        self.inner.insert(pc, init_predict);
-        init_predict
+        }
     }
 
-    /// Called in Fetch phase
-    pub fn predict(&mut self, pc: u64) -> bool {
-        let result = self
-            .inner
-            .get(&pc)
-            .cloned()
-            .unwrap_or_else(|| self.init_pc_predict(pc));
-
+    fn local_predict(&self, state: u8) -> bool {
         match self.predict_policy {
             PredictPolicy::OneBitPredict => {
-                assert!(result == 0 || result == 1);
-                result == 1
+                assert!(state == 0 || state == 1);
+                state == 1
             }
-            PredictPolicy::TwoBitsPredict => {
-                assert!(result == 0b00 || result == 0b01 || result == 0b10 || result == 0b11);
-                match result {
+            _ => {
+                assert!(state == 0b00 || state == 0b01 || state == 0b10 || state == 0b11);
+                match state {
                     0b00 | 0b01 => false, // branch not taken
                     0b10 | 0b11 => true,  // branch taken
                     _ => unreachable!(),
@@ -64,70 +368,298 @@ impl BHT {
         }
     }
 
-    /// Called by CPU with Exec phase result
-    pub fn update_with_result(&mut self, pc: u64, taken: bool) {
+    pub fn predict_policy(&self) -> PredictPolicy {
+        self.predict_policy
+    }
+
+    /// Global history to be passed back to [`BHT::update_with_result`] with
+    /// the prediction.
+    pub fn history(&self) -> u64 {
+        self.history
+    }
+
+    /// Called in Fetch phase
+    pub fn predict(&mut self, pc: u64) -> bool {
+        let local = match self.global {
+            None | Some(GlobalPredictor::Tournament { .. }) => {
+                let state = self.inner.lookup(pc).unwrap_or_else(|| self.init_predict());
+                self.local_predict(state)
+            }
+            Some(_) => false,
+        };
+        match &self.global {
+            Some(global) => global.predict(pc, self.history, local),
+            None => local,
+        }
+    }
+
+    /// Called by CPU with Exec phase result. `history` is the global history
+    /// the branch was predicted with and `predicted` the direction predicted.
+    /// Only conditional branches enter the history and the accuracy.
+    pub fn update_with_result(
+        &mut self,
+        pc: u64,
+        taken: bool,
+        conditional: bool,
+        history: u64,
+        predicted: bool,
+    ) {
+        if conditional {
+            self.stats.branches += 1;
+            if predicted == taken {
+                self.stats.correct += 1;
+            }
+            self.history = self.history << 1 | taken as u64;
+        }
+
+        let init_predict = self.init_predict();
+        let local = self.inner.peek(pc).unwrap_or(init_predict);
+        let local_predicted = self.local_predict(local);
         match self.predict_policy {
             PredictPolicy::OneBitPredict => {
-                self.inner.insert(pc, if taken { 1 } else { 0 });
-            }
-            PredictPolicy::TwoBitsPredict => {
-                let original_state = *self
-                    .inner
-                    .get(&pc)
-                    .expect(format!("Not initialized: {pc:#x}").as_str());
-                let new_state = match (original_state, taken) {
-                    (0b00, false) => 0b00,
-                    (0b00, true) => 0b01,
-                    (0b01, false) => 0b00,
-                    (0b01, true) => 0b11,
-                    (0b11, false) => 0b10,
-                    (0b11, true) => 0b11,
-                    (0b10, false) => 0b00,
-                    (0b10, true) => 0b11,
-                    _ => unreachable!(),
-                };
-                self.inner.insert(pc, new_state);
+                self.inner.update(pc, |_| if taken { 1 } else { 0 });
+            }
+            PredictPolicy::TwoBitsPredict | PredictPolicy::Tournament => {
+                self.inner.update(pc, |original_state| {
+                    // the entry may have been evicted since the prediction
+                    match (original_state.unwrap_or(init_predict), taken) {
+                        (0b00, false) => 0b00,
+                        (0b00, true) => 0b01,
+                        (0b01, false) => 0b00,
+                        (0b01, true) => 0b11,
+                        (0b11, false) => 0b10,
+                        (0b11, true) => 0b11,
+                        (0b10, false) => 0b00,
+                        (0b10, true) => 0b11,
+                        _ => unreachable!(),
+                    }
+                });
+            }
+            _ => {}
+        }
+        if let Some(global) = self.global.as_mut() {
+            global.update(pc, history, taken, local_predicted);
+        }
+    }
+
+    pub fn print_info(&self) {
+        if self.global.is_none() || self.predict_policy == PredictPolicy::Tournament {
+            self.inner.print_info("BHT");
+        }
+        let stats = &self.stats;
+        info!(
+            "Branch predictor ({:?}) conditional branches: {}, correct: {}, accuracy: {:.4}",
+            self.predict_policy,
+            stats.branches,
+            stats.correct,
+            if stats.branches == 0 {
+                0.0
+            } else {
+                stats.correct as f64 / stats.branches as f64
             }
+        );
+    }
+
+    pub fn add_stats(&self, stats: &mut Stats) {
+        let policy = clap::ValueEnum::to_possible_value(&self.predict_policy);
+        stats.set(
+            "policy",
+            policy.map_or(String::new(), |v| v.get_name().to_string()),
+        );
+        stats.set("branches", self.stats.branches);
+        stats.set("correct", self.stats.correct);
+        stats.set_ratio("accuracy", self.stats.correct, self.stats.branches);
+        if self.global.is_none() || self.predict_policy == PredictPolicy::Tournament {
+            stats.section("bht", |s| self.inner.add_stats(s));
         }
     }
 }
 
 impl BTB {
-    pub fn new() -> Self {
+    /// An unbounded table keyed by the full PC if `config` is [`None`].
+    pub fn new(config: Option<TableConfig>) -> Self {
         Self {
-            inner: HashMap::new(),
+            inner: Table::new(config),
         }
     }
 
     /// Called in Fetch phase
-    pub fn query_target(&self, pc: u64) -> Option<u64> {
-        self.inner.get(&pc).cloned()
+    pub fn query_target(&mut self, pc: u64) -> Option<u64> {
+        self.inner.lookup(pc)
     }
 
     /// Called by CPU with Exec phase result
     pub fn add_entry(&mut self, pc: u64, target: u64, is_jalr: bool) {
-        let old_target = self.inner.insert(pc, target);
-        // sanity check
-        if is_jalr {
-            /* Do nothing */
-        } else {
-            if let Some(old_target) = old_target {
+        // sanity check, only possible when entries are not shared between PCs
+        let check = !is_jalr && self.inner.is_exact();
+        self.inner.update(pc, |old_target| {
+            if let Some(old_target) = old_target.filter(|_| check) {
                 assert!(old_target == target)
             }
-        }
+            target
+        });
+    }
+
+    pub fn print_info(&self) {
+        self.inner.print_info("BTB");
+    }
+
+    pub fn add_stats(&self, stats: &mut Stats) {
+        self.inner.add_stats(stats);
     }
 }
 
 impl RAS {
-    pub fn new() -> Self {
-        Self { inner: Vec::new() }
+    pub fn new(depth: usize) -> Self {
+        assert!(depth > 0, "RAS depth must be positive");
+        Self {
+            inner: vec![0; depth],
+            tos: depth - 1,
+            count: 0,
+            stats: RasStatistics::default(),
+        }
     }
 
     pub fn push(&mut self, ra: u64) {
-        self.inner.push(ra);
+        self.stats.pushes += 1;
+        self.tos = (self.tos + 1) % self.inner.len();
+        self.inner[self.tos] = ra;
+        if self.count == self.inner.len() {
+            self.stats.overflows += 1;
+        } else {
+            self.count += 1;
+        }
     }
 
+    /// [`None`] if all pushed entries have been popped or overwritten.
     pub fn pop(&mut self) -> Option<u64> {
-        self.inner.pop()
+        self.stats.pops += 1;
+        if self.count == 0 {
+            self.stats.underflows += 1;
+            return None;
+        }
+        let ra = self.inner[self.tos];
+        self.count -= 1;
+        self.tos = (self.tos + self.inner.len() - 1) % self.inner.len();
+        Some(ra)
+    }
+
+    pub fn checkpoint(&self) -> RasCheckpoint {
+        RasCheckpoint {
+            tos: self.tos,
+            top: self.inner[self.tos],
+            count: self.count,
+        }
+    }
+
+    /// Restore the top of stack. Entries below it overwritten by flushed
+    /// calls are not recovered.
+    pub fn restore(&mut self, checkpoint: RasCheckpoint) {
+        self.tos = checkpoint.tos;
+        self.inner[self.tos] = checkpoint.top;
+        self.count = checkpoint.count;
+    }
+
+    /// Restore the top of stack on a misprediction
+    pub fn repair(&mut self, checkpoint: RasCheckpoint) {
+        self.stats.repairs += 1;
+        self.restore(checkpoint);
+    }
+
+    /// Called by CPU when a return predicted by the RAS resolves
+    pub fn record(&mut self, hit: bool) {
+        if hit {
+            self.stats.hits += 1;
+        } else {
+            self.stats.mispredictions += 1;
+        }
+    }
+
+    pub fn print_info(&self) {
+        let stats = &self.stats;
+        info!(
+            "RAS pushes: {}, pops: {}, overflows: {}, underflows: {}, repairs: {}",
+            stats.pushes, stats.pops, stats.overflows, stats.underflows, stats.repairs
+        );
+        info!(
+            "RAS hits: {}, mispredictions: {}",
+            stats.hits, stats.mispredictions
+        );
+    }
+
+    pub fn add_stats(&self, stats: &mut Stats) {
+        let ras = &self.stats;
+        stats.set("pushes", ras.pushes);
+        stats.set("pops", ras.pops);
+        stats.set("overflows", ras.overflows);
+        stats.set("underflows", ras.underflows);
+        stats.set("repairs", ras.repairs);
+        stats.set("hits", ras.hits);
+        stats.set("mispredictions", ras.mispredictions);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_table_config() {
+        let config: TableConfig = "index=6,assoc=4,tag=8,repl=fifo".parse().unwrap();
+        assert_eq!(config.entries, 256);
+        assert_eq!(config.num_sets(), 64);
+        assert_eq!(config.index_bits(), 6);
+        assert_eq!(config.replacement, ReplacementPolicy::Fifo);
+
+        assert!("entries=1000".parse::<TableConfig>().is_err());
+        assert!("assoc=2".parse::<TableConfig>().is_err());
+    }
+
+    #[test]
+    fn untagged_bht_aliases() {
+        // 4 entries, pc 0x0 and 0x10 share an entry
+        let mut bht = BHT::new(
+            PredictPolicy::OneBitPredict,
+            Some("entries=4".parse().unwrap()),
+            &PredictorConfig::default(),
+        );
+        bht.update_with_result(0x0, true, true, 0, false);
+        assert!(bht.predict(0x10));
+        assert!(!bht.predict(0x4));
+    }
+
+    #[test]
+    fn btb_partial_tags_and_replacement() {
+        // one set of two ways with 2-bit tags
+        let mut btb = BTB::new(Some("entries=2,assoc=2,tag=2".parse().unwrap()));
+        btb.add_entry(0x0, 0x100, false);
+        btb.add_entry(0x4, 0x200, false);
+        // the tag of 0x10 equals the tag of 0x0
+        assert_eq!(btb.query_target(0x10), Some(0x100));
+        // 0x4 is now least recently used and gets evicted
+        btb.add_entry(0x8, 0x300, false);
+        assert_eq!(btb.query_target(0x8), Some(0x300));
+        assert_eq!(btb.query_target(0x0), Some(0x100));
+        assert_eq!(btb.query_target(0x4), None);
+        assert_eq!(btb.inner.stats.evictions, 1);
+    }
+
+    #[test]
+    fn ras_wraps_and_repairs() {
+        let mut ras = RAS::new(2);
+        ras.push(0x10);
+        ras.push(0x20);
+        // overwrites 0x10
+        ras.push(0x30);
+        let checkpoint = ras.checkpoint();
+        assert_eq!(ras.pop(), Some(0x30));
+        // a flushed call overwrites the entry below the top
+        ras.push(0x40);
+        ras.repair(checkpoint);
+        assert_eq!(ras.pop(), Some(0x30));
+        assert_eq!(ras.pop(), Some(0x20));
+        assert_eq!(ras.pop(), None);
+        assert_eq!(ras.stats.overflows, 1);
+        assert_eq!(ras.stats.underflows, 1);
     }
 }
@@ -0,0 +1,148 @@
+//! Per-branch prediction statistics of the pipeline CPU.
+//!
+//! Every control instruction resolving in EX is recorded under its PC. At the
+//! end of the run the branches with the most mispredictions are listed with
+//! their function, and the mispredictions per thousand instructions (MPKI)
+//! summarize the whole run.
+
+use std::collections::{BTreeMap, HashMap};
+
+use log::info;
+
+use crate::{multi_stage::debug::disasm, profile::FunctionSymbols, stats::Stats};
+
+#[derive(Debug, Clone, Default)]
+pub struct BranchRecord {
+    pub raw_inst: u32,
+    pub executions: u64,
+    pub taken: u64,
+    pub mispredictions: u64,
+    pub btb_misses: u64,
+    /// What predicted the branch the last time it executed
+    pub predictor: &'static str,
+}
+
+pub struct BranchStats {
+    branches: BTreeMap<u64, BranchRecord>,
+    functions: FunctionSymbols,
+    // how many branches to list at the end of the run
+    report_size: usize,
+}
+
+impl BranchStats {
+    pub fn new(symbol_map: &HashMap<u64, String>, report_size: usize) -> BranchStats {
+        BranchStats {
+            branches: BTreeMap::new(),
+            functions: FunctionSymbols::new(symbol_map),
+            report_size,
+        }
+    }
+
+    /// Called by CPU when a control instruction resolves in EX.
+    pub fn record(
+        &mut self,
+        pc: u64,
+        raw_inst: u32,
+        taken: bool,
+        mispredicted: bool,
+        btb_miss: bool,
+        predictor: &'static str,
+    ) {
+        let record = self.branches.entry(pc).or_default();
+        record.raw_inst = raw_inst;
+        record.executions += 1;
+        record.taken += taken as u64;
+        record.mispredictions += mispredicted as u64;
+        record.btb_misses += btb_miss as u64;
+        record.predictor = predictor;
+    }
+
+    pub fn mispredictions(&self) -> u64 {
+        self.branches.values().map(|r| r.mispredictions).sum()
+    }
+
+    /// Branches with at least one misprediction, the worst first.
+    pub fn worst(&self) -> Vec<(u64, &BranchRecord)> {
+        let mut worst: Vec<(u64, &BranchRecord)> = self
+            .branches
+            .iter()
+            .filter(|(_, r)| r.mispredictions != 0)
+            .map(|(pc, r)| (*pc, r))
+            .collect();
+        worst.sort_by_key(|(pc, r)| (std::cmp::Reverse(r.mispredictions), *pc));
+        worst
+    }
+
+    /// `insts` is the number of executed instructions.
+    pub fn add_stats(&self, stats: &mut Stats, insts: u64) {
+        let mispredictions = self.mispredictions();
+        stats.set("mispredictions", mispredictions);
+        stats.set_ratio("mpki", mispredictions * 1000, insts);
+        stats.set("static", self.branches.len());
+    }
+
+    /// `insts` is the number of executed instructions.
+    pub fn print_info(&self, insts: u64) {
+        let mispredictions = self.mispredictions();
+        info!(
+            "CPU branch mispredictions: {}, MPKI: {:.3}, static branches: {}",
+            mispredictions,
+            if insts == 0 {
+                0.0
+            } else {
+                mispredictions as f64 * 1000.0 / insts as f64
+            },
+            self.branches.len()
+        );
+
+        let worst = self.worst();
+        if self.report_size == 0 || worst.is_empty() {
+            return;
+        }
+        info!(
+            "Worst {} branches by mispredictions:",
+            self.report_size.min(worst.len())
+        );
+        info!(
+            "{:>8} {:>7} {:>8} {:>6} {:>8} {:>10}  {:<24} instruction",
+            "execs", "taken%", "mispred", "rate", "btb-miss", "predictor", "location"
+        );
+        for (pc, r) in worst.into_iter().take(self.report_size) {
+            info!(
+                "{:>8} {:>6.1}% {:>8} {:>6.3} {:>8} {:>10}  {:<24} {}",
+                r.executions,
+                r.taken as f64 * 100.0 / r.executions as f64,
+                r.mispredictions,
+                r.mispredictions as f64 / r.executions as f64,
+                r.btb_misses,
+                r.predictor,
+                self.functions.location(pc),
+                disasm(pc, r.raw_inst)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn worst_branches_first() {
+        let mut symbols = HashMap::new();
+        symbols.insert(0x1000, "loop".to_string());
+        let mut stats = BranchStats::new(&symbols, 10);
+        for i in 0..4 {
+            stats.record(0x1008, 0, true, i == 0, false, "gshare");
+            stats.record(0x1010, 0, i % 2 == 0, true, i == 0, "gshare");
+        }
+        stats.record(0x1020, 0, false, false, false, "gshare");
+
+        assert_eq!(stats.mispredictions(), 5);
+        let worst: Vec<u64> = stats.worst().iter().map(|(pc, _)| *pc).collect();
+        assert_eq!(worst, vec![0x1010, 0x1008]);
+        assert_eq!(stats.branches[&0x1010].taken, 2);
+        assert_eq!(stats.branches[&0x1010].btb_misses, 1);
+        assert_eq!(stats.functions.location(0x1010), "loop+0x10");
+    }
+}
@@ -0,0 +1,405 @@
+//! Cache models for the pipeline CPU.
+//!
+//! Caches only keep tags, the data always lives in `VirtualMemory`. A lookup
+//! reports the line transfers an access needs; a cache directly backed by
+//! memory charges its miss penalty for each of them, a cache inside a
+//! [`super::memory::MemoryHierarchy`] forwards them to the next level.
+//! Accesses are attributed to the line holding their first byte.
+
+use std::str::FromStr;
+
+use log::info;
+
+use crate::stats::Stats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplacementPolicy {
+    Lru,
+    Fifo,
+    Random,
+}
+
+impl FromStr for ReplacementPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(ReplacementPolicy::Lru),
+            "fifo" => Ok(ReplacementPolicy::Fifo),
+            "random" => Ok(ReplacementPolicy::Random),
+            _ => Err("expect lru, fifo or random"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    WriteBack,
+    WriteThrough,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Total size in bytes
+    pub size: usize,
+    pub assoc: usize,
+    /// Line size in bytes
+    pub line_size: usize,
+    pub replacement: ReplacementPolicy,
+    pub write_policy: WritePolicy,
+    pub write_allocate: bool,
+    /// Cycles to transfer one line from or to memory, when the cache is
+    /// directly backed by memory
+    pub miss_penalty: u64,
+    /// Extra cycles of a hit, when the cache is part of a hierarchy
+    pub hit_latency: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            size: 16 * 1024,
+            assoc: 4,
+            line_size: 64,
+            replacement: ReplacementPolicy::Lru,
+            write_policy: WritePolicy::WriteBack,
+            write_allocate: true,
+            miss_penalty: 20,
+            hit_latency: 0,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn num_sets(&self) -> usize {
+        self.size / (self.assoc * self.line_size)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.assoc == 0 || self.line_size == 0 || self.size == 0 {
+            return Err("size, assoc and line must be positive".into());
+        }
+        if !self.line_size.is_power_of_two() {
+            return Err(format!("line size {} is not a power of 2", self.line_size));
+        }
+        if !self.size.is_multiple_of(self.assoc * self.line_size)
+            || !self.num_sets().is_power_of_two()
+        {
+            return Err(format!(
+                "size {} is not a power of 2 multiple of assoc * line ({})",
+                self.size,
+                self.assoc * self.line_size
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Parse a size with an optional `K`/`M` suffix.
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (num, unit) = match s.to_ascii_uppercase().strip_suffix('K') {
+        Some(n) => (n.to_string(), 1024),
+        None => match s.to_ascii_uppercase().strip_suffix('M') {
+            Some(n) => (n.to_string(), 1024 * 1024),
+            None => (s.to_string(), 1),
+        },
+    };
+    num.parse::<usize>()
+        .map(|n| n * unit)
+        .map_err(|e| format!("invalid size `{s}`: {e}"))
+}
+
+impl CacheConfig {
+    /// Set one parameter from its textual value.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = |e: &dyn std::fmt::Display| format!("invalid {key} `{value}`: {e}");
+        match key {
+            "size" => self.size = parse_size(value)?,
+            "assoc" => self.assoc = value.parse().map_err(|e| invalid(&e))?,
+            "line" => self.line_size = parse_size(value)?,
+            "repl" => self.replacement = value.parse().map_err(|e| invalid(&e))?,
+            "write" => {
+                self.write_policy = match value {
+                    "back" => WritePolicy::WriteBack,
+                    "through" => WritePolicy::WriteThrough,
+                    _ => return Err(invalid(&"expect back or through")),
+                }
+            }
+            "alloc" => self.write_allocate = value.parse().map_err(|e| invalid(&e))?,
+            "penalty" => self.miss_penalty = value.parse().map_err(|e| invalid(&e))?,
+            "hit_latency" => self.hit_latency = value.parse().map_err(|e| invalid(&e))?,
+            _ => return Err(format!("unknown cache parameter `{key}`")),
+        }
+        Ok(())
+    }
+}
+
+/// `key=value` pairs separated by commas, e.g.
+/// `size=32K,assoc=8,line=64,repl=lru,write=back,alloc=true,penalty=20`.
+/// Omitted keys take their default value.
+impl FromStr for CacheConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = CacheConfig::default();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expect key=value, got `{item}`"))?;
+            config.set(key, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct CacheLine {
+    valid: bool,
+    dirty: bool,
+    tag: u64,
+    // last access time for LRU, fill time for FIFO
+    stamp: u64,
+}
+
+/// Line transfers needed by an access.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Lookup {
+    pub hit: bool,
+    /// Address of a dirty victim line to write to the next level
+    pub writeback: Option<u64>,
+    /// The line has to be read from the next level
+    pub fill: bool,
+    /// The written data goes to the next level
+    pub write_through: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CacheStatistics {
+    pub accesses: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub writebacks: u64,
+    pub stall_cycles: u64,
+}
+
+pub struct Cache {
+    name: &'static str,
+    config: CacheConfig,
+    sets: Vec<Vec<CacheLine>>,
+    // access counter used as timestamp
+    tick: u64,
+    // xorshift state for random replacement
+    rng: u64,
+    stats: CacheStatistics,
+}
+
+impl Cache {
+    pub fn new(name: &'static str, config: CacheConfig) -> Cache {
+        let sets = vec![vec![CacheLine::default(); config.assoc]; config.num_sets()];
+        Cache {
+            name,
+            config,
+            sets,
+            tick: 0,
+            rng: 0x2545_f491_4f6c_dd1d,
+            stats: CacheStatistics::default(),
+        }
+    }
+
+    /// Look up the byte at `addr` and update the tags, returns the line
+    /// transfers the access needs.
+    pub fn lookup(&mut self, addr: u64, write: bool) -> Lookup {
+        self.tick += 1;
+        self.stats.accesses += 1;
+
+        let line_size = self.config.line_size as u64;
+        let line_addr = addr / line_size;
+        let num_sets = self.sets.len() as u64;
+        let index = (line_addr % num_sets) as usize;
+        let tag = line_addr / num_sets;
+        let write_back = self.config.write_policy == WritePolicy::WriteBack;
+
+        let mut result = Lookup::default();
+        if let Some(line) = self.sets[index]
+            .iter_mut()
+            .find(|l| l.valid && l.tag == tag)
+        {
+            self.stats.hits += 1;
+            result.hit = true;
+            if self.config.replacement == ReplacementPolicy::Lru {
+                line.stamp = self.tick;
+            }
+            if write {
+                if write_back {
+                    line.dirty = true;
+                } else {
+                    result.write_through = true;
+                }
+            }
+            return result;
+        }
+
+        self.stats.misses += 1;
+        if write && !self.config.write_allocate {
+            // write around the cache
+            result.write_through = true;
+            return result;
+        }
+
+        let way = self.victim(index);
+        let tick = self.tick;
+        let line = &mut self.sets[index][way];
+        if line.valid {
+            self.stats.evictions += 1;
+            if line.dirty {
+                self.stats.writebacks += 1;
+                result.writeback = Some((line.tag * num_sets + index as u64) * line_size);
+            }
+        }
+        result.fill = true;
+        *line = CacheLine {
+            valid: true,
+            dirty: write && write_back,
+            tag,
+            stamp: tick,
+        };
+        result.write_through = write && !write_back;
+        result
+    }
+
+    /// Access the byte at `addr` of a cache directly backed by memory,
+    /// returns the stall cycles.
+    pub fn access(&mut self, addr: u64, write: bool) -> u64 {
+        let result = self.lookup(addr, write);
+        let transfers =
+            result.writeback.is_some() as u64 + result.fill as u64 + result.write_through as u64;
+        let stall = transfers * self.config.miss_penalty;
+        self.stats.stall_cycles += stall;
+        stall
+    }
+
+    pub fn hit_latency(&self) -> u64 {
+        self.config.hit_latency
+    }
+
+    pub fn line_size(&self) -> u64 {
+        self.config.line_size as u64
+    }
+
+    pub fn add_stall_cycles(&mut self, cycles: u64) {
+        self.stats.stall_cycles += cycles;
+    }
+
+    fn victim(&mut self, index: usize) -> usize {
+        let set = &self.sets[index];
+        if let Some(way) = set.iter().position(|l| !l.valid) {
+            return way;
+        }
+        match self.config.replacement {
+            ReplacementPolicy::Lru | ReplacementPolicy::Fifo => set
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, l)| l.stamp)
+                .map(|(way, _)| way)
+                .unwrap(),
+            ReplacementPolicy::Random => {
+                self.rng ^= self.rng << 13;
+                self.rng ^= self.rng >> 7;
+                self.rng ^= self.rng << 17;
+                (self.rng % set.len() as u64) as usize
+            }
+        }
+    }
+
+    pub fn print_info(&self) {
+        let stats = &self.stats;
+        info!(
+            "{} accesses: {}, hits: {}, misses: {}, miss rate: {:.4}",
+            self.name,
+            stats.accesses,
+            stats.hits,
+            stats.misses,
+            if stats.accesses == 0 {
+                0.0
+            } else {
+                stats.misses as f64 / stats.accesses as f64
+            }
+        );
+        info!(
+            "{} evictions: {}, writebacks: {}, stall cycles: {}",
+            self.name, stats.evictions, stats.writebacks, stats.stall_cycles
+        );
+    }
+
+    pub fn add_stats(&self, stats: &mut Stats) {
+        let cache = &self.stats;
+        stats.set("accesses", cache.accesses);
+        stats.set("hits", cache.hits);
+        stats.set("misses", cache.misses);
+        stats.set_ratio("miss_rate", cache.misses, cache.accesses);
+        stats.set("evictions", cache.evictions);
+        stats.set("writebacks", cache.writebacks);
+        stats.set("stall_cycles", cache.stall_cycles);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cache(spec: &str) -> Cache {
+        Cache::new("test", spec.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_config() {
+        let config: CacheConfig = "size=32K,assoc=8,repl=fifo,write=through,alloc=false"
+            .parse()
+            .unwrap();
+        assert_eq!(config.size, 32 * 1024);
+        assert_eq!(config.assoc, 8);
+        assert_eq!(config.line_size, 64);
+        assert_eq!(config.replacement, ReplacementPolicy::Fifo);
+        assert_eq!(config.write_policy, WritePolicy::WriteThrough);
+        assert!(!config.write_allocate);
+        assert_eq!(config.num_sets(), 64);
+
+        assert!("size=1000".parse::<CacheConfig>().is_err());
+        assert!("ways=2".parse::<CacheConfig>().is_err());
+    }
+
+    #[test]
+    fn lru_and_fifo() {
+        // one set of two ways
+        let mut lru = cache("size=128,assoc=2,line=64,penalty=10");
+        let mut fifo = cache("size=128,assoc=2,line=64,penalty=10,repl=fifo");
+        for c in [&mut lru, &mut fifo] {
+            assert_eq!(c.access(0x000, false), 10);
+            assert_eq!(c.access(0x040, false), 10);
+            assert_eq!(c.access(0x008, false), 0);
+            // evicts 0x040 under LRU, 0x000 under FIFO
+            assert_eq!(c.access(0x080, false), 10);
+        }
+        assert_eq!(lru.access(0x000, false), 0);
+        assert_eq!(fifo.access(0x040, false), 0);
+        assert_eq!(lru.stats.evictions, 1);
+    }
+
+    #[test]
+    fn write_back_and_through() {
+        let mut wb = cache("size=64,assoc=1,line=64,penalty=10");
+        assert_eq!(wb.access(0x00, true), 10);
+        assert_eq!(wb.access(0x08, true), 0);
+        // dirty victim is written back
+        assert_eq!(wb.access(0x40, false), 20);
+        assert_eq!(wb.stats.writebacks, 1);
+
+        let mut wt = cache("size=64,assoc=1,line=64,penalty=10,write=through,alloc=false");
+        assert_eq!(wt.access(0x00, true), 10);
+        assert_eq!(wt.access(0x00, false), 10);
+        assert_eq!(wt.access(0x00, true), 10);
+        assert_eq!(wt.stats.writebacks, 0);
+    }
+}
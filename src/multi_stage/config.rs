@@ -0,0 +1,19 @@
+use super::{
+    branch_predict::TableConfig,
+    cpu::{ControlPolicy, PredictPolicy},
+    func_unit::FuConfig,
+    global_predict::PredictorConfig,
+};
+
+/// Branch prediction and functional units of the pipeline, the dual-issue
+/// and the out-of-order CPU.
+#[derive(Debug, Clone)]
+pub struct CoreConfig {
+    pub control_policy: ControlPolicy,
+    pub predict_policy: Option<PredictPolicy>,
+    pub bht: Option<TableConfig>,
+    pub btb: Option<TableConfig>,
+    pub predictor: PredictorConfig,
+    pub ras_depth: usize,
+    pub fu_latency: FuConfig,
+}
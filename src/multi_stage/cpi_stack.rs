@@ -0,0 +1,133 @@
+//! CPI stack of the pipeline CPU.
+//!
+//! Every cycle is charged to one class. A clock in which an instruction
+//! leaves EX is a base cycle, otherwise the clock is charged to whatever put
+//! the bubble into the pipeline: a stall of ID on a data hazard or a load-use
+//! hazard, or a flush or a stall of IF on a control instruction. The cycles
+//! added by iterative units, memory accesses and longer stages are charged
+//! where they are added, so the classes sum up to the run clock. The delayed
+//! cycles of the data hazard counter are the data and load-use cycles.
+
+use log::info;
+
+use crate::stats::Stats;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CycleClass {
+    Base,
+    Data,
+    LoadUse,
+    Control,
+    Structural,
+    Memory,
+}
+
+impl CycleClass {
+    const ALL: [CycleClass; 6] = [
+        CycleClass::Base,
+        CycleClass::Data,
+        CycleClass::LoadUse,
+        CycleClass::Control,
+        CycleClass::Structural,
+        CycleClass::Memory,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            CycleClass::Base => "base",
+            CycleClass::Data => "data",
+            CycleClass::LoadUse => "load_use",
+            CycleClass::Control => "control",
+            CycleClass::Structural => "structural",
+            CycleClass::Memory => "memory",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct CpiStack {
+    cycles: [u64; 6],
+    // memory stalls are only reported when the memory hierarchy is modelled
+    memory: bool,
+}
+
+impl CpiStack {
+    pub(super) fn new(memory: bool) -> CpiStack {
+        CpiStack {
+            cycles: [0; 6],
+            memory,
+        }
+    }
+
+    pub(super) fn add(&mut self, class: CycleClass, cycles: u64) {
+        self.cycles[class as usize] += cycles;
+    }
+
+    pub(super) fn cycles(&self, class: CycleClass) -> u64 {
+        self.cycles[class as usize]
+    }
+
+    fn classes(&self) -> impl Iterator<Item = CycleClass> + '_ {
+        CycleClass::ALL
+            .into_iter()
+            .filter(|class| *class != CycleClass::Memory || self.memory)
+    }
+
+    pub(super) fn print_info(&self, insts: u64) {
+        let total: u64 = self.cycles.iter().sum();
+        info!("CPI stack:");
+        for class in self.classes() {
+            let cycles = self.cycles(class);
+            info!(
+                "  {:<10} {:>10} cycles  CPI {:.3}  {:5.1}%",
+                class.name(),
+                cycles,
+                cycles as f64 / insts as f64,
+                cycles as f64 * 100.0 / total as f64
+            );
+        }
+    }
+
+    pub(super) fn add_stats(&self, stats: &mut Stats, insts: u64) {
+        for class in self.classes() {
+            stats.section(class.name(), |s| {
+                s.set("cycles", self.cycles(class));
+                s.set_ratio("cpi", self.cycles(class), insts);
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stats::StatValue;
+
+    #[test]
+    fn stack() {
+        let mut stack = CpiStack::new(false);
+        stack.add(CycleClass::Base, 8);
+        stack.add(CycleClass::LoadUse, 1);
+        stack.add(CycleClass::Control, 2);
+        stack.add(CycleClass::Control, 1);
+        assert_eq!(stack.cycles(CycleClass::Control), 3);
+
+        let mut stats = Stats::new();
+        stats.section("cpi_stack", |s| stack.add_stats(s, 8));
+        assert_eq!(
+            stats.get("cpi_stack.base.cpi"),
+            Some(&StatValue::Float(1.0))
+        );
+        assert_eq!(
+            stats.get("cpi_stack.control.cpi"),
+            Some(&StatValue::Float(0.375))
+        );
+        assert_eq!(stats.get("cpi_stack.data.cycles"), Some(&StatValue::Int(0)));
+        assert_eq!(stats.get("cpi_stack.memory.cycles"), None);
+
+        let stack = CpiStack::new(true);
+        let mut stats = Stats::new();
+        stack.add_stats(&mut stats, 0);
+        assert_eq!(stats.get("memory.cpi"), Some(&StatValue::Float(0.0)));
+    }
+}
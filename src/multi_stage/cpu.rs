@@ -2,27 +2,44 @@ use log::{debug, error, info, warn};
 
 use crate::{
     callstack::CallStack,
+    commit_log::{CommitLog, RetireInfo},
     core::{
+        csr::{serializes, CsrFile},
         insts::Inst64,
         reg::{ProgramCounter, RegisterFile, REGNAME},
         vm::VirtualMemory,
     },
     elf::LoadElfInfo,
     error::{Error, Result},
+    iringbuf::InstRingBuffer,
+    profile::Profiler,
+    stats::Stats,
+    trace::Tracer,
 };
 
 use super::{
     branch_predict::{BHT, BTB, RAS},
+    branch_stats::BranchStats,
+    config::CoreConfig,
+    cpi_stack::{CpiStack, CycleClass},
+    debug::w_pinst,
     decode::decode,
+    diagram::PipelineDiagram,
     exec::exec,
     fetch::fetch,
+    func_unit::{FuClass, FuConfig},
+    kanata::KanataLog,
     mem::mem,
+    memory::MemoryHierarchy,
+    occupancy::LatchStates,
     phases::*,
+    stage_latency::{StageLatency, SubStages},
+    vcd::{Controls, Registers, VcdDump},
     writeback::writeback,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum PipelineState {
+pub(super) enum PipelineState {
     Stall,
     Bubble,
     Normal,
@@ -78,7 +95,8 @@ pub enum DataHazardPolicy {
 pub enum ControlPolicy {
     AllStall,       // stall when branch instructions encountered
     AlwaysNotTaken, // static branch prediction: always not taken
-    // AlwaysTaken,    // static branch prediction: always taken
+    AlwaysTaken,    // static branch prediction: always taken
+    Btfn,           // static branch prediction: backward taken, forward not taken
     DynamicPredict, // dynamic branch prediction
 }
 
@@ -86,6 +104,44 @@ pub enum ControlPolicy {
 pub enum PredictPolicy {
     OneBitPredict,
     TwoBitsPredict,
+    /// PC xor global history indexed 2-bit counters
+    Gshare,
+    /// Local 2-bit counters and gshare with a chooser
+    Tournament,
+    Perceptron,
+    /// Tagged tables with geometric history lengths
+    Tage,
+}
+
+/// Options of the pipeline CPU.
+pub struct PipelineConfig {
+    pub core: CoreConfig,
+    pub data_hazard_policy: DataHazardPolicy,
+    pub stage_latency: StageLatency,
+    /// Caches and DRAM, [`None`] for a memory answering in one cycle
+    pub memory: Option<MemoryHierarchy>,
+    /// How many of the worst predicted branches to report
+    pub branch_report: usize,
+    pub info: PipelineInfo,
+}
+
+/// What the pipeline CPU logs every cycle.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PipelineInfo {
+    pub pre_pipeline: bool,
+    pub pipeline: bool,
+    pub post_pipeline: bool,
+    pub control_hazard: bool,
+    pub data_hazard: bool,
+}
+
+/// Files the pipeline CPU writes while running.
+#[derive(Default)]
+pub struct PipelineLogs {
+    pub commit_log: Option<CommitLog>,
+    pub kanata: Option<KanataLog>,
+    pub diagram: Option<PipelineDiagram>,
+    pub vcd: Option<VcdDump>,
 }
 
 #[derive(Debug, Clone)]
@@ -94,7 +150,11 @@ pub struct CPUStatistics {
     control_hazard_count: u64,
     data_hazard_delayed_cycles: u64,
     control_hazard_delayed_cycles: u64,
+    structural_hazard_count: u64,
+    structural_hazard_delayed_cycles: u64,
+    memory_stall_cycles: u64,
     executed_inst_count: u64,
+    retired_inst_count: u64,
 }
 
 impl Default for CPUStatistics {
@@ -104,7 +164,11 @@ impl Default for CPUStatistics {
             control_hazard_count: 0,
             data_hazard_delayed_cycles: 0,
             control_hazard_delayed_cycles: 0,
+            structural_hazard_count: 0,
+            structural_hazard_delayed_cycles: 0,
+            memory_stall_cycles: 0,
             executed_inst_count: 0,
+            retired_inst_count: 0,
         }
     }
 }
@@ -125,21 +189,37 @@ pub struct CPU<'a> {
     // Program counter (PC) which is not included in general purpose register file.
     pc: ProgramCounter,
 
+    // Control and status registers
+    csr: CsrFile,
+
     // Reference to virtual memory
     vm: &'a mut VirtualMemory,
 
     // Reference to call stack
     callstack: &'a mut CallStack<'a>,
 
+    // Instruction trace
+    itrace: Tracer,
+
+    // IF / IF of a split fetch
+    inner_fetch: SubStages<InternalFetchDecode>,
+
     // IF / ID
     itl_f_d: InternalFetchDecode,
 
     // ID / Exec
     itl_d_e: InternalDecodeExec,
 
+    // Exec / Exec of a split execute, before and from branch resolution on
+    exec_before: SubStages<InternalDecodeExec>,
+    exec_after: SubStages<InternalExecMem>,
+
     // Exec / Mem
     itl_e_m: InternalExecMem,
 
+    // Mem / Mem of a split memory stage
+    inner_mem: SubStages<InternalMemWb>,
+
     // Mem / Wb
     itl_m_w: InternalMemWb,
 
@@ -158,6 +238,14 @@ pub struct CPU<'a> {
     // PC next states
     pc_next_states: [PipelineState; PIPELINE_STATES_DEPTH],
 
+    // Why the bubbles in ID/EX and IF/ID states are inserted
+    d_e_bubble_causes: [CycleClass; PIPELINE_STATES_DEPTH],
+    f_d_bubble_causes: [CycleClass; PIPELINE_STATES_DEPTH],
+
+    // Why ID/EX and IF/ID hold a bubble, if they do
+    d_e_cause: CycleClass,
+    f_d_cause: CycleClass,
+
     // Data hazard policy
     data_hazard_policy: DataHazardPolicy,
 
@@ -185,6 +273,9 @@ pub struct CPU<'a> {
     // Statistics data of CPU
     cpu_statistics: CPUStatistics,
 
+    // Cycles by what the pipeline spent them on
+    cpi_stack: CpiStack,
+
     // Branch history table
     bht: Option<BHT>,
 
@@ -193,35 +284,77 @@ pub struct CPU<'a> {
 
     // Return address stack
     ras: RAS,
+
+    // Per-branch prediction statistics
+    branch_stats: BranchStats,
+
+    // Latencies of the functional units
+    fu_config: FuConfig,
+
+    // Cycles of fetch, execute and memory
+    stage_latency: StageLatency,
+
+    // Cycles until the results of slow functional units can be used, by register
+    fu_busy: [u64; 32],
+
+    // Whether the instruction in ID waited for a source last cycle
+    data_waiting: bool,
+
+    // Caches and DRAM
+    memory: Option<MemoryHierarchy>,
+
+    // Instruction-level profiler
+    profiler: Option<&'a mut Profiler>,
+
+    // Spike-compatible commit log
+    commit_log: Option<CommitLog>,
+
+    // Pipeline log for the Konata viewer
+    kanata: Option<KanataLog>,
+
+    // Pipeline diagram of a window of cycles
+    diagram: Option<PipelineDiagram>,
+
+    // Waveform of the pipeline registers
+    vcd: Option<VcdDump>,
+
+    // Effects of the last retired instruction, for difftest
+    last_retired: Option<RetireInfo>,
+
+    // Last retired instructions, dumped on failure
+    iringbuf: InstRingBuffer,
+
+    // Cycles since the last instruction retired, charged to the next one
+    unretired_cycles: u64,
 }
 
 impl<'a> CPU<'a> {
     pub fn new(
         vm: &'a mut VirtualMemory,
         callstack: &'a mut CallStack<'a>,
-        data_hazard_policy: DataHazardPolicy,
-        control_policy: ControlPolicy,
-        predict_policy: Option<PredictPolicy>,
-        pre_pipeline_info: bool,
-        pipeline_info: bool,
-        post_pipeline_info: bool,
-        control_hazard_info: bool,
-        data_hazard_info: bool,
+        itrace: Tracer,
+        config: PipelineConfig,
+        profiler: Option<&'a mut Profiler>,
+        logs: PipelineLogs,
+        iringbuf_size: usize,
     ) -> CPU<'a> {
+        let PipelineConfig {
+            core,
+            data_hazard_policy,
+            stage_latency,
+            memory,
+            branch_report,
+            info,
+        } = config;
         // x0 already set to 0
         let reg_file = RegisterFile::empty();
         let pc = ProgramCounter::new();
 
-        let bht = if let Some(predict_policy) = predict_policy {
-            Some(BHT::new(predict_policy))
-        } else {
-            None
-        };
-        let btb = if let Some(_) = predict_policy {
-            Some(BTB::new())
-        } else {
-            None
-        };
+        let bht = core
+            .predict_policy
+            .map(|predict_policy| BHT::new(predict_policy, core.bht, &core.predictor));
+        let btb = core.predict_policy.map(|_| BTB::new(core.btb));
+        let branch_stats = BranchStats::new(callstack.symbol_map(), branch_report);
 
         CPU {
             running: false,
@@ -229,33 +362,59 @@ impl<'a> CPU<'a> {
             clock: 0,
             reg_file,
             pc,
+            csr: CsrFile::new(),
             vm,
             callstack,
+            itrace,
+            inner_fetch: SubStages::new(stage_latency.fetch),
             itl_f_d: InternalFetchDecode::default(),
             itl_d_e: InternalDecodeExec::default(),
+            exec_before: SubStages::new(stage_latency.branch),
+            exec_after: SubStages::new(stage_latency.exec - stage_latency.branch + 1),
             itl_e_m: InternalExecMem::default(),
+            inner_mem: SubStages::new(stage_latency.mem),
             itl_m_w: InternalMemWb::default(),
             m_w_pipeline_states: [PipelineState::Normal; PIPELINE_STATES_DEPTH],
             e_m_pipeline_states: [PipelineState::Normal; PIPELINE_STATES_DEPTH],
             d_e_pipeline_states: [PipelineState::Normal; PIPELINE_STATES_DEPTH],
             f_d_pipeline_states: [PipelineState::Normal; PIPELINE_STATES_DEPTH],
             pc_next_states: [PipelineState::Normal; PIPELINE_STATES_DEPTH],
+            d_e_bubble_causes: [CycleClass::Base; PIPELINE_STATES_DEPTH],
+            f_d_bubble_causes: [CycleClass::Base; PIPELINE_STATES_DEPTH],
+            // the pipeline fills up in base cycles
+            d_e_cause: CycleClass::Base,
+            f_d_cause: CycleClass::Base,
             data_hazard_policy,
-            control_policy,
-            pre_pipeline_info,
-            pipeline_info,
-            post_pipeline_info,
-            control_hazard_info,
-            data_hazard_info,
-            clock_info: pre_pipeline_info
-                || pipeline_info
-                || post_pipeline_info
-                || control_hazard_info
-                || data_hazard_info,
+            control_policy: core.control_policy,
+            pre_pipeline_info: info.pre_pipeline,
+            pipeline_info: info.pipeline,
+            post_pipeline_info: info.post_pipeline,
+            control_hazard_info: info.control_hazard,
+            data_hazard_info: info.data_hazard,
+            clock_info: info.pre_pipeline
+                || info.pipeline
+                || info.post_pipeline
+                || info.control_hazard
+                || info.data_hazard,
             cpu_statistics: CPUStatistics::default(),
+            cpi_stack: CpiStack::new(memory.is_some()),
             bht,
             btb,
-            ras: RAS::new(),
+            ras: RAS::new(core.ras_depth),
+            branch_stats,
+            fu_config: core.fu_latency,
+            fu_busy: [0; 32],
+            data_waiting: false,
+            memory,
+            profiler,
+            commit_log: logs.commit_log,
+            kanata: logs.kanata.map(|log| log.with_stages(&stage_latency)),
+            diagram: logs.diagram.map(|diagram| diagram.with_stages(&stage_latency)),
+            vcd: logs.vcd,
+            stage_latency,
+            last_retired: None,
+            iringbuf: InstRingBuffer::new(iringbuf_size),
+            unretired_cycles: 0,
         }
     }
 
@@ -290,6 +449,13 @@ impl<'a> CPU<'a> {
                 break;
             }
             self.clock()?;
+            if let Some(code) = self.vm.htif_exit(self.last_retired.as_ref()) {
+                if code != 0 {
+                    self.iringbuf.dump();
+                }
+                halt(self.last_retired.map_or(0, |info| info.pc), code);
+                self.running = false;
+            }
             i += 1;
         }
 
@@ -314,20 +480,117 @@ impl<'a> CPU<'a> {
             "CPU control hazard delayed cycles: {}",
             self.cpu_statistics.control_hazard_delayed_cycles
         );
+        info!("CPU stage latency: {}", self.stage_latency);
+        info!("CPU functional units: {}", self.fu_config);
+        info!(
+            "CPU structural hazard count: {}",
+            self.cpu_statistics.structural_hazard_count
+        );
+        info!(
+            "CPU structural hazard delayed cycles: {}",
+            self.cpu_statistics.structural_hazard_delayed_cycles
+        );
+        if let Some(bht) = &self.bht {
+            bht.print_info();
+        }
+        if let Some(btb) = &self.btb {
+            btb.print_info();
+        }
+        if self.control_policy == ControlPolicy::DynamicPredict {
+            self.ras.print_info();
+        }
+        if let Some(memory) = &self.memory {
+            info!(
+                "CPU memory stall cycles: {}",
+                self.cpu_statistics.memory_stall_cycles
+            );
+            memory.print_info();
+        }
         info!(
             "CPU executed valid instructions: {}",
             self.cpu_statistics.executed_inst_count
         );
+        info!(
+            "CPU retired instructions: {}",
+            self.cpu_statistics.retired_inst_count
+        );
         info!("CPI = {}", {
             let cycles = self.clock;
-            let insts = self.cpu_statistics.executed_inst_count;
+            let insts = self.cpu_statistics.retired_inst_count;
             (cycles as f64) / (insts as f64)
         });
+        self.cpi_stack
+            .print_info(self.cpu_statistics.retired_inst_count);
+        self.branch_stats
+            .print_info(self.cpu_statistics.retired_inst_count);
+    }
+
+    pub fn add_stats(&self, stats: &mut Stats) {
+        let cpu = &self.cpu_statistics;
+        let insts = cpu.retired_inst_count;
+        stats.set("cycles", self.clock);
+        stats.set("instret", insts);
+        stats.set_ratio("cpi", self.clock, insts);
+        stats.set_ratio("ipc", insts, self.clock);
+        stats.section("hazards", |s| {
+            s.section("data", |s| {
+                s.set("count", cpu.data_hazard_count);
+                s.set("cycles", cpu.data_hazard_delayed_cycles);
+            });
+            s.section("control", |s| {
+                s.set("count", cpu.control_hazard_count);
+                s.set("cycles", cpu.control_hazard_delayed_cycles);
+            });
+            s.section("structural", |s| {
+                s.set("count", cpu.structural_hazard_count);
+                s.set("cycles", cpu.structural_hazard_delayed_cycles);
+            });
+            s.section("memory", |s| s.set("cycles", cpu.memory_stall_cycles));
+        });
+        stats.section("cpi_stack", |s| self.cpi_stack.add_stats(s, insts));
+        if let Some(bht) = &self.bht {
+            stats.section("predictor", |s| bht.add_stats(s));
+        }
+        if let Some(btb) = &self.btb {
+            stats.section("btb", |s| btb.add_stats(s));
+        }
+        if self.control_policy == ControlPolicy::DynamicPredict {
+            stats.section("ras", |s| self.ras.add_stats(s));
+        }
+        stats.section("branches", |s| self.branch_stats.add_stats(s, insts));
+        if let Some(memory) = &self.memory {
+            stats.section("memory", |s| memory.add_stats(s));
+        }
+    }
+
+    /// What predicted a control instruction, for the per-branch statistics.
+    fn predictor_name(&self, itl_e_m: &InternalExecMem) -> &'static str {
+        use Inst64::*;
+        match self.control_policy {
+            ControlPolicy::AllStall => "all-stall",
+            ControlPolicy::AlwaysNotTaken => "not-taken",
+            ControlPolicy::AlwaysTaken => "taken",
+            ControlPolicy::Btfn => "btfn",
+            ControlPolicy::DynamicPredict => match itl_e_m.alu_op {
+                _ if itl_e_m.branch_flags.ras_predicted => "ras",
+                jal | jalr => "btb",
+                _ => match self.bht.as_ref().map(|bht| bht.predict_policy()) {
+                    Some(PredictPolicy::OneBitPredict) => "one-bit",
+                    Some(PredictPolicy::TwoBitsPredict) => "two-bits",
+                    Some(PredictPolicy::Gshare) => "gshare",
+                    Some(PredictPolicy::Tournament) => "tournament",
+                    Some(PredictPolicy::Perceptron) => "perceptron",
+                    Some(PredictPolicy::Tage) => "tage",
+                    None => unreachable!(),
+                },
+            },
+        }
     }
 
     pub(super) fn clock(&mut self) -> Result<()> {
         // begin the clock
         self.clock += 1;
+        let clock_begin = self.clock;
         if self.clock_info {
             debug!(
                 "#################### CLOCK: {} ####################",
@@ -335,64 +598,74 @@ impl<'a> CPU<'a> {
             );
         }
 
-        // detect load-use hazard
-        let load_use_detected = {
-            // debug!("Detecting load-use hazard");
-            if self.itl_d_e.mem_flags.mem_read
-                && ((self.itl_d_e.rd == self.itl_f_d.rs1) || (self.itl_d_e.rd == self.itl_f_d.rs2))
-            {
-                // stall the pipeline
-                // id est set the control bits in the EX,MEM, and WB control fields of the ID/EX
-                // pipeline register to 0 (noop).
-                if self.data_hazard_info {
-                    warn!("Load-use hazard detected.");
-                    warn!(
-                        "  IF/ID.rs1={}({})",
-                        self.itl_d_e.rd, REGNAME[self.itl_d_e.rd as usize],
-                    );
-                    warn!(
-                        "  ID/EX.rd={}({})",
-                        self.itl_f_d.rs1, REGNAME[self.itl_f_d.rs1 as usize]
-                    );
-                    warn!(
-                        "  IF/ID.rs2={}({})",
-                        self.itl_f_d.rs2, REGNAME[self.itl_f_d.rs2 as usize]
-                    );
-                    warn!("  Stall 1 cycle");
+        // detect sources not produced yet: the consumer in ID waits until the
+        // youngest producer of each source is in reach of a forwarding path
+        // when the consumer enters EX, or of the register file with naive stall
+        let (load_use_detected, exec_use_detected) = {
+            let producers = self.producers();
+            let exec = self.stage_latency.exec;
+            let mem = self.stage_latency.mem;
+            let mut load_use = false;
+            let mut exec_use = false;
+            for rs in [self.itl_f_d.rs1, self.itl_f_d.rs2] {
+                let Some(&(cycles, _, load)) =
+                    producers.iter().find(|&&(_, rd, _)| rs != 0 && rd == rs)
+                else {
+                    continue;
+                };
+                // loaded data leaves MEM last, the other results leave EX
+                let wait = match self.data_hazard_policy {
+                    DataHazardPolicy::NaiveStall => cycles < exec + mem,
+                    DataHazardPolicy::DataForward => {
+                        cycles + 1 < if load { exec + mem } else { exec }
+                    }
+                };
+                if wait {
+                    if self.data_hazard_info {
+                        warn!(
+                            "{} hazard detected, {} cycles after entering EX",
+                            if load { "Load-use" } else { "Data" },
+                            cycles
+                        );
+                        warn!("  IF/ID.rs={}({})", rs, REGNAME[rs as usize]);
+                        warn!("  Stall 1 cycle");
+                    }
+                    load_use |= load;
+                    exec_use |= !load;
                 }
-                self.cpu_statistics.data_hazard_count += 1;
-                true
-            } else {
-                false
             }
+            (load_use, exec_use)
+        };
+
+        // detect use of a result a pipelined functional unit has not produced
+        // yet, a load slower than the load-use stall is held back here too
+        let fu_use_detected = {
+            let sources = [self.itl_f_d.rs1, self.itl_f_d.rs2];
+            let rd = self.itl_d_e.rd;
+            let latency = self.result_latency(self.itl_d_e.exec_flags.alu_op);
+            let producing = !self.itl_d_e.mem_flags.mem_read
+                && self.itl_d_e.wb_flags.mem_to_reg
+                && rd != 0
+                && latency > 1
+                && sources.contains(&rd);
+            if producing && self.data_hazard_info {
+                warn!(
+                    "Functional unit use hazard detected, {:?} latency {}",
+                    self.itl_d_e.exec_flags.alu_op, latency
+                );
+                warn!("  ID/EX.rd={}({})", rd, REGNAME[rd as usize]);
+            }
+            producing
+                || sources
+                    .iter()
+                    .any(|&rs| rs != 0 && self.fu_busy[rs as usize] != 0)
         };
 
         // detect memory-to-memory copy
         {
             match self.data_hazard_policy {
                 DataHazardPolicy::NaiveStall => {
-                    // solved by EX/MEM NaiveInstall policy logic
-                    let id_ex_rd = self.itl_d_e.rd;
-                    let id_ex_mem_read = self.itl_d_e.mem_flags.mem_read;
-                    let if_id_rs2 = self.itl_f_d.rs2;
-                    let if_id_mem_write = self.itl_f_d.mem_flags.mem_write;
-                    if (id_ex_rd != 0)
-                        && (id_ex_rd == if_id_rs2)
-                        && id_ex_mem_read
-                        && if_id_mem_write
-                    {
-                        if self.data_hazard_info {
-                            warn!("Memory-to-memory copy hazard detected");
-                        }
-                        self.cpu_statistics.data_hazard_count += 1;
-                        self.cpu_statistics.data_hazard_delayed_cycles += 2;
-                        self.d_e_pipeline_states_set(&mut [PipelineState::Bubble]);
-                        self.f_d_pipeline_states_set(&mut [
-                            PipelineState::Stall,
-                            PipelineState::Bubble,
-                        ]);
-                        self.pc_next_states_set(&mut [PipelineState::Stall, PipelineState::Stall]);
-                    }
+                    // the store waits in ID for the write back of the load
                 }
                 DataHazardPolicy::DataForward => {
                     // debug!("Detecting memory-to-memory hazard");
@@ -400,10 +673,17 @@ impl<'a> CPU<'a> {
                     let mem_wb_mem_read = self.itl_m_w.mem_read;
                     let exec_mem_rs2 = self.itl_e_m.rs2;
                     let exec_mem_mem_write = self.itl_e_m.mem_flags.mem_write;
+                    // a younger producer between the MEM cycles has the value
+                    let overwritten = self
+                        .inner_mem
+                        .latches()
+                        .iter()
+                        .any(|m| m.wb_flags.mem_to_reg && m.rd == exec_mem_rs2);
                     if (mem_wb_rd != 0)
                         && (mem_wb_rd == exec_mem_rs2)
                         && mem_wb_mem_read
                         && exec_mem_mem_write
+                        && !overwritten
                     {
                         if self.data_hazard_info {
                             warn!("Memory-to-memory hazard detected");
@@ -424,29 +704,11 @@ impl<'a> CPU<'a> {
             }
         }
 
-        // detect data hazards
+        // forward the sources of the instruction entering EX, the youngest
+        // producer first: EX/MEM, the registers between the MEM cycles, MEM/WB
         match self.data_hazard_policy {
             DataHazardPolicy::NaiveStall => {
-                let id_ex_reg_write = self.itl_d_e.wb_flags.mem_to_reg;
-                let id_ex_rd = self.itl_d_e.rd;
-                let if_id_rs1 = self.itl_f_d.rs1;
-                let if_id_rs2 = self.itl_f_d.rs2;
-                if id_ex_reg_write
-                    && (id_ex_rd != 0)
-                    && ((id_ex_rd == if_id_rs1) || (id_ex_rd == if_id_rs2))
-                {
-                    if self.data_hazard_info {
-                        warn!("EX/MEM data hazard detected");
-                    }
-                    self.cpu_statistics.data_hazard_count += 1;
-                    self.cpu_statistics.data_hazard_delayed_cycles += 2;
-                    self.d_e_pipeline_states_set(&mut [PipelineState::Bubble]);
-                    self.f_d_pipeline_states_set(&mut [
-                        PipelineState::Stall,
-                        PipelineState::Bubble,
-                    ]);
-                    self.pc_next_states_set(&mut [PipelineState::Stall, PipelineState::Stall]);
-                }
+                // the consumer waits in ID for the write back of its producers
             }
             DataHazardPolicy::DataForward => {
                 let ex_mem_forward = {
@@ -485,43 +747,40 @@ impl<'a> CPU<'a> {
                     self.itl_e_m.alu_out
                 };
                 self.itl_d_e.ex_mem_forward = ex_mem_forward;
-            }
-        }
 
-        match self.data_hazard_policy {
-            DataHazardPolicy::NaiveStall => {
-                let ex_mem_regwrite = self.itl_e_m.wb_flags.mem_to_reg;
-                let ex_mem_rd = self.itl_e_m.rd;
-                let id_ex_rd = self.itl_d_e.rd;
-                let if_id_rs1 = self.itl_f_d.rs1;
-                let if_id_rs2 = self.itl_f_d.rs2;
-                if ex_mem_regwrite
-                    && (ex_mem_rd != 0)
-                    && (((id_ex_rd != if_id_rs1) && (ex_mem_rd == if_id_rs1))
-                        || ((id_ex_rd != if_id_rs2) && (ex_mem_rd == if_id_rs2)))
-                {
-                    if self.data_hazard_info {
-                        warn!("MEM/WB data hazard detected");
+                // MEM/MEM hazards of a split memory stage
+                for latch in self.inner_mem.latches() {
+                    if !latch.wb_flags.mem_to_reg || latch.rd == 0 {
+                        continue;
+                    }
+                    let forward_a = self.itl_d_e.forward_a == 0 && latch.rd == self.itl_d_e.rs1;
+                    let forward_b = self.itl_d_e.forward_b == 0 && latch.rd == self.itl_d_e.rs2;
+                    if forward_a {
+                        self.itl_d_e.forward_a = 0b11;
+                        self.itl_d_e.mem_forward_a = latch.regval;
+                    }
+                    if forward_b {
+                        self.itl_d_e.forward_b = 0b11;
+                        self.itl_d_e.mem_forward_b = latch.regval;
+                    }
+                    if forward_a || forward_b {
+                        if self.data_hazard_info {
+                            warn!("MEM/MEM data hazard detected");
+                            warn!("  MEM.rd={}({})", latch.rd, REGNAME[latch.rd as usize]);
+                        }
+                        self.cpu_statistics.data_hazard_count += 1;
                     }
-                    self.cpu_statistics.data_hazard_count += 1;
-                    self.cpu_statistics.data_hazard_delayed_cycles += 1;
-
-                    self.d_e_pipeline_states_set(&mut [PipelineState::Bubble]); // ?
-                    self.f_d_pipeline_states_set(&mut [PipelineState::Stall]);
-                    self.pc_next_states_set(&mut [PipelineState::Stall]);
                 }
-            }
-            DataHazardPolicy::DataForward => {
+
                 let mem_wb_forward = {
                     // MEM/WB hazard
                     let mem_wb_regwrite = self.itl_m_w.wb_flags.mem_to_reg;
                     let mem_wb_rd = self.itl_m_w.rd;
-                    let ex_mem_rd = self.itl_e_m.rd;
                     let id_ex_rs1 = self.itl_d_e.rs1;
                     let id_ex_rs2 = self.itl_d_e.rs2;
                     if mem_wb_regwrite
                         && (mem_wb_rd != 0)
-                        && (ex_mem_rd != id_ex_rs1)
+                        && (self.itl_d_e.forward_a == 0)
                         && (mem_wb_rd == id_ex_rs1)
                     {
                         if self.data_hazard_info {
@@ -530,12 +789,11 @@ impl<'a> CPU<'a> {
                             warn!("  ID/EX.rs1={}({})", id_ex_rs1, REGNAME[id_ex_rs1 as usize]);
                         }
                         // forward A from MEM/WB
-                        assert_eq!(self.itl_d_e.forward_a, 0);
                         self.itl_d_e.forward_a = 0b01;
                     }
                     if mem_wb_regwrite
                         && (mem_wb_rd != 0)
-                        && (ex_mem_rd != id_ex_rs2)
+                        && (self.itl_d_e.forward_b == 0)
                         && (mem_wb_rd == id_ex_rs2)
                     {
                         if self.data_hazard_info {
@@ -544,15 +802,10 @@ impl<'a> CPU<'a> {
                             warn!("  ID/EX.rs1={}({})", id_ex_rs2, REGNAME[id_ex_rs2 as usize]);
                         }
                         // forward B from MEM/WB
-                        assert_eq!(self.itl_d_e.forward_b, 0);
                         self.itl_d_e.forward_b = 0b01;
                     }
 
-                    if mem_wb_regwrite
-                        && (mem_wb_rd != 0)
-                        && ((ex_mem_rd != id_ex_rs1) && (mem_wb_rd == id_ex_rs1)
-                            || (ex_mem_rd != id_ex_rs2) && (mem_wb_rd == id_ex_rs2))
-                    {
+                    if self.itl_d_e.forward_a == 0b01 || self.itl_d_e.forward_b == 0b01 {
                         self.cpu_statistics.data_hazard_count += 1;
                     }
                     self.itl_m_w.regval
@@ -575,30 +828,76 @@ impl<'a> CPU<'a> {
             );
         }
         let running = writeback(&self.itl_m_w, &mut self.reg_file, self.pipeline_info);
-        let new_itl_m_w = mem(&self.itl_e_m, &mut self.vm, self.pipeline_info);
-        let (new_itl_e_m, new_pc_0, new_pc_1) = exec(
-            &self.itl_d_e,
+        if self.itl_m_w.alu_op != Inst64::noop {
+            if self.itrace.enabled() {
+                self.itrace.inst(
+                    self.itl_m_w.pc,
+                    self.itl_m_w.raw_inst,
+                    &w_pinst(&self.itl_m_w),
+                );
+            }
+            let info = self.itl_m_w.retire_info();
+            if let Some(commit_log) = self.commit_log.as_mut() {
+                commit_log.commit(&info);
+            }
+            self.last_retired = Some(info);
+            self.cpu_statistics.retired_inst_count += 1;
+            self.iringbuf.push(info);
+            if self.itl_m_w.alu_op == Inst64::ebreak && self.reg_file.read(10) != 0 {
+                self.iringbuf.dump();
+            }
+        }
+        if let Some(profiler) = self.profiler.as_deref_mut() {
+            // bubbles charge their cycle to the next retired instruction
+            self.unretired_cycles += 1;
+            if self.itl_m_w.alu_op != Inst64::noop {
+                profiler.retire(
+                    self.itl_m_w.pc,
+                    self.itl_m_w.raw_inst,
+                    self.unretired_cycles,
+                );
+                self.unretired_cycles = 0;
+            }
+        }
+        // the registers between the cycles of a split stage move on every
+        // clock, a misprediction flushes the younger instructions
+        let mut inner_mem = self.inner_mem.clone();
+        let (new_itl_m_w, _) = inner_mem.shift(
+            mem(&self.itl_e_m, &mut self.vm, self.pipeline_info),
+            CycleClass::Base,
+        );
+        let mut exec_before = self.exec_before.clone();
+        let mut exec_after = self.exec_after.clone();
+        // the instruction executed in the last clock
+        let ahead = *exec_after.first().unwrap_or(&self.itl_e_m);
+        let (executing, executing_cause) = exec_before.shift(self.itl_d_e, self.d_e_cause);
+        let (executed, new_pc_0, new_pc_1) = exec(
+            &executing,
             self.pipeline_info,
             &mut self.callstack,
-            Some(&mut self.ras),
+            &mut self.csr,
         )?;
+        let (new_itl_e_m, leaving_cause) = exec_after.shift(executed, executing_cause);
         let new_itl_d_e = decode(&self.reg_file, &self.itl_f_d, self.pipeline_info);
 
         // fetch code
-        let new_itl_f_d = fetch(
+        let fetch_pc = self.pc.read();
+        let ras_checkpoint = self.ras.checkpoint();
+        let new_fetch = fetch(
             &self.pc,
             &mut self.vm,
             self.pipeline_info,
             self.control_policy,
             self.bht.as_mut(),
-            self.btb.as_ref(),
+            self.btb.as_mut(),
             Some(&mut self.ras),
         );
+        let fetch_raw = new_fetch.raw_inst;
 
         // handle executed branch instruction
-        let ex_branch = new_itl_e_m.branch_flags.branch;
-        let pc_src = new_itl_e_m.branch_flags.pc_src;
-        let predicted_src = new_itl_e_m.branch_flags.predicted_src;
+        let ex_branch = executed.branch_flags.branch;
+        let pc_src = executed.branch_flags.pc_src;
+        let predicted_src = executed.branch_flags.predicted_src;
 
         if ex_branch && self.control_policy == ControlPolicy::DynamicPredict {
             assert!(self.btb.is_some() && self.bht.is_some());
@@ -606,19 +905,27 @@ impl<'a> CPU<'a> {
             //     "UPDATE BTB: PC={:#x}, new_pc_1={:#x}",
             //     new_itl_e_m.pc, new_pc_1
             // );
-            use crate::core::insts::Inst64::jalr;
-            let is_jalr = new_itl_e_m.alu_op == jalr;
+            use crate::core::insts::Inst64::{jal, jalr};
+            let is_jalr = executed.alu_op == jalr;
             // fill BTB with potential new entry
             // NOTE: branch target is calculated at EX phase.
             self.btb
                 .as_mut()
                 .unwrap()
-                .add_entry(new_itl_e_m.pc, new_pc_1, is_jalr); // new_pc_1 is branch target
+                .add_entry(executed.pc, new_pc_1, is_jalr); // new_pc_1 is branch target
                                                                // update BHT
-            self.bht
-                .as_mut()
-                .unwrap()
-                .update_with_result(new_itl_e_m.pc, pc_src);
+            let branch_flags = executed.branch_flags;
+            if branch_flags.ras_predicted {
+                self.ras.record(new_pc_1 == branch_flags.predicted_target);
+            }
+            let conditional = !matches!(executed.alu_op, jal | jalr);
+            self.bht.as_mut().unwrap().update_with_result(
+                executed.pc,
+                pc_src,
+                conditional,
+                branch_flags.predict_history,
+                branch_flags.predicted_taken,
+            );
         }
 
         // debug!("Before checking misprediction:");
@@ -630,10 +937,55 @@ impl<'a> CPU<'a> {
         // debug!("new_pc_1={:#x}",new_pc_1);
         // debug!("self.itl_e_m.branch_flags.predicted_target={:#x}",new_itl_e_m.branch_flags.predicted_target);
 
+        // all stall: fetch nothing while a control instruction is fetched,
+        // decoded or executed before it resolves, it redirects the PC like a
+        // misprediction when it resolves
+        let mut fetch_bubble = false;
+        if self.control_policy == ControlPolicy::AllStall
+            && (self.inner_fetch.latches().iter().any(|f| f.branch_flags.branch)
+                || self.itl_f_d.branch_flags.branch
+                || self.itl_d_e.branch_flags.branch
+                || self.exec_before.latches().iter().any(|e| e.branch_flags.branch))
+        {
+            if self.control_hazard_info {
+                warn!("Control instruction in pipeline, stall IF");
+            }
+            if self.itl_d_e.branch_flags.branch {
+                self.cpu_statistics.control_hazard_count += 1;
+            }
+            // a data stall of the control instruction is not charged here
+            if self.f_d_pipeline_states[0] != PipelineState::Stall
+                && !load_use_detected
+                && !exec_use_detected
+                && !fu_use_detected
+            {
+                self.cpu_statistics.control_hazard_delayed_cycles += 1;
+            }
+            fetch_bubble = true;
+            self.pc_next_states_set(&mut [PipelineState::Stall]);
+        }
+
         // mispredict
-        let mispredict = ex_branch
-            && ((pc_src != predicted_src)
-                || (new_itl_e_m.is_ret() && new_pc_1 != new_itl_e_m.branch_flags.predicted_target));
+        // a taken prediction may use a wrong target: a return address popped
+        // from the RAS, or a BTB entry of another PC aliasing with this one
+        // a SYSTEM instruction always redirects to its next instruction, as it
+        // may trap or return from a trap
+        let serialize = serializes(executed.alu_op);
+        let mispredict = serialize
+            || ex_branch
+                && ((pc_src != predicted_src)
+                    || (pc_src && new_pc_1 != executed.branch_flags.predicted_target));
+        if ex_branch {
+            self.branch_stats.record(
+                executed.pc,
+                executed.raw_inst,
+                pc_src,
+                // all stall does not predict
+                mispredict && self.control_policy != ControlPolicy::AllStall,
+                executed.branch_flags.btb_miss,
+                self.predictor_name(&executed),
+            );
+        }
         if mispredict {
             // compulsory flush
             // so do not use self.x_y_pipeline_states_set
@@ -641,27 +993,64 @@ impl<'a> CPU<'a> {
             if self.control_hazard_info {
                 warn!("Misprediction detected");
             }
-            self.cpu_statistics.control_hazard_count += 1;
-            self.cpu_statistics.control_hazard_delayed_cycles += 2;
+            // undo the RAS updates of the flushed instructions
+            if self.control_policy == ControlPolicy::DynamicPredict {
+                self.ras.repair(executed.branch_flags.ras_checkpoint);
+            }
+            // with all stall the stall cycles of a branch are already counted,
+            // otherwise every IF cycle, ID and the EX cycles up to this one
+            // are flushed
+            if self.control_policy != ControlPolicy::AllStall || serialize {
+                self.cpu_statistics.control_hazard_count += 1;
+                self.cpu_statistics.control_hazard_delayed_cycles +=
+                    self.stage_latency.fetch + self.stage_latency.branch;
+            }
+            // the data stalls behind the branch are control cycles now
+            let flushed_stalls = exec_before
+                .causes()
+                .iter()
+                .filter(|&&cause| matches!(cause, CycleClass::Data | CycleClass::LoadUse))
+                .count() as u64;
+            self.cpu_statistics.data_hazard_delayed_cycles -= flushed_stalls;
+            exec_before.flush(CycleClass::Control);
             self.d_e_pipeline_states[0] = PipelineState::Bubble;
             self.f_d_pipeline_states[0] = PipelineState::Bubble;
+            self.d_e_bubble_causes[0] = CycleClass::Control;
+            self.f_d_bubble_causes[0] = CycleClass::Control;
             self.pc_next_states[0] = PipelineState::Normal;
         }
 
-        // handle load-use hazard
-        if load_use_detected {
-            match self.data_hazard_policy {
-                DataHazardPolicy::NaiveStall => {}
-                DataHazardPolicy::DataForward => {
-                    // e_pipeline_state = e_pipeline_state.max(PipelineState::Bubble);
-                    self.d_e_pipeline_states_set(&mut [PipelineState::Bubble]);
-                    // d_pipeline_state = d_pipeline_state.max(PipelineState::Stall);
-                    self.f_d_pipeline_states_set(&mut [PipelineState::Stall]);
-                    // f_pipeline_state = f_pipeline_state.max(PipelineState::Stall);
-                    self.pc_next_states_set(&mut [PipelineState::Stall]);
-                    self.cpu_statistics.data_hazard_delayed_cycles += 1;
-                }
+        // hold the dependent instruction in ID, unless it is flushed, counting
+        // one hazard per wait
+        let data_wait = (load_use_detected || exec_use_detected) && !mispredict;
+        if data_wait {
+            let cause = Self::stall_cause(load_use_detected);
+            if !self.data_waiting {
+                self.cpu_statistics.data_hazard_count += 1;
+            }
+            self.cpu_statistics.data_hazard_delayed_cycles += 1;
+            self.d_e_pipeline_states_set(&mut [PipelineState::Bubble], cause);
+            self.f_d_pipeline_states_set(&mut [PipelineState::Stall], cause);
+            self.pc_next_states_set(&mut [PipelineState::Stall]);
+        }
+        let fu_wait = fu_use_detected && !data_wait && !mispredict;
+        if fu_wait {
+            if !self.data_waiting {
+                self.cpu_statistics.data_hazard_count += 1;
             }
+            self.cpu_statistics.data_hazard_delayed_cycles += 1;
+            self.d_e_pipeline_states_set(&mut [PipelineState::Bubble], CycleClass::Data);
+            self.f_d_pipeline_states_set(&mut [PipelineState::Stall], CycleClass::Data);
+            self.pc_next_states_set(&mut [PipelineState::Stall]);
+        }
+        self.data_waiting = data_wait || fu_wait;
+
+        // the executed instruction sets when its result can be used, a
+        // consumer right behind it is already held back by the checks above
+        self.fu_advance(1);
+        if self.itl_d_e.wb_flags.mem_to_reg && self.itl_d_e.rd != 0 {
+            let latency = self.result_latency(self.itl_d_e.exec_flags.alu_op);
+            self.fu_busy[self.itl_d_e.rd as usize] = latency.saturating_sub(2);
         }
 
         let m_w_pipeline_state = self.m_w_pipeline_states[0];
@@ -688,44 +1077,159 @@ impl<'a> CPU<'a> {
             PipelineState::Stall => self.itl_d_e,
         };
 
-        let new_itl_f_d = match f_d_pipeline_state {
-            PipelineState::Normal => new_itl_f_d,
-            PipelineState::Bubble => InternalFetchDecode::default(),
-            PipelineState::Stall => self.itl_f_d,
+        // the register behind the first IF cycle, all stall latches a bubble
+        let fetch_state = match f_d_pipeline_state {
+            PipelineState::Normal if fetch_bubble => PipelineState::Bubble,
+            state => state,
         };
 
-        // mul/div/rem
+        // a fetched instruction that is not latched is fetched again
+        if fetch_state != PipelineState::Normal && !mispredict {
+            self.ras.restore(ras_checkpoint);
+        }
+
+        let mut inner_fetch = self.inner_fetch.clone();
+        let (new_itl_f_d, new_f_d_cause) = match f_d_pipeline_state {
+            PipelineState::Normal if fetch_bubble => {
+                inner_fetch.shift(InternalFetchDecode::default(), CycleClass::Control)
+            }
+            PipelineState::Normal => inner_fetch.shift(new_fetch, CycleClass::Base),
+            PipelineState::Bubble => {
+                inner_fetch.flush(self.f_d_bubble_causes[0]);
+                (InternalFetchDecode::default(), self.f_d_bubble_causes[0])
+            }
+            PipelineState::Stall => (self.itl_f_d, self.f_d_cause),
+        };
+
+        // iterative mul/div/rem units keep EX busy
         {
             use Inst64::*;
-            match new_itl_e_m.alu_op {
-                div | divw | divu | divuw => {
-                    self.clock += 39;
-                }
-                r @ (rem | remw | remu | remuw) => match (r, new_itl_m_w.alu_op) {
+            let extra_cycles = match executed.alu_op {
+                r @ (rem | remw | remu | remuw) => match (r, ahead.alu_op) {
+                    // the remainder of the division just done comes for free
                     (rem, div) | (remw, divw) | (remu, divu) | (remuw, divuw)
-                        if new_itl_e_m.rs1 == new_itl_m_w.rs1
-                            && new_itl_e_m.rs2 == new_itl_m_w.rs2 => {}
-                    _ => {
-                        self.clock += 39;
+                        if executed.rs1 == ahead.rs1 && executed.rs2 == ahead.rs2 =>
+                    {
+                        0
                     }
+                    _ => self.fu_config.blocking_cycles(FuClass::Div),
                 },
-                mul | mulh | mulhsu | mulhu | mulw => {
-                    self.clock += 1;
+                op => self.fu_config.blocking_cycles(FuClass::of(op)),
+            };
+            self.clock += extra_cycles;
+            self.fu_advance(extra_cycles);
+            self.cpi_stack.add(CycleClass::Structural, extra_cycles);
+            if extra_cycles != 0 {
+                self.cpu_statistics.structural_hazard_count += 1;
+                self.cpu_statistics.structural_hazard_delayed_cycles += extra_cycles;
+                if let Some(profiler) = self.profiler.as_deref_mut() {
+                    profiler.add_cycles(executed.pc, executed.raw_inst, extra_cycles);
+                }
+            }
+        }
+
+        // memory latency stalls the whole pipeline, IF and MEM accesses overlap
+        if let Some(memory) = self.memory.as_mut() {
+            let now = self.clock;
+            let fetch_stall = if fetch_state == PipelineState::Normal {
+                memory.fetch(fetch_pc, now)
+            } else {
+                0
+            };
+            let mem_flags = self.itl_e_m.mem_flags;
+            let mem_stall = if mem_flags.mem_read || mem_flags.mem_write {
+                memory.data(self.itl_e_m.mem_addr, mem_flags.mem_write, now)
+            } else {
+                0
+            };
+            let stall = fetch_stall.max(mem_stall);
+            self.clock += stall;
+            self.fu_advance(stall);
+            self.cpu_statistics.memory_stall_cycles += stall;
+            self.cpi_stack.add(CycleClass::Memory, stall);
+            if let Some(profiler) = self.profiler.as_deref_mut() {
+                if mem_stall != 0 {
+                    profiler.add_cycles(self.itl_e_m.pc, self.itl_e_m.raw_inst, mem_stall);
+                }
+                if stall > mem_stall {
+                    profiler.add_cycles(new_fetch.pc, new_fetch.raw_inst, stall - mem_stall);
                 }
-                _ => {}
             }
         }
 
         // whether executed a non-noop instruction
         if new_itl_e_m.alu_op != Inst64::noop {
             self.cpu_statistics.executed_inst_count += 1;
+            self.cpi_stack.add(CycleClass::Base, 1);
+        } else {
+            // the bubble leaving EX
+            self.cpi_stack.add(leaving_cause, 1);
+        }
+
+        let states = LatchStates {
+            fetch: fetch_state,
+            inner_fetch: f_d_pipeline_state,
+            f_d: if self.inner_fetch.latches().is_empty() {
+                fetch_state
+            } else {
+                f_d_pipeline_state
+            },
+            d_e: d_e_pipeline_state,
+            inner_exec: if mispredict {
+                PipelineState::Bubble
+            } else {
+                PipelineState::Normal
+            },
+            e_m: e_m_pipeline_state,
+            m_w: m_w_pipeline_state,
+            pc_next: pc_next_state,
+        };
+        if let Some(kanata) = self.kanata.as_mut() {
+            kanata.clock(clock_begin, fetch_pc, fetch_raw, states);
+        }
+        if let Some(diagram) = self.diagram.as_mut() {
+            diagram.clock(clock_begin, fetch_pc, fetch_raw, states);
         }
+        if let Some(vcd) = self.vcd.as_mut() {
+            let controls = Controls {
+                pc: fetch_pc,
+                states,
+                load_use: load_use_detected,
+                exec_use: exec_use_detected,
+                fu_use: fu_use_detected,
+                mispredict,
+                extra_cycles: self.clock - clock_begin,
+            };
+            let registers = Registers {
+                inner_fetch: self.inner_fetch.latches(),
+                f_d: &self.itl_f_d,
+                d_e: &self.itl_d_e,
+                exec_before: self.exec_before.latches(),
+                exec_after: self.exec_after.latches(),
+                e_m: &self.itl_e_m,
+                inner_mem: self.inner_mem.latches(),
+                m_w: &self.itl_m_w,
+            };
+            vcd.clock(clock_begin, &registers, &controls);
+        }
+
+        // the bubbles move along with the instructions
+        self.d_e_cause = match d_e_pipeline_state {
+            PipelineState::Normal => self.f_d_cause,
+            PipelineState::Bubble => self.d_e_bubble_causes[0],
+            PipelineState::Stall => self.d_e_cause,
+        };
+        self.f_d_cause = new_f_d_cause;
 
         // push pipeline forward
         self.itl_m_w = new_itl_m_w;
+        self.inner_mem = inner_mem;
         self.itl_e_m = new_itl_e_m;
+        self.exec_after = exec_after;
+        self.exec_before = exec_before;
         self.itl_d_e = new_itl_d_e;
         self.itl_f_d = new_itl_f_d;
+        self.inner_fetch = inner_fetch;
 
         let next_pc = match pc_next_state {
             PipelineState::Stall => self.pc.read(),
@@ -743,9 +1247,9 @@ impl<'a> CPU<'a> {
                     }
                 } else {
                     // normal execution
-                    if new_itl_f_d.branch_flags.predicted_src {
+                    if new_fetch.branch_flags.predicted_src {
                         // Fetch phase decides that predicted
-                        new_itl_f_d.branch_flags.predicted_target
+                        new_fetch.branch_flags.predicted_target
                     } else {
                         self.pc.read().wrapping_add(4)
                     }
@@ -789,9 +1293,64 @@ impl<'a> CPU<'a> {
         self.f_d_pipeline_states[PIPELINE_STATES_DEPTH - 1] = PipelineState::Normal;
         self.pc_next_states.rotate_left(1);
         self.pc_next_states[PIPELINE_STATES_DEPTH - 1] = PipelineState::Normal;
+        self.d_e_bubble_causes.rotate_left(1);
+        self.d_e_bubble_causes[PIPELINE_STATES_DEPTH - 1] = CycleClass::Base;
+        self.f_d_bubble_causes.rotate_left(1);
+        self.f_d_bubble_causes[PIPELINE_STATES_DEPTH - 1] = CycleClass::Base;
         Ok(())
     }
 
+    /// Class of a stall waiting for a load or for another producer.
+    fn stall_cause(load: bool) -> CycleClass {
+        if load {
+            CycleClass::LoadUse
+        } else {
+            CycleClass::Data
+        }
+    }
+
+    /// Cycles from `op` entering EX until a dependent instruction may enter EX,
+    /// a split EX stage holds the dependent instruction at least as long.
+    fn result_latency(&self, op: Inst64) -> u64 {
+        self.fu_config.result_latency(FuClass::of(op))
+    }
+
+    /// Instructions writing a register, the youngest first, with the cycles
+    /// since they entered EX and whether they load.
+    fn producers(&self) -> Vec<(u64, u8, bool)> {
+        let exec = self.stage_latency.exec;
+        let branch = self.stage_latency.branch;
+        let mut producers = Vec::new();
+        let mut push = |cycles: u64, writes: bool, rd: u8, load: bool| {
+            if writes && rd != 0 {
+                producers.push((cycles, rd, load));
+            }
+        };
+        let d_e = &self.itl_d_e;
+        push(0, d_e.wb_flags.mem_to_reg, d_e.rd, d_e.mem_flags.mem_read);
+        for (j, e) in self.exec_before.latches().iter().enumerate() {
+            push(1 + j as u64, e.wb_flags.mem_to_reg, e.rd, e.mem_flags.mem_read);
+        }
+        for (j, e) in self.exec_after.latches().iter().enumerate() {
+            push(branch + j as u64, e.wb_flags.mem_to_reg, e.rd, e.mem_flags.mem_read);
+        }
+        let e_m = &self.itl_e_m;
+        push(exec, e_m.wb_flags.mem_to_reg, e_m.rd, e_m.mem_flags.mem_read);
+        for (j, m) in self.inner_mem.latches().iter().enumerate() {
+            push(exec + 1 + j as u64, m.wb_flags.mem_to_reg, m.rd, m.mem_read);
+        }
+        let m_w = &self.itl_m_w;
+        push(exec + self.stage_latency.mem, m_w.wb_flags.mem_to_reg, m_w.rd, m_w.mem_read);
+        producers
+    }
+
+    /// Pipelined functional units keep working while the pipeline is frozen.
+    fn fu_advance(&mut self, cycles: u64) {
+        for busy in self.fu_busy.iter_mut() {
+            *busy = busy.saturating_sub(cycles);
+        }
+    }
+
     #[allow(unused)]
     fn m_w_pipeline_states_set(&mut self, states: &mut [PipelineState]) {
         (0..self.m_w_pipeline_states.len().min(states.len())).for_each(|i| {
@@ -808,16 +1367,24 @@ impl<'a> CPU<'a> {
         });
     }
 
-    fn d_e_pipeline_states_set(&mut self, states: &mut [PipelineState]) {
+    /// A bubble is charged to the `cause` which inserts it first.
+    fn d_e_pipeline_states_set(&mut self, states: &mut [PipelineState], cause: CycleClass) {
         (0..self.d_e_pipeline_states.len().min(states.len())).for_each(|i| {
             let x = &mut self.d_e_pipeline_states[i];
+            if *x == PipelineState::Normal && states[i] == PipelineState::Bubble {
+                self.d_e_bubble_causes[i] = cause;
+            }
             *x = *x.max(&mut states[i]);
         });
     }
 
-    fn f_d_pipeline_states_set(&mut self, states: &mut [PipelineState]) {
+    /// A bubble is charged to the `cause` which inserts it first.
+    fn f_d_pipeline_states_set(&mut self, states: &mut [PipelineState], cause: CycleClass) {
         (0..self.f_d_pipeline_states.len().min(states.len())).for_each(|i| {
             let x = &mut self.f_d_pipeline_states[i];
+            if *x == PipelineState::Normal && states[i] == PipelineState::Bubble {
+                self.f_d_bubble_causes[i] = cause;
+            }
             *x = *x.max(&mut states[i]);
         });
     }
@@ -835,10 +1402,23 @@ impl<'a> CPU<'a> {
         self.pc.read()
     }
 
-    pub(super) fn mread<T: Sized + std::fmt::Display>(&self, vaddr: u64) -> T {
+    pub(super) fn mread<T: Sized + Copy + Into<u64>>(&self, vaddr: u64) -> T {
         self.vm.mread(vaddr as usize)
     }
 
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    pub fn read_reg(&self, idx: u8) -> u64 {
+        self.reg_file.read(idx)
+    }
+
+    /// Effects of the instruction retired since the last call.
+    pub fn take_retired(&mut self) -> Option<RetireInfo> {
+        self.last_retired.take()
+    }
+
     pub(super) fn backtrace(&self) {
         self.callstack.backtrace();
     }
@@ -919,14 +1499,17 @@ pub struct MultistageCPU<'a> {
     // Program counter (PC) which is not included in general purpose register file.
     pc: ProgramCounter,
 
+    // Control and status registers
+    csr: CsrFile,
+
     // Reference to virtual memory
     vm: &'a mut VirtualMemory,
 
     // Reference to call stack
     callstack: &'a mut CallStack<'a>,
 
-    // Itrace switch
-    itrace: bool,
+    // Instruction trace
+    itrace: Tracer,
 
     // IF / ID
     itl_f_d: InternalFetchDecode,
@@ -943,6 +1526,18 @@ pub struct MultistageCPU<'a> {
     cpu_statistics: CPUStatistics,
 
     last_inst_info: LastInstInfo,
+
+    // Instruction-level profiler
+    profiler: Option<&'a mut Profiler>,
+
+    // Spike-compatible commit log
+    commit_log: Option<CommitLog>,
+
+    // Effects of the last retired instruction, for difftest
+    last_retired: Option<RetireInfo>,
+
+    // Last retired instructions, dumped on failure
+    iringbuf: InstRingBuffer,
 }
 
 struct LastInstInfo {
@@ -969,7 +1564,10 @@ impl<'a> MultistageCPU<'a> {
     pub fn new(
         vm: &'a mut VirtualMemory,
         callstack: &'a mut CallStack<'a>,
-        itrace: bool,
+        itrace: Tracer,
+        profiler: Option<&'a mut Profiler>,
+        commit_log: Option<CommitLog>,
+        iringbuf_size: usize,
     ) -> MultistageCPU<'a> {
         // x0 already set to 0
         let reg_file = RegisterFile::empty();
@@ -981,6 +1579,7 @@ impl<'a> MultistageCPU<'a> {
             clock: 0,
             reg_file,
             pc,
+            csr: CsrFile::new(),
             vm,
             callstack,
             itrace,
@@ -990,6 +1589,10 @@ impl<'a> MultistageCPU<'a> {
             itl_m_w: InternalMemWb::default(),
             cpu_statistics: CPUStatistics::default(),
             last_inst_info: LastInstInfo::new(),
+            profiler,
+            commit_log,
+            last_retired: None,
+            iringbuf: InstRingBuffer::new(iringbuf_size),
         }
     }
 
@@ -1024,6 +1627,13 @@ impl<'a> MultistageCPU<'a> {
                 break;
             }
             self.exec_once()?;
+            if let Some(code) = self.vm.htif_exit(self.last_retired.as_ref()) {
+                if code != 0 {
+                    self.iringbuf.dump();
+                }
+                halt(self.last_retired.map_or(0, |info| info.pc), code);
+                self.running = false;
+            }
             i += 1;
         }
 
@@ -1052,22 +1662,59 @@ impl<'a> MultistageCPU<'a> {
             "CPU executed valid instructions: {}",
             self.cpu_statistics.executed_inst_count
         );
+        info!(
+            "CPU retired instructions: {}",
+            self.cpu_statistics.retired_inst_count
+        );
         info!("CPI = {}", {
             let cycles = self.clock;
-            let insts = self.cpu_statistics.executed_inst_count;
+            let insts = self.cpu_statistics.retired_inst_count;
             (cycles as f64) / (insts as f64)
         });
     }
 
+    pub fn add_stats(&self, stats: &mut Stats) {
+        let cpu = &self.cpu_statistics;
+        let insts = cpu.retired_inst_count;
+        stats.set("cycles", self.clock);
+        stats.set("instret", insts);
+        stats.set_ratio("cpi", self.clock, insts);
+        stats.set_ratio("ipc", insts, self.clock);
+        stats.section("hazards", |s| {
+            s.section("data", |s| {
+                s.set("count", cpu.data_hazard_count);
+                s.set("cycles", cpu.data_hazard_delayed_cycles);
+            });
+            s.section("control", |s| {
+                s.set("count", cpu.control_hazard_count);
+                s.set("cycles", cpu.control_hazard_delayed_cycles);
+            });
+        });
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    pub fn read_reg(&self, idx: u8) -> u64 {
+        self.reg_file.read(idx)
+    }
+
+    /// Effects of the instruction retired since the last call.
+    pub fn take_retired(&mut self) -> Option<RetireInfo> {
+        self.last_retired.take()
+    }
+
     pub(super) fn exec_once(&mut self) -> Result<()> {
         use crate::core::insts::Inst64::*;
+        let start_clock = self.clock;
 
         // fetch code
         self.clock += 1;
         let new_itl_f_d = fetch(
             &self.pc,
             &mut self.vm,
-            self.itrace,
+            false,
             ControlPolicy::AlwaysNotTaken,
             None,
             None,
@@ -1076,12 +1723,12 @@ impl<'a> MultistageCPU<'a> {
         self.itl_f_d = new_itl_f_d;
 
         self.clock += 1;
-        let new_itl_d_e = decode(&self.reg_file, &self.itl_f_d, self.itrace);
+        let new_itl_d_e = decode(&self.reg_file, &self.itl_f_d, false);
         self.itl_d_e = new_itl_d_e;
 
         self.clock += 1;
         let (new_itl_e_m, new_pc_0, new_pc_1) =
-            exec(&self.itl_d_e, self.itrace, &mut self.callstack, None)?;
+            exec(&self.itl_d_e, false, &mut self.callstack, &mut self.csr)?;
         self.itl_e_m = new_itl_e_m;
 
         match new_itl_e_m.alu_op {
@@ -1119,14 +1766,39 @@ impl<'a> MultistageCPU<'a> {
             // begin the clock
             self.clock += 1;
         }
-        let new_itl_m_w = mem(&self.itl_e_m, &mut self.vm, self.itrace);
+        let new_itl_m_w = mem(&self.itl_e_m, &mut self.vm, false);
         self.itl_m_w = new_itl_m_w;
 
         if self.itl_m_w.wb_flags.mem_to_reg {
             // begin the clock
             self.clock += 1;
         }
-        let running = writeback(&self.itl_m_w, &mut self.reg_file, self.itrace);
+        let running = writeback(&self.itl_m_w, &mut self.reg_file, false);
+        if self.itl_m_w.alu_op != noop {
+            if self.itrace.enabled() {
+                self.itrace.inst(
+                    self.itl_m_w.pc,
+                    self.itl_m_w.raw_inst,
+                    &w_pinst(&self.itl_m_w),
+                );
+            }
+            let info = self.itl_m_w.retire_info();
+            if let Some(commit_log) = self.commit_log.as_mut() {
+                commit_log.commit(&info);
+            }
+            self.last_retired = Some(info);
+            self.cpu_statistics.retired_inst_count += 1;
+            self.iringbuf.push(info);
+            if self.itl_m_w.alu_op == Inst64::ebreak && self.reg_file.read(10) != 0 {
+                self.iringbuf.dump();
+            }
+        }
+        if let Some(profiler) = self.profiler.as_deref_mut() {
+            if self.itl_m_w.alu_op != noop {
+                let cycles = self.clock - start_clock;
+                profiler.retire(self.itl_m_w.pc, self.itl_m_w.raw_inst, cycles);
+            }
+        }
 
         let next_pc = if new_itl_e_m.branch_flags.pc_src {
             new_pc_1
@@ -1145,3 +1817,263 @@ impl<'a> MultistageCPU<'a> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::multi_stage::global_predict::PredictorConfig;
+
+    const PROGRAM_START: u64 = 0x1000;
+
+    fn pipeline<'a>(
+        vm: &'a mut VirtualMemory,
+        callstack: &'a mut CallStack<'a>,
+        fu_config: FuConfig,
+        data_hazard_policy: DataHazardPolicy,
+        stage_latency: StageLatency,
+    ) -> CPU<'a> {
+        let config = PipelineConfig {
+            core: CoreConfig {
+                control_policy: ControlPolicy::AlwaysNotTaken,
+                predict_policy: None,
+                bht: None,
+                btb: None,
+                predictor: PredictorConfig::default(),
+                ras_depth: 16,
+                fu_latency: fu_config,
+            },
+            data_hazard_policy,
+            stage_latency,
+            memory: None,
+            branch_report: 0,
+            info: PipelineInfo::default(),
+        };
+        let mut cpu = CPU::new(
+            vm,
+            callstack,
+            Tracer::disabled(),
+            config,
+            None,
+            PipelineLogs::default(),
+            0,
+        );
+        cpu.pc.write(PROGRAM_START);
+        cpu.itl_f_d.pc = PROGRAM_START;
+        cpu.itl_d_e.pc = PROGRAM_START;
+        cpu.itl_e_m.pc = PROGRAM_START;
+        cpu.itl_m_w.pc = PROGRAM_START;
+        cpu
+    }
+
+    #[test]
+    fn load_use_stalls_with_any_load_latency() {
+        let program: [u32; 7] = [
+            0x70000093, // addi x1, x0, 0x700
+            0x02a00113, // addi x2, x0, 42
+            0x0020b023, // sd x2, 0(x1)
+            0x0000b183, // ld x3, 0(x1)
+            0x00118213, // addi x4, x3, 1
+            0x004182b3, // add x5, x3, x4
+            0x00100073, // ebreak
+        ];
+        for load in [1, 2, 3] {
+            let mut vm = VirtualMemory::new(0x2000, Tracer::disabled());
+            for (i, inst) in program.iter().enumerate() {
+                vm.mwrite(PROGRAM_START as usize + i * 4, *inst);
+            }
+            let symbols = HashMap::new();
+            let mut callstack = CallStack::new(&symbols, Tracer::disabled());
+            let fu_config = FuConfig {
+                load,
+                ..Default::default()
+            };
+            let mut cpu = pipeline(
+                &mut vm,
+                &mut callstack,
+                fu_config,
+                DataHazardPolicy::DataForward,
+                StageLatency::default(),
+            );
+            cpu.cpu_exec(Some(100)).unwrap();
+
+            assert!(!cpu.running(), "load={load}: the program did not end");
+            assert_eq!(cpu.read_reg(3), 42, "load={load}");
+            assert_eq!(cpu.read_reg(4), 43, "load={load}");
+            assert_eq!(cpu.read_reg(5), 85, "load={load}");
+        }
+    }
+
+    fn run(program: &[u32], fu_config: FuConfig) -> CPUStatistics {
+        run_policy(program, fu_config, DataHazardPolicy::DataForward).0
+    }
+
+    fn run_policy(
+        program: &[u32],
+        fu_config: FuConfig,
+        data_hazard_policy: DataHazardPolicy,
+    ) -> (CPUStatistics, CpiStack) {
+        run_stages(program, fu_config, data_hazard_policy, StageLatency::default())
+    }
+
+    fn run_stages(
+        program: &[u32],
+        fu_config: FuConfig,
+        data_hazard_policy: DataHazardPolicy,
+        stage_latency: StageLatency,
+    ) -> (CPUStatistics, CpiStack) {
+        let mut vm = VirtualMemory::new(0x2000, Tracer::disabled());
+        for (i, inst) in program.iter().enumerate() {
+            vm.mwrite(PROGRAM_START as usize + i * 4, *inst);
+        }
+        let symbols = HashMap::new();
+        let mut callstack = CallStack::new(&symbols, Tracer::disabled());
+        let mut cpu = pipeline(
+            &mut vm,
+            &mut callstack,
+            fu_config,
+            data_hazard_policy,
+            stage_latency,
+        );
+        cpu.cpu_exec(Some(200)).unwrap();
+        assert!(!cpu.running(), "the program did not end");
+        assert_eq!(cpu.read_reg(4), 42);
+        (cpu.cpu_statistics.clone(), cpu.cpi_stack.clone())
+    }
+
+    #[test]
+    fn fu_stalls_count_once_per_wait() {
+        let program: [u32; 9] = [
+            0x00600093, // addi x1, x0, 6
+            0x00700113, // addi x2, x0, 7
+            0x00100293, // addi x5, x0, 1
+            0x00100293, // addi x5, x0, 1
+            0x022081b3, // mul x3, x1, x2
+            0x00100293, // addi x5, x0, 1
+            0x00100293, // addi x5, x0, 1
+            0x00018233, // add x4, x3, x0
+            0x00100073, // ebreak
+        ];
+        let base = run(&program, FuConfig::default());
+        assert_eq!(base.data_hazard_count, 0);
+        assert_eq!(base.data_hazard_delayed_cycles, 0);
+
+        // the add waits in ID for the multiplier two cycles
+        let slow = run(&program, "mul=5".parse().unwrap());
+        assert_eq!(slow.data_hazard_count, 1);
+        assert_eq!(slow.data_hazard_delayed_cycles, 2);
+
+        let iterative = run(&program, "mul=5,mul-pipelined=false".parse().unwrap());
+        assert_eq!(iterative.data_hazard_count, 0);
+        assert_eq!(iterative.structural_hazard_count, 1);
+        assert_eq!(iterative.structural_hazard_delayed_cycles, 4);
+    }
+
+    #[test]
+    fn data_hazard_cycles_are_the_data_and_load_use_stack() {
+        let program: [u32; 8] = [
+            0x70000093, // addi x1, x0, 0x700
+            0x01400113, // addi x2, x0, 20
+            0x0020b023, // sd x2, 0(x1)
+            0x0000b183, // ld x3, 0(x1)
+            0x01618213, // addi x4, x3, 22
+            0x000002b3, // add x5, x0, x0
+            0x00020233, // add x4, x4, x0
+            0x00100073, // ebreak
+        ];
+        // naive stall: sd and the last add wait for an ALU result, the addi
+        // for the load
+        let (naive, stack) = run_policy(
+            &program,
+            FuConfig::default(),
+            DataHazardPolicy::NaiveStall,
+        );
+        assert_eq!(naive.data_hazard_count, 3);
+        assert_eq!(naive.data_hazard_delayed_cycles, 5);
+        assert_eq!(stack.cycles(CycleClass::Data), 3);
+        assert_eq!(stack.cycles(CycleClass::LoadUse), 2);
+
+        let (forward, stack) = run_policy(
+            &program,
+            FuConfig::default(),
+            DataHazardPolicy::DataForward,
+        );
+        assert_eq!(forward.data_hazard_delayed_cycles, 1);
+        assert_eq!(stack.cycles(CycleClass::Data), 0);
+        assert_eq!(stack.cycles(CycleClass::LoadUse), 1);
+
+        for fu_config in ["load=3", "mul=5"] {
+            let (stats, stack) = run_policy(
+                &program,
+                fu_config.parse().unwrap(),
+                DataHazardPolicy::NaiveStall,
+            );
+            assert_eq!(
+                stats.data_hazard_delayed_cycles,
+                stack.cycles(CycleClass::Data) + stack.cycles(CycleClass::LoadUse),
+                "{fu_config}"
+            );
+        }
+    }
+
+    #[test]
+    fn split_stages_forward_from_every_cycle() {
+        // x4 is increased by instructions 1 to 7 behind its producer
+        let mut program = vec![0x00100213]; // addi x4, x0, 1
+        for distance in 1..=7 {
+            program.extend(std::iter::repeat_n(0x00000293, distance - 1)); // addi x5, x0, 0
+            program.push(0x00120213); // addi x4, x4, 1
+        }
+        program.push(0x02220213); // addi x4, x4, 34
+        program.push(0x00100073); // ebreak
+
+        // EX results leave the third EX cycle: the first consumer and the last
+        // one wait two cycles, the second one cycle, the others are forwarded
+        // from EX/MEM, between the MEM cycles, from MEM/WB or read in ID
+        for stages in ["exec=3,mem=3", "fetch=2,exec=3,mem=3,branch=1"] {
+            let (stats, stack) = run_stages(
+                &program,
+                FuConfig::default(),
+                DataHazardPolicy::DataForward,
+                stages.parse().unwrap(),
+            );
+            assert_eq!(stats.data_hazard_delayed_cycles, 5, "{stages}");
+            assert_eq!(stack.cycles(CycleClass::Data), 5, "{stages}");
+        }
+
+        // naive stall waits until the producer is in WB, six cycles after
+        // entering EX
+        let (stats, _) = run_stages(
+            &program,
+            FuConfig::default(),
+            DataHazardPolicy::NaiveStall,
+            "exec=3,mem=3".parse().unwrap(),
+        );
+        assert_eq!(stats.data_hazard_delayed_cycles, 6 + 5 + 4 + 3 + 2 + 1 + 6);
+    }
+
+    #[test]
+    fn split_stages_flush_up_to_branch_resolution() {
+        let program: [u32; 6] = [
+            0x02a00213, // addi x4, x0, 42
+            0x00000663, // beq x0, x0, 12
+            0x00100213, // addi x4, x0, 1
+            0x00200213, // addi x4, x0, 2
+            0x00100073, // ebreak
+            0x00100073, // ebreak
+        ];
+        // every IF cycle, ID and the EX cycles up to branch resolution
+        for (stages, penalty) in [("", 2), ("fetch=3", 4), ("fetch=2,exec=3,branch=2", 4)] {
+            let (stats, stack) = run_stages(
+                &program,
+                FuConfig::default(),
+                DataHazardPolicy::DataForward,
+                stages.parse().unwrap(),
+            );
+            assert_eq!(stats.control_hazard_count, 1, "{stages}");
+            assert_eq!(stats.control_hazard_delayed_cycles, penalty, "{stages}");
+            assert_eq!(stack.cycles(CycleClass::Control), penalty, "{stages}");
+        }
+    }
+}
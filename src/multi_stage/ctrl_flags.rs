@@ -1,6 +1,8 @@
 #![allow(unused)]
 use crate::core::insts::Inst64;
 
+use super::branch_predict::RasCheckpoint;
+
 #[derive(Debug, Clone, Copy)]
 pub enum SextType {
     None,
@@ -28,6 +30,11 @@ pub struct BranchFlags {
     pub pc_src: bool,
     pub predicted_src: bool,
     pub predicted_target: u64,
+    pub predicted_taken: bool, // direction from the BHT, before BTB lookup
+    pub predict_history: u64,  // global history the prediction used
+    pub ras_predicted: bool,   // target popped from the RAS
+    pub ras_checkpoint: RasCheckpoint,
+    pub btb_miss: bool, // predicted without a BTB entry
 }
 
 impl BranchFlags {
@@ -49,6 +49,8 @@ pub fn decode(
         forward_b: 0, // default using self
         ex_mem_forward: 0, // set by data forwarding logic
         mem_wb_forward: 0, // set by data forwarding logic
+        mem_forward_a: 0, // set by data forwarding logic
+        mem_forward_b: 0, // set by data forwarding logic
     };
 
     itl_d_e
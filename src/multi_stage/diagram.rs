@@ -0,0 +1,375 @@
+//! Classic pipeline diagram of the pipeline CPU over a window of cycles.
+//!
+//! Every instruction and bubble is a row and every cycle a column, a cell
+//! holds the stage the row is in. A stage held for more than one cycle is
+//! marked as a stall (`ID*`), which also covers the cycles added by memory
+//! accesses and iterative units, and the cycle after a flush is marked `X`.
+//! The diagram is written as a plain text table, or as a standalone HTML page
+//! when the file name ends with `.html`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    str::FromStr,
+};
+
+use log::error;
+
+use super::{
+    debug::disasm,
+    occupancy::{ClockView, LatchStates, Occupancy, Slot},
+    stage_latency::StageLatency,
+};
+
+/// Inclusive range of cycles shown in the diagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleWindow {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl Default for CycleWindow {
+    fn default() -> Self {
+        Self { first: 1, last: 50 }
+    }
+}
+
+impl CycleWindow {
+    fn contains(&self, cycle: u64) -> bool {
+        (self.first..=self.last).contains(&cycle)
+    }
+}
+
+/// `<first>-<last>`, both inclusive.
+impl FromStr for CycleWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expect <first>-<last>, got `{s}`"))?;
+        let parse = |v: &str| {
+            v.trim()
+                .parse::<u64>()
+                .map_err(|e| format!("invalid cycle `{v}`: {e}"))
+        };
+        let window = CycleWindow {
+            first: parse(first)?,
+            last: parse(last)?,
+        };
+        if window.first == 0 || window.first > window.last {
+            return Err(format!("invalid cycle window `{s}`"));
+        }
+        Ok(window)
+    }
+}
+
+impl fmt::Display for CycleWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Cell {
+    Stage(usize),
+    Stall(usize),
+    Flushed,
+}
+
+impl Cell {
+    fn text(&self, occupancy: &Occupancy) -> String {
+        match self {
+            Cell::Stage(stage) => occupancy.name(*stage).to_string(),
+            Cell::Stall(stage) => format!("{}*", occupancy.name(*stage)),
+            Cell::Flushed => "X".to_string(),
+        }
+    }
+
+    fn class(&self) -> &'static str {
+        match self {
+            Cell::Stage(_) => "stage",
+            Cell::Stall(_) => "stall",
+            Cell::Flushed => "flush",
+        }
+    }
+}
+
+struct Row {
+    label: String,
+    bubble: bool,
+    cells: BTreeMap<u64, Cell>,
+}
+
+pub struct PipelineDiagram {
+    out: Option<Box<dyn Write>>,
+    html: bool,
+    window: CycleWindow,
+    occupancy: Occupancy,
+    // labels of the instructions in the pipeline
+    labels: HashMap<u64, String>,
+    rows: Vec<Row>,
+    row_of: HashMap<Slot, usize>,
+    // the last clock, drawn when its length is known
+    pending: Option<(u64, ClockView)>,
+}
+
+impl PipelineDiagram {
+    pub fn new(out: Box<dyn Write>, html: bool, window: CycleWindow) -> PipelineDiagram {
+        PipelineDiagram {
+            out: Some(out),
+            html,
+            window,
+            occupancy: Occupancy::default(),
+            labels: HashMap::new(),
+            rows: Vec::new(),
+            row_of: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    pub fn create(path: &str, window: CycleWindow) -> io::Result<PipelineDiagram> {
+        let out = Box::new(BufWriter::new(File::create(path)?));
+        Ok(PipelineDiagram::new(out, path.ends_with(".html"), window))
+    }
+
+    /// Name the stages of a pipeline split into `stages`.
+    pub(super) fn with_stages(mut self, stages: &StageLatency) -> PipelineDiagram {
+        self.occupancy = Occupancy::new(stages);
+        self
+    }
+
+    /// Record one clock starting at `cycle`, in which the instruction at
+    /// `fetch_pc` was fetched and the pipeline registers got `states`.
+    pub(super) fn clock(&mut self, cycle: u64, fetch_pc: u64, fetch_raw: u32, states: LatchStates) {
+        if let Some((begin, view)) = self.pending.take() {
+            self.draw(begin, cycle, view);
+        }
+        if cycle > self.window.last {
+            return;
+        }
+        let view = self.occupancy.clock(states);
+        if let Some(id) = view.fetched {
+            let label = disasm(fetch_pc, fetch_raw).trim().replace('\t', " ");
+            self.labels.insert(id, label);
+        }
+        self.pending = Some((cycle, view));
+    }
+
+    /// Fill the cells of the clock lasting from `begin` until `end`.
+    fn draw(&mut self, begin: u64, end: u64, view: ClockView) {
+        // older instructions first, so rows are in program order
+        for stage in (0..view.stages.len()).rev() {
+            let Some(slot) = view.stages[stage] else {
+                continue;
+            };
+            let window = self.window;
+            for cycle in (begin..end).filter(|c| window.contains(*c)) {
+                let cell = if view.stalled[stage] || cycle > begin {
+                    Cell::Stall(stage)
+                } else {
+                    Cell::Stage(stage)
+                };
+                let row = self.row(slot);
+                self.rows[row].cells.insert(cycle, cell);
+            }
+        }
+        for (id, flushed) in view.leaving {
+            self.labels.remove(&id);
+            if flushed && self.window.contains(end) {
+                if let Some(row) = self.row_of.get(&Slot::Inst(id)) {
+                    self.rows[*row].cells.insert(end, Cell::Flushed);
+                }
+            }
+        }
+    }
+
+    fn row(&mut self, slot: Slot) -> usize {
+        if let Some(row) = self.row_of.get(&slot) {
+            return *row;
+        }
+        let (label, bubble) = match slot {
+            Slot::Inst(id) => (self.labels.get(&id).cloned().unwrap_or_default(), false),
+            Slot::Bubble(_) => ("bubble".to_string(), true),
+        };
+        self.rows.push(Row {
+            label,
+            bubble,
+            cells: BTreeMap::new(),
+        });
+        self.row_of.insert(slot, self.rows.len() - 1);
+        self.rows.len() - 1
+    }
+
+    fn render_text(&self, out: &mut dyn Write) -> io::Result<()> {
+        let width = self.rows.iter().map(|r| r.label.len()).max().unwrap_or(0);
+        write!(out, "{:width$}", "cycle")?;
+        for cycle in self.window.first..=self.window.last {
+            write!(out, " {cycle:>5}")?;
+        }
+        writeln!(out)?;
+        for row in &self.rows {
+            write!(out, "{:width$}", row.label)?;
+            for cycle in self.window.first..=self.window.last {
+                let text = row
+                    .cells
+                    .get(&cycle)
+                    .map(|cell| cell.text(&self.occupancy))
+                    .unwrap_or_default();
+                write!(out, " {text:>5}")?;
+            }
+            writeln!(out)?;
+        }
+        writeln!(out, "\n* stall, X flushed")
+    }
+
+    fn render_html(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>Pipeline diagram, cycles {}</title>\n<style>\n\
+             table {{ border-collapse: collapse; font-family: monospace; }}\n\
+             th, td {{ border: 1px solid #ccc; padding: 2px 4px; text-align: center; }}\n\
+             td.inst {{ text-align: left; white-space: pre; }}\n\
+             tr.bubble td.inst {{ color: #888; font-style: italic; }}\n\
+             td.stage {{ background: #cde; }}\n\
+             td.stall {{ background: #fd9; }}\n\
+             td.flush {{ background: #f99; }}\n\
+             </style>\n</head>\n<body>\n<table>",
+            self.window
+        )?;
+        write!(out, "<tr><th>cycle</th>")?;
+        for cycle in self.window.first..=self.window.last {
+            write!(out, "<th>{cycle}</th>")?;
+        }
+        writeln!(out, "</tr>")?;
+        for row in &self.rows {
+            let class = if row.bubble { " class=\"bubble\"" } else { "" };
+            write!(
+                out,
+                "<tr{class}><td class=\"inst\">{}</td>",
+                escape(&row.label)
+            )?;
+            for cycle in self.window.first..=self.window.last {
+                match row.cells.get(&cycle) {
+                    Some(cell) => write!(
+                        out,
+                        "<td class=\"{}\">{}</td>",
+                        cell.class(),
+                        cell.text(&self.occupancy)
+                    )?,
+                    None => write!(out, "<td></td>")?,
+                }
+            }
+            writeln!(out, "</tr>")?;
+        }
+        writeln!(out, "</table>\n<p>* stall, X flushed</p>\n</body>\n</html>")
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+impl Drop for PipelineDiagram {
+    fn drop(&mut self) {
+        if let Some((begin, view)) = self.pending.take() {
+            self.draw(begin, begin + 1, view);
+        }
+        let Some(mut out) = self.out.take() else {
+            return;
+        };
+        let result = if self.html {
+            self.render_html(&mut out)
+        } else {
+            self.render_text(&mut out)
+        };
+        if let Err(e) = result.and_then(|_| out.flush()) {
+            error!("Fail to write pipeline diagram: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{super::cpu::PipelineState, *};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn text_diagram() {
+        assert_eq!(
+            "3-8".parse::<CycleWindow>(),
+            Ok(CycleWindow { first: 3, last: 8 })
+        );
+        assert!("8-3".parse::<CycleWindow>().is_err());
+        assert!("0-3".parse::<CycleWindow>().is_err());
+
+        use PipelineState::*;
+        let states = |f_d, d_e, pc_next| LatchStates {
+            fetch: f_d,
+            inner_fetch: f_d,
+            f_d,
+            d_e,
+            inner_exec: Normal,
+            e_m: Normal,
+            m_w: Normal,
+            pc_next,
+        };
+        let buffer = Buffer::default();
+        let window = CycleWindow { first: 1, last: 6 };
+        let mut diagram = PipelineDiagram::new(Box::new(buffer.clone()), false, window);
+        // addi a0,zero,1
+        diagram.clock(1, 0x1000, 0x00100513, states(Normal, Normal, Normal));
+        diagram.clock(2, 0x1004, 0x00100513, states(Normal, Normal, Normal));
+        // load-use: the second waits in ID, a bubble goes to EX
+        diagram.clock(3, 0x1008, 0x00100513, states(Stall, Bubble, Stall));
+        // a memory access of 2 more cycles stalls all stages
+        diagram.clock(4, 0x1008, 0x00100513, states(Normal, Normal, Normal));
+        diagram.clock(7, 0x100c, 0x00100513, states(Normal, Normal, Normal));
+        drop(diagram);
+
+        let output = String::from_utf8(buffer.0.take()).unwrap();
+        let rows: Vec<Vec<&str>> = output
+            .lines()
+            .map(|l| l.split_whitespace().collect())
+            .collect();
+        assert_eq!(rows[0], ["cycle", "1", "2", "3", "4", "5", "6"]);
+        assert_eq!(
+            rows[1],
+            [
+                "1000:",
+                "addi",
+                "a0,zero,1",
+                "IF",
+                "ID",
+                "EX",
+                "MEM",
+                "MEM*",
+                "MEM*"
+            ]
+        );
+        assert_eq!(rows[2][3..], ["IF", "ID", "ID*", "ID*", "ID*"]);
+        assert_eq!(rows[3][3..], ["IF", "IF*", "IF*", "IF*"]);
+        assert_eq!(rows[4], ["bubble", "EX", "EX*", "EX*"]);
+        assert_eq!(rows.len(), 7);
+    }
+}
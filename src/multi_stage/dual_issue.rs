@@ -0,0 +1,836 @@
+//! Dual-issue in-order superscalar CPU.
+//!
+//! A five-stage pipeline whose latches each hold a bundle of up to two
+//! instructions in program order. IF fetches up to two instructions per cycle
+//! into ID, and ID issues the oldest one or two of them to EX together when:
+//!
+//! + at most one of them accesses memory and at most one is a control
+//!   instruction;
+//! + the second one neither reads nor writes the register the first one
+//!   writes;
+//! + the first one is not predicted taken, since fetch continued elsewhere;
+//! + the operands of both can be forwarded when they enter EX.
+//!
+//! EX forwards the results of both slots of EX/MEM and MEM/WB to both slots,
+//! so a result is usable the next cycle and a loaded value one cycle later.
+//! Control instructions resolve in EX: a misprediction flushes the younger
+//! slot of its bundle, ID and IF, which costs two cycles. SYSTEM
+//! instructions run in EX and flush the same way. Both slots of a bundle
+//! retire in WB in the same cycle.
+
+use std::collections::VecDeque;
+
+use log::info;
+
+use crate::{
+    callstack::CallStack,
+    commit_log::{CommitLog, RetireInfo},
+    core::{
+        csr::{serializes, CsrFile},
+        insts::Inst64,
+        reg::{ProgramCounter, RegisterFile},
+        vm::VirtualMemory,
+    },
+    elf::LoadElfInfo,
+    error::Result,
+    iringbuf::InstRingBuffer,
+    profile::Profiler,
+    stats::Stats,
+    trace::Tracer,
+};
+
+use super::{
+    branch_predict::{BHT, BTB, RAS},
+    config::CoreConfig,
+    cpu::{halt, ControlPolicy},
+    debug::w_pinst,
+    decode::decode,
+    exec::exec,
+    fetch::fetch,
+    func_unit::{FuClass, FuConfig, RemFusion},
+    mem::mem,
+    phases::{InternalDecodeExec, InternalExecMem, InternalFetchDecode, InternalMemWb},
+    writeback::writeback,
+};
+
+/// Instructions issued per cycle at most.
+pub const ISSUE_WIDTH: usize = 2;
+
+/// Why ID issued a single instruction although it held two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitReason {
+    /// Both access memory
+    Memory,
+    /// Both are control instructions
+    Branch,
+    /// The second one uses or overwrites the result of the first one
+    Dependency,
+    /// Fetch was redirected after the first one
+    Redirect,
+    /// An operand of the second one is not ready
+    Stall,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IssueStatistics {
+    /// Bundles by number of instructions, index 0 unused
+    pub bundles: [u64; ISSUE_WIDTH + 1],
+    pub splits_memory: u64,
+    pub splits_branch: u64,
+    pub splits_dependency: u64,
+    pub splits_redirect: u64,
+    pub splits_stall: u64,
+    pub data_hazard_count: u64,
+    pub data_stall_cycles: u64,
+    pub control_hazard_count: u64,
+    pub control_stall_cycles: u64,
+    pub structural_hazard_count: u64,
+    pub structural_stall_cycles: u64,
+}
+
+impl IssueStatistics {
+    fn split(&mut self, reason: SplitReason) {
+        match reason {
+            SplitReason::Memory => self.splits_memory += 1,
+            SplitReason::Branch => self.splits_branch += 1,
+            SplitReason::Dependency => self.splits_dependency += 1,
+            SplitReason::Redirect => self.splits_redirect += 1,
+            SplitReason::Stall => self.splits_stall += 1,
+        }
+    }
+}
+
+pub struct DualIssueCPU<'a> {
+    // indicate whether the CPU is running
+    running: bool,
+
+    // CPU clock
+    clock: u64,
+
+    // General purpose register file
+    reg_file: RegisterFile,
+
+    // Program counter (PC) which is not included in general purpose register file.
+    pc: ProgramCounter,
+
+    // Control and status registers
+    csr: CsrFile,
+
+    // Reference to virtual memory
+    vm: &'a mut VirtualMemory,
+
+    // Reference to call stack
+    callstack: &'a mut CallStack<'a>,
+
+    // Instruction trace
+    itrace: Tracer,
+
+    // Control policy
+    control_policy: ControlPolicy,
+
+    // Branch history table
+    bht: Option<BHT>,
+
+    // Branch target buffer
+    btb: Option<BTB>,
+
+    // Return address stack
+    ras: RAS,
+
+    // Latencies of the functional units
+    fu_config: FuConfig,
+
+    // Pipeline latches, each holding a bundle in program order
+    itl_f_d: Vec<InternalFetchDecode>,
+    itl_d_e: Vec<InternalDecodeExec>,
+    itl_e_m: Vec<InternalExecMem>,
+    itl_m_w: Vec<InternalMemWb>,
+
+    // Cycles until each register is produced by a pipelined functional unit
+    fu_busy: [u64; 32],
+
+    // The oldest instruction in ID waited for an operand in the last clock
+    data_waiting: bool,
+
+    // Last division, whose remainder is free
+    rem_fusion: RemFusion,
+
+    stats: IssueStatistics,
+
+    retired_inst_count: u64,
+
+    // Cycles since the last retirement, for the profiler
+    unretired_cycles: u64,
+
+    // Instruction-level profiler
+    profiler: Option<&'a mut Profiler>,
+
+    // Spike-compatible commit log
+    commit_log: Option<CommitLog>,
+
+    // Effects of the instructions retired in the last clock, for difftest
+    retired: VecDeque<RetireInfo>,
+
+    // Last retired instructions, dumped on failure
+    iringbuf: InstRingBuffer,
+}
+
+impl<'a> DualIssueCPU<'a> {
+    pub fn new(
+        vm: &'a mut VirtualMemory,
+        callstack: &'a mut CallStack<'a>,
+        itrace: Tracer,
+        config: CoreConfig,
+        profiler: Option<&'a mut Profiler>,
+        commit_log: Option<CommitLog>,
+        iringbuf_size: usize,
+    ) -> DualIssueCPU<'a> {
+        let bht = config
+            .predict_policy
+            .map(|predict_policy| BHT::new(predict_policy, config.bht, &config.predictor));
+        let btb = config.predict_policy.map(|_| BTB::new(config.btb));
+
+        DualIssueCPU {
+            running: false,
+            clock: 0,
+            reg_file: RegisterFile::empty(),
+            pc: ProgramCounter::new(),
+            csr: CsrFile::new(),
+            vm,
+            callstack,
+            itrace,
+            control_policy: config.control_policy,
+            bht,
+            btb,
+            ras: RAS::new(config.ras_depth),
+            fu_config: config.fu_latency,
+            itl_f_d: Vec::with_capacity(ISSUE_WIDTH),
+            itl_d_e: Vec::with_capacity(ISSUE_WIDTH),
+            itl_e_m: Vec::with_capacity(ISSUE_WIDTH),
+            itl_m_w: Vec::with_capacity(ISSUE_WIDTH),
+            fu_busy: [0; 32],
+            data_waiting: false,
+            rem_fusion: RemFusion::default(),
+            stats: IssueStatistics::default(),
+            retired_inst_count: 0,
+            unretired_cycles: 0,
+            profiler,
+            commit_log,
+            retired: VecDeque::with_capacity(ISSUE_WIDTH),
+            iringbuf: InstRingBuffer::new(iringbuf_size),
+        }
+    }
+
+    /// Initialize CPU with ELF info
+    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo) {
+        // make sure we are running a ELF64 executable
+        assert!(info.is_64_bit());
+
+        self.reg_file.init_elfinfo_64(info);
+        self.pc.write(info.entry_point());
+    }
+
+    /// Run the cpu.
+    /// steps: how many clocks should be run, [`None`] means run until
+    /// end or exception raised.
+    pub fn cpu_exec(&mut self, steps: Option<i32>) -> Result<()> {
+        self.running = true;
+        let mut i = 0;
+
+        while self.running {
+            if steps.is_some_and(|n| i >= n) {
+                break;
+            }
+            self.clock()?;
+            let exit = self
+                .retired
+                .iter()
+                .find_map(|info| Some((info.pc, self.vm.htif_exit(Some(info))?)));
+            if let Some((pc, code)) = exit {
+                if code != 0 {
+                    self.iringbuf.dump();
+                }
+                halt(pc, code);
+                self.running = false;
+            }
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn print_info(&self) {
+        let stats = &self.stats;
+        info!("CPU run clock: {}", self.clock);
+        info!("CPU issue width: {}", ISSUE_WIDTH);
+        info!(
+            "CPU bundles: {}, single: {}, dual: {}",
+            stats.bundles[1..].iter().sum::<u64>(),
+            stats.bundles[1],
+            stats.bundles[2]
+        );
+        info!(
+            "CPU bundle splits: memory {}, branch {}, dependency {}, redirect {}, stall {}",
+            stats.splits_memory,
+            stats.splits_branch,
+            stats.splits_dependency,
+            stats.splits_redirect,
+            stats.splits_stall
+        );
+        info!("CPU data hazard count: {}", stats.data_hazard_count);
+        info!(
+            "CPU data hazard delayed cycles: {}",
+            stats.data_stall_cycles
+        );
+        info!("CPU control hazard count: {}", stats.control_hazard_count);
+        info!(
+            "CPU control hazard delayed cycles: {}",
+            stats.control_stall_cycles
+        );
+        info!("CPU functional units: {}", self.fu_config);
+        info!(
+            "CPU structural hazard count: {}",
+            stats.structural_hazard_count
+        );
+        info!(
+            "CPU structural hazard delayed cycles: {}",
+            stats.structural_stall_cycles
+        );
+        if let Some(bht) = &self.bht {
+            bht.print_info();
+        }
+        if let Some(btb) = &self.btb {
+            btb.print_info();
+        }
+        if self.control_policy == ControlPolicy::DynamicPredict {
+            self.ras.print_info();
+        }
+        info!(
+            "CPU executed valid instructions: {}",
+            self.retired_inst_count
+        );
+        info!("CPI = {}", {
+            (self.clock as f64) / (self.retired_inst_count as f64)
+        });
+        info!("IPC = {}", {
+            (self.retired_inst_count as f64) / (self.clock as f64)
+        });
+    }
+
+    pub fn add_stats(&self, stats: &mut Stats) {
+        let issue = &self.stats;
+        let clock = self.clock;
+        let insts = self.retired_inst_count;
+        stats.set("cycles", clock);
+        stats.set("instret", insts);
+        stats.set_ratio("cpi", clock, insts);
+        stats.set_ratio("ipc", insts, clock);
+        stats.section("hazards", |s| {
+            s.section("data", |s| {
+                s.set("count", issue.data_hazard_count);
+                s.set("cycles", issue.data_stall_cycles);
+            });
+            s.section("control", |s| {
+                s.set("count", issue.control_hazard_count);
+                s.set("cycles", issue.control_stall_cycles);
+            });
+            s.section("structural", |s| {
+                s.set("count", issue.structural_hazard_count);
+                s.set("cycles", issue.structural_stall_cycles);
+            });
+        });
+        stats.section("dual_issue", |s| {
+            s.set("width", ISSUE_WIDTH);
+            s.set("single_bundles", issue.bundles[1]);
+            s.set("dual_bundles", issue.bundles[2]);
+            s.section("splits", |s| {
+                s.set("memory", issue.splits_memory);
+                s.set("branch", issue.splits_branch);
+                s.set("dependency", issue.splits_dependency);
+                s.set("redirect", issue.splits_redirect);
+                s.set("stall", issue.splits_stall);
+            });
+        });
+        if let Some(bht) = &self.bht {
+            stats.section("predictor", |s| bht.add_stats(s));
+        }
+        if let Some(btb) = &self.btb {
+            stats.section("btb", |s| btb.add_stats(s));
+        }
+        if self.control_policy == ControlPolicy::DynamicPredict {
+            stats.section("ras", |s| self.ras.add_stats(s));
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    pub fn read_reg(&self, idx: u8) -> u64 {
+        self.reg_file.read(idx)
+    }
+
+    /// Effects of the next instruction retired in the last clock.
+    pub fn take_retired(&mut self) -> Option<RetireInfo> {
+        self.retired.pop_front()
+    }
+
+    fn result_latency(&self, op: Inst64) -> u64 {
+        self.fu_config.result_latency(FuClass::of(op))
+    }
+
+    /// Whether an operand of `inst` cannot be forwarded if it enters EX in
+    /// the next cycle: it is loaded by, or takes more than one cycle in, an
+    /// instruction in EX now, or a pipelined unit is still producing it.
+    fn waits(&self, inst: &InternalFetchDecode) -> bool {
+        [inst.rs1, inst.rs2].iter().any(|&rs| {
+            rs != 0
+                && (self.fu_busy[rs as usize] != 0
+                    || self.itl_d_e.iter().any(|d_e| {
+                        d_e.wb_flags.mem_to_reg
+                            && d_e.rd == rs
+                            && (d_e.mem_flags.mem_read
+                                || self.result_latency(d_e.exec_flags.alu_op) > 1)
+                    }))
+        })
+    }
+
+    /// Why `second` cannot be issued together with `first`, if it cannot.
+    fn split_reason(
+        &self,
+        first: &InternalFetchDecode,
+        second: &InternalFetchDecode,
+    ) -> Option<SplitReason> {
+        let mem = |f: &InternalFetchDecode| f.mem_flags.mem_read || f.mem_flags.mem_write;
+        let writes = first.wb_flags.mem_to_reg && first.rd != 0;
+        if first.branch_flags.branch && first.branch_flags.predicted_src {
+            Some(SplitReason::Redirect)
+        } else if mem(first) && mem(second) {
+            Some(SplitReason::Memory)
+        } else if first.branch_flags.branch && second.branch_flags.branch {
+            Some(SplitReason::Branch)
+        } else if writes && [second.rs1, second.rs2, second.rd].contains(&first.rd) {
+            Some(SplitReason::Dependency)
+        } else if self.waits(second) {
+            Some(SplitReason::Stall)
+        } else {
+            None
+        }
+    }
+
+    fn retire(&mut self, itl_m_w: &InternalMemWb) {
+        if self.itrace.enabled() {
+            self.itrace
+                .inst(itl_m_w.pc, itl_m_w.raw_inst, &w_pinst(itl_m_w));
+        }
+        let info = itl_m_w.retire_info();
+        if let Some(commit_log) = self.commit_log.as_mut() {
+            commit_log.commit(&info);
+        }
+        self.retired.push_back(info);
+        self.retired_inst_count += 1;
+        self.iringbuf.push(info);
+        if itl_m_w.alu_op == Inst64::ebreak && self.reg_file.read(10) != 0 {
+            self.iringbuf.dump();
+        }
+        if let Some(profiler) = self.profiler.as_deref_mut() {
+            // bubbles charge their cycle to the next retired instruction
+            profiler.retire(itl_m_w.pc, itl_m_w.raw_inst, self.unretired_cycles);
+            self.unretired_cycles = 0;
+        }
+    }
+
+    pub(super) fn clock(&mut self) -> Result<()> {
+        use Inst64::*;
+        self.clock += 1;
+        self.unretired_cycles += 1;
+        self.retired.clear();
+
+        // all stall: fetch nothing while a control instruction is decoded or
+        // executed before it resolves
+        let control_ahead = self.control_policy == ControlPolicy::AllStall
+            && (self.itl_f_d.iter().any(|f| f.branch_flags.branch)
+                || self.itl_d_e.iter().any(|d| d.branch_flags.branch));
+
+        // the instructions of ID issued at the end of this clock, decided on
+        // the instructions in EX now
+        let first_waits = self.itl_f_d.first().is_some_and(|f| self.waits(f));
+        let (issued, split) = match self.itl_f_d.as_slice() {
+            [] => (0, None),
+            _ if first_waits => (0, None),
+            [_] => (1, None),
+            [first, second, ..] => match self.split_reason(first, second) {
+                Some(reason) => (1, Some(reason)),
+                None => (2, None),
+            },
+        };
+
+        // WB, both slots in program order, nothing retires after a halt
+        let mut running = true;
+        for i in 0..self.itl_m_w.len() {
+            let itl_m_w = self.itl_m_w[i];
+            running = writeback(&itl_m_w, &mut self.reg_file, false);
+            if itl_m_w.alu_op != noop {
+                self.retire(&itl_m_w);
+            }
+            let exited = self
+                .retired
+                .back()
+                .is_some_and(|info| self.vm.htif_exit(Some(info)).is_some());
+            if !running || exited {
+                break;
+            }
+        }
+
+        // MEM
+        let mut new_itl_m_w = Vec::with_capacity(ISSUE_WIDTH);
+        for itl_e_m in &self.itl_e_m {
+            new_itl_m_w.push(mem(itl_e_m, self.vm, false));
+        }
+
+        // EX, a mispredicted control instruction flushes the younger slot
+        let mut new_itl_e_m = Vec::with_capacity(ISSUE_WIDTH);
+        let mut redirect = None;
+        for itl_d_e in &self.itl_d_e {
+            // forward from both slots of MEM/WB and EX/MEM, the youngest
+            // producer last
+            let mut itl_d_e = *itl_d_e;
+            let producers = self
+                .itl_m_w
+                .iter()
+                .map(|m_w| (m_w.wb_flags.mem_to_reg, m_w.rd, m_w.regval))
+                .chain(
+                    self.itl_e_m
+                        .iter()
+                        .map(|e_m| (e_m.wb_flags.mem_to_reg, e_m.rd, e_m.alu_out)),
+                );
+            let mut forwarded = false;
+            for (reg_write, rd, value) in producers {
+                if !reg_write || rd == 0 {
+                    continue;
+                }
+                if rd == itl_d_e.rs1 {
+                    itl_d_e.forward_a = 0b11;
+                    itl_d_e.mem_forward_a = value;
+                    forwarded = true;
+                }
+                if rd == itl_d_e.rs2 {
+                    itl_d_e.forward_b = 0b11;
+                    itl_d_e.mem_forward_b = value;
+                    forwarded = true;
+                }
+            }
+            if forwarded {
+                self.stats.data_hazard_count += 1;
+            }
+
+            let (itl_e_m, new_pc_0, new_pc_1) =
+                exec(&itl_d_e, false, self.callstack, &mut self.csr)?;
+            new_itl_e_m.push(itl_e_m);
+
+            // a SYSTEM instruction refetches its next instruction like a
+            // misprediction, as it may trap or return from a trap
+            if serializes(itl_e_m.alu_op) {
+                self.stats.control_hazard_count += 1;
+                self.stats.control_stall_cycles += 2;
+                redirect = Some((new_pc_1, itl_e_m.branch_flags.ras_checkpoint));
+                break;
+            }
+
+            let branch_flags = itl_e_m.branch_flags;
+            if !branch_flags.branch {
+                continue;
+            }
+            let pc_src = branch_flags.pc_src;
+            if self.control_policy == ControlPolicy::DynamicPredict {
+                self.btb
+                    .as_mut()
+                    .unwrap()
+                    .add_entry(itl_e_m.pc, new_pc_1, itl_e_m.alu_op == jalr);
+                if branch_flags.ras_predicted {
+                    self.ras.record(new_pc_1 == branch_flags.predicted_target);
+                }
+                self.bht.as_mut().unwrap().update_with_result(
+                    itl_e_m.pc,
+                    pc_src,
+                    !matches!(itl_e_m.alu_op, jal | jalr),
+                    branch_flags.predict_history,
+                    branch_flags.predicted_taken,
+                );
+            }
+            // all stall counts its fetch stall cycles below
+            if self.control_policy == ControlPolicy::AllStall {
+                self.stats.control_hazard_count += 1;
+            }
+            let mispredict = pc_src != branch_flags.predicted_src
+                || (pc_src && new_pc_1 != branch_flags.predicted_target);
+            if mispredict {
+                if self.control_policy != ControlPolicy::AllStall {
+                    // IF and ID of the next bundle are flushed
+                    self.stats.control_hazard_count += 1;
+                    self.stats.control_stall_cycles += 2;
+                }
+                let target = if pc_src { new_pc_1 } else { new_pc_0 };
+                redirect = Some((target, branch_flags.ras_checkpoint));
+                break;
+            }
+        }
+
+        // iterative mul/div/rem units keep EX, and the whole pipeline, busy
+        let mut extra_cycles = 0;
+        for itl_e_m in &new_itl_e_m {
+            let op = itl_e_m.alu_op;
+            let blocking = if self.rem_fusion.next(op, itl_e_m.rs1, itl_e_m.rs2) {
+                0
+            } else {
+                self.fu_config.blocking_cycles(FuClass::of(op))
+            };
+            if blocking != 0 {
+                self.stats.structural_hazard_count += 1;
+                self.stats.structural_stall_cycles += blocking;
+                if let Some(profiler) = self.profiler.as_deref_mut() {
+                    profiler.add_cycles(itl_e_m.pc, itl_e_m.raw_inst, blocking);
+                }
+            }
+            extra_cycles += blocking;
+        }
+
+        // the executed instructions set when their results can be used, a
+        // consumer right behind them is already held back by the checks above
+        self.fu_advance(1);
+        for itl_e_m in &new_itl_e_m {
+            if itl_e_m.wb_flags.mem_to_reg && itl_e_m.rd != 0 {
+                let latency = self.result_latency(itl_e_m.alu_op);
+                self.fu_busy[itl_e_m.rd as usize] = latency.saturating_sub(2);
+            }
+        }
+        self.clock += extra_cycles;
+        self.fu_advance(extra_cycles);
+
+        // ID, counting one data hazard per wait of the oldest instruction
+        let new_itl_d_e = if redirect.is_some() {
+            self.itl_f_d.clear();
+            self.data_waiting = false;
+            Vec::new()
+        } else {
+            if first_waits {
+                if !self.data_waiting {
+                    self.stats.data_hazard_count += 1;
+                }
+                self.stats.data_stall_cycles += 1;
+            }
+            self.data_waiting = first_waits;
+            if let Some(reason) = split {
+                self.stats.split(reason);
+            }
+            let bundle = self.itl_f_d.drain(..issued).collect::<Vec<_>>();
+            let valid = bundle
+                .iter()
+                .filter(|f| f.exec_flags.alu_op != noop)
+                .count();
+            if valid != 0 {
+                self.stats.bundles[valid] += 1;
+            }
+            bundle
+                .iter()
+                .map(|f| decode(&self.reg_file, f, false))
+                .collect()
+        };
+
+        // IF, up to the first control instruction predicted taken, or any
+        // control instruction with all stall
+        if let Some((target, ras_checkpoint)) = redirect {
+            // undo the RAS updates of the flushed instructions
+            if self.control_policy == ControlPolicy::DynamicPredict {
+                self.ras.repair(ras_checkpoint);
+            }
+            self.pc.write(target);
+        }
+        if control_ahead {
+            if self.itl_f_d.len() < ISSUE_WIDTH {
+                self.stats.control_stall_cycles += 1;
+            }
+        } else if redirect.is_none() {
+            while self.itl_f_d.len() < ISSUE_WIDTH {
+                let itl_f_d = fetch(
+                    &self.pc,
+                    self.vm,
+                    false,
+                    self.control_policy,
+                    self.bht.as_mut(),
+                    self.btb.as_mut(),
+                    Some(&mut self.ras),
+                );
+                let branch_flags = itl_f_d.branch_flags;
+                self.pc.write(if branch_flags.predicted_src {
+                    branch_flags.predicted_target
+                } else {
+                    self.pc.read().wrapping_add(4)
+                });
+                self.itl_f_d.push(itl_f_d);
+                if branch_flags.branch
+                    && (branch_flags.predicted_src
+                        || self.control_policy == ControlPolicy::AllStall)
+                {
+                    break;
+                }
+            }
+        }
+
+        // push pipeline forward
+        self.itl_m_w = new_itl_m_w;
+        self.itl_e_m = new_itl_e_m;
+        self.itl_d_e = new_itl_d_e;
+
+        // reset x0 to 0
+        self.reg_file.write(0, 0);
+
+        // decide whether continue to run
+        self.running = running;
+
+        Ok(())
+    }
+
+    fn fu_advance(&mut self, cycles: u64) {
+        for busy in self.fu_busy.iter_mut() {
+            *busy = busy.saturating_sub(cycles);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::multi_stage::global_predict::PredictorConfig;
+
+    const PROGRAM_START: u64 = 0x1000;
+
+    /// Clocks, issue statistics and registers after running `program`.
+    fn run(program: &[u32], control_policy: ControlPolicy) -> (u64, IssueStatistics, [u64; 32]) {
+        let mut vm = VirtualMemory::new(0x2000, Tracer::disabled());
+        for (i, inst) in program.iter().enumerate() {
+            vm.mwrite(PROGRAM_START as usize + i * 4, *inst);
+        }
+        let symbols = HashMap::new();
+        let mut callstack = CallStack::new(&symbols, Tracer::disabled());
+        let config = CoreConfig {
+            control_policy,
+            predict_policy: None,
+            bht: None,
+            btb: None,
+            predictor: PredictorConfig::default(),
+            ras_depth: 16,
+            fu_latency: FuConfig::default(),
+        };
+        let mut cpu = DualIssueCPU::new(
+            &mut vm,
+            &mut callstack,
+            Tracer::disabled(),
+            config,
+            None,
+            None,
+            0,
+        );
+        cpu.pc.write(PROGRAM_START);
+        cpu.cpu_exec(Some(100)).unwrap();
+        assert!(!cpu.running(), "the program did not end");
+        let regs = std::array::from_fn(|i| cpu.read_reg(i as u8));
+        (cpu.clock, cpu.stats.clone(), regs)
+    }
+
+    #[test]
+    fn issues_independent_pairs_together() {
+        let program: [u32; 7] = [
+            0x00100093, // addi x1, x0, 1
+            0x00200113, // addi x2, x0, 2
+            0x00300193, // addi x3, x0, 3
+            0x00400213, // addi x4, x0, 4
+            0x002082b3, // add x5, x1, x2
+            0x00418333, // add x6, x3, x4
+            0x00100073, // ebreak
+        ];
+        let (clock, stats, regs) = run(&program, ControlPolicy::AlwaysNotTaken);
+        assert_eq!(regs[5], 3);
+        assert_eq!(regs[6], 7);
+        // four bundles through the five stages
+        assert_eq!(clock, 8);
+        assert_eq!(stats.bundles, [0, 1, 3]);
+        // x1 and x2 from MEM/WB, x3 and x4 from EX/MEM
+        assert_eq!(stats.data_hazard_count, 2);
+        assert_eq!(stats.data_stall_cycles, 0);
+    }
+
+    #[test]
+    fn splits_bundles_and_stalls() {
+        let program: [u32; 9] = [
+            0x70000313, // addi x6, x0, 0x700
+            0x01400093, // addi x1, x0, 20
+            0x00133023, // sd x1, 0(x6)
+            0x01600113, // addi x2, x0, 22
+            0x00033183, // ld x3, 0(x6)
+            0x00033203, // ld x4, 0(x6)
+            0x002182b3, // add x5, x3, x2
+            0x005203b3, // add x7, x4, x5
+            0x00100073, // ebreak
+        ];
+        let (clock, stats, regs) = run(&program, ControlPolicy::AlwaysNotTaken);
+        assert_eq!(regs[5], 42);
+        assert_eq!(regs[7], 62);
+        assert_eq!(clock, 10);
+        assert_eq!(stats.bundles, [0, 3, 3]);
+        // both loads, the second load and the use of the first one, the
+        // uses of x5
+        assert_eq!(stats.splits_memory, 1);
+        assert_eq!(stats.splits_stall, 1);
+        assert_eq!(stats.splits_dependency, 1);
+        assert_eq!(stats.data_hazard_count, 4);
+        assert_eq!(stats.data_stall_cycles, 0);
+
+        // a load-use of the oldest instruction in ID holds both back a cycle
+        let program: [u32; 6] = [
+            0x70000313, // addi x6, x0, 0x700
+            0x01500093, // addi x1, x0, 21
+            0x00133023, // sd x1, 0(x6)
+            0x00033183, // ld x3, 0(x6)
+            0x00318233, // add x4, x3, x3
+            0x00100073, // ebreak
+        ];
+        let (clock, stats, regs) = run(&program, ControlPolicy::AlwaysNotTaken);
+        assert_eq!(regs[4], 42);
+        assert_eq!(clock, 9);
+        assert_eq!(stats.bundles, [0, 2, 2]);
+        assert_eq!(stats.splits_memory, 1);
+        assert_eq!(stats.splits_dependency, 1);
+        assert_eq!(stats.data_hazard_count, 4);
+        assert_eq!(stats.data_stall_cycles, 1);
+    }
+
+    #[test]
+    fn mispredict_flushes_younger_slot() {
+        let program: [u32; 6] = [
+            0x02a00213, // addi x4, x0, 42
+            0x00000293, // addi x5, x0, 0
+            0x00000663, // beq x0, x0, 12
+            0x00100213, // addi x4, x0, 1
+            0x00200213, // addi x4, x0, 2
+            0x00100073, // ebreak
+        ];
+        // the branch and the first wrong-path instruction share a bundle
+        let (clock, stats, regs) = run(&program, ControlPolicy::AlwaysNotTaken);
+        assert_eq!(regs[4], 42);
+        assert_eq!(clock, 9);
+        assert_eq!(stats.bundles, [0, 1, 2]);
+        assert_eq!(stats.control_hazard_count, 1);
+        assert_eq!(stats.control_stall_cycles, 2);
+
+        // all stall fetches nothing behind the branch until it resolves
+        let (clock, stats, regs) = run(&program, ControlPolicy::AllStall);
+        assert_eq!(regs[4], 42);
+        assert_eq!(clock, 9);
+        assert_eq!(stats.bundles, [0, 2, 1]);
+        assert_eq!(stats.control_hazard_count, 1);
+        assert_eq!(stats.control_stall_cycles, 2);
+    }
+}
@@ -3,20 +3,29 @@ use std::ops::{BitAnd, BitOr, BitXor};
 use log::{trace, warn};
 
 use crate::{
-    callstack::CallStack, core::insts::{
-        get_high_64_bit, sext, trunc_to_16_bit, trunc_to_32_bit, trunc_to_5_bit,
-        trunc_to_5_bit_and_check, trunc_to_6_bit, trunc_to_8_bit, BYTE_BITWIDTH, HALF_BITWIDTH,
-        WORD_BITWIDTH,
-    }, error::{Error, Exception, Result}, multi_stage::{ctrl_flags::BranchFlags, debug::e_pinst}
+    callstack::CallStack,
+    core::{
+        csr::CsrFile,
+        insts::{
+            get_high_64_bit, sext, trunc_to_16_bit, trunc_to_32_bit, trunc_to_5_bit,
+            trunc_to_5_bit_and_check, trunc_to_6_bit, trunc_to_8_bit, BYTE_BITWIDTH, HALF_BITWIDTH,
+            WORD_BITWIDTH,
+        },
+    },
+    error::{Error, Exception, Result},
+    multi_stage::{
+        ctrl_flags::{BranchFlags, WbFlags},
+        debug::e_pinst,
+    },
 };
 
-use super::{branch_predict::RAS, phases::{InternalDecodeExec, InternalExecMem}};
+use super::phases::{InternalDecodeExec, InternalExecMem};
 
 pub fn exec(
     itl_d_e: &InternalDecodeExec,
     pipeline_info: bool,
     callstack: &mut CallStack,
-    ras: Option<&mut RAS>,
+    csr: &mut CsrFile,
 ) -> Result<(InternalExecMem, u64, u64)> {
     use crate::core::insts::Inst64::*;
     if pipeline_info {
@@ -41,6 +50,12 @@ pub fn exec(
             }
             mem_wb_forward
         }
+        0b11 => {
+            if pipeline_info {
+                warn!("ALU SRC A received data from MEM: {}", itl_d_e.mem_forward_a);
+            }
+            itl_d_e.mem_forward_a
+        }
         _ => unreachable!("Data forwarding A"),
     };
 
@@ -58,6 +73,12 @@ pub fn exec(
             }
             mem_wb_forward
         }
+        0b11 => {
+            if pipeline_info {
+                warn!("ALU SRC B received data from MEM: {}", itl_d_e.mem_forward_b);
+            }
+            itl_d_e.mem_forward_b
+        }
         _ => unreachable!("Data forwarding B"),
     };
 
@@ -65,6 +86,7 @@ pub fn exec(
     let pc = itl_d_e.pc;
 
     let mut pc_src = itl_d_e.branch_flags.pc_src;
+    let mut reg_write = itl_d_e.wb_flags.mem_to_reg;
     let new_pc_0 = pc.wrapping_add(4);
     let mut new_pc_1 = pc.wrapping_add(4);
 
@@ -123,29 +145,12 @@ pub fn exec(
             pc_src = true;
             new_pc_1 = pc.wrapping_add(imm);
             let result = new_pc_0;
-
-            // call
-            let is_call = itl_d_e.rd == 1;
-            if let Some(ras) = ras {
-                if is_call {
-                    ras.push(result);
-                }
-            }
-            callstack.call(pc, new_pc_1);
-
             result
         }
         jalr => {
             pc_src = true;
             new_pc_1 = src1.wrapping_add(imm) & (!1);
             let result = new_pc_0;
-
-            // ret
-            // 00008067          	jalr	zero,0(ra)
-            if itl_d_e.rd == 0 && itl_d_e.imm == 0 && itl_d_e.rs1 == 1 {
-                callstack.ret(pc);
-            }
-
             result
         }
         beq => {
@@ -192,10 +197,7 @@ pub fn exec(
             }
             0
         }
-        ecall => {
-            todo!("ecall");
-        }
-        ebreak => 0,
+        ebreak | fence => 0,
         add => src1.wrapping_add(src2),
         addi => src1.wrapping_add(imm),
         addiw => {
@@ -355,78 +357,55 @@ pub fn exec(
             let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
             result as u64
         }
-        div => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = (src1 as i64).wrapping_div(src2 as i64);
-            result as u64
-        }
-        divu => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = src1.wrapping_div(src2);
-            result
-        }
+        // division by zero gives all ones and the remainder the dividend
+        div => match src2 {
+            0 => u64::MAX,
+            _ => (src1 as i64).wrapping_div(src2 as i64) as u64,
+        },
+        divu => src1.checked_div(src2).unwrap_or(u64::MAX),
         divuw => {
-            if trunc_to_32_bit(src2) == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = trunc_to_32_bit(src1).wrapping_div(trunc_to_32_bit(src2));
+            let result = trunc_to_32_bit(src1)
+                .checked_div(trunc_to_32_bit(src2))
+                .unwrap_or(u64::MAX);
             let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
             result as u64
         }
-        divw => {
-            if trunc_to_32_bit(src2) == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = (trunc_to_32_bit(src1) as i32).wrapping_div(trunc_to_32_bit(src2) as i32);
-            let result = sext(trunc_to_32_bit(result as u64), WORD_BITWIDTH);
-            result as u64
-        }
-        rem => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = (src1 as i64).wrapping_rem(src2 as i64);
-            result as u64
-        }
-        remu => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let result = src1.wrapping_rem(src2);
-            result
-        }
+        divw => match src2 as i32 {
+            0 => u64::MAX,
+            divisor => (src1 as i32).wrapping_div(divisor) as i64 as u64,
+        },
+        rem => match src2 {
+            0 => src1,
+            _ => (src1 as i64).wrapping_rem(src2 as i64) as u64,
+        },
+        remu => src1.checked_rem(src2).unwrap_or(src1),
         remuw => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
             let t_src1 = trunc_to_32_bit(src1);
             let t_src2 = trunc_to_32_bit(src2);
-            let result = t_src1.wrapping_rem(t_src2);
+            let result = t_src1.checked_rem(t_src2).unwrap_or(t_src1);
             let result = sext(result, WORD_BITWIDTH);
             result as u64
         }
-        remw => {
-            if src2 == 0 {
-                return Err(Error::Exception(Exception::DividedByZero));
-            }
-            let t_src1 = trunc_to_32_bit(src1);
-            let t_src2 = trunc_to_32_bit(src2);
-            let result = (t_src1 as i64).wrapping_rem(t_src2 as i64);
-            result as u64
-        }
-        csrrc | csrrci | csrrs | csrrsi | csrrw | csrrwi | mret | sret | fence | fence_i | wfi => {
-            unimplemented!("Control registers")
+        remw => match src2 as i32 {
+            0 => src1 as i32 as i64 as u64,
+            divisor => (src1 as i32).wrapping_rem(divisor) as i64 as u64,
+        },
+        // always redirected, to flush the instructions behind
+        csrrc | csrrci | csrrs | csrrsi | csrrw | csrrwi | ecall | mret | sret | fence_i | wfi => {
+            let outcome = csr.exec(itl_d_e.exec_flags.alu_op, pc, itl_d_e.raw_inst, src1)?;
+            pc_src = true;
+            new_pc_1 = outcome.next_pc;
+            reg_write = outcome.rd_val.is_some();
+            outcome.rd_val.unwrap_or(0)
         }
     };
 
     let itl_e_m = InternalExecMem {
         raw_inst: itl_d_e.raw_inst,
         mem_flags: itl_d_e.mem_flags,
-        wb_flags: itl_d_e.wb_flags,
+        wb_flags: WbFlags {
+            mem_to_reg: reg_write,
+        },
         branch_flags: BranchFlags {
             pc_src,
             ..itl_d_e.branch_flags
@@ -446,5 +425,22 @@ pub fn exec(
         alu_op: itl_d_e.exec_flags.alu_op,
     };
 
+    track_call(callstack, &itl_e_m, new_pc_1);
+
     Ok((itl_e_m, new_pc_0, new_pc_1))
 }
+
+/// Record the call made by a `jal`, or the return made by a `jalr`, jumping
+/// to `target` in the call stack.
+pub fn track_call(callstack: &mut CallStack, itl_e_m: &InternalExecMem, target: u64) {
+    use crate::core::insts::Inst64::*;
+    match itl_e_m.alu_op {
+        jal => callstack.call(itl_e_m.pc, target),
+        // ret
+        // 00008067          	jalr	zero,0(ra)
+        jalr if itl_e_m.rd == 0 && itl_e_m.imm == 0 && itl_e_m.rs1 == 1 => {
+            callstack.ret(itl_e_m.pc)
+        }
+        _ => {}
+    }
+}
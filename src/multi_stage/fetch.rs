@@ -12,7 +12,7 @@ use crate::{
 };
 
 use super::{
-    branch_predict::{BHT, BTB, RAS},
+    branch_predict::{RasCheckpoint, BHT, BTB, RAS},
     cpu::ControlPolicy,
     ctrl_flags::{BranchFlags, DecodeFlags, ExecFlags, MemFlags, SextType, WbFlags},
     phases::InternalFetchDecode,
@@ -25,7 +25,7 @@ pub fn fetch(
     pipeline_info: bool,
     control_policy: ControlPolicy,
     bht: Option<&mut BHT>,
-    btb: Option<&BTB>,
+    btb: Option<&mut BTB>,
     ras: Option<&mut RAS>,
 ) -> InternalFetchDecode {
     let pc = pc.read();
@@ -38,8 +38,8 @@ pub fn fetch(
             }
             itl
         })
-        .map(|itl| {
-            if control_policy == ControlPolicy::DynamicPredict {
+        .map(|itl| match control_policy {
+            ControlPolicy::DynamicPredict => {
                 assert!(bht.is_some() && btb.is_some());
                 branch_predict(
                     itl,
@@ -49,9 +49,10 @@ pub fn fetch(
                     btb.unwrap(),
                     ras.unwrap(),
                 )
-            } else {
-                itl
             }
+            ControlPolicy::AlwaysTaken | ControlPolicy::Btfn => static_predict(itl, control_policy),
+            // all stall is handled by the CPU, nothing is predicted
+            ControlPolicy::AllStall | ControlPolicy::AlwaysNotTaken => itl,
         })
         .unwrap_or_else(|_| InternalFetchDecode::default())
 }
@@ -62,7 +63,7 @@ fn branch_predict(
     #[allow(unused)]
     pipeline_info: bool,
     bht: &mut BHT,
-    btb: &BTB,
+    btb: &mut BTB,
     ras: &mut RAS,
 ) -> InternalFetchDecode {
     // predict for next instruction
@@ -74,45 +75,30 @@ fn branch_predict(
     };
     if next_inst_is_control {
         match control_policy {
-            ControlPolicy::AllStall => unimplemented!(),
-            ControlPolicy::AlwaysNotTaken => itl_f_d.branch_flags.predicted_src = false,
+            ControlPolicy::AllStall | ControlPolicy::AlwaysNotTaken => {
+                itl_f_d.branch_flags.predicted_src = false
+            }
+            ControlPolicy::AlwaysTaken | ControlPolicy::Btfn => {
+                return static_predict(itl_f_d, control_policy)
+            }
             ControlPolicy::DynamicPredict => {
                 // First check whether BTB is available
                 let target = btb.query_target(itl_f_d.pc);
                 let predicted_src = bht.predict(itl_f_d.pc);
-
-                if let Some(target) = target {
+                itl_f_d.branch_flags.predicted_taken = predicted_src;
+                itl_f_d.branch_flags.predict_history = bht.history();
+
+                if is_return(&itl_f_d) {
+                    // returns are recognized at fetch and always predicted
+                    // by the RAS, jalr一定跳转。
+                    // 如果RAS pop 没有结果（RAS空）那应当给一个地址：自己的pc.不用pc+4：有可能jalr是text段最后一个指令
+                    itl_f_d.branch_flags.predicted_src = true; // always taken!
+                    itl_f_d.branch_flags.predicted_target = ras.pop().unwrap_or(itl_f_d.pc);
+                    itl_f_d.branch_flags.ras_predicted = true;
+                } else if let Some(target) = target {
                     // BTB has information for this pc
                     match itl_f_d.exec_flags.alu_op {
-                        jalr => {
-                            itl_f_d.branch_flags.predicted_src = true; // always taken!
-                                                                       // ret instruction
-                            let is_ret = itl_f_d.raw_inst == 0x00008067;
-                            // debug!("JALR: is_ret: {is_ret}");
-
-                            // 它不是查询BTB，所以target不能用。jalr一定跳转。
-                            // 如果RAS pop 没有结果（RAS空）那应当给一个地址：自己的pc.不用pc+4：有可能jalr是text段最后一个指令
-
-                            let btb_predict_target = target;
-                            // debug!("btb_predict_target: {target:#x}");
-
-                            itl_f_d.branch_flags.predicted_target = if is_ret {
-                                // debug!("RAS: {:#x?}", ras);
-                                let ras_top = ras.pop();
-                                // debug!("ras_top: {:?}", ras_top);
-                                let ras_predict_target = if let Some(ras_top) = ras_top {
-                                    // debug!("RAS has value: ras_top = {ras_top:#x}");
-                                    ras_top
-                                } else {
-                                    // debug!("RAS empty, using {:#x}", itl_f_d.pc);
-                                    itl_f_d.pc
-                                };
-                                ras_predict_target
-                            } else {
-                                btb_predict_target
-                            };
-                        }
-                        jal => {
+                        jal | jalr => {
                             itl_f_d.branch_flags.predicted_src = true; // always taken!
                             itl_f_d.branch_flags.predicted_target = target;
                         }
@@ -125,19 +111,66 @@ fn branch_predict(
                 } else {
                     // BTB does not have information for this pc
                     // compulsory miss!
+                    itl_f_d.branch_flags.btb_miss = true;
                     // we have to wait for the result to be updated
                     // for now just predict not taken (easiest way)
                     itl_f_d.branch_flags.predicted_src = false;
                 }
+
+                // calls push at fetch, also on a mispredicted path: the CPU
+                // restores the checkpoint when flushing younger instructions
+                if is_call(&itl_f_d) {
+                    ras.push(itl_f_d.pc.wrapping_add(4));
+                }
+                itl_f_d.branch_flags.ras_checkpoint = ras.checkpoint();
             }
         }
     } else {
         itl_f_d.branch_flags.predicted_src = false;
         itl_f_d.branch_flags.predicted_target = 0;
+        // the CPU flushes behind a SYSTEM instruction too
+        itl_f_d.branch_flags.ras_checkpoint = ras.checkpoint();
     }
     itl_f_d
 }
 
+/// `jal`/`jalr` linking to `ra` or `t0`
+fn is_call(itl_f_d: &InternalFetchDecode) -> bool {
+    use crate::core::insts::Inst64::*;
+    matches!(itl_f_d.exec_flags.alu_op, jal | jalr) && matches!(itl_f_d.rd, 1 | 5)
+}
+
+/// `jalr` through `ra` or `t0` without linking
+fn is_return(itl_f_d: &InternalFetchDecode) -> bool {
+    itl_f_d.exec_flags.alu_op == Inst64::jalr && itl_f_d.rd == 0 && matches!(itl_f_d.rs1, 1 | 5)
+}
+
+/// Static prediction of always taken and BTFN. The target of `jal` and
+/// branches is computed at fetch, `jalr` is predicted not taken as its target
+/// is unknown.
+fn static_predict(
+    mut itl_f_d: InternalFetchDecode,
+    control_policy: ControlPolicy,
+) -> InternalFetchDecode {
+    use crate::core::insts::Inst64::*;
+
+    let offset = match itl_f_d.exec_flags.alu_op {
+        jal => sext(itl_f_d.imm, J_TYPE_IMM_BITWIDTH),
+        beq | bne | blt | bge | bltu | bgeu => sext(itl_f_d.imm, B_TYPE_IMM_BITWIDTH),
+        _ => return itl_f_d,
+    };
+    let target = itl_f_d.pc.wrapping_add(offset as u64);
+    let taken = match control_policy {
+        ControlPolicy::AlwaysTaken => true,
+        // backward branches close loops
+        ControlPolicy::Btfn => itl_f_d.exec_flags.alu_op == jal || offset < 0,
+        _ => unreachable!(),
+    };
+    itl_f_d.branch_flags.predicted_src = taken;
+    itl_f_d.branch_flags.predicted_target = target;
+    itl_f_d
+}
+
 /// Decode phase.
 /// ```
 /// R:  OP_IMM_32  AMO  OP  OP_32  OP_FP
@@ -148,7 +181,7 @@ fn branch_predict(
 /// S:  STORE STORE_FP
 /// SB: BRANCH
 /// ```
-fn inst_interpret(pc: u64, inst: u32) -> Result<InternalFetchDecode> {
+pub(super) fn inst_interpret(pc: u64, inst: u32) -> Result<InternalFetchDecode> {
     use crate::core::insts::inst_64_opcode::*;
     // Format
     let opcode = opcode(inst);
@@ -156,12 +189,12 @@ fn inst_interpret(pc: u64, inst: u32) -> Result<InternalFetchDecode> {
     let mut itl_f_d = match opcode {
         LOAD => decode_load(inst),
         LOAD_FP => return Err(Error::Fetch("todo".into())),
-        MISC_MEM => return Err(Error::Fetch("todo".into())),
+        MISC_MEM => decode_misc_mem(inst),
         OP_IMM => decode_op_imm(inst),
         AUIPC => decode_op_auipc(inst),
         OP_IMM_32 => decode_op_imm_32(inst),
         STORE => decode_store(inst),
-        STORE_FP => decode_store_fp(inst),
+        STORE_FP => return Err(Error::Fetch("todo".into())),
         AMO => return Err(Error::Fetch("todo".into())),
         OP => decode_op(inst),
         LUI => decode_lui(inst),
@@ -225,6 +258,11 @@ fn decode_load(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         wb_flags: WbFlags { mem_to_reg: true },
         pc: 0,
@@ -245,9 +283,52 @@ fn decode_load_fp(_inst: u32) -> Result<InternalFetchDecode> {
 }
 
 /// 0001111 MISC_MEM: I type
-#[allow(unused)]
-fn decode_misc_mem(_inst: u32) -> Result<InternalFetchDecode> {
-    todo!()
+fn decode_misc_mem(inst: u32) -> Result<InternalFetchDecode> {
+    let funct3 = funct3(inst);
+    let alu_op = match funct3 {
+        0b000 => Inst64::fence,
+        0b001 => Inst64::fence_i,
+        _ => {
+            let msg = format!("Unknown MISC_MEM instruction funct3={funct3}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+
+    let itl_f_d = InternalFetchDecode {
+        raw_inst: inst,
+        decode_flags: DecodeFlags {
+            sext: SextType::None,
+        },
+        exec_flags: ExecFlags {
+            alu_op,
+            alu_src: false,
+        },
+        mem_flags: MemFlags {
+            mem_read: false,
+            mem_write: false,
+        },
+        wb_flags: WbFlags { mem_to_reg: false },
+        branch_flags: BranchFlags {
+            branch: false,
+            pc_src: false,
+            predicted_src: false,
+            predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
+        },
+        pc: 0,
+        rs1: 0,
+        rs2: 0,
+        rs3: 0,
+        rd: 0,
+        imm: 0,
+    };
+
+    Ok(itl_f_d)
 }
 
 /// 0010011 OP_IMM: I type
@@ -319,6 +400,11 @@ fn decode_op_imm(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1,
@@ -354,6 +440,11 @@ fn decode_op_auipc(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false,
             predicted_src: false,
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1: 0,
@@ -416,6 +507,11 @@ fn decode_op_imm_32(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1,
@@ -464,6 +560,11 @@ fn decode_store(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1,
@@ -597,6 +698,11 @@ fn decode_op(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1,
@@ -632,6 +738,11 @@ fn decode_lui(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1: 0,
@@ -724,6 +835,11 @@ fn decode_op_32(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1,
@@ -806,6 +922,11 @@ fn decode_branch(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: false,        // not set until exec phase
             predicted_src: false, // set by branch prediction logic
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1,
@@ -851,6 +972,11 @@ fn decode_jalr(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: true,         // always jump
             predicted_src: false, // always predicted as taken
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1,
@@ -886,6 +1012,11 @@ fn decode_jal(inst: u32) -> Result<InternalFetchDecode> {
             pc_src: true,         // not set until exec phase
             predicted_src: false, // always predicted as taken
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1: 0,
@@ -906,6 +1037,9 @@ fn decode_system(inst: u32) -> Result<InternalFetchDecode> {
         0b000 => match csr {
             0 => Inst64::ecall,
             1 => Inst64::ebreak,
+            0x102 => Inst64::sret,
+            0x105 => Inst64::wfi,
+            0x302 => Inst64::mret,
             _ => {
                 let msg = format!("Unknown SYSTEM E- instruction csr={csr}");
                 error!("{msg}");
@@ -927,6 +1061,8 @@ fn decode_system(inst: u32) -> Result<InternalFetchDecode> {
 
     let rd = rd(inst);
     let rs1 = rs1(inst); // zimm for csrrwi, csrrsi, csrrci
+                         // the CSR instructions write rd
+    let mem_to_reg = funct3 != 0;
 
     let itl_f_d = InternalFetchDecode {
         raw_inst: inst,
@@ -941,19 +1077,24 @@ fn decode_system(inst: u32) -> Result<InternalFetchDecode> {
             mem_read: false,
             mem_write: false,
         },
-        wb_flags: WbFlags { mem_to_reg: false },
+        wb_flags: WbFlags { mem_to_reg },
         branch_flags: BranchFlags {
             branch: false,
             pc_src: false, // not set until exec phase
             predicted_src: false,
             predicted_target: 0,
+            predicted_taken: false,
+            predict_history: 0,
+            ras_predicted: false,
+            ras_checkpoint: RasCheckpoint::default(),
+            btb_miss: false,
         },
         pc: 0,
         rs1,
         rs2: 0,
         rs3: 0,
         rd,
-        imm: 0,
+        imm: csr,
     };
 
     Ok(itl_f_d)
@@ -0,0 +1,195 @@
+//! Latencies of the functional units in EX of the pipeline CPU.
+//!
+//! A latency is the number of cycles from an instruction entering EX until an
+//! instruction depending on it may enter EX, so 1 is a plain ALU operation.
+//! A pipelined unit accepts a new instruction every cycle and only holds back
+//! the dependent ones in ID. An iterative unit keeps EX busy for its whole
+//! latency, which stalls everything behind it as a structural hazard.
+
+use std::{fmt, str::FromStr};
+
+use crate::core::insts::Inst64;
+
+/// Functional unit executing an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FuClass {
+    Alu,
+    Load,
+    Mul,
+    Div,
+}
+
+impl FuClass {
+    pub fn of(op: Inst64) -> FuClass {
+        use Inst64::*;
+        match op {
+            lb | lbu | lh | lhu | lw | lwu | ld => FuClass::Load,
+            mul | mulh | mulhsu | mulhu | mulw => FuClass::Mul,
+            div | divu | divuw | divw | rem | remu | remuw | remw => FuClass::Div,
+            _ => FuClass::Alu,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuConfig {
+    pub mul: u64,
+    pub mul_pipelined: bool,
+    pub div: u64,
+    pub div_pipelined: bool,
+    /// Load to use latency, 2 is the usual one-cycle load-use stall. The
+    /// pipeline CPU always stalls that one cycle, so 1 behaves like 2 there
+    pub load: u64,
+}
+
+/// Like the five-stage pipeline without functional units: a multiplication
+/// or division takes one cycle in EX, longer latencies are opt-in.
+impl Default for FuConfig {
+    fn default() -> Self {
+        Self {
+            mul: 1,
+            mul_pipelined: true,
+            div: 1,
+            div_pipelined: false,
+            load: 2,
+        }
+    }
+}
+
+impl FuConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, latency) in [("mul", self.mul), ("div", self.div), ("load", self.load)] {
+            if latency == 0 {
+                return Err(format!("{name} latency must be at least 1"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Cycles until the result of `class` may be used, for pipelined units.
+    /// Iterative units give 1 since the pipeline waits for them in EX.
+    pub fn result_latency(&self, class: FuClass) -> u64 {
+        match class {
+            FuClass::Alu => 1,
+            FuClass::Load => self.load,
+            FuClass::Mul if self.mul_pipelined => self.mul,
+            FuClass::Div if self.div_pipelined => self.div,
+            FuClass::Mul | FuClass::Div => 1,
+        }
+    }
+
+    /// Extra cycles `class` keeps EX busy, for iterative units.
+    pub fn blocking_cycles(&self, class: FuClass) -> u64 {
+        match class {
+            FuClass::Mul if !self.mul_pipelined => self.mul - 1,
+            FuClass::Div if !self.div_pipelined => self.div - 1,
+            _ => 0,
+        }
+    }
+}
+
+/// Remembers the last division: the divider produces the remainder together
+/// with the quotient, so a remainder of the same operands right after it is
+/// free.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RemFusion {
+    last_div: Option<(Inst64, u8, u8)>,
+}
+
+impl RemFusion {
+    /// Feed the next instruction, returns whether it is a fused remainder.
+    pub fn next(&mut self, op: Inst64, rs1: u8, rs2: u8) -> bool {
+        use Inst64::*;
+        let fused = matches!(
+            (self.last_div, op),
+            (Some((div, r1, r2)), rem)
+                | (Some((divw, r1, r2)), remw)
+                | (Some((divu, r1, r2)), remu)
+                | (Some((divuw, r1, r2)), remuw)
+                if r1 == rs1 && r2 == rs2
+        );
+        self.last_div = match op {
+            div | divw | divu | divuw => Some((op, rs1, rs2)),
+            _ => None,
+        };
+        fused
+    }
+}
+
+/// `key=value` pairs separated by commas, omitted keys take their default
+/// value (`mul=1,mul-pipelined=true,div=1,div-pipelined=false,load=2`).
+impl FromStr for FuConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = FuConfig::default();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expect key=value, got `{item}`"))?;
+            let invalid = |e: &dyn std::fmt::Display| format!("invalid {key} `{value}`: {e}");
+            match key {
+                "mul" => config.mul = value.parse().map_err(|e| invalid(&e))?,
+                "mul-pipelined" => config.mul_pipelined = value.parse().map_err(|e| invalid(&e))?,
+                "div" => config.div = value.parse().map_err(|e| invalid(&e))?,
+                "div-pipelined" => config.div_pipelined = value.parse().map_err(|e| invalid(&e))?,
+                "load" => config.load = value.parse().map_err(|e| invalid(&e))?,
+                _ => return Err(format!("unknown functional unit parameter `{key}`")),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl fmt::Display for FuConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = |pipelined| if pipelined { "pipelined" } else { "iterative" };
+        write!(
+            f,
+            "mul {} ({}), div {} ({}), load {}",
+            self.mul,
+            kind(self.mul_pipelined),
+            self.div,
+            kind(self.div_pipelined),
+            self.load
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_fu_config() {
+        let config: FuConfig = "mul=4,div=20,div-pipelined=true".parse().unwrap();
+        assert_eq!(config.mul, 4);
+        assert!(config.mul_pipelined);
+        assert_eq!(config.load, 2);
+        assert_eq!(config.result_latency(FuClass::Div), 20);
+        assert_eq!(config.blocking_cycles(FuClass::Div), 0);
+
+        // the default keeps the five-stage timing
+        let config = FuConfig::default();
+        assert_eq!(config.result_latency(FuClass::Mul), 1);
+        assert_eq!(config.result_latency(FuClass::Div), 1);
+        assert_eq!(config.blocking_cycles(FuClass::Div), 0);
+
+        let config: FuConfig = "div=40".parse().unwrap();
+        assert_eq!(config.result_latency(FuClass::Div), 1);
+        assert_eq!(config.blocking_cycles(FuClass::Div), 39);
+        assert_eq!(FuClass::of(Inst64::remuw), FuClass::Div);
+
+        let mut fusion = RemFusion::default();
+        assert!(!fusion.next(Inst64::divu, 5, 6));
+        assert!(fusion.next(Inst64::remu, 5, 6));
+        assert!(!fusion.next(Inst64::rem, 5, 6));
+        fusion.next(Inst64::div, 5, 6);
+        assert!(!fusion.next(Inst64::rem, 5, 7));
+
+        assert!("mul=0".parse::<FuConfig>().is_err());
+        assert!("sqrt=3".parse::<FuConfig>().is_err());
+        assert!("fp=4".parse::<FuConfig>().is_err());
+    }
+}
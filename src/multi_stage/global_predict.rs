@@ -0,0 +1,507 @@
+//! Direction predictors using a global history register (GHR).
+//!
+//! The GHR holds the outcomes of the last conditional branches, newest in
+//! bit 0. It is updated when a branch resolves in EX; each prediction carries
+//! the GHR it was made with down the pipeline so the tables are trained with
+//! the same indices they were read with.
+
+use std::{fmt, str::FromStr};
+
+use super::cpu::PredictPolicy;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PredictorConfig {
+    /// Global history length in bits, the longest one for TAGE
+    pub history_len: u32,
+    /// Entries of each table (PHT, chooser, perceptrons, TAGE tables)
+    pub entries: usize,
+    /// Number of TAGE tagged tables
+    pub tables: usize,
+    /// Tag width of the TAGE tagged tables
+    pub tag_bits: u32,
+}
+
+impl Default for PredictorConfig {
+    fn default() -> Self {
+        Self {
+            history_len: 12,
+            entries: 4096,
+            tables: 4,
+            tag_bits: 8,
+        }
+    }
+}
+
+impl PredictorConfig {
+    fn index_bits(&self) -> u32 {
+        self.entries.trailing_zeros()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(1..=64).contains(&self.history_len) {
+            return Err(format!("history length {} not in 1..=64", self.history_len));
+        }
+        if !self.entries.is_power_of_two() {
+            return Err(format!("entries {} is not a power of 2", self.entries));
+        }
+        if !(1..=8).contains(&self.tables) {
+            return Err(format!("number of tables {} not in 1..=8", self.tables));
+        }
+        if !(1..=16).contains(&self.tag_bits) {
+            return Err(format!("tag width {} not in 1..=16", self.tag_bits));
+        }
+        Ok(())
+    }
+}
+
+/// `key=value` pairs separated by commas, omitted keys take their default
+/// value (`history=12,entries=4096,tables=4,tag=8`).
+impl FromStr for PredictorConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = PredictorConfig::default();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expect key=value, got `{item}`"))?;
+            let invalid = |e: &dyn std::fmt::Display| format!("invalid {key} `{value}`: {e}");
+            match key {
+                "history" => config.history_len = value.parse().map_err(|e| invalid(&e))?,
+                "entries" => config.entries = value.parse().map_err(|e| invalid(&e))?,
+                "tables" => config.tables = value.parse().map_err(|e| invalid(&e))?,
+                "tag" => config.tag_bits = value.parse().map_err(|e| invalid(&e))?,
+                _ => return Err(format!("unknown predictor parameter `{key}`")),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl fmt::Display for PredictorConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "history {} bits, {} entries, {} tables, tag {} bits",
+            self.history_len, self.entries, self.tables, self.tag_bits
+        )
+    }
+}
+
+fn low_bits(value: u64, bits: u32) -> u64 {
+    if bits >= 64 {
+        value
+    } else {
+        value & ((1 << bits) - 1)
+    }
+}
+
+/// XOR the low `len` bits of `history` into `bits` bits.
+fn fold(history: u64, len: u32, bits: u32) -> u64 {
+    if bits == 0 {
+        return 0;
+    }
+    let mut h = low_bits(history, len);
+    let mut folded = 0;
+    while h != 0 {
+        folded ^= low_bits(h, bits);
+        h = h.checked_shr(bits).unwrap_or(0);
+    }
+    folded
+}
+
+/// 2-bit saturating counter, taken if >= 2.
+fn counter_update(counter: u8, taken: bool) -> u8 {
+    if taken {
+        (counter + 1).min(3)
+    } else {
+        counter.saturating_sub(1)
+    }
+}
+
+/// PHT of 2-bit counters indexed by PC xor global history.
+pub(super) struct Gshare {
+    pht: Vec<u8>,
+    history_len: u32,
+}
+
+impl Gshare {
+    fn new(config: &PredictorConfig) -> Self {
+        Self {
+            pht: vec![0b01; config.entries],
+            history_len: config.history_len,
+        }
+    }
+
+    fn index(&self, pc: u64, history: u64) -> usize {
+        (((pc >> 2) ^ low_bits(history, self.history_len)) % self.pht.len() as u64) as usize
+    }
+
+    fn predict(&self, pc: u64, history: u64) -> bool {
+        self.pht[self.index(pc, history)] >= 2
+    }
+
+    fn update(&mut self, pc: u64, history: u64, taken: bool) {
+        let i = self.index(pc, history);
+        self.pht[i] = counter_update(self.pht[i], taken);
+    }
+}
+
+/// Perceptron predictor (Jiménez & Lin), one perceptron per PC index.
+pub(super) struct Perceptron {
+    // bias weight followed by one weight per history bit
+    weights: Vec<Vec<i16>>,
+    history_len: u32,
+    theta: i32,
+}
+
+impl Perceptron {
+    // weights are 8-bit signed
+    const WEIGHT_MAX: i16 = 127;
+
+    fn new(config: &PredictorConfig) -> Self {
+        Self {
+            weights: vec![vec![0; config.history_len as usize + 1]; config.entries],
+            history_len: config.history_len,
+            theta: (1.93 * config.history_len as f64 + 14.0) as i32,
+        }
+    }
+
+    fn output(&self, pc: u64, history: u64) -> i32 {
+        let w = &self.weights[((pc >> 2) % self.weights.len() as u64) as usize];
+        let mut y = w[0] as i32;
+        for i in 0..self.history_len as usize {
+            if history >> i & 1 == 1 {
+                y += w[i + 1] as i32;
+            } else {
+                y -= w[i + 1] as i32;
+            }
+        }
+        y
+    }
+
+    fn predict(&self, pc: u64, history: u64) -> bool {
+        self.output(pc, history) >= 0
+    }
+
+    fn update(&mut self, pc: u64, history: u64, taken: bool) {
+        let y = self.output(pc, history);
+        if (y >= 0) == taken && y.abs() > self.theta {
+            return;
+        }
+        let len = self.weights.len() as u64;
+        let w = &mut self.weights[((pc >> 2) % len) as usize];
+        let train = |w: &mut i16, agree: bool| {
+            *w = if agree { *w + 1 } else { *w - 1 }.clamp(-Self::WEIGHT_MAX, Self::WEIGHT_MAX);
+        };
+        train(&mut w[0], taken);
+        for i in 0..self.history_len as usize {
+            train(&mut w[i + 1], (history >> i & 1 == 1) == taken);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct TageEntry {
+    // allocated since the start, a fresh entry never provides
+    valid: bool,
+    tag: u16,
+    // 3-bit signed counter, taken if >= 0
+    ctr: i8,
+    // 2-bit useful counter
+    useful: u8,
+}
+
+/// TAGE: a bimodal base predictor and tagged tables indexed with
+/// geometrically increasing history lengths.
+pub(super) struct Tage {
+    base: Vec<u8>,
+    tables: Vec<Vec<TageEntry>>,
+    history_lens: Vec<u32>,
+    index_bits: u32,
+    tag_bits: u32,
+    // updates since the useful counters were last aged
+    tick: u64,
+}
+
+/// Matching entries of a TAGE lookup.
+struct TageLookup {
+    // (table, index) of the longest and second longest matching entries
+    provider: Option<(usize, usize)>,
+    alt: Option<(usize, usize)>,
+}
+
+impl Tage {
+    const USEFUL_RESET_PERIOD: u64 = 256 * 1024;
+
+    fn new(config: &PredictorConfig) -> Self {
+        // history lengths from 4 (or less) to history_len
+        let n = config.tables;
+        let (min, max) = (config.history_len.min(4) as f64, config.history_len as f64);
+        let history_lens = (0..n)
+            .map(|i| {
+                if n == 1 {
+                    max as u32
+                } else {
+                    (min * (max / min).powf(i as f64 / (n - 1) as f64)).round() as u32
+                }
+            })
+            .collect();
+        Self {
+            base: vec![0b01; config.entries],
+            tables: vec![vec![TageEntry::default(); config.entries]; n],
+            history_lens,
+            index_bits: config.index_bits(),
+            tag_bits: config.tag_bits,
+            tick: 0,
+        }
+    }
+
+    fn base_index(&self, pc: u64) -> usize {
+        ((pc >> 2) % self.base.len() as u64) as usize
+    }
+
+    fn index(&self, table: usize, pc: u64, history: u64) -> usize {
+        let len = self.history_lens[table];
+        let key = (pc >> 2) ^ (pc >> (2 + self.index_bits)) ^ fold(history, len, self.index_bits);
+        low_bits(key, self.index_bits) as usize
+    }
+
+    fn tag(&self, table: usize, pc: u64, history: u64) -> u16 {
+        let len = self.history_lens[table];
+        let key = (pc >> 2)
+            ^ fold(history, len, self.tag_bits)
+            ^ (fold(history, len, self.tag_bits - 1) << 1);
+        low_bits(key, self.tag_bits) as u16
+    }
+
+    fn lookup(&self, pc: u64, history: u64) -> TageLookup {
+        let mut matches = (0..self.tables.len()).rev().filter_map(|t| {
+            let i = self.index(t, pc, history);
+            let entry = &self.tables[t][i];
+            (entry.valid && entry.tag == self.tag(t, pc, history)).then_some((t, i))
+        });
+        TageLookup {
+            provider: matches.next(),
+            alt: matches.next(),
+        }
+    }
+
+    fn entry_predict(&self, entry: Option<(usize, usize)>, pc: u64) -> bool {
+        match entry {
+            Some((t, i)) => self.tables[t][i].ctr >= 0,
+            None => self.base[self.base_index(pc)] >= 2,
+        }
+    }
+
+    fn predict(&self, pc: u64, history: u64) -> bool {
+        self.entry_predict(self.lookup(pc, history).provider, pc)
+    }
+
+    fn update(&mut self, pc: u64, history: u64, taken: bool) {
+        let TageLookup { provider, alt } = self.lookup(pc, history);
+        let predicted = self.entry_predict(provider, pc);
+        let alt_predicted = self.entry_predict(alt, pc);
+
+        match provider {
+            Some((t, i)) => {
+                let entry = &mut self.tables[t][i];
+                entry.ctr = if taken { entry.ctr + 1 } else { entry.ctr - 1 }.clamp(-4, 3);
+                if predicted != alt_predicted {
+                    entry.useful = if predicted == taken {
+                        (entry.useful + 1).min(3)
+                    } else {
+                        entry.useful.saturating_sub(1)
+                    };
+                }
+            }
+            None => {
+                let i = self.base_index(pc);
+                self.base[i] = counter_update(self.base[i], taken);
+            }
+        }
+
+        // allocate an entry with a longer history on a misprediction
+        if predicted != taken {
+            let start = provider.map_or(0, |(t, _)| t + 1);
+            let free = (start..self.tables.len())
+                .find(|&t| self.tables[t][self.index(t, pc, history)].useful == 0);
+            match free {
+                Some(t) => {
+                    let i = self.index(t, pc, history);
+                    self.tables[t][i] = TageEntry {
+                        valid: true,
+                        tag: self.tag(t, pc, history),
+                        ctr: if taken { 0 } else { -1 },
+                        useful: 0,
+                    };
+                }
+                None => {
+                    for t in start..self.tables.len() {
+                        let i = self.index(t, pc, history);
+                        let entry = &mut self.tables[t][i];
+                        entry.useful = entry.useful.saturating_sub(1);
+                    }
+                }
+            }
+        }
+
+        // age the useful counters
+        self.tick += 1;
+        if self.tick.is_multiple_of(Self::USEFUL_RESET_PERIOD) {
+            for entry in self.tables.iter_mut().flatten() {
+                entry.useful >>= 1;
+            }
+        }
+    }
+}
+
+pub(super) enum GlobalPredictor {
+    Gshare(Gshare),
+    /// Global side and chooser of a tournament predictor, the local side is
+    /// the per-PC table of the BHT
+    Tournament {
+        global: Gshare,
+        // 2-bit counters indexed by history, >= 2 selects the global side
+        chooser: Vec<u8>,
+        history_len: u32,
+    },
+    Perceptron(Perceptron),
+    Tage(Tage),
+}
+
+impl GlobalPredictor {
+    /// [`None`] for the per-PC local predictors.
+    pub(super) fn new(policy: PredictPolicy, config: &PredictorConfig) -> Option<Self> {
+        Some(match policy {
+            PredictPolicy::OneBitPredict | PredictPolicy::TwoBitsPredict => return None,
+            PredictPolicy::Gshare => GlobalPredictor::Gshare(Gshare::new(config)),
+            PredictPolicy::Tournament => GlobalPredictor::Tournament {
+                global: Gshare::new(config),
+                chooser: vec![0b01; config.entries],
+                history_len: config.history_len,
+            },
+            PredictPolicy::Perceptron => GlobalPredictor::Perceptron(Perceptron::new(config)),
+            PredictPolicy::Tage => GlobalPredictor::Tage(Tage::new(config)),
+        })
+    }
+
+    fn chooser_index(chooser: &[u8], history: u64, history_len: u32) -> usize {
+        (low_bits(history, history_len) % chooser.len() as u64) as usize
+    }
+
+    /// `local` is the local prediction, only used by the tournament predictor.
+    pub(super) fn predict(&self, pc: u64, history: u64, local: bool) -> bool {
+        match self {
+            GlobalPredictor::Gshare(g) => g.predict(pc, history),
+            GlobalPredictor::Tournament {
+                global,
+                chooser,
+                history_len,
+            } => {
+                if chooser[Self::chooser_index(chooser, history, *history_len)] >= 2 {
+                    global.predict(pc, history)
+                } else {
+                    local
+                }
+            }
+            GlobalPredictor::Perceptron(p) => p.predict(pc, history),
+            GlobalPredictor::Tage(t) => t.predict(pc, history),
+        }
+    }
+
+    pub(super) fn update(&mut self, pc: u64, history: u64, taken: bool, local: bool) {
+        match self {
+            GlobalPredictor::Gshare(g) => g.update(pc, history, taken),
+            GlobalPredictor::Tournament {
+                global,
+                chooser,
+                history_len,
+            } => {
+                let global_correct = global.predict(pc, history) == taken;
+                // train the chooser towards the side that was right
+                if global_correct != (local == taken) {
+                    let i = Self::chooser_index(chooser, history, *history_len);
+                    chooser[i] = counter_update(chooser[i], global_correct);
+                }
+                global.update(pc, history, taken);
+            }
+            GlobalPredictor::Perceptron(p) => p.update(pc, history, taken),
+            GlobalPredictor::Tage(t) => t.update(pc, history, taken),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Train on a repeating taken/not-taken pattern of one branch, returns
+    /// the mispredictions of the last round.
+    fn mispredicts(policy: PredictPolicy, pattern: &[bool]) -> usize {
+        let config = PredictorConfig::default();
+        let mut predictor = GlobalPredictor::new(policy, &config).unwrap();
+        let mut history = 0u64;
+        let mut wrong = 0;
+        for round in 0..200 {
+            for &taken in pattern {
+                if round == 199 && predictor.predict(0x1000, history, false) != taken {
+                    wrong += 1;
+                }
+                predictor.update(0x1000, history, taken, false);
+                history = history << 1 | taken as u64;
+            }
+        }
+        wrong
+    }
+
+    #[test]
+    fn learn_history_patterns() {
+        // a per-PC counter can not learn this pattern
+        let pattern = [true, true, false, true, false, false];
+        for policy in [
+            PredictPolicy::Gshare,
+            PredictPolicy::Tournament,
+            PredictPolicy::Perceptron,
+            PredictPolicy::Tage,
+        ] {
+            assert_eq!(mispredicts(policy, &pattern), 0, "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn fold_history() {
+        assert_eq!(fold(0b1011_0110, 8, 4), 0b1011 ^ 0b0110);
+        assert_eq!(fold(0b1011_0110, 4, 4), 0b0110);
+        assert_eq!(fold(u64::MAX, 64, 64), u64::MAX);
+        // nothing to fold into
+        assert_eq!(fold(0b1011_0110, 8, 0), 0);
+    }
+
+    #[test]
+    fn smallest_tage() {
+        // one-bit tags and one entry per table fold into zero bits
+        for config in ["tag=1", "entries=1", "entries=1,tag=1,tables=1"] {
+            let config: PredictorConfig = config.parse().unwrap();
+            let mut tage = Tage::new(&config);
+            let mut history = 0u64;
+            for taken in [true, false, true, true, false, false].repeat(10) {
+                tage.predict(0x1000, history);
+                tage.update(0x1000, history, taken);
+                history = history << 1 | taken as u64;
+            }
+        }
+    }
+
+    #[test]
+    fn fresh_tage_entries_do_not_provide() {
+        let tage = Tage::new(&PredictorConfig::default());
+        // the tags of this branch are 0 in every table, as the fresh entries
+        let pc = 0x1000;
+        assert!((0..tage.tables.len()).all(|t| tage.tag(t, pc, 0) == 0));
+        let lookup = tage.lookup(pc, 0);
+        assert!(lookup.provider.is_none());
+        assert!(lookup.alt.is_none());
+    }
+}
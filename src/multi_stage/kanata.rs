@@ -0,0 +1,206 @@
+//! Pipeline log in the Kanata format of the Konata pipeline viewer.
+//!
+//! Every fetched instruction gets an id and a label with its disassembly. At
+//! each clock the log records which instruction enters which stage, and
+//! instructions leave the pipeline either retired after WB or flushed. A stall
+//! shows as an instruction staying in its stage, with a note in its hover
+//! text; the extra cycles of memory accesses and iterative units stretch all
+//! stages alike.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use log::error;
+
+use super::{
+    debug::disasm,
+    occupancy::{LatchStates, Occupancy, Slot},
+    stage_latency::StageLatency,
+};
+
+/// Writer of the Kanata log.
+pub struct KanataLog {
+    out: Option<Box<dyn Write>>,
+    // cycle of the last clock
+    cycle: u64,
+    next_retire_id: u64,
+    occupancy: Occupancy,
+    // a stall of the instruction in the stage is noted
+    stall_noted: Vec<bool>,
+    // (id, flushed) leaving the pipeline at the end of the last clock
+    leaving: Vec<(u64, bool)>,
+}
+
+impl KanataLog {
+    pub fn new(out: Box<dyn Write>) -> KanataLog {
+        let mut log = KanataLog {
+            out: Some(out),
+            cycle: 0,
+            next_retire_id: 0,
+            occupancy: Occupancy::default(),
+            stall_noted: vec![false; 5],
+            leaving: Vec::new(),
+        };
+        log.write(format_args!("Kanata\t0004\nC=\t0\n"));
+        log
+    }
+
+    pub fn create(path: &str) -> io::Result<KanataLog> {
+        Ok(KanataLog::new(Box::new(BufWriter::new(File::create(
+            path,
+        )?))))
+    }
+
+    /// Name the stages of a pipeline split into `stages`.
+    pub(super) fn with_stages(mut self, stages: &StageLatency) -> KanataLog {
+        self.occupancy = Occupancy::new(stages);
+        self.stall_noted = vec![false; stages.depth() as usize];
+        self
+    }
+
+    /// The log is turned off after the first write error.
+    fn write(&mut self, args: std::fmt::Arguments) {
+        if let Some(out) = self.out.as_mut() {
+            if let Err(e) = out.write_fmt(args) {
+                error!("Fail to write Kanata log: {e}, Kanata log disabled");
+                self.out = None;
+            }
+        }
+    }
+
+    /// Move to `cycle` and end the instructions which left the pipeline.
+    fn advance(&mut self, cycle: u64) {
+        if cycle > self.cycle {
+            self.write(format_args!("C\t{}\n", cycle - self.cycle));
+            self.cycle = cycle;
+        }
+        for (id, flushed) in std::mem::take(&mut self.leaving) {
+            let retire_id = if flushed {
+                0
+            } else {
+                self.next_retire_id += 1;
+                self.next_retire_id
+            };
+            self.write(format_args!("R\t{id}\t{retire_id}\t{}\n", flushed as u8));
+        }
+    }
+
+    /// Record one clock starting at `cycle`, in which the instruction at
+    /// `fetch_pc` was fetched and the pipeline registers got `states`.
+    pub(super) fn clock(&mut self, cycle: u64, fetch_pc: u64, fetch_raw: u32, states: LatchStates) {
+        self.advance(cycle);
+
+        let view = self.occupancy.clock(states);
+        if let Some(id) = view.fetched {
+            let label = disasm(fetch_pc, fetch_raw).trim().replace('\t', " ");
+            self.write(format_args!("I\t{id}\t{id}\t0\nL\t{id}\t0\t{label}\n"));
+        }
+        for (stage, slot) in view.stages.iter().enumerate() {
+            let Some(Slot::Inst(id)) = *slot else {
+                self.stall_noted[stage] = false;
+                continue;
+            };
+            let name = self.occupancy.name(stage).to_string();
+            if !view.stalled[stage] {
+                self.write(format_args!("S\t{id}\t0\t{name}\n"));
+                self.stall_noted[stage] = false;
+            } else if !self.stall_noted[stage] {
+                self.write(format_args!(
+                    "L\t{id}\t1\tstalled in {name} at cycle {cycle}\n"
+                ));
+                self.stall_noted[stage] = true;
+            }
+        }
+        self.leaving = view.leaving;
+    }
+}
+
+impl Drop for KanataLog {
+    fn drop(&mut self) {
+        // instructions behind the last one never retire
+        let in_flight: Vec<_> = self.occupancy.in_flight().collect();
+        self.leaving
+            .extend(in_flight.into_iter().map(|id| (id, true)));
+        let cycle = self.cycle + 1;
+        self.advance(cycle);
+        if let Some(out) = self.out.as_mut() {
+            let _ = out.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::{super::cpu::PipelineState, *};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lifecycle() {
+        use PipelineState::*;
+        let states = |f_d, d_e, pc_next| LatchStates {
+            fetch: f_d,
+            inner_fetch: f_d,
+            f_d,
+            d_e,
+            inner_exec: Normal,
+            e_m: Normal,
+            m_w: Normal,
+            pc_next,
+        };
+        let buffer = Buffer::default();
+        let mut log = KanataLog::new(Box::new(buffer.clone()));
+        // addi a0,zero,1
+        log.clock(1, 0x1000, 0x00100513, states(Normal, Normal, Normal));
+        log.clock(2, 0x1004, 0x00100513, states(Normal, Normal, Normal));
+        // load-use: 1 waits in ID, 2 is fetched again
+        log.clock(3, 0x1008, 0x00100513, states(Stall, Bubble, Stall));
+        log.clock(4, 0x1008, 0x00100513, states(Normal, Normal, Normal));
+        // mispredict: 2 in ID and 3 in IF are flushed
+        log.clock(7, 0x100c, 0x00100513, states(Bubble, Bubble, Normal));
+        drop(log);
+
+        let output = String::from_utf8(buffer.0.take()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[..2], ["Kanata\t0004", "C=\t0"]);
+        assert_eq!(lines.iter().filter(|l| l.starts_with("I\t")).count(), 4);
+        assert!(lines.contains(&"L\t0\t0\t1000: addi a0,zero,1"));
+        assert!(lines.contains(&"L\t1\t1\tstalled in ID at cycle 4"));
+        assert!(lines.contains(&"L\t2\t1\tstalled in IF at cycle 4"));
+        assert!(lines.contains(&"C\t3"));
+        for line in ["R\t1\t0\t1", "R\t2\t0\t1", "R\t3\t0\t1", "R\t0\t1\t0"] {
+            assert!(lines.contains(&line), "missing `{line}` in\n{output}");
+        }
+        let s_lines: Vec<_> = lines
+            .iter()
+            .copied()
+            .filter(|l| l.starts_with("S\t0"))
+            .collect();
+        assert_eq!(
+            s_lines,
+            [
+                "S\t0\t0\tIF",
+                "S\t0\t0\tID",
+                "S\t0\t0\tEX",
+                "S\t0\t0\tMEM",
+                "S\t0\t0\tWB"
+            ]
+        );
+    }
+}
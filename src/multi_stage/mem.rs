@@ -71,6 +71,9 @@ pub fn mem(
         wb_flags: itl_e_m.wb_flags,
         branch_flags: itl_e_m.branch_flags,
         mem_read,
+        mem_write,
+        mem_addr: itl_e_m.mem_addr,
+        mem_bitwidth: itl_e_m.mem_bitwidth,
         pc: itl_e_m.pc,
         rs1: itl_e_m.rs1,
         rs2: itl_e_m.rs2,
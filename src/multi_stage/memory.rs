@@ -0,0 +1,561 @@
+//! Memory hierarchy of the pipeline CPU.
+//!
+//! Either only L1 caches directly backed by memory with a fixed miss penalty
+//! (`--icache`/`--dcache`), or a hierarchy loaded from a configuration file:
+//! private L1 I/D caches, a shared unified L2, an optional L3 and a DRAM
+//! model with per-bank row buffers. Data misses are tracked in MSHRs so that
+//! store misses do not block the pipeline and later accesses to a line in
+//! flight only wait for the outstanding miss.
+//!
+//! Configuration file (TOML), every key is optional:
+//!
+//! ```toml
+//! mshrs = 4
+//!
+//! [l1i]
+//! size = "16K"
+//! assoc = 4
+//! line = 64
+//! hit_latency = 0
+//!
+//! [l1d]
+//! size = "16K"
+//! repl = "lru"
+//! write = "back"
+//! alloc = true
+//!
+//! [l2]
+//! size = "256K"
+//! assoc = 8
+//! hit_latency = 10
+//!
+//! [dram]
+//! banks = 8
+//! row_size = "2K"
+//! t_cas = 14
+//! t_rcd = 14
+//! t_rp = 14
+//! t_burst = 4
+//! ```
+//!
+//! Cache tables take the same keys as `--icache`. Omitting `[l1i]`/`[l1d]`
+//! disables that cache; `[l2]` and `[l3]` are only present if given. All
+//! latencies are in CPU cycles.
+
+use std::{fs, path::Path};
+
+use log::info;
+
+use crate::{
+    error::{Error, Result},
+    stats::Stats,
+};
+
+use super::cache::{parse_size, Cache, CacheConfig};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DramConfig {
+    pub banks: usize,
+    /// Row buffer size in bytes
+    pub row_size: usize,
+    /// Column access latency
+    pub t_cas: u64,
+    /// Row activation latency
+    pub t_rcd: u64,
+    /// Precharge latency
+    pub t_rp: u64,
+    /// Transfer time of one line
+    pub t_burst: u64,
+}
+
+impl Default for DramConfig {
+    fn default() -> Self {
+        Self {
+            banks: 8,
+            row_size: 2048,
+            t_cas: 14,
+            t_rcd: 14,
+            t_rp: 14,
+            t_burst: 4,
+        }
+    }
+}
+
+impl DramConfig {
+    pub fn set(&mut self, key: &str, value: &str) -> std::result::Result<(), String> {
+        let invalid = |e: &dyn std::fmt::Display| format!("invalid {key} `{value}`: {e}");
+        match key {
+            "banks" => self.banks = value.parse().map_err(|e| invalid(&e))?,
+            "row_size" => self.row_size = parse_size(value)?,
+            "t_cas" => self.t_cas = value.parse().map_err(|e| invalid(&e))?,
+            "t_rcd" => self.t_rcd = value.parse().map_err(|e| invalid(&e))?,
+            "t_rp" => self.t_rp = value.parse().map_err(|e| invalid(&e))?,
+            "t_burst" => self.t_burst = value.parse().map_err(|e| invalid(&e))?,
+            _ => return Err(format!("unknown dram parameter `{key}`")),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryConfig {
+    pub l1i: Option<CacheConfig>,
+    pub l1d: Option<CacheConfig>,
+    pub l2: Option<CacheConfig>,
+    pub l3: Option<CacheConfig>,
+    /// MSHRs of the L1 D-cache, 0 makes every miss blocking
+    pub mshrs: usize,
+    pub dram: DramConfig,
+}
+
+impl MemoryConfig {
+    pub fn load(path: &Path) -> Result<MemoryConfig> {
+        let text = fs::read_to_string(path)?;
+        MemoryConfig::from_toml(&text)
+            .map_err(|e| Error::Config(format!("{}: {e}", path.display())))
+    }
+
+    pub fn from_toml(text: &str) -> std::result::Result<MemoryConfig, String> {
+        let table: toml::Table = toml::from_str(text).map_err(|e| e.to_string())?;
+        let mut config = MemoryConfig::default();
+        for (key, value) in &table {
+            match key.as_str() {
+                "l1i" | "l1d" | "l2" | "l3" => {
+                    let mut cache = CacheConfig::default();
+                    for (k, v) in section(key, value)? {
+                        cache
+                            .set(k, &value_str(v))
+                            .map_err(|e| format!("[{key}] {e}"))?;
+                    }
+                    cache.validate().map_err(|e| format!("[{key}] {e}"))?;
+                    let slot = match key.as_str() {
+                        "l1i" => &mut config.l1i,
+                        "l1d" => &mut config.l1d,
+                        "l2" => &mut config.l2,
+                        _ => &mut config.l3,
+                    };
+                    *slot = Some(cache);
+                }
+                "dram" => {
+                    for (k, v) in section(key, value)? {
+                        config
+                            .dram
+                            .set(k, &value_str(v))
+                            .map_err(|e| format!("[{key}] {e}"))?;
+                    }
+                }
+                "mshrs" => {
+                    config.mshrs = value_str(value)
+                        .parse()
+                        .map_err(|e| format!("invalid mshrs: {e}"))?
+                }
+                _ => return Err(format!("unknown memory configuration key `{key}`")),
+            }
+        }
+        if config.l3.is_some() && config.l2.is_none() {
+            return Err("[l3] needs an [l2]".into());
+        }
+        if config.dram.banks == 0 || config.dram.row_size == 0 {
+            return Err("[dram] banks and row_size must be positive".into());
+        }
+        Ok(config)
+    }
+}
+
+fn section<'t>(key: &str, value: &'t toml::Value) -> std::result::Result<&'t toml::Table, String> {
+    value
+        .as_table()
+        .ok_or_else(|| format!("`{key}` must be a table"))
+}
+
+fn value_str(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DramStatistics {
+    pub accesses: u64,
+    pub row_hits: u64,
+    pub row_empty: u64,
+    pub row_conflicts: u64,
+    pub bank_conflicts: u64,
+    pub bank_wait_cycles: u64,
+}
+
+/// DRAM with an open-page policy and one row buffer per bank.
+pub struct Dram {
+    config: DramConfig,
+    open_rows: Vec<Option<u64>>,
+    // cycle at which each bank finishes its last access
+    bank_ready: Vec<u64>,
+    stats: DramStatistics,
+}
+
+impl Dram {
+    pub fn new(config: DramConfig) -> Dram {
+        Dram {
+            open_rows: vec![None; config.banks],
+            bank_ready: vec![0; config.banks],
+            config,
+            stats: DramStatistics::default(),
+        }
+    }
+
+    /// Access the line at `addr` arriving at cycle `t`, returns the latency.
+    pub fn access(&mut self, addr: u64, t: u64) -> u64 {
+        let c = &self.config;
+        let row_addr = addr / c.row_size as u64;
+        let bank = (row_addr % c.banks as u64) as usize;
+        let row = row_addr / c.banks as u64;
+
+        self.stats.accesses += 1;
+        let start = t.max(self.bank_ready[bank]);
+        if start > t {
+            self.stats.bank_conflicts += 1;
+            self.stats.bank_wait_cycles += start - t;
+        }
+        let latency = match self.open_rows[bank] {
+            Some(open) if open == row => {
+                self.stats.row_hits += 1;
+                c.t_cas
+            }
+            None => {
+                self.stats.row_empty += 1;
+                c.t_rcd + c.t_cas
+            }
+            Some(_) => {
+                self.stats.row_conflicts += 1;
+                c.t_rp + c.t_rcd + c.t_cas
+            }
+        } + c.t_burst;
+        self.open_rows[bank] = Some(row);
+        self.bank_ready[bank] = start + latency;
+        start + latency - t
+    }
+
+    pub fn print_info(&self) {
+        let stats = &self.stats;
+        info!(
+            "DRAM accesses: {}, row hits: {}, row empty: {}, row conflicts: {}",
+            stats.accesses, stats.row_hits, stats.row_empty, stats.row_conflicts
+        );
+        info!(
+            "DRAM bank conflicts: {}, bank wait cycles: {}",
+            stats.bank_conflicts, stats.bank_wait_cycles
+        );
+    }
+
+    pub fn add_stats(&self, stats: &mut Stats) {
+        let dram = &self.stats;
+        stats.set("accesses", dram.accesses);
+        stats.set("row_hits", dram.row_hits);
+        stats.set("row_empty", dram.row_empty);
+        stats.set("row_conflicts", dram.row_conflicts);
+        stats.set("bank_conflicts", dram.bank_conflicts);
+        stats.set("bank_wait_cycles", dram.bank_wait_cycles);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MshrEntry {
+    line: u64,
+    ready: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MshrStatistics {
+    pub allocations: u64,
+    pub merges: u64,
+    pub full_stalls: u64,
+}
+
+/// Miss status holding registers of the L1 D-cache.
+struct Mshrs {
+    capacity: usize,
+    entries: Vec<MshrEntry>,
+    stats: MshrStatistics,
+}
+
+impl Mshrs {
+    fn new(capacity: usize) -> Mshrs {
+        Mshrs {
+            capacity,
+            entries: Vec::with_capacity(capacity),
+            stats: MshrStatistics::default(),
+        }
+    }
+
+    /// Free the entries whose miss has completed by cycle `now`.
+    fn retire(&mut self, now: u64) {
+        self.entries.retain(|e| e.ready > now);
+    }
+
+    fn find(&self, line: u64) -> Option<u64> {
+        self.entries
+            .iter()
+            .find(|e| e.line == line)
+            .map(|e| e.ready)
+    }
+}
+
+pub struct MemoryHierarchy {
+    l1i: Option<Cache>,
+    l1d: Option<Cache>,
+    // shared L2 and L3
+    shared: Vec<Cache>,
+    // None if the L1s are directly backed by memory
+    dram: Option<Dram>,
+    mshrs: Mshrs,
+}
+
+impl MemoryHierarchy {
+    /// L1 caches directly backed by memory with their miss penalty.
+    pub fn from_l1(icache: Option<CacheConfig>, dcache: Option<CacheConfig>) -> MemoryHierarchy {
+        MemoryHierarchy {
+            l1i: icache.map(|config| Cache::new("L1 I-cache", config)),
+            l1d: dcache.map(|config| Cache::new("L1 D-cache", config)),
+            shared: Vec::new(),
+            dram: None,
+            mshrs: Mshrs::new(0),
+        }
+    }
+
+    pub fn from_config(config: MemoryConfig) -> MemoryHierarchy {
+        let mut shared = Vec::new();
+        if let Some(l2) = config.l2 {
+            shared.push(Cache::new("L2 cache", l2));
+        }
+        if let Some(l3) = config.l3 {
+            shared.push(Cache::new("L3 cache", l3));
+        }
+        MemoryHierarchy {
+            l1i: config.l1i.map(|config| Cache::new("L1 I-cache", config)),
+            l1d: config.l1d.map(|config| Cache::new("L1 D-cache", config)),
+            shared,
+            dram: Some(Dram::new(config.dram)),
+            mshrs: Mshrs::new(config.mshrs),
+        }
+    }
+
+    /// Fetch an instruction at cycle `now`, returns the stall cycles.
+    pub fn fetch(&mut self, addr: u64, now: u64) -> u64 {
+        let Some(l1i) = self.l1i.as_mut() else {
+            return 0;
+        };
+        match self.dram.as_mut() {
+            None => l1i.access(addr, false),
+            Some(dram) => {
+                let latency = access_level(l1i, &mut self.shared, dram, addr, false, now);
+                l1i.add_stall_cycles(latency);
+                latency
+            }
+        }
+    }
+
+    /// Load or store at cycle `now`, returns the stall cycles.
+    pub fn data(&mut self, addr: u64, write: bool, now: u64) -> u64 {
+        let Some(l1d) = self.l1d.as_mut() else {
+            return 0;
+        };
+        let Some(dram) = self.dram.as_mut() else {
+            return l1d.access(addr, write);
+        };
+
+        let mshrs = &mut self.mshrs;
+        let line = addr / l1d.line_size();
+        mshrs.retire(now);
+
+        // the line is already in flight, wait for it
+        if let Some(ready) = mshrs.find(line) {
+            mshrs.stats.merges += 1;
+            l1d.lookup(addr, write);
+            let stall = if write { 0 } else { ready - now };
+            l1d.add_stall_cycles(stall);
+            return stall;
+        }
+
+        let latency = access_level(l1d, &mut self.shared, dram, addr, write, now);
+        if latency <= l1d.hit_latency() || mshrs.capacity == 0 {
+            l1d.add_stall_cycles(latency);
+            return latency;
+        }
+
+        // a miss needs a free MSHR
+        let mut stall = 0;
+        if mshrs.entries.len() >= mshrs.capacity {
+            let earliest = mshrs.entries.iter().map(|e| e.ready).min().unwrap();
+            stall = earliest - now;
+            mshrs.stats.full_stalls += 1;
+            mshrs.retire(earliest);
+        }
+        mshrs.stats.allocations += 1;
+        mshrs.entries.push(MshrEntry {
+            line,
+            ready: now + stall + latency,
+        });
+        // stores retire without waiting for the line
+        if !write {
+            stall += latency;
+        }
+        l1d.add_stall_cycles(stall);
+        stall
+    }
+
+    pub fn print_info(&self) {
+        for cache in self
+            .l1i
+            .iter()
+            .chain(self.l1d.iter())
+            .chain(self.shared.iter())
+        {
+            cache.print_info();
+        }
+        if self.mshrs.capacity != 0 {
+            let stats = &self.mshrs.stats;
+            info!(
+                "MSHR allocations: {}, merges: {}, full stalls: {}",
+                stats.allocations, stats.merges, stats.full_stalls
+            );
+        }
+        if let Some(dram) = &self.dram {
+            dram.print_info();
+        }
+    }
+
+    pub fn add_stats(&self, stats: &mut Stats) {
+        if let Some(l1i) = &self.l1i {
+            stats.section("l1i", |s| l1i.add_stats(s));
+        }
+        if let Some(l1d) = &self.l1d {
+            stats.section("l1d", |s| l1d.add_stats(s));
+        }
+        for (level, cache) in self.shared.iter().enumerate() {
+            stats.section(&format!("l{}", level + 2), |s| cache.add_stats(s));
+        }
+        if self.mshrs.capacity != 0 {
+            let mshrs = &self.mshrs.stats;
+            stats.section("mshr", |s| {
+                s.set("allocations", mshrs.allocations);
+                s.set("merges", mshrs.merges);
+                s.set("full_stalls", mshrs.full_stalls);
+            });
+        }
+        if let Some(dram) = &self.dram {
+            stats.section("dram", |s| dram.add_stats(s));
+        }
+    }
+}
+
+/// Access `cache` at cycle `t` and forward its line transfers to the levels
+/// below, returns the latency.
+fn access_level(
+    cache: &mut Cache,
+    below: &mut [Cache],
+    dram: &mut Dram,
+    addr: u64,
+    write: bool,
+    t: u64,
+) -> u64 {
+    let lookup = cache.lookup(addr, write);
+    let mut latency = cache.hit_latency();
+    let mut next = |addr: u64, write: bool, t: u64| match below.split_first_mut() {
+        Some((next, rest)) => {
+            let latency = access_level(next, rest, dram, addr, write, t);
+            next.add_stall_cycles(latency);
+            latency
+        }
+        None => dram.access(addr, t),
+    };
+    if let Some(victim) = lookup.writeback {
+        latency += next(victim, true, t + latency);
+    }
+    if lookup.fill {
+        latency += next(addr, false, t + latency);
+    }
+    if lookup.write_through {
+        latency += next(addr, true, t + latency);
+    }
+    latency
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = MemoryConfig::from_toml(
+            r#"
+            mshrs = 2
+            [l1d]
+            size = "1K"
+            assoc = 2
+            [l2]
+            size = "64K"
+            hit_latency = 10
+            [dram]
+            banks = 4
+            row_size = "1K"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.mshrs, 2);
+        assert!(config.l1i.is_none());
+        assert_eq!(config.l1d.as_ref().unwrap().size, 1024);
+        assert_eq!(config.l2.as_ref().unwrap().hit_latency, 10);
+        assert_eq!(config.dram.banks, 4);
+        assert_eq!(config.dram.row_size, 1024);
+
+        assert!(MemoryConfig::from_toml("[l3]\nsize = \"1M\"").is_err());
+        assert!(MemoryConfig::from_toml("[l2]\nways = 2").is_err());
+    }
+
+    #[test]
+    fn dram_row_buffer() {
+        let mut dram = Dram::new(DramConfig {
+            banks: 2,
+            row_size: 1024,
+            t_cas: 10,
+            t_rcd: 20,
+            t_rp: 30,
+            t_burst: 0,
+        });
+        // empty row, then row hit
+        assert_eq!(dram.access(0x0000, 0), 30);
+        assert_eq!(dram.access(0x0040, 100), 10);
+        // other row in the same bank: conflict
+        assert_eq!(dram.access(0x0800, 200), 60);
+        // same bank while busy waits for it
+        assert_eq!(dram.access(0x0840, 200), 60 + 10);
+        assert_eq!(dram.stats.bank_conflicts, 1);
+    }
+
+    #[test]
+    fn mshr_merge_and_nonblocking_store() {
+        let config = MemoryConfig::from_toml(
+            r#"
+            mshrs = 1
+            [l1d]
+            size = "1K"
+            [dram]
+            t_cas = 10
+            t_rcd = 10
+            t_rp = 10
+            t_burst = 0
+            "#,
+        )
+        .unwrap();
+        let mut memory = MemoryHierarchy::from_config(config);
+        // store miss does not stall
+        assert_eq!(memory.data(0x1000, true, 0), 0);
+        // load to the same line waits for the outstanding miss
+        assert_eq!(memory.data(0x1008, false, 5), 15);
+        // the only MSHR is busy until cycle 20
+        assert_eq!(memory.data(0x2000, true, 6), 14);
+        assert_eq!(memory.mshrs.stats.merges, 1);
+        assert_eq!(memory.mshrs.stats.full_stalls, 1);
+    }
+}
@@ -1,10 +1,24 @@
 pub mod branch_predict;
+pub mod branch_stats;
+pub mod cache;
+pub mod cpi_stack;
+pub mod config;
 pub mod cpu;
 pub mod ctrl_flags;
 pub mod debug;
 pub mod decode;
+pub mod diagram;
+pub mod dual_issue;
 pub mod exec;
 pub mod fetch;
+pub mod func_unit;
+pub mod global_predict;
+pub mod kanata;
 pub mod mem;
+pub mod memory;
+pub mod occupancy;
+pub mod ooo;
 pub mod phases;
+pub mod stage_latency;
+pub mod vcd;
 pub mod writeback;
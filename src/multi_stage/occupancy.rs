@@ -0,0 +1,159 @@
+//! Which instruction is in which stage of the pipeline CPU.
+//!
+//! The pipeline registers only hold decoded fields, so the viewers of the
+//! pipeline follow the instructions by replaying the states of the pipeline
+//! registers: an instruction moves on when the register after its stage is
+//! latched, stays when it is stalled and disappears on a bubble. A split
+//! stage is one stage per cycle.
+
+use super::{cpu::PipelineState, stage_latency::StageLatency};
+
+/// States of the pipeline registers decided in a clock.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct LatchStates {
+    /// Register behind the first IF cycle when IF is split, `f_d` otherwise
+    pub(super) fetch: PipelineState,
+    /// Registers behind the other IF cycles before the last
+    pub(super) inner_fetch: PipelineState,
+    pub(super) f_d: PipelineState,
+    pub(super) d_e: PipelineState,
+    /// Registers behind the EX cycles before branch resolution, the later
+    /// ones are always latched
+    pub(super) inner_exec: PipelineState,
+    pub(super) e_m: PipelineState,
+    pub(super) m_w: PipelineState,
+    pub(super) pc_next: PipelineState,
+}
+
+/// Content of a stage, instructions and bubbles are numbered separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(super) enum Slot {
+    Inst(u64),
+    Bubble(u64),
+}
+
+/// The pipeline during one clock.
+#[derive(Debug, Default)]
+pub(super) struct ClockView {
+    /// Instruction fetched in this clock, a stalled fetch is not fetched again
+    pub(super) fetched: Option<u64>,
+    pub(super) stages: Vec<Option<Slot>>,
+    /// The slot was in the same stage in the last clock
+    pub(super) stalled: Vec<bool>,
+    /// Instructions leaving at the end of this clock, with whether flushed
+    pub(super) leaving: Vec<(u64, bool)>,
+}
+
+#[derive(Debug)]
+pub(super) struct Occupancy {
+    names: Vec<String>,
+    // first cycle of ID, EX and MEM, and the EX cycle resolving branches
+    id: usize,
+    ex: usize,
+    mem: usize,
+    branch: usize,
+    next_id: u64,
+    next_bubble: u64,
+    last: Vec<Option<Slot>>,
+    // the first IF cycle is only kept when the fetch is stalled
+    next: Vec<Option<Slot>>,
+}
+
+impl Default for Occupancy {
+    fn default() -> Self {
+        Occupancy::new(&StageLatency::default())
+    }
+}
+
+impl Occupancy {
+    pub(super) fn new(stages: &StageLatency) -> Occupancy {
+        let names = stages.stage_names();
+        let id = stages.fetch as usize;
+        Occupancy {
+            id,
+            ex: id + 1,
+            mem: id + 1 + stages.exec as usize,
+            branch: id + stages.branch as usize,
+            next_id: 0,
+            next_bubble: 0,
+            last: vec![None; names.len()],
+            next: vec![None; names.len()],
+            names,
+        }
+    }
+
+    /// Name of the stage at `index`, IF first.
+    pub(super) fn name(&self, index: usize) -> &str {
+        &self.names[index]
+    }
+
+    /// State of the register in front of the stage at `index`.
+    fn state(&self, index: usize, states: &LatchStates) -> PipelineState {
+        match index {
+            1 if index < self.id => states.fetch,
+            i if i < self.id => states.inner_fetch,
+            i if i == self.id => states.f_d,
+            i if i == self.ex => states.d_e,
+            i if i <= self.branch => states.inner_exec,
+            i if i < self.mem => PipelineState::Normal,
+            i if i == self.mem => states.e_m,
+            i if i == self.names.len() - 1 => states.m_w,
+            _ => PipelineState::Normal,
+        }
+    }
+
+    pub(super) fn clock(&mut self, states: LatchStates) -> ClockView {
+        let stages = self.names.len();
+        let mut view = ClockView {
+            stages: self.next.clone(),
+            stalled: vec![false; stages],
+            ..Default::default()
+        };
+        if view.stages[0].is_none() {
+            view.fetched = Some(self.next_id);
+            view.stages[0] = Some(Slot::Inst(self.next_id));
+            self.next_id += 1;
+        }
+        for stage in 0..stages {
+            view.stalled[stage] =
+                view.stages[stage].is_some() && view.stages[stage] == self.last[stage];
+        }
+
+        let current = &view.stages;
+        let mut next = vec![None; stages];
+        for stage in 1..stages {
+            next[stage] = match self.state(stage, &states) {
+                PipelineState::Normal => current[stage - 1],
+                PipelineState::Bubble => {
+                    self.next_bubble += 1;
+                    Some(Slot::Bubble(self.next_bubble - 1))
+                }
+                PipelineState::Stall => current[stage],
+            };
+        }
+        if states.pc_next == PipelineState::Stall
+            && self.state(1, &states) != PipelineState::Normal
+        {
+            next[0] = current[0];
+        }
+
+        for (stage, slot) in current.iter().enumerate() {
+            if let Some(Slot::Inst(id)) = *slot {
+                if !next.contains(slot) {
+                    view.leaving.push((id, stage != stages - 1));
+                }
+            }
+        }
+        self.last = view.stages.clone();
+        self.next = next;
+        view
+    }
+
+    /// Instructions still in the pipeline.
+    pub(super) fn in_flight(&self) -> impl Iterator<Item = u64> + '_ {
+        self.next.iter().filter_map(|slot| match slot {
+            Some(Slot::Inst(id)) => Some(*id),
+            _ => None,
+        })
+    }
+}
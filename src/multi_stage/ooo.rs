@@ -0,0 +1,1330 @@
+//! Out-of-order CPU with Tomasulo's algorithm and a reorder buffer.
+//!
+//! Each clock runs the stages from the oldest instructions to the youngest:
+//!
+//! + commit: the oldest completed instructions of the reorder buffer (ROB)
+//!   retire in order, `issue` per cycle, through the MEM and WB phases of
+//!   the pipeline CPU, so only committed instructions write the registers
+//!   and the memory. An instruction that failed (a fetch or decode error, a
+//!   memory access outside the memory or an error of EX) raises its error
+//!   once it is the oldest: every older instruction committed and no younger
+//!   one did. SYSTEM instructions execute here, on the committed state, and
+//!   refetch the instructions after them;
+//! + issue: out of order, the oldest `issue` instructions of the reservation
+//!   stations (RS) whose operands are ready execute the EX phase, with the
+//!   latencies of the functional units. The iterative units accept one
+//!   instruction at a time. Results are broadcast to the waiting RS entries
+//!   when they complete. A mispredicted control instruction flushes the
+//!   younger instructions from the ROB, the RS and the load/store queue
+//!   (LSQ), restores the rename table and the RAS, and redirects the fetch;
+//! + loads and stores: a store writes the memory at commit. A load takes its
+//!   data from the youngest older store in the LSQ whose bytes overlap its
+//!   own, `[addr, addr + size)`: forwarded if the store covers all of them,
+//!   after the store commits if it covers only some. Loads do not wait for
+//!   older stores whose address is unknown, a load whose value changed by
+//!   commit is replayed with everything after it;
+//! + dispatch: in order, `issue` per cycle, two cycles after fetch, into the
+//!   ROB, an RS entry and, for memory accesses, an LSQ entry, renaming the
+//!   source registers to the ROB entries producing them. It stops while the
+//!   ROB, the RS or the LSQ is full;
+//! + fetch: `issue` instructions per cycle along the predicted path, up to
+//!   the first control instruction predicted taken. With all stall, it waits
+//!   for every control instruction to resolve, and always for a SYSTEM
+//!   instruction to commit.
+
+use std::{collections::VecDeque, fmt, str::FromStr};
+
+use log::info;
+
+use crate::{
+    callstack::CallStack,
+    commit_log::{CommitLog, RetireInfo},
+    core::{
+        csr::{serializes, CsrFile},
+        insts::{sext, Inst64},
+        reg::{ProgramCounter, RegisterFile},
+        vm::VirtualMemory,
+    },
+    elf::LoadElfInfo,
+    error::{self, Error},
+    iringbuf::InstRingBuffer,
+    profile::Profiler,
+    stats::Stats,
+    trace::Tracer,
+};
+
+use super::{
+    branch_predict::{RasCheckpoint, BHT, BTB, RAS},
+    config::CoreConfig,
+    cpu::{halt, ControlPolicy},
+    debug::w_pinst,
+    decode::decode,
+    exec::{exec, track_call},
+    fetch::fetch,
+    func_unit::{FuClass, FuConfig, RemFusion},
+    mem::mem,
+    phases::{InternalDecodeExec, InternalExecMem, InternalFetchDecode, InternalMemWb},
+    writeback::writeback,
+};
+
+/// Cycles from fetch to dispatch.
+const FRONTEND_CYCLES: u64 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OooConfig {
+    /// Reorder buffer entries
+    pub rob: usize,
+    /// Instructions dispatched, issued and committed per cycle
+    pub issue: usize,
+    /// Reservation station entries
+    pub rs: usize,
+    /// Load/store queue entries
+    pub lsq: usize,
+}
+
+impl Default for OooConfig {
+    fn default() -> Self {
+        Self {
+            rob: 64,
+            issue: 2,
+            rs: 16,
+            lsq: 16,
+        }
+    }
+}
+
+impl OooConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, size) in [
+            ("rob", self.rob),
+            ("issue", self.issue),
+            ("rs", self.rs),
+            ("lsq", self.lsq),
+        ] {
+            if size == 0 {
+                return Err(format!("{name} must be at least 1"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `key=value` pairs separated by commas, omitted keys take their default
+/// value (`rob=64,issue=2,rs=16,lsq=16`).
+impl FromStr for OooConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = OooConfig::default();
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expect key=value, got `{item}`"))?;
+            let invalid = |e: &dyn std::fmt::Display| format!("invalid {key} `{value}`: {e}");
+            match key {
+                "rob" => config.rob = value.parse().map_err(|e| invalid(&e))?,
+                "issue" => config.issue = value.parse().map_err(|e| invalid(&e))?,
+                "rs" => config.rs = value.parse().map_err(|e| invalid(&e))?,
+                "lsq" => config.lsq = value.parse().map_err(|e| invalid(&e))?,
+                _ => return Err(format!("unknown out-of-order parameter `{key}`")),
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl fmt::Display for OooConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ROB {}, issue width {}, RS {}, LSQ {}",
+            self.rob, self.issue, self.rs, self.lsq
+        )
+    }
+}
+
+/// An operand of an instruction waiting in a reservation station.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Value(u64),
+    /// Result of the instruction with this sequence number
+    Tag(u64),
+}
+
+/// An instruction of the reorder buffer.
+struct RobEntry {
+    /// Sequence number, in program order
+    seq: u64,
+    itl_d_e: InternalDecodeExec,
+    /// Result of EX, once executed
+    itl_e_m: Option<InternalExecMem>,
+    /// Target of a control or SYSTEM instruction, once executed
+    target: u64,
+    /// Cycle the result is ready, once executed
+    complete: Option<u64>,
+    /// Executes at commit, on the committed state, instead of from a
+    /// reservation station
+    at_commit: bool,
+    /// Value written to rd, the loaded value of a load
+    value: u64,
+    /// Error raised when the instruction commits
+    fault: Option<Error>,
+}
+
+impl RobEntry {
+    fn completed(&self, now: u64) -> bool {
+        self.complete.is_some_and(|complete| complete <= now)
+    }
+}
+
+/// An instruction waiting in a reservation station.
+struct RsEntry {
+    seq: u64,
+    operands: [Operand; 2],
+    dispatch: u64,
+    /// Cycle its operands were all ready
+    ready: Option<u64>,
+    /// Cycles from issue until the result can be used
+    latency: u64,
+    /// Uses an iterative unit, which is busy for the whole latency
+    iterative: bool,
+}
+
+/// A memory access of the load/store queue.
+struct LsqEntry {
+    seq: u64,
+    store: bool,
+    /// Bytes `[start, end)` accessed, once the address is computed
+    range: Option<(u64, u64)>,
+    /// Data of a store, once executed
+    data: u64,
+}
+
+/// A fetched instruction waiting for dispatch.
+struct Fetched {
+    itl_f_d: InternalFetchDecode,
+    /// First cycle it can dispatch
+    ready: u64,
+    /// The fetch failed, raised if the instruction commits
+    fault: Option<Error>,
+}
+
+/// Where a load takes its data from.
+enum LoadSource {
+    /// The data of an older store covering all the loaded bytes
+    Forward(u64),
+    /// An older store covers some of the loaded bytes, wait for it to commit
+    Wait,
+    Memory,
+}
+
+fn find(rob: &VecDeque<RobEntry>, seq: u64) -> Option<&RobEntry> {
+    let head = rob.front()?.seq;
+    rob.get(seq.checked_sub(head)? as usize)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OooStatistics {
+    /// Instructions issued after dispatch waiting for operands, and the cycles
+    pub data_hazard_count: u64,
+    pub data_stall_cycles: u64,
+    /// Instructions with ready operands waiting for the iterative unit, an
+    /// issue slot or an older store, and the cycles
+    pub structural_hazard_count: u64,
+    pub structural_stall_cycles: u64,
+    pub rob_full_cycles: u64,
+    pub rs_full_cycles: u64,
+    pub lsq_full_cycles: u64,
+    /// Cycles nothing was fetched to dispatch, after a redirect or while the
+    /// fetch waits
+    pub redirect_cycles: u64,
+    /// Fetch redirects, by mispredictions, all stall, SYSTEM instructions and
+    /// load replays
+    pub redirects: u64,
+    pub mispredictions: u64,
+    /// Loads whose value changed by commit, replayed with what follows them
+    pub load_replays: u64,
+    pub store_forwards: u64,
+    /// Instructions flushed from the ROB
+    pub flushed: u64,
+    /// Sum of the ROB entries over the cycles
+    pub rob_cycles: u64,
+}
+
+/// Configuration of the out-of-order CPU.
+#[derive(Debug, Clone)]
+pub struct OooCpuConfig {
+    pub core: CoreConfig,
+    pub sizes: OooConfig,
+}
+
+pub struct OooCPU<'a> {
+    // indicate whether the CPU is running
+    running: bool,
+
+    // clock
+    clock: u64,
+
+    // General purpose register file, committed state
+    reg_file: RegisterFile,
+
+    // Program counter (PC) of the next instruction fetched
+    pc: ProgramCounter,
+
+    // Control and status registers
+    csr: CsrFile,
+
+    // Reference to virtual memory
+    vm: &'a mut VirtualMemory,
+
+    // Reference to call stack
+    callstack: &'a mut CallStack<'a>,
+
+    // Instruction trace
+    itrace: Tracer,
+
+    // Control policy
+    control_policy: ControlPolicy,
+
+    // Branch history table
+    bht: Option<BHT>,
+
+    // Branch target buffer
+    btb: Option<BTB>,
+
+    // Return address stack
+    ras: RAS,
+
+    // Latencies of the functional units
+    fu_config: FuConfig,
+
+    // Last division, whose remainder is free
+    rem_fusion: RemFusion,
+
+    // Sizes of the ROB, the RS and the LSQ, and the issue width
+    config: OooConfig,
+
+    // Fetched instructions, oldest first
+    fetch_queue: VecDeque<Fetched>,
+
+    // Whether IF fetches, it stops until the next redirect after a SYSTEM
+    // instruction, a failed fetch, and control instructions with all stall
+    fetching: bool,
+
+    // First cycle IF fetches after a redirect
+    fetch_resume: u64,
+
+    // Reorder buffer, oldest first, with consecutive sequence numbers
+    rob: VecDeque<RobEntry>,
+
+    // Sequence number of the next instruction dispatched
+    next_seq: u64,
+
+    // Youngest instruction in flight writing each register
+    rename: [Option<u64>; 32],
+
+    // Reservation stations, oldest first
+    rs: Vec<RsEntry>,
+
+    // Load/store queue, oldest first
+    lsq: VecDeque<LsqEntry>,
+
+    // Cycle the iterative unit is free
+    iterative_free: u64,
+
+    stats: OooStatistics,
+
+    retired_inst_count: u64,
+
+    // Cycles since the last retirement, charged to the next one
+    unretired_cycles: u64,
+
+    // Instruction-level profiler
+    profiler: Option<&'a mut Profiler>,
+
+    // Spike-compatible commit log
+    commit_log: Option<CommitLog>,
+
+    // Effects of the instructions retired in the last cycle, for difftest
+    retired: VecDeque<RetireInfo>,
+
+    // Last retired instructions, dumped on failure
+    iringbuf: InstRingBuffer,
+}
+
+impl<'a> OooCPU<'a> {
+    pub fn new(
+        vm: &'a mut VirtualMemory,
+        callstack: &'a mut CallStack<'a>,
+        itrace: Tracer,
+        config: OooCpuConfig,
+        profiler: Option<&'a mut Profiler>,
+        commit_log: Option<CommitLog>,
+        iringbuf_size: usize,
+    ) -> OooCPU<'a> {
+        let OooCpuConfig { core, sizes } = config;
+        let bht = core
+            .predict_policy
+            .map(|predict_policy| BHT::new(predict_policy, core.bht, &core.predictor));
+        let btb = core.predict_policy.map(|_| BTB::new(core.btb));
+
+        OooCPU {
+            running: false,
+            clock: 0,
+            reg_file: RegisterFile::empty(),
+            pc: ProgramCounter::new(),
+            csr: CsrFile::new(),
+            vm,
+            callstack,
+            itrace,
+            control_policy: core.control_policy,
+            bht,
+            btb,
+            ras: RAS::new(core.ras_depth),
+            fu_config: core.fu_latency,
+            rem_fusion: RemFusion::default(),
+            config: sizes,
+            fetch_queue: VecDeque::new(),
+            fetching: true,
+            fetch_resume: 0,
+            rob: VecDeque::new(),
+            next_seq: 0,
+            rename: [None; 32],
+            rs: Vec::new(),
+            lsq: VecDeque::new(),
+            iterative_free: 0,
+            stats: OooStatistics::default(),
+            retired_inst_count: 0,
+            unretired_cycles: 0,
+            profiler,
+            commit_log,
+            retired: VecDeque::new(),
+            iringbuf: InstRingBuffer::new(iringbuf_size),
+        }
+    }
+
+    /// Initialize CPU with ELF info
+    pub fn init_elfinfo_64(&mut self, info: &LoadElfInfo) {
+        // make sure we are running a ELF64 executable
+        assert!(info.is_64_bit());
+
+        self.reg_file.init_elfinfo_64(info);
+        self.pc.write(info.entry_point());
+    }
+
+    /// Run the cpu.
+    /// steps: how many clocks should be run, [`None`] means run until end or
+    /// exception raised.
+    pub fn cpu_exec(&mut self, steps: Option<i32>) -> error::Result<()> {
+        self.running = true;
+        let mut i = 0;
+
+        while self.running {
+            if steps.is_some_and(|n| i >= n) {
+                break;
+            }
+            self.clock()?;
+            let exit = self
+                .retired
+                .iter()
+                .find_map(|info| Some((info.pc, self.vm.htif_exit(Some(info))?)));
+            if let Some((pc, code)) = exit {
+                if code != 0 {
+                    self.iringbuf.dump();
+                }
+                halt(pc, code);
+                self.running = false;
+            }
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    pub fn print_info(&self) {
+        let stats = &self.stats;
+        info!("CPU run clock: {}", self.clock);
+        info!("CPU out-of-order core: {}", self.config);
+        info!(
+            "CPU dispatch stall cycles: ROB full {}, RS full {}, LSQ full {}, redirect {}",
+            stats.rob_full_cycles,
+            stats.rs_full_cycles,
+            stats.lsq_full_cycles,
+            stats.redirect_cycles
+        );
+        info!(
+            "CPU ROB average occupancy: {:.2}",
+            stats.rob_cycles as f64 / self.clock.max(1) as f64
+        );
+        info!("CPU store-to-load forwards: {}", stats.store_forwards);
+        info!("CPU load replays: {}", stats.load_replays);
+        info!(
+            "CPU data hazard count: {}, waiting cycles: {}",
+            stats.data_hazard_count, stats.data_stall_cycles
+        );
+        info!(
+            "CPU structural hazard count: {}, waiting cycles: {}",
+            stats.structural_hazard_count, stats.structural_stall_cycles
+        );
+        info!(
+            "CPU branch mispredictions: {}, fetch redirects: {}, flushed instructions: {}",
+            stats.mispredictions, stats.redirects, stats.flushed
+        );
+        info!("CPU functional units: {}", self.fu_config);
+        if let Some(bht) = &self.bht {
+            bht.print_info();
+        }
+        if let Some(btb) = &self.btb {
+            btb.print_info();
+        }
+        if self.control_policy == ControlPolicy::DynamicPredict {
+            self.ras.print_info();
+        }
+        info!(
+            "CPU executed valid instructions: {}",
+            self.retired_inst_count
+        );
+        info!("CPI = {}", {
+            (self.clock as f64) / (self.retired_inst_count as f64)
+        });
+        info!("IPC = {}", {
+            (self.retired_inst_count as f64) / (self.clock as f64)
+        });
+    }
+
+    pub fn add_stats(&self, stats: &mut Stats) {
+        let clock = self.clock;
+        let ooo = &self.stats;
+        let insts = self.retired_inst_count;
+        stats.set("cycles", clock);
+        stats.set("instret", insts);
+        stats.set_ratio("cpi", clock, insts);
+        stats.set_ratio("ipc", insts, clock);
+        stats.section("hazards", |s| {
+            s.section("data", |s| {
+                s.set("count", ooo.data_hazard_count);
+                s.set("cycles", ooo.data_stall_cycles);
+            });
+            s.section("control", |s| {
+                s.set("count", ooo.redirects);
+                s.set("cycles", ooo.redirect_cycles);
+            });
+            s.section("structural", |s| {
+                s.set("count", ooo.structural_hazard_count);
+                s.set("cycles", ooo.structural_stall_cycles);
+            });
+        });
+        stats.section("ooo", |s| {
+            s.section("dispatch_stall_cycles", |s| {
+                s.set("rob_full", ooo.rob_full_cycles);
+                s.set("rs_full", ooo.rs_full_cycles);
+                s.set("lsq_full", ooo.lsq_full_cycles);
+                s.set("redirect", ooo.redirect_cycles);
+            });
+            s.set_ratio("rob_occupancy", ooo.rob_cycles, clock.max(1));
+            s.set("store_forwards", ooo.store_forwards);
+            s.set("load_replays", ooo.load_replays);
+            s.set("mispredictions", ooo.mispredictions);
+            s.set("flushed", ooo.flushed);
+        });
+        if let Some(bht) = &self.bht {
+            stats.section("predictor", |s| bht.add_stats(s));
+        }
+        if let Some(btb) = &self.btb {
+            stats.section("btb", |s| btb.add_stats(s));
+        }
+        if self.control_policy == ControlPolicy::DynamicPredict {
+            stats.section("ras", |s| self.ras.add_stats(s));
+        }
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    pub fn read_reg(&self, idx: u8) -> u64 {
+        self.reg_file.read(idx)
+    }
+
+    /// Effects of the instructions retired in the last cycle, one per call.
+    pub fn take_retired(&mut self) -> Option<RetireInfo> {
+        self.retired.pop_front()
+    }
+
+    pub(super) fn clock(&mut self) -> error::Result<()> {
+        self.clock += 1;
+        self.unretired_cycles += 1;
+        self.retired.clear();
+        self.stats.rob_cycles += self.rob.len() as u64;
+
+        // from the oldest instructions to the youngest, so that no
+        // instruction goes through two stages in a cycle
+        self.commit()?;
+        if !self.running {
+            return Ok(());
+        }
+        self.issue();
+        self.dispatch();
+        self.fetch();
+
+        Ok(())
+    }
+
+    /// Commit the oldest completed instructions, in order.
+    fn commit(&mut self) -> error::Result<()> {
+        for _ in 0..self.config.issue {
+            let Some(head) = self.rob.front_mut() else {
+                break;
+            };
+            if !head.at_commit && !head.completed(self.clock) {
+                break;
+            }
+            // every older instruction committed and no younger one did
+            if let Some(fault) = head.fault.take() {
+                self.iringbuf.dump();
+                return Err(fault);
+            }
+            let head = self.rob.pop_front().unwrap();
+            let (itl_e_m, target) = match head.itl_e_m {
+                Some(itl_e_m) => (itl_e_m, head.target),
+                None => {
+                    // the operands are all committed
+                    let mut itl_d_e = head.itl_d_e;
+                    itl_d_e.src1 = self.reg_file.read(itl_d_e.rs1);
+                    itl_d_e.src2 = self.reg_file.read(itl_d_e.rs2);
+                    let mut callstack =
+                        CallStack::new(self.callstack.symbol_map(), Tracer::disabled());
+                    let (itl_e_m, _, new_pc_1) =
+                        exec(&itl_d_e, false, &mut callstack, &mut self.csr)?;
+                    (itl_e_m, new_pc_1)
+                }
+            };
+            let next_pc = if itl_e_m.branch_flags.pc_src {
+                target
+            } else {
+                itl_e_m.pc.wrapping_add(4)
+            };
+
+            let itl_m_w = mem(&itl_e_m, self.vm, false);
+            let running = writeback(&itl_m_w, &mut self.reg_file, false);
+            // reset x0 to 0
+            self.reg_file.write(0, 0);
+            if itl_m_w.mem_read || itl_m_w.mem_write {
+                self.lsq.pop_front();
+            }
+            let rd = itl_m_w.rd as usize;
+            if self.rename[rd] == Some(head.seq) {
+                self.rename[rd] = None;
+            }
+            for entry in &mut self.rs {
+                for operand in &mut entry.operands {
+                    if *operand == Operand::Tag(head.seq) {
+                        *operand = Operand::Value(itl_m_w.regval);
+                    }
+                }
+            }
+            if itl_e_m.branch_flags.branch {
+                self.train(&itl_e_m, target);
+            }
+            track_call(self.callstack, &itl_e_m, target);
+            self.retire(&itl_m_w);
+
+            if !running {
+                self.running = false;
+                break;
+            }
+            if self.vm.htif_exit(self.retired.back()).is_some() {
+                break;
+            }
+            let checkpoint = itl_e_m.branch_flags.ras_checkpoint;
+            // a SYSTEM instruction refetches behind itself
+            if serializes(itl_e_m.alu_op) {
+                self.flush_after(head.seq, next_pc, checkpoint);
+                break;
+            }
+            // a store the load speculated past wrote its bytes
+            if itl_m_w.mem_read && itl_m_w.regval != head.value {
+                self.stats.load_replays += 1;
+                self.flush_after(head.seq, next_pc, checkpoint);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn retire(&mut self, itl_m_w: &InternalMemWb) {
+        if self.itrace.enabled() {
+            self.itrace
+                .inst(itl_m_w.pc, itl_m_w.raw_inst, &w_pinst(itl_m_w));
+        }
+        let info = itl_m_w.retire_info();
+        if let Some(commit_log) = self.commit_log.as_mut() {
+            commit_log.commit(&info);
+        }
+        self.retired.push_back(info);
+        self.retired_inst_count += 1;
+        self.iringbuf.push(info);
+        if itl_m_w.alu_op == Inst64::ebreak && self.reg_file.read(10) != 0 {
+            self.iringbuf.dump();
+        }
+        if let Some(profiler) = self.profiler.as_deref_mut() {
+            // cycles without a retirement charge the next retired instruction
+            profiler.retire(itl_m_w.pc, itl_m_w.raw_inst, self.unretired_cycles);
+            self.unretired_cycles = 0;
+        }
+    }
+
+    /// Train the predictors with a committed control instruction.
+    fn train(&mut self, itl_e_m: &InternalExecMem, target: u64) {
+        use Inst64::*;
+        if self.control_policy != ControlPolicy::DynamicPredict {
+            return;
+        }
+        let branch_flags = itl_e_m.branch_flags;
+        self.btb
+            .as_mut()
+            .unwrap()
+            .add_entry(itl_e_m.pc, target, itl_e_m.alu_op == jalr);
+        if branch_flags.ras_predicted {
+            self.ras.record(target == branch_flags.predicted_target);
+        }
+        self.bht.as_mut().unwrap().update_with_result(
+            itl_e_m.pc,
+            branch_flags.pc_src,
+            !matches!(itl_e_m.alu_op, jal | jalr),
+            branch_flags.predict_history,
+            branch_flags.predicted_taken,
+        );
+    }
+
+    /// Flush the instructions younger than `seq` and fetch again from
+    /// `target`.
+    fn flush_after(&mut self, seq: u64, target: u64, ras_checkpoint: RasCheckpoint) {
+        while self.rob.back().is_some_and(|entry| entry.seq > seq) {
+            self.rob.pop_back();
+            self.stats.flushed += 1;
+        }
+        self.rs.retain(|entry| entry.seq <= seq);
+        while self.lsq.back().is_some_and(|entry| entry.seq > seq) {
+            self.lsq.pop_back();
+        }
+        self.next_seq = seq + 1;
+        self.rename = [None; 32];
+        for entry in &self.rob {
+            if entry.itl_d_e.wb_flags.mem_to_reg {
+                self.rename[entry.itl_d_e.rd as usize] = Some(entry.seq);
+            }
+        }
+        self.rename[0] = None;
+        self.rem_fusion = RemFusion::default();
+
+        self.fetch_queue.clear();
+        if self.control_policy == ControlPolicy::DynamicPredict {
+            self.ras.repair(ras_checkpoint);
+        }
+        self.pc.write(target);
+        self.fetching = true;
+        self.fetch_resume = self.clock + 1;
+        self.stats.redirects += 1;
+    }
+
+    /// Issue the oldest instructions of the reservation stations whose
+    /// operands are ready.
+    fn issue(&mut self) {
+        let now = self.clock;
+        for entry in &mut self.rs {
+            for operand in &mut entry.operands {
+                if let Operand::Tag(seq) = *operand {
+                    if let Some(producer) = find(&self.rob, seq).filter(|p| p.completed(now)) {
+                        *operand = Operand::Value(producer.value);
+                    }
+                }
+            }
+            let ready = entry
+                .operands
+                .iter()
+                .all(|operand| matches!(operand, Operand::Value(_)));
+            if ready && entry.ready.is_none() {
+                entry.ready = Some(now);
+            }
+        }
+
+        let mut issued = 0;
+        let mut i = 0;
+        while i < self.rs.len() && issued < self.config.issue {
+            let entry = &self.rs[i];
+            if entry.ready.is_none() || (entry.iterative && self.iterative_free > now) {
+                i += 1;
+                continue;
+            }
+            if self.execute(i) {
+                issued += 1;
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Execute the instruction of reservation station `index`, removing it,
+    /// or leave it there if it cannot execute yet.
+    fn execute(&mut self, index: usize) -> bool {
+        let now = self.clock;
+        let entry = &self.rs[index];
+        let seq = entry.seq;
+        let pos = (seq - self.rob[0].seq) as usize;
+        let mut itl_d_e = self.rob[pos].itl_d_e;
+        let [Operand::Value(src1), Operand::Value(src2)] = entry.operands else {
+            unreachable!("issue with operands not ready");
+        };
+        itl_d_e.src1 = src1;
+        itl_d_e.src2 = src2;
+
+        // SYSTEM instructions execute at commit, the others touch neither the
+        // CSRs nor the call stack
+        let mut callstack = CallStack::new(self.callstack.symbol_map(), Tracer::disabled());
+        let (itl_e_m, _, target) = match exec(&itl_d_e, false, &mut callstack, &mut CsrFile::new())
+        {
+            Ok(result) => result,
+            Err(e) => {
+                let rob_entry = &mut self.rob[pos];
+                rob_entry.fault = Some(e);
+                rob_entry.complete = Some(now + 1);
+                self.rs.remove(index);
+                return true;
+            }
+        };
+
+        let mut latency = entry.latency;
+        let mut value = itl_e_m.alu_out;
+        let mut fault = None;
+        let load = itl_e_m.mem_flags.mem_read;
+        if load || itl_e_m.mem_flags.mem_write {
+            let size = itl_e_m.mem_bitwidth as u64 / 8;
+            let start = itl_e_m.mem_addr;
+            let range = (start, start.wrapping_add(size));
+            match self.vm.read_bytes(start as usize, size as usize) {
+                None => {
+                    let access = if load { "load" } else { "store" };
+                    fault = Some(Error::Execute(format!(
+                        "{access} of {size} bytes at {start:#x} by {:#x} outside the memory",
+                        itl_e_m.pc
+                    )));
+                }
+                Some(bytes) if load => {
+                    let raw = match self.load_source(seq, range) {
+                        LoadSource::Wait => return false,
+                        LoadSource::Forward(data) => {
+                            self.stats.store_forwards += 1;
+                            latency = 1;
+                            data
+                        }
+                        LoadSource::Memory => {
+                            let mut buf = [0; 8];
+                            buf[..bytes.len()].copy_from_slice(bytes);
+                            u64::from_le_bytes(buf)
+                        }
+                    };
+                    let raw = match size {
+                        8 => raw,
+                        _ => raw & ((1 << (size * 8)) - 1),
+                    };
+                    value = match itl_e_m.mem_sext_to {
+                        0 => raw,
+                        bits => sext(raw, bits) as u64,
+                    };
+                }
+                Some(_) => {}
+            }
+            let lsq_entry = self.lsq.iter_mut().find(|e| e.seq == seq).unwrap();
+            lsq_entry.range = Some(range);
+            lsq_entry.data = itl_e_m.alu_out;
+        }
+
+        let entry = self.rs.remove(index);
+        let ready = entry.ready.unwrap();
+        if ready > entry.dispatch + 1 {
+            self.stats.data_hazard_count += 1;
+            self.stats.data_stall_cycles += ready - (entry.dispatch + 1);
+        }
+        if now > ready {
+            self.stats.structural_hazard_count += 1;
+            self.stats.structural_stall_cycles += now - ready;
+        }
+        if entry.iterative {
+            self.iterative_free = now + latency;
+        }
+        let rob_entry = &mut self.rob[pos];
+        rob_entry.itl_e_m = Some(itl_e_m);
+        rob_entry.target = target;
+        rob_entry.complete = Some(now + latency);
+        rob_entry.value = value;
+        rob_entry.fault = fault;
+
+        // resolve a control instruction
+        let branch_flags = itl_e_m.branch_flags;
+        if branch_flags.branch {
+            let pc_src = branch_flags.pc_src;
+            let next_pc = if pc_src {
+                target
+            } else {
+                itl_e_m.pc.wrapping_add(4)
+            };
+            let mispredicted = pc_src != branch_flags.predicted_src
+                || (pc_src && target != branch_flags.predicted_target);
+            if mispredicted {
+                self.stats.mispredictions += 1;
+            }
+            // all stall waits for every control instruction
+            if mispredicted || self.control_policy == ControlPolicy::AllStall {
+                self.flush_after(seq, next_pc, branch_flags.ras_checkpoint);
+            }
+        }
+        true
+    }
+
+    /// Where the load `seq` of the bytes `[start, end)` takes its data from:
+    /// the youngest older store whose bytes overlap. Stores whose address is
+    /// unknown yet are speculated past.
+    fn load_source(&self, seq: u64, (start, end): (u64, u64)) -> LoadSource {
+        let store = self
+            .lsq
+            .iter()
+            .rev()
+            .filter(|entry| entry.store && entry.seq < seq)
+            .find_map(|entry| {
+                let (s, e) = entry.range?;
+                (s < end && start < e).then_some((s, e, entry.data))
+            });
+        match store {
+            Some((s, e, data)) if s <= start && end <= e => {
+                LoadSource::Forward(data >> ((start - s) * 8))
+            }
+            Some(_) => LoadSource::Wait,
+            None => LoadSource::Memory,
+        }
+    }
+
+    /// Dispatch the fetched instructions in order, renaming their operands.
+    fn dispatch(&mut self) {
+        let now = self.clock;
+        for slot in 0..self.config.issue {
+            let Some(fetched) = self.fetch_queue.front().filter(|f| f.ready <= now) else {
+                if slot == 0 {
+                    self.stats.redirect_cycles += 1;
+                }
+                break;
+            };
+            let itl_f_d = fetched.itl_f_d;
+            let op = itl_f_d.exec_flags.alu_op;
+            let at_commit = fetched.fault.is_some() || serializes(op);
+            let memory = itl_f_d.mem_flags.mem_read || itl_f_d.mem_flags.mem_write;
+            if self.rob.len() >= self.config.rob {
+                self.stats.rob_full_cycles += 1;
+                break;
+            }
+            if !at_commit && self.rs.len() >= self.config.rs {
+                self.stats.rs_full_cycles += 1;
+                break;
+            }
+            if !at_commit && memory && self.lsq.len() >= self.config.lsq {
+                self.stats.lsq_full_cycles += 1;
+                break;
+            }
+            let fault = self.fetch_queue.pop_front().unwrap().fault;
+
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            let class = FuClass::of(op);
+            let blocking = self.fu_config.blocking_cycles(class);
+            // a fused remainder completes with its division, without the divider
+            let fused = self.rem_fusion.next(op, itl_f_d.rs1, itl_f_d.rs2);
+            if !at_commit {
+                let operands = [itl_f_d.rs1, itl_f_d.rs2].map(|rs| self.operand(rs));
+                self.rs.push(RsEntry {
+                    seq,
+                    operands,
+                    dispatch: now,
+                    ready: None,
+                    latency: self.fu_config.result_latency(class) + blocking,
+                    iterative: blocking != 0 && !fused,
+                });
+                if memory {
+                    self.lsq.push_back(LsqEntry {
+                        seq,
+                        store: itl_f_d.mem_flags.mem_write,
+                        range: None,
+                        data: 0,
+                    });
+                }
+            }
+            self.rob.push_back(RobEntry {
+                seq,
+                itl_d_e: decode(&self.reg_file, &itl_f_d, false),
+                itl_e_m: None,
+                target: 0,
+                complete: None,
+                at_commit,
+                value: 0,
+                fault,
+            });
+            if itl_f_d.wb_flags.mem_to_reg && itl_f_d.rd != 0 {
+                self.rename[itl_f_d.rd as usize] = Some(seq);
+            }
+        }
+    }
+
+    /// Value of register `rs` for an instruction dispatched now, or the tag
+    /// of the instruction producing it.
+    fn operand(&self, rs: u8) -> Operand {
+        match self.rename[rs as usize] {
+            None => Operand::Value(self.reg_file.read(rs)),
+            Some(seq) => match find(&self.rob, seq) {
+                Some(producer) if producer.completed(self.clock) => Operand::Value(producer.value),
+                _ => Operand::Tag(seq),
+            },
+        }
+    }
+
+    /// Fetch along the predicted path, up to the first control instruction
+    /// predicted taken.
+    fn fetch(&mut self) {
+        use Inst64::*;
+        if !self.fetching || self.clock < self.fetch_resume {
+            return;
+        }
+        for _ in 0..self.config.issue {
+            if self.fetch_queue.len() >= FRONTEND_CYCLES as usize * self.config.issue {
+                break;
+            }
+            let pc = self.pc.read();
+            let ready = self.clock + FRONTEND_CYCLES;
+            // a wrong path may lead anywhere, IF reads nothing outside the memory
+            let raw = self
+                .vm
+                .read_bytes(pc as usize, 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+            let itl_f_d = match raw {
+                Some(_) => fetch(
+                    &self.pc,
+                    self.vm,
+                    false,
+                    self.control_policy,
+                    self.bht.as_mut(),
+                    self.btb.as_mut(),
+                    Some(&mut self.ras),
+                ),
+                None => InternalFetchDecode::default(),
+            };
+            let op = itl_f_d.exec_flags.alu_op;
+            if op == noop {
+                let fault = match raw {
+                    Some(raw) => {
+                        Error::Decode(format!("invalid instruction {raw:#010x} at {pc:#x}"))
+                    }
+                    None => Error::Fetch(format!("pc {pc:#x} outside the memory")),
+                };
+                self.fetch_queue.push_back(Fetched {
+                    itl_f_d: InternalFetchDecode { pc, ..itl_f_d },
+                    ready,
+                    fault: Some(fault),
+                });
+                self.fetching = false;
+                break;
+            }
+            let branch_flags = itl_f_d.branch_flags;
+            self.pc.write(if branch_flags.predicted_src {
+                branch_flags.predicted_target
+            } else {
+                pc.wrapping_add(4)
+            });
+            self.fetch_queue.push_back(Fetched {
+                itl_f_d,
+                ready,
+                fault: None,
+            });
+            // wait for a SYSTEM instruction to commit, and with all stall
+            // for every control instruction to resolve
+            if serializes(op)
+                || (branch_flags.branch && self.control_policy == ControlPolicy::AllStall)
+            {
+                self.fetching = false;
+                break;
+            }
+            if branch_flags.predicted_src {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::multi_stage::global_predict::PredictorConfig;
+
+    const PROGRAM_START: u64 = 0x1000;
+    const DATA: usize = 0x1400;
+
+    struct Run {
+        result: error::Result<()>,
+        clock: u64,
+        stats: OooStatistics,
+        regs: [u64; 32],
+        retired: Vec<u64>,
+    }
+
+    /// Run `program` with the doublewords `data` at [`DATA`], `div` cycles
+    /// per division.
+    fn run(
+        program: &[u32],
+        data: &[u64],
+        control_policy: ControlPolicy,
+        sizes: OooConfig,
+        div: u64,
+    ) -> Run {
+        let mut vm = VirtualMemory::new(0x2000, Tracer::disabled());
+        for (i, inst) in program.iter().enumerate() {
+            vm.mwrite(PROGRAM_START as usize + i * 4, *inst);
+        }
+        for (i, dword) in data.iter().enumerate() {
+            vm.mwrite(DATA + i * 8, *dword);
+        }
+        let symbols = HashMap::new();
+        let mut callstack = CallStack::new(&symbols, Tracer::disabled());
+        let core = CoreConfig {
+            control_policy,
+            predict_policy: None,
+            bht: None,
+            btb: None,
+            predictor: PredictorConfig::default(),
+            ras_depth: 16,
+            fu_latency: FuConfig {
+                div,
+                ..Default::default()
+            },
+        };
+        let mut cpu = OooCPU::new(
+            &mut vm,
+            &mut callstack,
+            Tracer::disabled(),
+            OooCpuConfig { core, sizes },
+            None,
+            None,
+            0,
+        );
+        cpu.pc.write(PROGRAM_START);
+        let mut retired = Vec::new();
+        let mut result = Ok(());
+        for _ in 0..200 {
+            result = cpu.cpu_exec(Some(1));
+            retired.extend(std::iter::from_fn(|| cpu.take_retired()).map(|info| info.pc));
+            if result.is_err() || !cpu.running() {
+                break;
+            }
+        }
+        assert!(result.is_err() || !cpu.running(), "the program did not end");
+        Run {
+            result,
+            clock: cpu.clock,
+            stats: cpu.stats.clone(),
+            regs: std::array::from_fn(|i| cpu.read_reg(i as u8)),
+            retired,
+        }
+    }
+
+    fn pcs(n: usize) -> Vec<u64> {
+        (0..n as u64).map(|i| PROGRAM_START + i * 4).collect()
+    }
+
+    #[test]
+    fn parse_ooo_config() {
+        let config: OooConfig = "rob=8,issue=4".parse().unwrap();
+        assert_eq!(config.rob, 8);
+        assert_eq!(config.issue, 4);
+        assert_eq!(config.rs, 16);
+        assert!("rob=0".parse::<OooConfig>().is_err());
+        assert!("width=2".parse::<OooConfig>().is_err());
+    }
+
+    const SLOW_DIV: [u32; 7] = [
+        0x06400093, // addi x1, x0, 100
+        0x00700113, // addi x2, x0, 7
+        0x0220c1b3, // div x3, x1, x2
+        0x00100213, // addi x4, x0, 1
+        0x00120293, // addi x5, x4, 1
+        0x00518333, // add x6, x3, x5
+        0x00100073, // ebreak
+    ];
+
+    #[test]
+    fn issues_around_a_slow_division() {
+        let run = run(
+            &SLOW_DIV,
+            &[],
+            ControlPolicy::AlwaysNotTaken,
+            OooConfig::default(),
+            20,
+        );
+        run.result.unwrap();
+        assert_eq!(run.regs[3], 14);
+        assert_eq!(run.regs[6], 16);
+        // x4 and x5 execute while the division does, only the add waits
+        assert_eq!(run.stats.data_hazard_count, 1);
+        assert_eq!(run.stats.data_stall_cycles, 25 - 6);
+        assert_eq!(run.clock, 27);
+        // and everything commits in order
+        assert_eq!(run.retired, pcs(7));
+    }
+
+    #[test]
+    fn rob_limits_dispatch() {
+        let sizes = OooConfig {
+            rob: 2,
+            ..Default::default()
+        };
+        let run = run(&SLOW_DIV, &[], ControlPolicy::AlwaysNotTaken, sizes, 20);
+        run.result.unwrap();
+        assert_eq!(run.regs[6], 16);
+        assert!(run.stats.rob_full_cycles >= 20);
+        assert!(run.clock > 27);
+    }
+
+    #[test]
+    fn misprediction_flushes_the_wrong_path() {
+        let program = [
+            0x00100093, // addi x1, x0, 1
+            0x00009663, // bne x1, x0, 12
+            0x06300393, // addi x7, x0, 99
+            0x06200413, // addi x8, x0, 98
+            0x00500493, // addi x9, x0, 5
+            0x00100073, // ebreak
+        ];
+        let run = run(
+            &program,
+            &[],
+            ControlPolicy::AlwaysNotTaken,
+            OooConfig::default(),
+            1,
+        );
+        run.result.unwrap();
+        assert_eq!(run.regs[7], 0);
+        assert_eq!(run.regs[8], 0);
+        assert_eq!(run.regs[9], 5);
+        assert_eq!(run.stats.mispredictions, 1);
+        assert_eq!(run.stats.flushed, 2);
+        assert_eq!(run.retired, [0x1000, 0x1004, 0x1010, 0x1014]);
+    }
+
+    #[test]
+    fn forwards_stores_covering_the_loaded_bytes() {
+        let program = [
+            0x00001137, // lui x2, 1
+            0x40010113, // addi x2, x2, 1024
+            0xfff00093, // addi x1, x0, -1
+            0x00113023, // sd x1, 0(x2)
+            0x00412183, // lw x3, 4(x2)
+            0x00012423, // sw x0, 8(x2)
+            0x00c12203, // lw x4, 12(x2)
+            0x00110823, // sb x1, 16(x2)
+            0x01013283, // ld x5, 16(x2)
+            0x00100073, // ebreak
+        ];
+        let data = [0, 0x1234_5678_0000_0000, 0xabcd_0000];
+        let run = run(
+            &program,
+            &data,
+            ControlPolicy::AlwaysNotTaken,
+            OooConfig::default(),
+            1,
+        );
+        run.result.unwrap();
+        // inside the doubleword stored
+        assert_eq!(run.regs[3], u64::MAX);
+        // next to the word stored, in the same doubleword
+        assert_eq!(run.regs[4], 0x1234_5678);
+        // a byte stored in the doubleword loaded
+        assert_eq!(run.regs[5], 0xabcd_00ff);
+        assert_eq!(run.stats.store_forwards, 1);
+        assert_eq!(run.stats.load_replays, 0);
+    }
+
+    #[test]
+    fn replays_a_load_past_a_store_to_its_bytes() {
+        let program = [
+            0x06400093, // addi x1, x0, 100
+            0x00a00113, // addi x2, x0, 10
+            0x0220c1b3, // div x3, x1, x2
+            0x00001237, // lui x4, 1
+            0x3f620213, // addi x4, x4, 1014
+            0x00320333, // add x6, x4, x3
+            0x02a00293, // addi x5, x0, 42
+            0x00533023, // sd x5, 0(x6)
+            0x00001437, // lui x8, 1
+            0x40040413, // addi x8, x8, 1024
+            0x00043383, // ld x7, 0(x8)
+            0x00138493, // addi x9, x7, 1
+            0x00100073, // ebreak
+        ];
+        let run = run(
+            &program,
+            &[5],
+            ControlPolicy::AlwaysNotTaken,
+            OooConfig::default(),
+            20,
+        );
+        run.result.unwrap();
+        assert_eq!(run.regs[7], 42);
+        assert_eq!(run.regs[9], 43);
+        assert_eq!(run.stats.load_replays, 1);
+        assert_eq!(run.retired, pcs(13));
+    }
+
+    #[test]
+    fn faults_precisely_at_commit() {
+        let program = [
+            0x00500093, // addi x1, x0, 5
+            0x000101b7, // lui x3, 16
+            0x0001b103, // ld x2, 0(x3)
+            0x00700213, // addi x4, x0, 7
+            0x00100073, // ebreak
+        ];
+        let run = run(
+            &program,
+            &[],
+            ControlPolicy::AlwaysNotTaken,
+            OooConfig::default(),
+            1,
+        );
+        assert!(matches!(run.result, Err(Error::Execute(_))));
+        assert_eq!(run.regs[1], 5);
+        // executed, but younger than the load
+        assert_eq!(run.regs[4], 0);
+        assert_eq!(run.retired, pcs(2));
+    }
+
+    #[test]
+    fn csr_instructions_commit_in_order() {
+        let program = [
+            0x04d00093, // addi x1, x0, 77
+            0x34009073, // csrrw x0, mscratch, x1
+            0x34002173, // csrrs x2, mscratch, x0
+            0x00110193, // addi x3, x2, 1
+            0x00100073, // ebreak
+        ];
+        let run = run(
+            &program,
+            &[],
+            ControlPolicy::AlwaysNotTaken,
+            OooConfig::default(),
+            1,
+        );
+        run.result.unwrap();
+        assert_eq!(run.regs[2], 77);
+        assert_eq!(run.regs[3], 78);
+        // each refetches behind itself
+        assert_eq!(run.stats.redirects, 2);
+    }
+}
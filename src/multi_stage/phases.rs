@@ -1,6 +1,9 @@
-use crate::core::insts::Inst64;
+use crate::{
+    commit_log::{MemAccess, RetireInfo},
+    core::insts::Inst64,
+};
 
-use super::ctrl_flags::*;
+use super::{branch_predict::RasCheckpoint, ctrl_flags::*};
 
 #[derive(Debug, Clone, Copy)]
 pub struct InternalFetchDecode {
@@ -39,6 +42,10 @@ pub struct InternalDecodeExec {
     pub forward_b: u8,
     pub ex_mem_forward: u64,
     pub mem_wb_forward: u64,
+    // data forward per operand, from the registers between the MEM cycles or
+    // from either slot of the dual-issue CPU
+    pub mem_forward_a: u64,
+    pub mem_forward_b: u64,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -63,12 +70,6 @@ pub struct InternalExecMem {
     pub alu_op: Inst64,       // for branch hazard detection
 }
 
-impl InternalExecMem {
-    pub fn is_ret(&self) -> bool {
-        self.raw_inst == 0x00008067
-    }
-}
-
 #[allow(unused)]
 #[derive(Debug, Clone, Copy)]
 pub struct InternalMemWb {
@@ -76,7 +77,10 @@ pub struct InternalMemWb {
     pub wb_flags: WbFlags,
     pub branch_flags: BranchFlags,
     pub mem_read: bool, // for memory-to-memory hazard detection
-    pub pc: u64,        // current instruction PC
+    pub mem_write: bool,
+    pub mem_addr: u64,
+    pub mem_bitwidth: u8,
+    pub pc: u64, // current instruction PC
     pub rs1: u8,
     pub rs2: u8,
     #[allow(unused)]
@@ -87,6 +91,41 @@ pub struct InternalMemWb {
     pub alu_op: Inst64, // for ebreak
 }
 
+impl InternalMemWb {
+    /// Architectural effects of the instruction retiring in WB.
+    pub fn retire_info(&self) -> RetireInfo {
+        let reg_write = if self.wb_flags.mem_to_reg && self.rd != 0 {
+            Some((self.rd, self.regval))
+        } else {
+            None
+        };
+        let mem = if self.mem_read {
+            Some(MemAccess::Read {
+                addr: self.mem_addr,
+            })
+        } else if self.mem_write {
+            let size = self.mem_bitwidth / 8;
+            let value = match size {
+                8 => self.regval,
+                _ => self.regval & ((1u64 << self.mem_bitwidth) - 1),
+            };
+            Some(MemAccess::Write {
+                addr: self.mem_addr,
+                size,
+                value,
+            })
+        } else {
+            None
+        };
+        RetireInfo {
+            pc: self.pc,
+            raw_inst: self.raw_inst,
+            reg_write,
+            mem,
+        }
+    }
+}
+
 impl Default for InternalFetchDecode {
     fn default() -> Self {
         Self {
@@ -108,6 +147,11 @@ impl Default for InternalFetchDecode {
                 pc_src: false,
                 predicted_src: false,
                 predicted_target: 0,
+                predicted_taken: false,
+                predict_history: 0,
+                ras_predicted: false,
+                ras_checkpoint: RasCheckpoint::default(),
+                btb_miss: false,
             },
             pc: 0,
             rs1: 0,
@@ -137,6 +181,11 @@ impl Default for InternalDecodeExec {
                 pc_src: false,
                 predicted_src: false,
                 predicted_target: 0,
+                predicted_taken: false,
+                predict_history: 0,
+                ras_predicted: false,
+                ras_checkpoint: RasCheckpoint::default(),
+                btb_miss: false,
             },
             pc: 0,
             rs1: 0,
@@ -150,6 +199,8 @@ impl Default for InternalDecodeExec {
             forward_b: 0,
             ex_mem_forward: 0,
             mem_wb_forward: 0,
+            mem_forward_a: 0,
+            mem_forward_b: 0,
         }
     }
 }
@@ -168,6 +219,11 @@ impl Default for InternalExecMem {
                 pc_src: false,
                 predicted_src: false,
                 predicted_target: 0,
+                predicted_taken: false,
+                predict_history: 0,
+                ras_predicted: false,
+                ras_checkpoint: RasCheckpoint::default(),
+                btb_miss: false,
             },
             pc: 0,
             rs1: 0,
@@ -196,8 +252,16 @@ impl Default for InternalMemWb {
                 pc_src: false,
                 predicted_src: false,
                 predicted_target: 0,
+                predicted_taken: false,
+                predict_history: 0,
+                ras_predicted: false,
+                ras_checkpoint: RasCheckpoint::default(),
+                btb_miss: false,
             },
             mem_read: false,
+            mem_write: false,
+            mem_addr: 0,
+            mem_bitwidth: 0,
             pc: 0,
             rs1: 0,
             rs2: 0,
@@ -0,0 +1,215 @@
+//! Depth of the pipeline CPU.
+//!
+//! Fetch, execute and memory may be split into several cycles, each with a
+//! pipeline register behind it, so the pipeline has `depth()` stages:
+//!
+//! + IF fetches in its first cycle, the fetched instruction reaches ID after
+//!   `fetch` cycles and a redirect flushes every IF cycle;
+//! + EX computes in the cycle where branches resolve, a misprediction flushes
+//!   the younger instructions in the EX cycles before it, and a result can be
+//!   forwarded once it leaves the last EX cycle;
+//! + MEM accesses memory in its first cycle, loaded data can be forwarded once
+//!   it leaves the last MEM cycle, and ALU results from every MEM cycle.
+//!
+//! The hazard detection, the forwarding paths and the Kanata, diagram and VCD
+//! views follow the registers of every cycle.
+
+use std::{fmt, str::FromStr};
+
+use super::cpi_stack::CycleClass;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageLatency {
+    /// Cycles of each stage
+    pub fetch: u64,
+    pub exec: u64,
+    pub mem: u64,
+    /// Execute cycle where branches resolve, `1..=exec`
+    pub branch: u64,
+}
+
+impl Default for StageLatency {
+    fn default() -> Self {
+        Self {
+            fetch: 1,
+            exec: 1,
+            mem: 1,
+            branch: 1,
+        }
+    }
+}
+
+impl StageLatency {
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, cycles) in [
+            ("fetch", self.fetch),
+            ("exec", self.exec),
+            ("mem", self.mem),
+        ] {
+            if cycles == 0 {
+                return Err(format!("{name} needs at least 1 cycle"));
+            }
+        }
+        if !(1..=self.exec).contains(&self.branch) {
+            return Err(format!(
+                "branch cycle {} not in 1..={}",
+                self.branch, self.exec
+            ));
+        }
+        Ok(())
+    }
+
+    /// Cycles from fetch to write back.
+    pub fn depth(&self) -> u64 {
+        self.fetch + 1 + self.exec + self.mem + 1
+    }
+
+    /// Names of the stages, a split stage numbers its cycles.
+    pub(super) fn stage_names(&self) -> Vec<String> {
+        let split = |name: &str, cycles: u64| -> Vec<String> {
+            match cycles {
+                1 => vec![name.to_string()],
+                _ => (1..=cycles).map(|i| format!("{name}{i}")).collect(),
+            }
+        };
+        let mut names = split("IF", self.fetch);
+        names.push("ID".to_string());
+        names.extend(split("EX", self.exec));
+        names.extend(split("MEM", self.mem));
+        names.push("WB".to_string());
+        names
+    }
+}
+
+/// Pipeline registers between the cycles of a split stage, the youngest
+/// first, each with why it holds a bubble if it does.
+#[derive(Debug, Clone)]
+pub(super) struct SubStages<T> {
+    latches: Vec<T>,
+    causes: Vec<CycleClass>,
+}
+
+impl<T: Copy + Default> SubStages<T> {
+    /// Registers of a stage of `cycles` cycles.
+    pub(super) fn new(cycles: u64) -> SubStages<T> {
+        let len = cycles as usize - 1;
+        SubStages {
+            latches: vec![T::default(); len],
+            causes: vec![CycleClass::Base; len],
+        }
+    }
+
+    /// Move every instruction one cycle on, `input` enters the first cycle.
+    /// Returns the instruction leaving the last cycle.
+    pub(super) fn shift(&mut self, input: T, cause: CycleClass) -> (T, CycleClass) {
+        match (self.latches.pop(), self.causes.pop()) {
+            (Some(latch), Some(last_cause)) => {
+                self.latches.insert(0, input);
+                self.causes.insert(0, cause);
+                (latch, last_cause)
+            }
+            _ => (input, cause),
+        }
+    }
+
+    /// The instruction leaving the first cycle in the last shift.
+    pub(super) fn first(&self) -> Option<&T> {
+        self.latches.first()
+    }
+
+    /// Replace every instruction with a bubble of `cause`.
+    pub(super) fn flush(&mut self, cause: CycleClass) {
+        self.latches.fill(T::default());
+        self.causes.fill(cause);
+    }
+
+    pub(super) fn latches(&self) -> &[T] {
+        &self.latches
+    }
+
+    pub(super) fn causes(&self) -> &[CycleClass] {
+        &self.causes
+    }
+}
+
+/// `key=value` pairs separated by commas, omitted keys take their default
+/// value (`fetch=1,exec=1,mem=1,branch=1`), `branch` defaults to the last
+/// execute cycle.
+impl FromStr for StageLatency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = StageLatency::default();
+        let mut branch = None;
+        for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .ok_or_else(|| format!("expect key=value, got `{item}`"))?;
+            let invalid = |e: &dyn std::fmt::Display| format!("invalid {key} `{value}`: {e}");
+            match key {
+                "fetch" => config.fetch = value.parse().map_err(|e| invalid(&e))?,
+                "exec" => config.exec = value.parse().map_err(|e| invalid(&e))?,
+                "mem" => config.mem = value.parse().map_err(|e| invalid(&e))?,
+                "branch" => branch = Some(value.parse().map_err(|e| invalid(&e))?),
+                _ => return Err(format!("unknown stage latency parameter `{key}`")),
+            }
+        }
+        config.branch = branch.unwrap_or(config.exec);
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl fmt::Display for StageLatency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} stages ({}), branches resolve in exec cycle {}",
+            self.depth(),
+            self.stage_names().join(" "),
+            self.branch
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_stage_latency() {
+        let config = StageLatency::default();
+        assert_eq!(config.depth(), 5);
+        assert_eq!(config.stage_names(), ["IF", "ID", "EX", "MEM", "WB"]);
+
+        let config: StageLatency = "fetch=2,exec=3,mem=2".parse().unwrap();
+        assert_eq!(config.branch, 3);
+        assert_eq!(config.depth(), 9);
+        assert_eq!(
+            config.stage_names(),
+            ["IF1", "IF2", "ID", "EX1", "EX2", "EX3", "MEM1", "MEM2", "WB"]
+        );
+
+        let config: StageLatency = "exec=3,branch=1".parse().unwrap();
+        assert_eq!(config.branch, 1);
+
+        assert!("exec=2,branch=3".parse::<StageLatency>().is_err());
+        assert!("fetch=0".parse::<StageLatency>().is_err());
+        assert!("wb=2".parse::<StageLatency>().is_err());
+    }
+
+    #[test]
+    fn sub_stages() {
+        let mut stages = SubStages::<u64>::new(3);
+        assert_eq!(stages.shift(1, CycleClass::Base), (0, CycleClass::Base));
+        assert_eq!(stages.shift(2, CycleClass::Base), (0, CycleClass::Base));
+        assert_eq!(stages.latches(), [2, 1]);
+        assert_eq!(stages.shift(3, CycleClass::Base), (1, CycleClass::Base));
+        stages.flush(CycleClass::Control);
+        assert_eq!(stages.shift(4, CycleClass::Base), (0, CycleClass::Control));
+
+        let mut stages = SubStages::<u64>::new(1);
+        assert_eq!(stages.shift(5, CycleClass::Data), (5, CycleClass::Data));
+        assert!(stages.latches().is_empty());
+    }
+}
@@ -0,0 +1,411 @@
+//! Value Change Dump of the pipeline registers of the pipeline CPU.
+//!
+//! One timestep is one clock of the CPU: the values at `#t` are the contents
+//! of the pipeline registers during the clock starting at cycle `t`, together
+//! with the control signals decided in it. A clock stretched by memory or
+//! iterative units keeps its values until the next timestep.
+//!
+//! Every field of the pipeline registers is a signal in the scope of its
+//! register, `alu_op` holds the mnemonic as ASCII to be shown with the ASCII
+//! data format of GTKWave. The RAS checkpoint carried along for recovery is
+//! not dumped. The registers between the cycles of a split stage are named
+//! after the cycles, e.g. `ex1_ex2`.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use log::error;
+
+use super::{
+    cpu::PipelineState,
+    ctrl_flags::BranchFlags,
+    occupancy::LatchStates,
+    phases::{InternalDecodeExec, InternalExecMem, InternalFetchDecode, InternalMemWb},
+};
+use crate::core::insts::Inst64;
+
+struct Signal {
+    name: &'static str,
+    width: u32,
+    value: u64,
+}
+
+struct Scope {
+    name: String,
+    signals: Vec<Signal>,
+}
+
+impl Scope {
+    fn new(name: impl Into<String>) -> Scope {
+        Scope {
+            name: name.into(),
+            signals: Vec::new(),
+        }
+    }
+
+    fn value(mut self, name: &'static str, width: u32, value: u64) -> Scope {
+        self.signals.push(Signal { name, width, value });
+        self
+    }
+
+    fn bit(self, name: &'static str, value: bool) -> Scope {
+        self.value(name, 1, value as u64)
+    }
+
+    fn reg(self, name: &'static str, reg: u8) -> Scope {
+        self.value(name, 5, reg as u64)
+    }
+
+    fn op(self, name: &'static str, op: Inst64) -> Scope {
+        let ascii = format!("{op:?}")
+            .bytes()
+            .take(8)
+            .fold(0, |v, c| v << 8 | c as u64);
+        self.value(name, 64, ascii)
+    }
+
+    fn branch(self, flags: &BranchFlags) -> Scope {
+        self.bit("branch", flags.branch)
+            .bit("pc_src", flags.pc_src)
+            .bit("predicted_src", flags.predicted_src)
+            .value("predicted_target", 64, flags.predicted_target)
+            .bit("predicted_taken", flags.predicted_taken)
+            .value("predict_history", 64, flags.predict_history)
+            .bit("ras_predicted", flags.ras_predicted)
+            .bit("btb_miss", flags.btb_miss)
+    }
+}
+
+/// Signals of the pipeline control besides the pipeline registers.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Controls {
+    pub(super) pc: u64,
+    pub(super) states: LatchStates,
+    pub(super) load_use: bool,
+    /// A source is not produced yet by a split EX stage
+    pub(super) exec_use: bool,
+    /// A source is not produced yet by a slow functional unit
+    pub(super) fu_use: bool,
+    pub(super) mispredict: bool,
+    /// Cycles the clock lasts beyond the first, for iterative units and
+    /// memory accesses
+    pub(super) extra_cycles: u64,
+}
+
+/// Pipeline registers during a clock, the ones between the cycles of a split
+/// stage youngest first.
+pub(super) struct Registers<'a> {
+    pub(super) inner_fetch: &'a [InternalFetchDecode],
+    pub(super) f_d: &'a InternalFetchDecode,
+    pub(super) d_e: &'a InternalDecodeExec,
+    /// Behind the EX cycles before branch resolution
+    pub(super) exec_before: &'a [InternalDecodeExec],
+    /// Behind the EX cycles from branch resolution on
+    pub(super) exec_after: &'a [InternalExecMem],
+    pub(super) e_m: &'a InternalExecMem,
+    pub(super) inner_mem: &'a [InternalMemWb],
+    pub(super) m_w: &'a InternalMemWb,
+}
+
+pub struct VcdDump {
+    out: Option<Box<dyn Write>>,
+    // value of every signal at the last timestep, in declaration order
+    last: Vec<u64>,
+    time: u64,
+}
+
+impl VcdDump {
+    pub fn new(out: Box<dyn Write>) -> VcdDump {
+        VcdDump {
+            out: Some(out),
+            last: Vec::new(),
+            time: 0,
+        }
+    }
+
+    pub fn create(path: &str) -> io::Result<VcdDump> {
+        Ok(VcdDump::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    /// Dump the pipeline registers during the clock starting at `cycle`.
+    pub(super) fn clock(&mut self, cycle: u64, registers: &Registers, controls: &Controls) {
+        let split = |name: &str, first: usize, i: usize| {
+            format!("{name}{}_{name}{}", first + i, first + i + 1)
+        };
+        let exec_after = registers.exec_before.len() + 1;
+        let mut scopes = vec![control_scope(controls, registers)];
+        for (i, f) in registers.inner_fetch.iter().enumerate() {
+            scopes.push(fetch_decode_scope(split("if", 1, i), f));
+        }
+        scopes.push(fetch_decode_scope("if_id", registers.f_d));
+        scopes.push(decode_exec_scope("id_ex", registers.d_e));
+        for (i, d) in registers.exec_before.iter().enumerate() {
+            scopes.push(decode_exec_scope(split("ex", 1, i), d));
+        }
+        for (i, e) in registers.exec_after.iter().enumerate() {
+            scopes.push(exec_mem_scope(split("ex", exec_after, i), e));
+        }
+        scopes.push(exec_mem_scope("ex_mem", registers.e_m));
+        for (i, m) in registers.inner_mem.iter().enumerate() {
+            scopes.push(mem_wb_scope(split("mem", 1, i), m));
+        }
+        scopes.push(mem_wb_scope("mem_wb", registers.m_w));
+        if let Err(e) = self.dump(cycle, &scopes) {
+            error!("Fail to write VCD: {e}, VCD disabled");
+            self.out = None;
+        }
+    }
+
+    fn dump(&mut self, cycle: u64, scopes: &[Scope]) -> io::Result<()> {
+        let Some(out) = self.out.as_mut() else {
+            return Ok(());
+        };
+        let signals = scopes.iter().flat_map(|s| &s.signals);
+        let first = self.last.is_empty();
+        if first {
+            write_header(out, scopes)?;
+        }
+        writeln!(out, "#{cycle}")?;
+        if first {
+            writeln!(out, "$dumpvars")?;
+        }
+        for (index, signal) in signals.enumerate() {
+            if first {
+                self.last.push(signal.value);
+            } else if self.last[index] == signal.value {
+                continue;
+            }
+            self.last[index] = signal.value;
+            write_value(out, signal, &identifier(index))?;
+        }
+        if first {
+            writeln!(out, "$end")?;
+        }
+        self.time = cycle;
+        Ok(())
+    }
+}
+
+fn control_scope(controls: &Controls, registers: &Registers) -> Scope {
+    let stall = |state| state == PipelineState::Stall;
+    let flush = |state| state == PipelineState::Bubble;
+    let states = &controls.states;
+    let mut scope = Scope::new("control")
+        .value("pc", 64, controls.pc)
+        .bit("pc_stall", stall(states.pc_next));
+    if !registers.inner_fetch.is_empty() {
+        scope = scope
+            .bit("if_stall", stall(states.fetch))
+            .bit("if_flush", flush(states.fetch));
+    }
+    scope = scope
+        .bit("if_id_stall", stall(states.f_d))
+        .bit("if_id_flush", flush(states.f_d))
+        .bit("id_ex_stall", stall(states.d_e))
+        .bit("id_ex_flush", flush(states.d_e));
+    if !registers.exec_before.is_empty() {
+        scope = scope.bit("ex_flush", flush(states.inner_exec));
+    }
+    scope
+        .bit("ex_mem_stall", stall(states.e_m))
+        .bit("ex_mem_flush", flush(states.e_m))
+        .bit("mem_wb_stall", stall(states.m_w))
+        .bit("mem_wb_flush", flush(states.m_w))
+        .bit("load_use", controls.load_use)
+        .bit("exec_use", controls.exec_use)
+        .bit("fu_use", controls.fu_use)
+        .bit("mispredict", controls.mispredict)
+        .value("extra_cycles", 32, controls.extra_cycles)
+}
+
+fn fetch_decode_scope(name: impl Into<String>, f_d: &InternalFetchDecode) -> Scope {
+    Scope::new(name)
+        .value("raw_inst", 32, f_d.raw_inst as u64)
+        .value("pc", 64, f_d.pc)
+        .value("sext", 3, f_d.decode_flags.sext as u64)
+        .op("alu_op", f_d.exec_flags.alu_op)
+        .bit("alu_src", f_d.exec_flags.alu_src)
+        .bit("mem_read", f_d.mem_flags.mem_read)
+        .bit("mem_write", f_d.mem_flags.mem_write)
+        .bit("mem_to_reg", f_d.wb_flags.mem_to_reg)
+        .branch(&f_d.branch_flags)
+        .reg("rs1", f_d.rs1)
+        .reg("rs2", f_d.rs2)
+        .reg("rs3", f_d.rs3)
+        .reg("rd", f_d.rd)
+        .value("imm", 64, f_d.imm)
+}
+
+fn decode_exec_scope(name: impl Into<String>, d_e: &InternalDecodeExec) -> Scope {
+    Scope::new(name)
+        .value("raw_inst", 32, d_e.raw_inst as u64)
+        .value("pc", 64, d_e.pc)
+        .op("alu_op", d_e.exec_flags.alu_op)
+        .bit("alu_src", d_e.exec_flags.alu_src)
+        .bit("mem_read", d_e.mem_flags.mem_read)
+        .bit("mem_write", d_e.mem_flags.mem_write)
+        .bit("mem_to_reg", d_e.wb_flags.mem_to_reg)
+        .branch(&d_e.branch_flags)
+        .reg("rs1", d_e.rs1)
+        .reg("rs2", d_e.rs2)
+        .reg("rs3", d_e.rs3)
+        .reg("rd", d_e.rd)
+        .value("src1", 64, d_e.src1)
+        .value("src2", 64, d_e.src2)
+        .value("imm", 64, d_e.imm)
+        .value("forward_a", 2, d_e.forward_a as u64)
+        .value("forward_b", 2, d_e.forward_b as u64)
+        .value("ex_mem_forward", 64, d_e.ex_mem_forward)
+        .value("mem_wb_forward", 64, d_e.mem_wb_forward)
+        .value("mem_forward_a", 64, d_e.mem_forward_a)
+        .value("mem_forward_b", 64, d_e.mem_forward_b)
+}
+
+fn exec_mem_scope(name: impl Into<String>, e_m: &InternalExecMem) -> Scope {
+    Scope::new(name)
+        .value("raw_inst", 32, e_m.raw_inst as u64)
+        .value("pc", 64, e_m.pc)
+        .op("alu_op", e_m.alu_op)
+        .bit("mem_read", e_m.mem_flags.mem_read)
+        .bit("mem_write", e_m.mem_flags.mem_write)
+        .bit("mem_to_reg", e_m.wb_flags.mem_to_reg)
+        .branch(&e_m.branch_flags)
+        .reg("rs1", e_m.rs1)
+        .reg("rs2", e_m.rs2)
+        .reg("rs3", e_m.rs3)
+        .reg("rd", e_m.rd)
+        .value("imm", 64, e_m.imm)
+        .value("alu_out", 64, e_m.alu_out)
+        .value("mem_addr", 64, e_m.mem_addr)
+        .value("mem_bitwidth", 8, e_m.mem_bitwidth as u64)
+        .value("mem_sext_to", 8, e_m.mem_sext_to as u64)
+        .bit("m2m_forward", e_m.m2m_forward)
+        .value("m2m_forward_val", 64, e_m.m2m_forward_val)
+}
+
+fn mem_wb_scope(name: impl Into<String>, m_w: &InternalMemWb) -> Scope {
+    Scope::new(name)
+        .value("raw_inst", 32, m_w.raw_inst as u64)
+        .value("pc", 64, m_w.pc)
+        .op("alu_op", m_w.alu_op)
+        .bit("mem_to_reg", m_w.wb_flags.mem_to_reg)
+        .branch(&m_w.branch_flags)
+        .bit("mem_read", m_w.mem_read)
+        .bit("mem_write", m_w.mem_write)
+        .value("mem_addr", 64, m_w.mem_addr)
+        .value("mem_bitwidth", 8, m_w.mem_bitwidth as u64)
+        .reg("rs1", m_w.rs1)
+        .reg("rs2", m_w.rs2)
+        .reg("rs3", m_w.rs3)
+        .reg("rd", m_w.rd)
+        .value("imm", 64, m_w.imm)
+        .value("regval", 64, m_w.regval)
+}
+
+fn write_header(out: &mut dyn Write, scopes: &[Scope]) -> io::Result<()> {
+    writeln!(out, "$version riscv-emulator pipeline CPU $end")?;
+    writeln!(out, "$timescale 1ns $end")?;
+    writeln!(out, "$scope module pipeline $end")?;
+    let mut index = 0;
+    for scope in scopes {
+        writeln!(out, "$scope module {} $end", scope.name)?;
+        for signal in &scope.signals {
+            writeln!(
+                out,
+                "$var wire {} {} {} $end",
+                signal.width,
+                identifier(index),
+                signal.name
+            )?;
+            index += 1;
+        }
+        writeln!(out, "$upscope $end")?;
+    }
+    writeln!(out, "$upscope $end")?;
+    writeln!(out, "$enddefinitions $end")
+}
+
+fn write_value(out: &mut dyn Write, signal: &Signal, id: &str) -> io::Result<()> {
+    if signal.width == 1 {
+        writeln!(out, "{}{id}", signal.value)
+    } else {
+        writeln!(out, "b{:b} {id}", signal.value)
+    }
+}
+
+/// Short identifier of the `index`th signal, from printable ASCII.
+fn identifier(mut index: usize) -> String {
+    let mut id = String::new();
+    loop {
+        id.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return id;
+        }
+        index -= 1;
+    }
+}
+
+impl Drop for VcdDump {
+    fn drop(&mut self) {
+        let time = self.time + 1;
+        if let Some(out) = self.out.as_mut() {
+            if let Err(e) = writeln!(out, "#{time}").and_then(|_| out.flush()) {
+                error!("Fail to write VCD: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn value_changes() {
+        assert_eq!(identifier(0), "!");
+        assert_eq!(identifier(93), "~");
+        assert_eq!(identifier(94), "!!");
+        assert_eq!(identifier(95), "\"!");
+
+        let buffer = Buffer::default();
+        let mut vcd = VcdDump::new(Box::new(buffer.clone()));
+        let scopes = |pc, stall| {
+            [Scope::new("control")
+                .value("pc", 64, pc)
+                .bit("pc_stall", stall)
+                .op("alu_op", Inst64::addi)]
+        };
+        vcd.dump(1, &scopes(0x1000, false)).unwrap();
+        vcd.dump(2, &scopes(0x1004, false)).unwrap();
+        vcd.dump(5, &scopes(0x1004, true)).unwrap();
+        drop(vcd);
+
+        let output = String::from_utf8(buffer.0.take()).unwrap();
+        let body = output.split("$enddefinitions $end\n").nth(1).unwrap();
+        assert!(output.contains("$scope module control $end\n$var wire 64 ! pc $end\n"));
+        assert_eq!(
+            body,
+            "#1\n$dumpvars\nb1000000000000 !\n0\"\nb1100001011001000110010001101001 #\n$end\n\
+             #2\nb1000000000100 !\n#5\n1\"\n#6\n"
+        );
+    }
+}
@@ -0,0 +1,511 @@
+//! Instruction-level profiler.
+//!
+//! CPU models report every retired instruction together with the cycles it
+//! is charged for. The profiler keeps per-PC counters, splits the executed
+//! code into basic blocks after the run, and writes the result either as an
+//! annotated disassembly or in callgrind format.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use log::{error, info};
+
+use crate::{
+    core::insts::{inst_64_opcode, opcode},
+    elf::LoadElfInfo,
+    error::Result,
+    multi_stage::debug::disasm,
+};
+
+/// Counters of a single static instruction.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InstProfile {
+    pub raw_inst: u32,
+    pub count: u64,
+    pub cycles: u64,
+}
+
+impl InstProfile {
+    /// Cycles spent beyond one cycle per execution.
+    pub fn stall(&self) -> u64 {
+        self.cycles.saturating_sub(self.count)
+    }
+}
+
+/// A straight-line sequence of executed instructions.
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub start: u64,
+    pub end: u64, // pc of the last instruction in the block
+    pub count: u64,
+    pub insts: u64,
+    pub cycles: u64,
+    pub stall: u64,
+}
+
+/// Inclusive cost of a call edge.
+#[derive(Debug, Clone, Copy, Default)]
+struct CallCost {
+    calls: u64,
+    insts: u64,
+    cycles: u64,
+}
+
+/// Call frame on the profiler's shadow stack.
+struct CallFrame {
+    site: u64,
+    callee: u64,
+    start_insts: u64,
+    start_cycles: u64,
+}
+
+/// Function symbols sorted by address.
+pub struct FunctionSymbols {
+    // (address, name)
+    functions: Vec<(u64, String)>,
+}
+
+impl FunctionSymbols {
+    pub fn new(symbol_map: &HashMap<u64, String>) -> FunctionSymbols {
+        let mut functions: Vec<(u64, String)> = symbol_map
+            .iter()
+            .filter(|(_, name)| is_function_name(name))
+            .map(|(addr, name)| (*addr, name.clone()))
+            .collect();
+        functions.sort();
+        FunctionSymbols { functions }
+    }
+
+    /// Name and entry address of the function containing `pc`.
+    pub fn function_of(&self, pc: u64) -> Option<(u64, &str)> {
+        let idx = self.functions.partition_point(|(addr, _)| *addr <= pc);
+        if idx == 0 {
+            None
+        } else {
+            let (addr, name) = &self.functions[idx - 1];
+            Some((*addr, name.as_str()))
+        }
+    }
+
+    /// `function+offset`, or the bare address outside any function.
+    pub fn location(&self, pc: u64) -> String {
+        match self.function_of(pc) {
+            Some((addr, name)) if addr == pc => name.to_string(),
+            Some((addr, name)) => format!("{name}+{:#x}", pc - addr),
+            None => format!("{pc:#x}"),
+        }
+    }
+}
+
+pub struct Profiler {
+    insts: BTreeMap<u64, InstProfile>,
+
+    // pcs reached by a taken control transfer
+    leaders: HashSet<u64>,
+
+    last_pc: Option<u64>,
+
+    functions: FunctionSymbols,
+
+    // shadow call stack for the call graph
+    frames: Vec<CallFrame>,
+
+    // (call site, callee entry) -> inclusive cost
+    calls: HashMap<(u64, u64), CallCost>,
+
+    // call site whose callee entry is the next retired pc
+    pending_call: Option<u64>,
+
+    total_insts: u64,
+    total_cycles: u64,
+}
+
+impl Profiler {
+    pub fn new(symbol_map: &HashMap<u64, String>) -> Profiler {
+        let functions = FunctionSymbols::new(symbol_map);
+
+        Profiler {
+            insts: BTreeMap::new(),
+            leaders: HashSet::new(),
+            last_pc: None,
+            functions,
+            frames: Vec::new(),
+            calls: HashMap::new(),
+            pending_call: None,
+            total_insts: 0,
+            total_cycles: 0,
+        }
+    }
+
+    pub fn from_elf_info(info: &LoadElfInfo) -> Profiler {
+        Profiler::new(info.symbol_map())
+    }
+
+    /// Called by CPU when an instruction retires.
+    /// `cycles` is the number of cycles charged to this execution.
+    pub fn retire(&mut self, pc: u64, raw_inst: u32, cycles: u64) {
+        if let Some(last_pc) = self.last_pc {
+            if pc != last_pc.wrapping_add(4) {
+                self.leaders.insert(pc);
+            }
+        }
+        self.last_pc = Some(pc);
+
+        if let Some(site) = self.pending_call.take() {
+            self.frames.push(CallFrame {
+                site,
+                callee: pc,
+                start_insts: self.total_insts,
+                start_cycles: self.total_cycles,
+            });
+        }
+
+        let entry = self.insts.entry(pc).or_default();
+        entry.raw_inst = raw_inst;
+        entry.count += 1;
+        entry.cycles += cycles;
+        self.total_insts += 1;
+        self.total_cycles += cycles;
+
+        if is_call(raw_inst) {
+            self.pending_call = Some(pc);
+        } else if is_ret(raw_inst) {
+            if let Some(frame) = self.frames.pop() {
+                let cost = self.calls.entry((frame.site, frame.callee)).or_default();
+                cost.calls += 1;
+                cost.insts += self.total_insts - frame.start_insts;
+                cost.cycles += self.total_cycles - frame.start_cycles;
+            }
+        }
+    }
+
+    /// Charge extra cycles to an instruction, e.g. a multi-cycle functional
+    /// unit that is modelled by bumping the clock.
+    pub fn add_cycles(&mut self, pc: u64, raw_inst: u32, cycles: u64) {
+        let entry = self.insts.entry(pc).or_default();
+        entry.raw_inst = raw_inst;
+        entry.cycles += cycles;
+        self.total_cycles += cycles;
+    }
+
+    /// Name and entry address of the function containing `pc`.
+    pub fn function_of(&self, pc: u64) -> Option<(u64, &str)> {
+        self.functions.function_of(pc)
+    }
+
+    fn function_name(&self, pc: u64) -> String {
+        self.function_of(pc)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| format!("{pc:#x}"))
+    }
+
+    /// Split executed instructions into basic blocks, in address order.
+    pub fn basic_blocks(&self) -> Vec<BasicBlock> {
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        let mut prev: Option<(u64, u32)> = None;
+
+        for (&pc, inst) in self.insts.iter() {
+            let new_block = match prev {
+                None => true,
+                Some((prev_pc, prev_raw)) => {
+                    prev_pc.wrapping_add(4) != pc
+                        || is_control(prev_raw)
+                        || self.leaders.contains(&pc)
+                        || self.function_of(pc).is_some_and(|(addr, _)| addr == pc)
+                }
+            };
+            if new_block {
+                blocks.push(BasicBlock {
+                    start: pc,
+                    end: pc,
+                    count: inst.count,
+                    insts: 0,
+                    cycles: 0,
+                    stall: 0,
+                });
+            }
+            let block = blocks.last_mut().unwrap();
+            block.end = pc;
+            block.insts += 1;
+            block.cycles += inst.cycles;
+            block.stall += inst.stall();
+            prev = Some((pc, inst.raw_inst));
+        }
+        blocks
+    }
+
+    /// Write the profile as a disassembly annotated with counts and cycles.
+    pub fn write_annotated(&self, path: &Path) -> Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        let blocks = self.basic_blocks();
+
+        writeln!(
+            w,
+            "Total: {} instructions, {} cycles, {} stall cycles",
+            self.total_insts,
+            self.total_cycles,
+            self.total_cycles.saturating_sub(self.total_insts)
+        )?;
+        writeln!(w)?;
+
+        let mut hottest: Vec<&BasicBlock> = blocks.iter().collect();
+        hottest.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.start.cmp(&b.start)));
+        writeln!(w, "Hottest basic blocks:")?;
+        writeln!(
+            w,
+            "{:>18} {:>18} {:>10} {:>12} {:>12}  function",
+            "start", "end", "count", "cycles", "stall"
+        )?;
+        for block in hottest.iter().take(HOTTEST_BLOCKS) {
+            writeln!(
+                w,
+                "{:>#18x} {:>#18x} {:>10} {:>12} {:>12}  {}",
+                block.start,
+                block.end,
+                block.count,
+                block.cycles,
+                block.stall,
+                self.function_name(block.start)
+            )?;
+        }
+
+        let mut current_function = None;
+        for block in blocks.iter() {
+            let function = self.function_of(block.start).map(|(addr, _)| addr);
+            if current_function != Some(function) {
+                current_function = Some(function);
+                writeln!(w)?;
+                writeln!(w, "<{}>:", self.function_name(block.start))?;
+                writeln!(w, "{:>10} {:>12} {:>12}", "count", "cycles", "stall")?;
+            }
+            writeln!(
+                w,
+                "{:->36} block {:#x} count {} cycles {} stall {}",
+                " ", block.start, block.count, block.cycles, block.stall
+            )?;
+            for (&pc, inst) in self.insts.range(block.start..=block.end) {
+                writeln!(
+                    w,
+                    "{:>10} {:>12} {:>12}  {}",
+                    inst.count,
+                    inst.cycles,
+                    inst.stall(),
+                    disasm(pc, inst.raw_inst)
+                )?;
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Write the profile in callgrind format, to be opened by
+    /// `callgrind_annotate` or KCachegrind.
+    pub fn write_callgrind(&self, path: &Path, object: &str) -> Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+
+        writeln!(w, "# callgrind format")?;
+        writeln!(w, "version: 1")?;
+        writeln!(w, "creator: riscv-emulator")?;
+        writeln!(w, "cmd: {object}")?;
+        writeln!(w, "positions: instr")?;
+        writeln!(w, "events: Ir Cycles Stall")?;
+        writeln!(
+            w,
+            "summary: {} {} {}",
+            self.total_insts,
+            self.total_cycles,
+            self.total_cycles.saturating_sub(self.total_insts)
+        )?;
+        writeln!(w)?;
+        writeln!(w, "ob={object}")?;
+        writeln!(w, "fl=???")?;
+
+        // call edges grouped by call site
+        let mut calls_by_site: BTreeMap<u64, Vec<(u64, CallCost)>> = BTreeMap::new();
+        for (&(site, callee), &cost) in self.calls.iter() {
+            calls_by_site.entry(site).or_default().push((callee, cost));
+        }
+
+        let mut current_function = None;
+        for (&pc, inst) in self.insts.iter() {
+            let function = self.function_of(pc).map(|(addr, _)| addr);
+            if current_function != Some(function) {
+                current_function = Some(function);
+                writeln!(w)?;
+                writeln!(w, "fn={}", self.function_name(pc))?;
+            }
+            writeln!(
+                w,
+                "{:#x} {} {} {}",
+                pc,
+                inst.count,
+                inst.cycles,
+                inst.stall()
+            )?;
+            if let Some(callees) = calls_by_site.get_mut(&pc) {
+                callees.sort_by_key(|(callee, _)| *callee);
+                for (callee, cost) in callees.iter() {
+                    writeln!(w, "cfn={}", self.function_name(*callee))?;
+                    writeln!(w, "calls={} {:#x}", cost.calls, callee)?;
+                    writeln!(
+                        w,
+                        "{:#x} {} {} {}",
+                        pc,
+                        cost.insts,
+                        cost.cycles,
+                        cost.cycles.saturating_sub(cost.insts)
+                    )?;
+                }
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+const HOTTEST_BLOCKS: usize = 20;
+
+/// Skip section symbols, local labels and mapping symbols.
+fn is_function_name(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with('.') && !name.starts_with('$')
+}
+
+fn is_control(raw_inst: u32) -> bool {
+    use inst_64_opcode::*;
+    matches!(opcode(raw_inst), BRANCH | JAL | JALR)
+}
+
+/// `jal ra, offset` or `jalr ra, offset(rs1)`
+fn is_call(raw_inst: u32) -> bool {
+    use crate::core::insts::rd;
+    use inst_64_opcode::*;
+    matches!(opcode(raw_inst), JAL | JALR) && rd(raw_inst) == 1
+}
+
+/// `jalr zero, 0(ra)`
+fn is_ret(raw_inst: u32) -> bool {
+    raw_inst == 0x00008067
+}
+
+/// A profiler with the files it is written to when dropped, so that a run
+/// failing with a panic or an error still leaves its profile.
+pub struct ProfileFiles {
+    pub profiler: Profiler,
+    annotated: Option<String>,
+    callgrind: Option<String>,
+    // the program, named in the callgrind file
+    object: String,
+}
+
+impl ProfileFiles {
+    pub fn new(
+        profiler: Profiler,
+        annotated: Option<String>,
+        callgrind: Option<String>,
+        object: &str,
+    ) -> ProfileFiles {
+        ProfileFiles {
+            profiler,
+            annotated,
+            callgrind,
+            object: object.to_string(),
+        }
+    }
+}
+
+impl Drop for ProfileFiles {
+    fn drop(&mut self) {
+        if let Some(path) = &self.annotated {
+            match self.profiler.write_annotated(Path::new(path)) {
+                Ok(()) => info!("Profile written to {path}"),
+                Err(e) => error!("Fail to write profile to {path}: {e}"),
+            }
+        }
+        if let Some(path) = &self.callgrind {
+            match self.profiler.write_callgrind(Path::new(path), &self.object) {
+                Ok(()) => info!("Callgrind profile written to {path}"),
+                Err(e) => error!("Fail to write callgrind profile to {path}: {e}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDI: u32 = 0x00000013; // addi zero,zero,0
+    const BLT: u32 = 0xfe72cce3; // blt t0,t2,-8
+    const JAL_RA: u32 = 0x008000ef; // jal ra,8
+    const RET: u32 = 0x00008067;
+
+    fn profiler() -> Profiler {
+        let mut symbols = HashMap::new();
+        symbols.insert(0x1000, "main".to_string());
+        symbols.insert(0x1100, "func".to_string());
+        symbols.insert(0x1104, ".Llocal".to_string());
+        Profiler::new(&symbols)
+    }
+
+    #[test]
+    fn loop_blocks() {
+        let mut p = profiler();
+        // 0x1000: addi; loop body 0x1004..0x1008 executed 3 times; exit 0x100c
+        p.retire(0x1000, ADDI, 5);
+        for i in 0..3 {
+            p.retire(0x1004, ADDI, 1);
+            p.retire(0x1008, BLT, if i == 2 { 1 } else { 3 });
+        }
+        p.retire(0x100c, ADDI, 1);
+
+        let blocks = p.basic_blocks();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(
+            (blocks[0].start, blocks[0].end, blocks[0].count),
+            (0x1000, 0x1000, 1)
+        );
+        assert_eq!(
+            (blocks[1].start, blocks[1].end, blocks[1].count),
+            (0x1004, 0x1008, 3)
+        );
+        assert_eq!(blocks[1].cycles, 10);
+        assert_eq!(blocks[1].stall, 4);
+        assert_eq!((blocks[2].start, blocks[2].count), (0x100c, 1));
+        assert_eq!(p.total_insts, 8);
+        assert_eq!(p.total_cycles, 16);
+    }
+
+    #[test]
+    fn call_edges() {
+        let mut p = profiler();
+        p.retire(0x10f8, JAL_RA, 1);
+        p.retire(0x1100, ADDI, 2);
+        p.retire(0x1104, RET, 3);
+        p.retire(0x10fc, ADDI, 1);
+
+        assert_eq!(p.function_of(0x1104), Some((0x1100, "func")));
+        let cost = p.calls[&(0x10f8, 0x1100)];
+        assert_eq!((cost.calls, cost.insts, cost.cycles), (1, 2, 5));
+        assert!(p.leaders.contains(&0x1100));
+        assert!(p.leaders.contains(&0x10fc));
+    }
+
+    #[test]
+    fn written_when_the_run_panics() {
+        let path = std::env::temp_dir().join(format!("profile-{}.txt", std::process::id()));
+        let annotated = path.to_str().unwrap().to_string();
+        let result = std::panic::catch_unwind(|| {
+            let mut files = ProfileFiles::new(profiler(), Some(annotated), None, "a.elf");
+            files.profiler.retire(0x1000, ADDI, 1);
+            panic!("the CPU failed");
+        });
+        assert!(result.is_err());
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(text.starts_with("Total: 1 instructions, 1 cycles"));
+    }
+}
@@ -1,23 +1,25 @@
 //! Mono-core CPU
 
-use std::{
-    fmt::Display,
-    ops::{BitAnd, BitOr, BitXor},
-};
+use std::ops::{BitAnd, BitOr, BitXor};
 
-use log::{error, info, trace};
+use log::{error, info};
 
 use crate::{
     callstack::CallStack,
     check,
+    commit_log::{CommitLog, RetireInfo},
     core::{
+        csr::CsrFile,
         insts::*,
         reg::{ProgramCounter, RegisterFile},
         vm::VirtualMemory,
     },
     elf::LoadElfInfo,
     error::{Error, Exception, Result},
-    pinst,
+    iringbuf::InstRingBuffer,
+    profile::Profiler,
+    stats::Stats,
+    trace::Tracer,
 };
 
 use super::decode::decode;
@@ -32,21 +34,45 @@ pub struct CPU<'a> {
     // Program counter (PC) which is not included in general purpose register file.
     pc: ProgramCounter,
 
+    // Control and status registers
+    csr: CsrFile,
+
+    // Whether the last instruction trapped, without writing rd
+    trapped: bool,
+
     // Reference to virtual memory
     vm: &'a mut VirtualMemory,
 
     // Reference to call stack
     callstack: &'a mut CallStack<'a>,
 
-    // Itrace switch
-    itrace: bool,
+    // Instruction trace
+    itrace: Tracer,
+
+    // Instruction-level profiler
+    profiler: Option<&'a mut Profiler>,
+
+    // Spike-compatible commit log
+    commit_log: Option<CommitLog>,
+
+    // Effects of the last retired instruction, for difftest
+    last_retired: Option<RetireInfo>,
+
+    // Last retired instructions, dumped on failure
+    iringbuf: InstRingBuffer,
+
+    // Retired instructions, one per cycle
+    executed_inst_count: u64,
 }
 
 impl<'a> CPU<'a> {
     pub fn new(
         vm: &'a mut VirtualMemory,
         callstack: &'a mut CallStack<'a>,
-        itrace: bool,
+        itrace: Tracer,
+        profiler: Option<&'a mut Profiler>,
+        commit_log: Option<CommitLog>,
+        iringbuf_size: usize,
     ) -> CPU<'a> {
         // x0 already set to 0
         let reg_file = RegisterFile::empty();
@@ -56,9 +82,16 @@ impl<'a> CPU<'a> {
             running: false,
             reg_file,
             pc,
+            csr: CsrFile::new(),
+            trapped: false,
             vm,
             callstack,
             itrace,
+            profiler,
+            commit_log,
+            last_retired: None,
+            iringbuf: InstRingBuffer::new(iringbuf_size),
+            executed_inst_count: 0,
         }
     }
 
@@ -85,6 +118,13 @@ impl<'a> CPU<'a> {
                 break;
             }
             self.exec_once()?;
+            if let Some(code) = self.vm.htif_exit(self.last_retired.as_ref()) {
+                if code != 0 {
+                    self.iringbuf.dump();
+                }
+                let pc = self.last_retired.map_or(0, |info| info.pc);
+                self.halt(pc, code);
+            }
             i += 1;
         }
 
@@ -99,15 +139,40 @@ impl<'a> CPU<'a> {
 
         // Decode
         let exec_internal = decode(inst)?;
+        let src1 = self.reg_file.read(exec_internal.rs1);
+        let src2 = self.reg_file.read(exec_internal.rs2);
+        let rd = exec_internal.rd;
+        let is_ebreak = exec_internal.inst == Inst64::ebreak;
 
         // Execute
-        self.exec_inst(exec_internal)?;
+        let result = self.exec_inst(exec_internal);
 
         // Memory
 
         // Write Back
 
-        Ok(())
+        if result.is_ok() {
+            self.executed_inst_count += 1;
+            // Every instruction takes exactly one cycle
+            if let Some(profiler) = self.profiler.as_deref_mut() {
+                profiler.retire(pc, inst, 1);
+            }
+            let rd_val = self.reg_file.read(rd);
+            let mut info = RetireInfo::from_exec(pc, inst, src1, src2, rd_val);
+            if self.trapped {
+                info.reg_write = None;
+            }
+            if let Some(commit_log) = self.commit_log.as_mut() {
+                commit_log.commit(&info);
+            }
+            self.last_retired = Some(info);
+            self.iringbuf.push(info);
+            if is_ebreak && self.reg_file.read(10) != 0 {
+                self.iringbuf.dump();
+            }
+        }
+
+        result
     }
 
     pub fn fetch_inst(&mut self, pc: u64) -> u32 {
@@ -139,6 +204,9 @@ impl<'a> CPU<'a> {
         exec_itrnl.pc = self.pc.read();
         let pc = exec_itrnl.pc; // read pc into intermediate register
         let mut use_new_pc = false;
+        self.trapped = false;
+        // traced before executing, so that a trapping instruction shows up
+        self.trace_inst(&exec_itrnl);
 
         // Get source from register
         let reg_file = &mut self.reg_file;
@@ -148,8 +216,6 @@ impl<'a> CPU<'a> {
         let src3 = reg_file.read(exec_itrnl.rs3); // TODO: float instructions
         let imm = exec_itrnl.imm;
 
-        let rs1 = exec_itrnl.rs1;
-        let rs2 = exec_itrnl.rs2;
         #[allow(unused)]
         let rs3 = exec_itrnl.rs3; // TODO: float instructions
         let rd = exec_itrnl.rd;
@@ -158,67 +224,43 @@ impl<'a> CPU<'a> {
         match exec_itrnl.inst {
             Inst64::add => {
                 // R x[rd] = x[rs1] + x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, add, rd, rs1, rs2));
-                }
                 let result = src1.wrapping_add(src2); // ignore overflow
                 reg_file.write(rd, result);
             }
             Inst64::addi => {
-                if self.itrace {
-                    trace!("{}", pinst!(pc, addi, rd, rs1, imm=>imm));
-                }
                 // I x[rd] = x[rs1] + sext(immediate)
                 let result = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 reg_file.write(rd, result);
             }
             Inst64::addiw => {
                 // I x[rd] = sext((x[rs1] + sext(immediate))[31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, addiw, rd, rs1, imm=>imm));
-                }
                 let result = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
             }
             Inst64::addw => {
                 // R x[rd] = sext((x[rs1] + x[rs2])[31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, addw, rd, rs1, rs2));
-                }
                 let result = src1.wrapping_add(src2);
                 let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
             }
             Inst64::and => {
                 // R x[rd] = x[rs1] & x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, and, rd, rs1, rs2));
-                }
                 let result = src1.bitand(src2);
                 reg_file.write(rd, result);
             }
             Inst64::andi => {
                 // I x[rd] = x[rs1] & sext(immediate)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, andi, rd, rs1, imm=>imm));
-                }
                 let result = src1.bitand(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 reg_file.write(rd, result);
             }
             Inst64::auipc => {
                 // U x[rd] = pc + sext(immediate[31:12] << 12)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, auipc, rd, imm=>imm));
-                }
                 let result = pc.wrapping_add((sext(imm, U_TYPE_IMM_BITWIDTH) as u64) << 12);
                 reg_file.write(rd, result);
             }
             Inst64::beq => {
                 // B if (rs1 == rs2) pc += sext(offset)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, beq, rs1, rs2, imm=>offset));
-                }
                 if src1 == src2 {
                     exec_itrnl.pc = pc.wrapping_add(sext(imm, B_TYPE_IMM_BITWIDTH) as u64);
                     use_new_pc = true;
@@ -226,9 +268,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::bge => {
                 // B if (rs1 >= rs2) pc += sext(offset)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, bge, rs1, rs2, imm=>offset));
-                }
                 if (src1 as i64) >= (src2 as i64) {
                     exec_itrnl.pc = pc.wrapping_add(sext(imm, B_TYPE_IMM_BITWIDTH) as u64);
                     use_new_pc = true;
@@ -236,9 +275,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::bgeu => {
                 // B if (rs1 >= rs2) pc += sext(offset)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, bgeu, rs1, rs2, imm=>offset));
-                }
                 if (src1 as u64) >= (src2 as u64) {
                     exec_itrnl.pc = pc.wrapping_add(sext(imm, B_TYPE_IMM_BITWIDTH) as u64);
                     use_new_pc = true;
@@ -246,9 +282,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::blt => {
                 // B if (rs1 < rs2) pc += sext(offset)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, blt, rs1, rs2, imm=>offset));
-                }
                 if (src1 as i64) < (src2 as i64) {
                     exec_itrnl.pc = pc.wrapping_add(sext(imm, B_TYPE_IMM_BITWIDTH) as u64);
                     use_new_pc = true;
@@ -256,9 +289,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::bltu => {
                 // B if (rs1 < rs2) pc += sext(offset)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, bltu, rs1, rs2, imm=>offset));
-                }
                 if (src1 as u64) < (src2 as u64) {
                     exec_itrnl.pc = pc.wrapping_add(sext(imm, B_TYPE_IMM_BITWIDTH) as u64);
                     use_new_pc = true;
@@ -266,9 +296,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::bne => {
                 // B if (rs1 != rs2) pc += sext(offset)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, bne, rs1, rs2, imm=>offset));
-                }
                 if src1 != src2 {
                     exec_itrnl.pc = pc.wrapping_add(sext(imm, B_TYPE_IMM_BITWIDTH) as u64);
                     use_new_pc = true;
@@ -276,72 +303,68 @@ impl<'a> CPU<'a> {
             }
 
             Inst64::div => {
-                // R x[rd] = x[rs1] ÷s x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, div, rd, rs1, rs2));
-                }
-                if src2 == 0 {
-                    return Err(Error::Exception(Exception::DividedByZero));
-                }
-                let result = (src1 as i64).wrapping_div(src2 as i64);
+                // R x[rd] = x[rs1] ÷s x[rs2], all ones when dividing by zero
+                let result = match src2 {
+                    0 => -1,
+                    _ => (src1 as i64).wrapping_div(src2 as i64),
+                };
                 reg_file.write(rd, result as u64);
             }
             Inst64::divu => {
                 // R x[rd] = x[rs1] ÷u x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, divu, rd, rs1, rs2));
-                }
-                if src2 == 0 {
-                    return Err(Error::Exception(Exception::DividedByZero));
-                }
-                let result = src1.wrapping_div(src2);
+                let result = src1.checked_div(src2).unwrap_or(u64::MAX);
                 reg_file.write(rd, result);
             }
             Inst64::divuw => {
                 // R x[rd] = sext(x[rs1][31:0] ÷u x[rs2][31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, divuw, rd, rs1, rs2));
-                }
-                if trunc_to_32_bit(src2) == 0 {
-                    return Err(Error::Exception(Exception::DividedByZero));
-                }
-                let result = trunc_to_32_bit(src1).wrapping_div(trunc_to_32_bit(src2));
+                let result = trunc_to_32_bit(src1)
+                    .checked_div(trunc_to_32_bit(src2))
+                    .unwrap_or(u64::MAX);
                 let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
             }
             Inst64::divw => {
                 // R x[rd] = sext(x[rs1][31:0] ÷s x[rs2][31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, divw, rd, rs1, rs2));
-                }
-                if trunc_to_32_bit(src2) == 0 {
-                    return Err(Error::Exception(Exception::DividedByZero));
-                }
-                let result =
-                    (trunc_to_32_bit(src1) as i32).wrapping_div(trunc_to_32_bit(src2) as i32);
+                let result = match src2 as i32 {
+                    0 => -1,
+                    divisor => (src1 as i32).wrapping_div(divisor),
+                };
                 let result = sext(trunc_to_32_bit(result as u64), WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
             }
             Inst64::ebreak => {
                 // I RaiseException(Breakpoint)
                 // Temporary implementation: return exit code at x10.
-                if self.itrace {
-                    trace!("{}", pinst!(pc, ebreak));
-                }
                 let x10 = reg_file.read(10);
+                info!("ebreak at {:#x}, code {}", pc, x10);
                 self.halt(pc, x10); // HALT at current code.
-                let msg = format!("ebreak at {:#x}, code {}", pc, x10);
-                return Err(Error::Execute(msg)); // Simulate exception
-            }
-            Inst64::ecall => {
-                todo!("ecall");
+                return Ok(());
+            }
+            Inst64::csrrc
+            | Inst64::csrrci
+            | Inst64::csrrs
+            | Inst64::csrrsi
+            | Inst64::csrrw
+            | Inst64::csrrwi
+            | Inst64::ecall
+            | Inst64::mret
+            | Inst64::sret
+            | Inst64::wfi
+            | Inst64::fence
+            | Inst64::fence_i => {
+                let outcome = self
+                    .csr
+                    .exec(exec_itrnl.inst, pc, exec_itrnl.raw_inst, src1)?;
+                if let Some(rd_val) = outcome.rd_val {
+                    reg_file.write(rd, rd_val);
+                }
+                self.trapped = outcome.trapped;
+                exec_itrnl.pc = outcome.next_pc;
+                use_new_pc = true;
             }
 
             Inst64::jal => {
                 // J x[rd] = pc+4; pc += sext(offset)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, jal, rd, imm=>offset));
-                }
                 reg_file.write(rd, pc + 4); // rd default to x1
                 exec_itrnl.pc = pc.wrapping_add(sext(imm, J_TYPE_IMM_BITWIDTH) as u64);
 
@@ -353,9 +376,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::jalr => {
                 // I t=pc+4; pc=(x[rs1]+sext(offset))&∼1; x[rd]=t
-                if self.itrace {
-                    trace!("{}", pinst!(pc, jalr, rd, imm(rs1)));
-                }
 
                 // ret
                 if exec_itrnl.raw_inst == 0x00008067 {
@@ -369,9 +389,6 @@ impl<'a> CPU<'a> {
 
             Inst64::lb => {
                 // I x[rd] = sext(M[x[rs1] + sext(offset)][31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, lb, rd, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 let result = self.vm.mread::<u8>(vaddr as usize);
                 // SEXT in RV64I
@@ -380,9 +397,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::lbu => {
                 // I x[rd] = M[x[rs1] + sext(offset)][31:0]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, lbu, rd, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 let result = self.vm.mread::<u8>(vaddr as usize);
                 // ZERO extend: just as u64
@@ -390,18 +404,12 @@ impl<'a> CPU<'a> {
             }
             Inst64::ld => {
                 // I x[rd] = M[x[rs1] + sext(offset)][63:0]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, ld, rd, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 let result = self.vm.mread::<u64>(vaddr as usize);
                 reg_file.write(rd, result);
             }
             Inst64::lh => {
                 // I x[rd] = sext(M[x[rs1] + sext(offset)][15:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, lh, rd, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 let result = self.vm.mread::<u16>(vaddr as usize);
                 // SEXT in RV64I
@@ -410,9 +418,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::lhu => {
                 // I x[rd] = M[x[rs1] + sext(offset)][31:0]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, lhu, rd, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 let result = self.vm.mread::<u16>(vaddr as usize);
                 // ZERO extend: just as u64
@@ -420,18 +425,12 @@ impl<'a> CPU<'a> {
             }
             Inst64::lui => {
                 // U x[rd] = sext(immediate[31:12] << 12)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, lui, rd, imm=>imm));
-                }
                 let mask: u64 = !0b1111_1111_1111;
                 let result = ((sext(imm, U_TYPE_IMM_BITWIDTH) << 12) as u64) & mask;
                 reg_file.write(rd, result);
             }
             Inst64::lw => {
                 // I x[rd] = sext(M[x[rs1] + sext(offset)][31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, lw, rd, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 let result = self.vm.mread::<u32>(vaddr as usize);
                 // SEXT in RV64I
@@ -440,34 +439,18 @@ impl<'a> CPU<'a> {
             }
             Inst64::lwu => {
                 // I x[rd] = M[x[rs1] + sext(offset)][31:0]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, lwu, rd, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 let result = self.vm.mread::<u32>(vaddr as usize);
                 // ZERO extend: just as u64
                 reg_file.write(rd, result as u64);
             }
-            Inst64::mret => {
-                // R
-                if self.itrace {
-                    trace!("{}", pinst!(pc, mret));
-                }
-                todo!()
-            }
             Inst64::mul => {
                 // R x[rd] = x[rs1] × x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, mul, rd, rs1, rs2));
-                }
                 let result = src1.wrapping_mul(src2);
                 reg_file.write(rd, result);
             }
             Inst64::mulh => {
                 // R x[rd] = (x[rs1] s×s x[rs2]) >>s XLEN
-                if self.itrace {
-                    trace!("{}", pinst!(pc, mulh, rd, rs1, rs2));
-                }
                 // RV64
                 let result = (src1 as i128).wrapping_mul(src2 as i128);
                 let result = get_high_64_bit(result as u128);
@@ -475,9 +458,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::mulhsu => {
                 // R x[rd] = (x[rs1] s×u x[rs2]) >>s XLEN
-                if self.itrace {
-                    trace!("{}", pinst!(pc, mulhsu, rd, rs1, rs2));
-                }
                 let t_src1 = src1 as i64;
                 let t_src2 = src2 as u64;
                 let result = (t_src1 as i128).wrapping_mul(t_src2 as i128);
@@ -486,120 +466,76 @@ impl<'a> CPU<'a> {
             }
             Inst64::mulhu => {
                 // R x[rd] = (x[rs1] u×u x[rs2]) >>u XLEN
-                if self.itrace {
-                    trace!("{}", pinst!(pc, mulhu, rd, rs1, rs2));
-                }
                 let result = (src1 as u128).wrapping_mul(src2 as u128);
                 let result = get_high_64_bit(result);
                 reg_file.write(rd, result);
             }
             Inst64::mulw => {
                 // R x[rd] = sext((x[rs1] × x[rs2])[31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, mulw, rd, rs1, rs2));
-                }
                 let result = src1.wrapping_mul(src2);
                 let result = sext(trunc_to_32_bit(result), WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
             }
             Inst64::or => {
                 // R x[rd] = x[rs1] | x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, or, rd, rs1, rs2));
-                }
                 let result = src1.bitor(src2);
                 reg_file.write(rd, result);
             }
             Inst64::ori => {
                 // I x[rd] = x[rs1] | sext(immediate)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, ori, rd, rs1, imm=>imm));
-                }
                 let result = src1.bitor(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 reg_file.write(rd, result);
             }
 
             Inst64::rem => {
-                // R x[rd] = x[rs1] %s x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, rem, rd, rs1, rs2));
-                }
-                if src2 == 0 {
-                    return Err(Error::Exception(Exception::DividedByZero));
-                }
-                let result = (src1 as i64).wrapping_rem(src2 as i64);
+                // R x[rd] = x[rs1] %s x[rs2], x[rs1] when dividing by zero
+                let result = match src2 {
+                    0 => src1 as i64,
+                    _ => (src1 as i64).wrapping_rem(src2 as i64),
+                };
                 reg_file.write(rd, result as u64);
             }
             Inst64::remu => {
                 // R x[rd] = x[rs1] %u x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, remu, rd, rs1, rs2));
-                }
-                if src2 == 0 {
-                    return Err(Error::Exception(Exception::DividedByZero));
-                }
-                let result = src1.wrapping_rem(src2);
+                let result = src1.checked_rem(src2).unwrap_or(src1);
                 reg_file.write(rd, result);
             }
             Inst64::remuw => {
                 // R x[rd] = sext(x[rs1][31:0] %u x[rs2][31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, remuw, rd, rs1, rs2));
-                }
-                if src2 == 0 {
-                    return Err(Error::Exception(Exception::DividedByZero));
-                }
                 let t_src1 = trunc_to_32_bit(src1);
                 let t_src2 = trunc_to_32_bit(src2);
-                let result = t_src1.wrapping_rem(t_src2);
+                let result = t_src1.checked_rem(t_src2).unwrap_or(t_src1);
                 let result = sext(result, WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
             }
             Inst64::remw => {
                 // R x[rd] = sext(x[rs1][31:0] %s x[rs2][31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, remw, rd, rs1, rs2));
-                }
-                if src2 == 0 {
-                    return Err(Error::Exception(Exception::DividedByZero));
-                }
-                let t_src1 = trunc_to_32_bit(src1);
-                let t_src2 = trunc_to_32_bit(src2);
-                let result = (t_src1 as i64).wrapping_rem(t_src2 as i64);
-                reg_file.write(rd, result as u64);
+                let result = match src2 as i32 {
+                    0 => src1 as i32,
+                    divisor => (src1 as i32).wrapping_rem(divisor),
+                };
+                reg_file.write(rd, result as i64 as u64);
             }
             Inst64::sb => {
                 // S M[x[rs1] + sext(offset)] = x[rs2][7:0]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sb, rs2, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, S_TYPE_IMM_BITWIDTH) as u64);
                 let result = trunc_to_8_bit(src2);
                 self.vm.mwrite::<u8>(vaddr as usize, result as u8);
             }
             Inst64::sd => {
                 // S M[x[rs1] + sext(offset)] = x[rs2][63:0]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sd, rs2, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, S_TYPE_IMM_BITWIDTH) as u64);
                 self.vm.mwrite::<u64>(vaddr as usize, src2);
                 // self.vm.mread::<u64>(vaddr as usize);
             }
             Inst64::sh => {
                 // S M[x[rs1] + sext(offset)] = x[rs2][15:0]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sh, rs2, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, S_TYPE_IMM_BITWIDTH) as u64);
                 self.vm
                     .mwrite::<u16>(vaddr as usize, trunc_to_16_bit(src2) as u16);
             }
             Inst64::sll => {
                 // R x[rd] = x[rs1] << x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sll, rd, rs1, rs2));
-                }
                 // let t_src2 = trunc_to_5_bit(src2); // RV32
                 let t_src2 = trunc_to_6_bit(src2); // RV64
                 let result = src1.wrapping_shl(t_src2 as u32);
@@ -607,9 +543,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::slli => {
                 // I x[rd] = x[rs1] << shamt
-                if self.itrace {
-                    trace!("{}", pinst!(pc, slli, rd, rs1, imm=>imm));
-                }
                 // RV32I
                 // let (shamt, legal) = trunc_to_5_bit_and_check(imm);
                 // if !legal {
@@ -622,9 +555,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::slliw => {
                 // I x[rd] = x[rs1] << shamt
-                if self.itrace {
-                    trace!("{}", pinst!(pc, slliw, rd, rs1, imm=>imm));
-                }
                 let (shamt, legal) = trunc_to_5_bit_and_check(imm);
                 if !legal {
                     return Err(Error::Exception(Exception::IllegalInstruction));
@@ -635,9 +565,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::sllw => {
                 // R x[rd] = sext((x[rs1] << x[rs2][4:0])[31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sllw, rd, rs1, rs2));
-                }
                 let t_src1 = trunc_to_32_bit(src1);
                 let t_src2 = trunc_to_5_bit(src2);
                 let result = t_src1.wrapping_shl(t_src2 as u32);
@@ -646,43 +573,28 @@ impl<'a> CPU<'a> {
             }
             Inst64::slt => {
                 // R x[rd] = x[rs1] <s x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, slt, rd, rs1, rs2));
-                }
                 let write_val = if (src1 as i64) < (src2 as i64) { 1 } else { 0 };
                 reg_file.write(rd, write_val);
             }
             Inst64::slti => {
                 // I x[rd] = x[rs1] <s sext(immediate)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, slti, rd, rs1, imm=>imm));
-                }
                 let ext_imm = sext(imm, I_TYPE_IMM_BITWIDTH) as i64;
                 let write_val = if (src1 as i64) < ext_imm { 1 } else { 0 };
                 reg_file.write(rd, write_val);
             }
             Inst64::sltiu => {
                 // I x[rd] = x[rs1] <u sext(immediate)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sltiu, rd, rs1, imm=>imm));
-                }
                 let ext_imm: u64 = sext(imm, I_TYPE_IMM_BITWIDTH) as u64;
                 let write_val = if src1 < ext_imm { 1 } else { 0 };
                 reg_file.write(rd, write_val);
             }
             Inst64::sltu => {
                 // R x[rd] = x[rs1] <u x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sltu, rd, rs1, rs2));
-                }
                 let write_val = if (src1 as u64) < (src2 as u64) { 1 } else { 0 };
                 reg_file.write(rd, write_val);
             }
             Inst64::sra => {
                 // R x[rd] = x[rs1] >>s x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sra, rd, rs1, rs2));
-                }
                 // let t_src2 = trunc_to_5_bit(src2); // RV32
                 let t_src2 = trunc_to_6_bit(src2); // RV64
                                                    // i64 shr automatically fill high bits with sign-bit
@@ -691,9 +603,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::srai => {
                 // I x[rd] = x[rs1] >>s shamt
-                if self.itrace {
-                    trace!("{}", pinst!(pc, srai, rd, rs1, imm=>imm));
-                }
                 // RV32I
                 // let (shamt, legal) = trunc_to_5_bit_and_check(imm);
                 // if !legal {
@@ -706,9 +615,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::sraiw => {
                 // I x[rd] = sext(x[rs1][31:0] >>s shamt)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sraiw, rd, rs1, imm=>imm));
-                }
                 let t_src1: i64 = sext(trunc_to_32_bit(src1), WORD_BITWIDTH);
                 let (shamt, legal) = trunc_to_5_bit_and_check(imm);
                 if !legal {
@@ -719,27 +625,14 @@ impl<'a> CPU<'a> {
             }
             Inst64::sraw => {
                 // R x[rd] = x[rs1] >>s x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sraw, rd, rs1, rs2));
-                }
                 let t_src1: i64 = sext(trunc_to_32_bit(src1), WORD_BITWIDTH);
                 let t_src2 = trunc_to_5_bit(src2);
                 let result = t_src1.wrapping_shr(t_src2 as u32);
                 let result = sext(result as u64, WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
             }
-            Inst64::sret => {
-                // R
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sret));
-                }
-                todo!()
-            }
             Inst64::srl => {
                 // R x[rd] = x[rs1] >>u x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, srl, rd, rs1, rs2));
-                }
                 // let t_src2 = trunc_to_5_bit(src2); // RV32
                 let t_src2 = trunc_to_6_bit(src2); // RV64
                                                    // i64 shr automatically fill high bits with 0-bit
@@ -748,9 +641,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::srli => {
                 // I x[rd] = x[rs1] >>s shamt
-                if self.itrace {
-                    trace!("{}", pinst!(pc, srli, rd, rs1, imm=>imm));
-                }
                 // RV32I
                 // let (shamt, legal) = trunc_to_5_bit_and_check(imm);
                 // if !legal {
@@ -763,9 +653,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::srliw => {
                 // I x[rd] = sext(x[rs1][31:0] >>s shamt)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, srliw, rd, rs1, imm=>imm));
-                }
                 let t_src1: u64 = trunc_to_32_bit(src1);
                 let (shamt, legal) = trunc_to_5_bit_and_check(imm);
                 if !legal {
@@ -779,9 +666,6 @@ impl<'a> CPU<'a> {
             }
             Inst64::srlw => {
                 // R x[rd] = x[rs1] >>s x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, srlw, rd, rs1, rs2));
-                }
                 let t_src1: u64 = trunc_to_32_bit(src1);
                 let t_src2 = trunc_to_5_bit(src2);
                 let result = t_src1.wrapping_shr(t_src2 as u32);
@@ -790,26 +674,17 @@ impl<'a> CPU<'a> {
             }
             Inst64::sub => {
                 // R x[rd] = x[rs1] - x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sub, rd, rs1, rs2));
-                }
                 let result = src1.wrapping_sub(src2);
                 reg_file.write(rd, result);
             }
             Inst64::subw => {
                 // R x[rd] = sext((x[rs1] - x[rs2])[31:0])
-                if self.itrace {
-                    trace!("{}", pinst!(pc, subw, rd, rs1, rs2));
-                }
                 let result = trunc_to_32_bit(src1.wrapping_sub(src2));
                 let result = sext(result, WORD_BITWIDTH);
                 reg_file.write(rd, result as u64);
             }
             Inst64::sw => {
                 // S M[x[rs1] + sext(offset)] = x[rs2][31:0]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, sw, rs2, imm(rs1)));
-                }
                 let vaddr = src1.wrapping_add(sext(imm, S_TYPE_IMM_BITWIDTH) as u64);
                 let write_val = trunc_to_32_bit(src2);
                 self.vm.mwrite::<u32>(vaddr as usize, write_val as u32);
@@ -818,17 +693,11 @@ impl<'a> CPU<'a> {
 
             Inst64::xor => {
                 // R x[rd] = x[rs1] ˆ x[rs2]
-                if self.itrace {
-                    trace!("{}", pinst!(pc, xor, rd, rs1, rs2));
-                }
                 let result = src1.bitxor(src2);
                 reg_file.write(rd, result);
             }
             Inst64::xori => {
                 // I x[rd] = x[rs1] ˆ sext(immediate)
-                if self.itrace {
-                    trace!("{}", pinst!(pc, xori, rd, rs1, imm=>imm));
-                }
                 let result = src1.bitxor(sext(imm, I_TYPE_IMM_BITWIDTH) as u64);
                 reg_file.write(rd, result);
             }
@@ -858,10 +727,37 @@ impl<'a> CPU<'a> {
         info!("Program ended at pc {:#x}, with exit code {}", pc, code);
     }
 
-    pub fn mread<T: Sized + Display>(&self, vaddr: u64) -> T {
+    fn trace_inst(&self, itl: &ExecInternal) {
+        if self.itrace.enabled() {
+            let asm = format_inst(itl.pc, itl.inst, itl.rd, itl.rs1, itl.rs2, itl.imm);
+            self.itrace.inst(itl.pc, itl.raw_inst, &asm);
+        }
+    }
+
+    pub fn mread<T: Sized + Copy + Into<u64>>(&self, vaddr: u64) -> T {
         self.vm.mread(vaddr as usize)
     }
 
+    pub fn add_stats(&self, stats: &mut Stats) {
+        stats.set("cycles", self.executed_inst_count);
+        stats.set("instret", self.executed_inst_count);
+        stats.set("cpi", 1.0);
+        stats.set("ipc", 1.0);
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    pub fn read_reg(&self, idx: u8) -> u64 {
+        self.reg_file.read(idx)
+    }
+
+    /// Effects of the instruction retired since the last call.
+    pub fn take_retired(&mut self) -> Option<RetireInfo> {
+        self.last_retired.take()
+    }
+
     pub fn backtrace(&self) {
         self.callstack.backtrace();
     }
@@ -86,8 +86,22 @@ fn decode_load_fp(_inst: u32) -> Result<ExecInternal> {
 }
 
 /// 0001111 MISC_MEM: I type
-fn decode_misc_mem(_inst: u32) -> Result<ExecInternal> {
-    todo!()
+fn decode_misc_mem(inst: u32) -> Result<ExecInternal> {
+    let mut exec_internal = ExecInternal::default();
+    exec_internal.raw_inst = inst;
+
+    let funct3 = funct3(inst);
+    exec_internal.inst = match funct3 {
+        0b000 => Inst64::fence,
+        0b001 => Inst64::fence_i,
+        _ => {
+            let msg = format!("Unknown MISC_MEM instruction funct3={funct3}");
+            error!("{msg}");
+            return Err(Error::Decode(msg));
+        }
+    };
+
+    Ok(exec_internal)
 }
 
 /// 0010011 OP_IMM: I type
@@ -510,6 +524,9 @@ fn decode_system(inst: u32) -> Result<ExecInternal> {
         0b000 => match csr {
             0 => Inst64::ecall,
             1 => Inst64::ebreak,
+            0x102 => Inst64::sret,
+            0x105 => Inst64::wfi,
+            0x302 => Inst64::mret,
             _ => {
                 let msg = format!("Unknown SYSTEM E- instruction csr={csr}");
                 error!("{msg}");
@@ -531,6 +548,7 @@ fn decode_system(inst: u32) -> Result<ExecInternal> {
 
     exec_internal.rd = rd(inst);
     exec_internal.rs1 = rs1(inst); // zimm for csrrwi, csrrsi, csrrci
+    exec_internal.imm = csr;
 
     Ok(exec_internal)
 }
@@ -0,0 +1,220 @@
+//! Machine-readable statistics of a run.
+//!
+//! Statistics are flat `section.name` keys in the order they are added. The
+//! JSON report nests the sections into objects, the CSV report has the keys
+//! as header and one row of values, so the reports of many runs can be
+//! concatenated into one table.
+
+use std::{
+    fmt::{self, Write as _},
+    fs, io,
+};
+
+use crate::trace::escape_json;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatValue {
+    Int(u64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+impl From<u64> for StatValue {
+    fn from(value: u64) -> Self {
+        StatValue::Int(value)
+    }
+}
+
+impl From<usize> for StatValue {
+    fn from(value: usize) -> Self {
+        StatValue::Int(value as u64)
+    }
+}
+
+impl From<f64> for StatValue {
+    fn from(value: f64) -> Self {
+        StatValue::Float(value)
+    }
+}
+
+impl From<bool> for StatValue {
+    fn from(value: bool) -> Self {
+        StatValue::Bool(value)
+    }
+}
+
+impl From<String> for StatValue {
+    fn from(value: String) -> Self {
+        StatValue::Str(value)
+    }
+}
+
+impl From<&str> for StatValue {
+    fn from(value: &str) -> Self {
+        StatValue::Str(value.to_string())
+    }
+}
+
+impl fmt::Display for StatValue {
+    /// JSON representation, NaN and infinities are `null`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatValue::Int(v) => write!(f, "{v}"),
+            StatValue::Float(v) if v.is_finite() => write!(f, "{v}"),
+            StatValue::Float(_) => write!(f, "null"),
+            StatValue::Bool(v) => write!(f, "{v}"),
+            StatValue::Str(s) => write!(f, "\"{}\"", escape_json(s)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    entries: Vec<(String, StatValue)>,
+    // prefix of the keys in the current section
+    prefix: String,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    pub fn set(&mut self, key: &str, value: impl Into<StatValue>) {
+        let key = format!("{}{key}", self.prefix);
+        let value = value.into();
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    /// `numerator / denominator`, 0 if the denominator is 0.
+    pub fn set_ratio(&mut self, key: &str, numerator: u64, denominator: u64) {
+        let ratio = if denominator == 0 {
+            0.0
+        } else {
+            numerator as f64 / denominator as f64
+        };
+        self.set(key, ratio);
+    }
+
+    /// Add the statistics of `f` in section `name`.
+    pub fn section(&mut self, name: &str, f: impl FnOnce(&mut Stats)) {
+        let len = self.prefix.len();
+        self.prefix.push_str(name);
+        self.prefix.push('.');
+        f(self);
+        self.prefix.truncate(len);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&StatValue> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        let mut open: Vec<&str> = Vec::new();
+        let mut first = true;
+        for (key, value) in &self.entries {
+            let path: Vec<&str> = key.split('.').collect();
+            let (sections, name) = path.split_at(path.len() - 1);
+            let common = open
+                .iter()
+                .zip(sections)
+                .take_while(|(a, b)| a == b)
+                .count();
+            while open.len() > common {
+                open.pop();
+                let _ = write!(json, "\n{}}}", "  ".repeat(open.len() + 1));
+                first = false;
+            }
+            for section in &sections[common..] {
+                let indent = "  ".repeat(open.len() + 1);
+                let comma = if first { "" } else { "," };
+                let _ = write!(json, "{comma}\n{indent}\"{}\": {{", escape_json(section));
+                open.push(section);
+                first = true;
+            }
+            let indent = "  ".repeat(open.len() + 1);
+            let comma = if first { "" } else { "," };
+            let _ = write!(
+                json,
+                "{comma}\n{indent}\"{}\": {value}",
+                escape_json(name[0])
+            );
+            first = false;
+        }
+        while open.pop().is_some() {
+            let _ = write!(json, "\n{}}}", "  ".repeat(open.len() + 1));
+        }
+        format!("{{{json}\n}}\n")
+    }
+
+    pub fn to_csv(&self) -> String {
+        let header: Vec<String> = self.entries.iter().map(|(k, _)| escape_csv(k)).collect();
+        let values: Vec<String> = self
+            .entries
+            .iter()
+            .map(|(_, v)| match v {
+                StatValue::Str(s) => escape_csv(s),
+                StatValue::Float(v) if !v.is_finite() => String::new(),
+                v => v.to_string(),
+            })
+            .collect();
+        format!("{}\n{}\n", header.join(","), values.join(","))
+    }
+
+    /// Write the report to `path`, as CSV if it ends with `.csv` and as JSON
+    /// otherwise.
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        if path.ends_with(".csv") {
+            fs::write(path, self.to_csv())
+        } else {
+            fs::write(path, self.to_json())
+        }
+    }
+}
+
+pub(crate) fn escape_csv(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_and_csv() {
+        let mut stats = Stats::new();
+        stats.section("config", |s| {
+            s.set("cpu", "pipeline");
+            s.set("args", "-c pipeline, \"x\"");
+        });
+        stats.set("cycles", 120u64);
+        stats.set_ratio("cpi", 120, 100);
+        stats.section("hazards", |s| {
+            s.section("data", |s| s.set("count", 3u64));
+            s.set("flush", true);
+        });
+        stats.set("ipc", f64::NAN);
+
+        assert_eq!(stats.get("hazards.data.count"), Some(&StatValue::Int(3)));
+        assert_eq!(
+            stats.to_json(),
+            "{\n  \"config\": {\n    \"cpu\": \"pipeline\",\n    \"args\": \"-c pipeline, \\\"x\\\"\"\n  },\
+             \n  \"cycles\": 120,\n  \"cpi\": 1.2,\n  \"hazards\": {\n    \"data\": {\n      \"count\": 3\n    },\
+             \n    \"flush\": true\n  },\n  \"ipc\": null\n}\n"
+        );
+        assert_eq!(
+            stats.to_csv(),
+            "config.cpu,config.args,cycles,cpi,hazards.data.count,hazards.flush,ipc\n\
+             pipeline,\"-c pipeline, \"\"x\"\"\",120,1.2,3,true,\n"
+        );
+    }
+}
@@ -0,0 +1,443 @@
+//! Batch runs of programs over a matrix of CPU configurations.
+//!
+//! Every program is run on every CPU mode with every combination of the
+//! policies the mode uses: the data hazard policy only matters to the
+//! pipeline CPU, and the predict policy only to dynamic prediction. The runs
+//! are spread over worker threads, each run is an ordinary run of the
+//! simulator built from its command line, and the results are compared in a
+//! table of cycles, CPI and hazard counts.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use clap::{Parser, ValueEnum};
+use log::{info, LevelFilter};
+
+use crate::{
+    config,
+    error::{Error, Result},
+    multi_stage::cpu::{ControlPolicy, DataHazardPolicy, PredictPolicy},
+    stats::{escape_csv, StatValue, Stats},
+    CPUMode, Cli,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct SweepArgs {
+    /// Programs to run
+    #[arg(required = true)]
+    inputs: Vec<String>,
+
+    /// CPU modes, separated by commas
+    #[arg(short, long, value_delimiter = ',', required = true)]
+    cpu_mode: Vec<CPUMode>,
+
+    /// Data hazard policies of the pipeline CPU, separated by commas
+    #[arg(long, value_delimiter = ',')]
+    data_hazard_policy: Vec<DataHazardPolicy>,
+
+    /// Control policies, separated by commas
+    #[arg(long, value_delimiter = ',')]
+    control_policy: Vec<ControlPolicy>,
+
+    /// Branch prediction policies of dynamic prediction, separated by commas
+    #[arg(long, value_delimiter = ',')]
+    predict_policy: Vec<PredictPolicy>,
+
+    /// Number of runs in parallel, by default the number of CPUs
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Write the table as markdown to this file.
+    #[arg(long)]
+    markdown: Option<String>,
+
+    /// Write the table as CSV to this file.
+    #[arg(long)]
+    csv: Option<String>,
+
+    /// Options given to every run, after `--`, e.g. `-- --difftest`
+    #[arg(last = true)]
+    options: Vec<String>,
+}
+
+/// One run of the sweep.
+#[derive(Debug, Clone)]
+struct Job {
+    input: String,
+    cpu_mode: CPUMode,
+    data_hazard_policy: Option<DataHazardPolicy>,
+    control_policy: Option<ControlPolicy>,
+    predict_policy: Option<PredictPolicy>,
+}
+
+impl Job {
+    fn command_line(&self, options: &[String]) -> Vec<String> {
+        let mut argv = vec![
+            "riscv-emulator".to_string(),
+            "-i".to_string(),
+            self.input.clone(),
+            "-c".to_string(),
+            name(&self.cpu_mode),
+        ];
+        if let Some(policy) = &self.data_hazard_policy {
+            argv.extend(["--data-hazard-policy".to_string(), name(policy)]);
+        }
+        if let Some(policy) = &self.control_policy {
+            argv.extend(["--control-policy".to_string(), name(policy)]);
+        }
+        if let Some(policy) = &self.predict_policy {
+            argv.extend(["--predict-policy".to_string(), name(policy)]);
+        }
+        argv.extend(options.iter().cloned());
+        argv
+    }
+
+    fn program(&self) -> String {
+        Path::new(&self.input)
+            .file_stem()
+            .map_or(self.input.clone(), |s| s.to_string_lossy().to_string())
+    }
+}
+
+fn name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map_or(String::new(), |v| v.get_name().to_string())
+}
+
+fn name_or_dash<T: ValueEnum>(value: Option<&T>) -> String {
+    value.map_or("-".to_string(), name)
+}
+
+#[derive(Debug)]
+enum Outcome {
+    GoodTrap,
+    BadTrap(u64),
+    /// The run failed before the program ended
+    Failed,
+}
+
+impl Outcome {
+    fn from_stats(stats: Option<&Stats>) -> Outcome {
+        match stats.and_then(|stats| stats.get("exit_code")) {
+            Some(StatValue::Int(0)) => Outcome::GoodTrap,
+            Some(StatValue::Int(code)) => Outcome::BadTrap(*code),
+            _ => Outcome::Failed,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Outcome::GoodTrap => "GOOD TRAP".to_string(),
+            Outcome::BadTrap(code) => format!("BAD TRAP ({code})"),
+            Outcome::Failed => "FAILED".to_string(),
+        }
+    }
+}
+
+struct RunResult {
+    job: Job,
+    outcome: Outcome,
+    stats: Option<Stats>,
+}
+
+/// Columns of the table after the configuration, as (title, statistic).
+const COLUMNS: [(&str, &str); 8] = [
+    ("cycles", "cycles"),
+    ("insts", "instret"),
+    ("CPI", "cpi"),
+    ("DH count", "hazards.data.count"),
+    ("DH cycles", "hazards.data.cycles"),
+    ("CH count", "hazards.control.count"),
+    ("CH cycles", "hazards.control.cycles"),
+    ("SH cycles", "hazards.structural.cycles"),
+];
+
+impl RunResult {
+    fn cells(&self) -> Vec<String> {
+        let mut cells = vec![
+            self.job.program(),
+            name(&self.job.cpu_mode),
+            name_or_dash(self.job.data_hazard_policy.as_ref()),
+            name_or_dash(self.job.control_policy.as_ref()),
+            name_or_dash(self.job.predict_policy.as_ref()),
+            self.outcome.text(),
+        ];
+        for (_, key) in COLUMNS {
+            let value = self.stats.as_ref().and_then(|stats| stats.get(key));
+            cells.push(match value {
+                Some(StatValue::Float(v)) => format!("{v:.3}"),
+                Some(v) => v.to_string(),
+                None => "-".to_string(),
+            });
+        }
+        cells
+    }
+}
+
+fn header() -> Vec<&'static str> {
+    let mut header = vec![
+        "program",
+        "cpu",
+        "data hazard",
+        "control",
+        "predict",
+        "result",
+    ];
+    header.extend(COLUMNS.iter().map(|(title, _)| *title));
+    header
+}
+
+/// Every combination of the programs, CPU modes and the policies they use.
+fn jobs(args: &SweepArgs) -> Result<Vec<Job>> {
+    let uses_control = |mode: &CPUMode| {
+        matches!(
+            mode,
+            CPUMode::Pipeline | CPUMode::DualIssue | CPUMode::OutOfOrder
+        )
+    };
+    if args.cpu_mode.contains(&CPUMode::Pipeline) && args.data_hazard_policy.is_empty() {
+        return Err(Error::Config(
+            "the pipeline CPU needs --data-hazard-policy".to_string(),
+        ));
+    }
+    if args.cpu_mode.iter().any(uses_control) && args.control_policy.is_empty() {
+        return Err(Error::Config(format!(
+            "CPU modes {:?} need --control-policy",
+            args.cpu_mode
+        )));
+    }
+    if args.control_policy.contains(&ControlPolicy::DynamicPredict)
+        && args.predict_policy.is_empty()
+    {
+        return Err(Error::Config(
+            "dynamic prediction needs --predict-policy".to_string(),
+        ));
+    }
+
+    let mut jobs = Vec::new();
+    for input in &args.inputs {
+        for cpu_mode in &args.cpu_mode {
+            let data_hazard_policies: Vec<_> = if *cpu_mode == CPUMode::Pipeline {
+                args.data_hazard_policy.iter().copied().map(Some).collect()
+            } else {
+                vec![None]
+            };
+            let control_policies: Vec<_> = if uses_control(cpu_mode) {
+                args.control_policy.iter().copied().map(Some).collect()
+            } else {
+                vec![None]
+            };
+            for data_hazard_policy in &data_hazard_policies {
+                for control_policy in &control_policies {
+                    let predict_policies: Vec<_> =
+                        if *control_policy == Some(ControlPolicy::DynamicPredict) {
+                            args.predict_policy.iter().copied().map(Some).collect()
+                        } else {
+                            vec![None]
+                        };
+                    for predict_policy in predict_policies {
+                        jobs.push(Job {
+                            input: input.clone(),
+                            cpu_mode: cpu_mode.clone(),
+                            data_hazard_policy: *data_hazard_policy,
+                            control_policy: *control_policy,
+                            predict_policy,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    Ok(jobs)
+}
+
+/// Number of worker threads for `runs` runs, `jobs` if given and the number
+/// of CPUs otherwise.
+pub(crate) fn workers(jobs: Option<usize>, runs: usize) -> usize {
+    jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .clamp(1, runs.max(1))
+}
+
+/// Run every command line on `workers` threads, returning the statistics of
+/// each run in order, [`None`] if its options are invalid or it failed.
+pub(crate) fn run_all(command_lines: &[Vec<String>], workers: usize) -> Vec<Option<Stats>> {
+    // the log of parallel runs is interleaved, only keep the warnings
+    let level = log::max_level();
+    log::set_max_level(level.min(LevelFilter::Warn));
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(command_line) = command_lines.get(index) else {
+                    break;
+                };
+                let stats = run_command_line(command_line);
+                results.lock().unwrap().push((index, stats));
+            });
+        }
+    });
+    log::set_max_level(level);
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, stats)| stats).collect()
+}
+
+fn run_command_line(command_line: &[String]) -> Option<Stats> {
+    let parsed = config::expand_args(command_line.to_vec())
+        .map_err(|e| e.to_string())
+        .and_then(|argv| Cli::try_parse_from(argv).map_err(|e| e.to_string()));
+    let args = match parsed {
+        Ok(Cli {
+            run: Some(args), ..
+        }) => args,
+        Ok(_) => unreachable!("a run without subcommand"),
+        Err(e) => {
+            log::error!("Invalid options of `{}`: {e}", command_line.join(" "));
+            return None;
+        }
+    };
+    // a failed run panics, the message is printed by the panic hook
+    let mut stats = panic::catch_unwind(AssertUnwindSafe(|| crate::run(&args))).ok()?;
+    stats.set("config.command_line", command_line.join(" "));
+    Some(stats)
+}
+
+/// Run the sweep and write the table, returning whether every program hit
+/// the good trap.
+pub fn sweep(args: &SweepArgs) -> Result<bool> {
+    let jobs = jobs(args)?;
+    let workers = workers(args.jobs, jobs.len());
+    info!("Sweep of {} runs on {workers} threads", jobs.len());
+
+    let command_lines: Vec<_> = jobs
+        .iter()
+        .map(|job| job.command_line(&args.options))
+        .collect();
+    let results: Vec<RunResult> = jobs
+        .into_iter()
+        .zip(run_all(&command_lines, workers))
+        .map(|(job, stats)| RunResult {
+            job,
+            outcome: Outcome::from_stats(stats.as_ref()),
+            stats,
+        })
+        .collect();
+
+    let markdown = to_markdown(&results);
+    for line in markdown.lines() {
+        info!("{line}");
+    }
+    if let Some(path) = &args.markdown {
+        fs::write(path, &markdown)?;
+        info!("Sweep table written to {path}");
+    }
+    if let Some(path) = &args.csv {
+        fs::write(path, to_csv(&results))?;
+        info!("Sweep table written to {path}");
+    }
+    Ok(results
+        .iter()
+        .all(|result| matches!(result.outcome, Outcome::GoodTrap)))
+}
+
+fn to_markdown(results: &[RunResult]) -> String {
+    let header = header();
+    let mut table = String::new();
+    let _ = writeln!(table, "| {} |", header.join(" | "));
+    let _ = writeln!(table, "|{}", "---|".repeat(header.len()));
+    for result in results {
+        let _ = writeln!(table, "| {} |", result.cells().join(" | "));
+    }
+    table
+}
+
+fn to_csv(results: &[RunResult]) -> String {
+    let mut table = String::new();
+    let header: Vec<String> = header().into_iter().map(escape_csv).collect();
+    let _ = writeln!(table, "{}", header.join(","));
+    for result in results {
+        let cells: Vec<String> = result.cells().iter().map(|c| escape_csv(c)).collect();
+        let _ = writeln!(table, "{}", cells.join(","));
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn matrix() {
+        let cli = Cli::try_parse_from([
+            "riscv-emulator",
+            "sweep",
+            "-c",
+            "single,pipeline,dual-issue",
+            "--data-hazard-policy",
+            "naive-stall,data-forward",
+            "--control-policy",
+            "always-not-taken,dynamic-predict",
+            "--predict-policy",
+            "one-bit-predict,gshare",
+            "a.elf",
+            "b.elf",
+            "--",
+            "--difftest",
+        ])
+        .unwrap();
+        let Some(crate::Command::Sweep(args)) = cli.command else {
+            panic!("not a sweep");
+        };
+        let jobs = jobs(&args).unwrap();
+        // single 1, pipeline 2 * (1 + 2), dual-issue 1 + 2, for each program
+        assert_eq!(jobs.len(), 2 * (1 + 6 + 3));
+        assert_eq!(
+            jobs[2].command_line(&args.options),
+            [
+                "riscv-emulator",
+                "-i",
+                "a.elf",
+                "-c",
+                "pipeline",
+                "--data-hazard-policy",
+                "naive-stall",
+                "--control-policy",
+                "dynamic-predict",
+                "--predict-policy",
+                "one-bit-predict",
+                "--difftest"
+            ]
+        );
+        assert_eq!(jobs[9].program(), "a");
+        assert_eq!(jobs[10].program(), "b");
+
+        let result = RunResult {
+            job: jobs[0].clone(),
+            outcome: Outcome::BadTrap(1),
+            stats: None,
+        };
+        assert_eq!(
+            to_markdown(&[result]).lines().nth(2),
+            Some("| a | single | - | - | - | BAD TRAP (1) | - | - | - | - | - | - | - | - |")
+        );
+
+        let cli = Cli::try_parse_from(["riscv-emulator", "sweep", "-c", "pipeline", "a.elf"]);
+        let Some(crate::Command::Sweep(args)) = cli.unwrap().command else {
+            panic!("not a sweep");
+        };
+        assert!(super::jobs(&args).is_err());
+    }
+}
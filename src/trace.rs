@@ -0,0 +1,435 @@
+//! Trace subsystem.
+//!
+//! Instruction (itrace), memory (mtrace) and function (ftrace) traces are
+//! produced as [`TraceEvent`]s and handed to a [`Tracer`], which forwards them
+//! to a pluggable [`TraceSink`]. Without an output file the events go through
+//! the logger as before; with one they are written in one of the
+//! [`TraceFormat`]s so that long runs can be stored and post-processed.
+//!
+//! Binary format: the file starts with the 8-byte magic `RVTRACE\0` followed
+//! by a little-endian `u32` version. Each record is a one byte tag followed by
+//! little-endian fields:
+//!
+//! | tag | event  | fields                                      |
+//! |-----|--------|---------------------------------------------|
+//! | 0   | inst   | pc: u64, raw_inst: u32                      |
+//! | 1   | mread  | addr: u64, size: u8, value: u64             |
+//! | 2   | mwrite | addr: u64, size: u8, value: u64             |
+//! | 3   | call   | pc: u64, target: u64, depth: u32            |
+//! | 4   | ret    | pc: u64, depth: u32                         |
+//!
+//! Disassembly and symbol names are not stored; they can be recovered from the
+//! ELF file.
+
+use std::{
+    cell::RefCell,
+    fmt::{self, Display},
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use clap::ValueEnum;
+use log::{error, trace};
+
+pub const BINARY_MAGIC: &[u8; 8] = b"RVTRACE\0";
+pub const BINARY_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent<'a> {
+    /// An executed instruction.
+    Inst {
+        pc: u64,
+        raw_inst: u32,
+        disasm: &'a str,
+    },
+    /// A memory read of `size` bytes.
+    MemRead { addr: u64, size: u8, value: u64 },
+    /// A memory write of `size` bytes.
+    MemWrite { addr: u64, size: u8, value: u64 },
+    /// A call into a known function, `depth` is the call depth before the call.
+    Call {
+        pc: u64,
+        target: u64,
+        depth: usize,
+        name: &'a str,
+    },
+    /// A return from a function, `depth` is the call depth after the return.
+    Ret {
+        pc: u64,
+        depth: usize,
+        name: &'a str,
+    },
+}
+
+impl<'a> Display for TraceEvent<'a> {
+    /// Same text as the traces printed through the logger.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TraceEvent::Inst { disasm, .. } => write!(f, "{}", disasm),
+            TraceEvent::MemRead { addr, value, .. } => write!(f, "mread {:#x}\t{}", addr, value),
+            TraceEvent::MemWrite { addr, value, .. } => {
+                write!(f, "mwrite {:#x}\t{}", addr, value)
+            }
+            TraceEvent::Call {
+                pc,
+                target,
+                depth,
+                name,
+            } => write!(
+                f,
+                "{:x}:{} call [{name}@{:#x}]",
+                pc,
+                " ".repeat(depth),
+                target
+            ),
+            TraceEvent::Ret { pc, depth, name } => {
+                write!(f, "{:x}:{} ret [{name}]", pc, " ".repeat(depth))
+            }
+        }
+    }
+}
+
+/// Destination of trace events.
+pub trait TraceSink {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()>;
+}
+
+/// Print events through the logger at trace level.
+pub struct LogSink;
+
+impl TraceSink for LogSink {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        trace!("{}", event);
+        Ok(())
+    }
+}
+
+/// One event per line, in the same text as [`LogSink`].
+pub struct TextSink<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TextSink<W> {
+    pub fn new(out: W) -> Self {
+        TextSink { out }
+    }
+}
+
+impl<W: Write> TraceSink for TextSink<W> {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        writeln!(self.out, "{}", event)
+    }
+}
+
+/// Compact fixed-size records, see the module documentation for the layout.
+pub struct BinarySink<W: Write> {
+    out: W,
+}
+
+impl<W: Write> BinarySink<W> {
+    pub fn new(mut out: W) -> io::Result<Self> {
+        out.write_all(BINARY_MAGIC)?;
+        out.write_all(&BINARY_VERSION.to_le_bytes())?;
+        Ok(BinarySink { out })
+    }
+}
+
+impl<W: Write> TraceSink for BinarySink<W> {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        let out = &mut self.out;
+        match *event {
+            TraceEvent::Inst { pc, raw_inst, .. } => {
+                out.write_all(&[0])?;
+                out.write_all(&pc.to_le_bytes())?;
+                out.write_all(&raw_inst.to_le_bytes())
+            }
+            TraceEvent::MemRead { addr, size, value }
+            | TraceEvent::MemWrite { addr, size, value } => {
+                let tag = if matches!(event, TraceEvent::MemRead { .. }) {
+                    1
+                } else {
+                    2
+                };
+                out.write_all(&[tag])?;
+                out.write_all(&addr.to_le_bytes())?;
+                out.write_all(&[size])?;
+                out.write_all(&value.to_le_bytes())
+            }
+            TraceEvent::Call {
+                pc, target, depth, ..
+            } => {
+                out.write_all(&[3])?;
+                out.write_all(&pc.to_le_bytes())?;
+                out.write_all(&target.to_le_bytes())?;
+                out.write_all(&(depth as u32).to_le_bytes())
+            }
+            TraceEvent::Ret { pc, depth, .. } => {
+                out.write_all(&[4])?;
+                out.write_all(&pc.to_le_bytes())?;
+                out.write_all(&(depth as u32).to_le_bytes())
+            }
+        }
+    }
+}
+
+/// One JSON object per line.
+pub struct JsonSink<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(out: W) -> Self {
+        JsonSink { out }
+    }
+}
+
+impl<W: Write> TraceSink for JsonSink<W> {
+    fn record(&mut self, event: &TraceEvent) -> io::Result<()> {
+        match *event {
+            TraceEvent::Inst {
+                pc,
+                raw_inst,
+                disasm,
+            } => writeln!(
+                self.out,
+                r#"{{"type":"inst","pc":{},"raw_inst":{},"disasm":"{}"}}"#,
+                pc,
+                raw_inst,
+                escape_json(disasm)
+            ),
+            TraceEvent::MemRead { addr, size, value } => writeln!(
+                self.out,
+                r#"{{"type":"mread","addr":{},"size":{},"value":{}}}"#,
+                addr, size, value
+            ),
+            TraceEvent::MemWrite { addr, size, value } => writeln!(
+                self.out,
+                r#"{{"type":"mwrite","addr":{},"size":{},"value":{}}}"#,
+                addr, size, value
+            ),
+            TraceEvent::Call {
+                pc,
+                target,
+                depth,
+                name,
+            } => writeln!(
+                self.out,
+                r#"{{"type":"call","pc":{},"target":{},"depth":{},"name":"{}"}}"#,
+                pc,
+                target,
+                depth,
+                escape_json(name)
+            ),
+            TraceEvent::Ret { pc, depth, name } => writeln!(
+                self.out,
+                r#"{{"type":"ret","pc":{},"depth":{},"name":"{}"}}"#,
+                pc,
+                depth,
+                escape_json(name)
+            ),
+        }
+    }
+}
+
+pub(crate) fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Output format of trace files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+    Json,
+}
+
+/// Handle held by a trace producer. A disabled tracer drops every event.
+#[derive(Default)]
+pub struct Tracer {
+    sink: RefCell<Option<Box<dyn TraceSink>>>,
+}
+
+impl Tracer {
+    pub fn new(sink: Box<dyn TraceSink>) -> Tracer {
+        Tracer {
+            sink: RefCell::new(Some(sink)),
+        }
+    }
+
+    pub fn disabled() -> Tracer {
+        Tracer::default()
+    }
+
+    /// Create the tracer for one kind of trace.
+    /// Giving a `path` enables the trace and writes it to that file in
+    /// `format`; otherwise `enable` selects logging through the logger.
+    pub fn open(enable: bool, path: Option<&str>, format: TraceFormat) -> io::Result<Tracer> {
+        let sink: Box<dyn TraceSink> = match path {
+            Some(path) => {
+                let out = BufWriter::new(File::create(path)?);
+                match format {
+                    TraceFormat::Text => Box::new(TextSink::new(out)),
+                    TraceFormat::Binary => Box::new(BinarySink::new(out)?),
+                    TraceFormat::Json => Box::new(JsonSink::new(out)),
+                }
+            }
+            None if enable => Box::new(LogSink),
+            None => return Ok(Tracer::disabled()),
+        };
+        Ok(Tracer::new(sink))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.sink.borrow().is_some()
+    }
+
+    /// Record an event. The trace is turned off after the first write error.
+    pub fn record(&self, event: &TraceEvent) {
+        let mut sink = self.sink.borrow_mut();
+        if let Some(s) = sink.as_mut() {
+            if let Err(e) = s.record(event) {
+                error!("Fail to write trace: {e}, trace disabled");
+                *sink = None;
+            }
+        }
+    }
+
+    /// Record an executed instruction.
+    pub fn inst(&self, pc: u64, raw_inst: u32, disasm: &str) {
+        self.record(&TraceEvent::Inst {
+            pc,
+            raw_inst,
+            disasm,
+        });
+    }
+}
+
+impl fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tracer")
+            .field("enabled", &self.enabled())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn events() -> [TraceEvent<'static>; 5] {
+        [
+            TraceEvent::Inst {
+                pc: 0x80000000,
+                raw_inst: 0x00500513,
+                disasm: "li\ta0,5",
+            },
+            TraceEvent::MemRead {
+                addr: 0x1008,
+                size: 4,
+                value: 0x12345678,
+            },
+            TraceEvent::MemWrite {
+                addr: 0x1010,
+                size: 1,
+                value: 0xff,
+            },
+            TraceEvent::Call {
+                pc: 0x80000004,
+                target: 0x80000100,
+                depth: 2,
+                name: "main",
+            },
+            TraceEvent::Ret {
+                pc: 0x80000104,
+                depth: 2,
+                name: "main",
+            },
+        ]
+    }
+
+    #[test]
+    fn binary_records() {
+        let mut buf = Vec::new();
+        let mut sink = BinarySink::new(&mut buf).unwrap();
+        for event in events() {
+            sink.record(&event).unwrap();
+        }
+
+        let (header, records) = buf.split_at(12);
+        assert_eq!(&header[..8], b"RVTRACE\0");
+        assert_eq!(header[8..], 1u32.to_le_bytes());
+
+        let mut expected = Vec::new();
+        expected.push(0);
+        expected.extend(0x80000000u64.to_le_bytes());
+        expected.extend(0x00500513u32.to_le_bytes());
+        expected.push(1);
+        expected.extend(0x1008u64.to_le_bytes());
+        expected.push(4);
+        expected.extend(0x12345678u64.to_le_bytes());
+        expected.push(2);
+        expected.extend(0x1010u64.to_le_bytes());
+        expected.push(1);
+        expected.extend(0xffu64.to_le_bytes());
+        expected.push(3);
+        expected.extend(0x80000004u64.to_le_bytes());
+        expected.extend(0x80000100u64.to_le_bytes());
+        expected.extend(2u32.to_le_bytes());
+        expected.push(4);
+        expected.extend(0x80000104u64.to_le_bytes());
+        expected.extend(2u32.to_le_bytes());
+        assert_eq!(records, expected);
+        // inst, mread, mwrite, call, ret
+        assert_eq!(records.len(), 13 + 18 + 18 + 21 + 13);
+    }
+
+    #[test]
+    fn json_lines() {
+        let mut buf = Vec::new();
+        let mut sink = JsonSink::new(&mut buf);
+        for event in events() {
+            sink.record(&event).unwrap();
+        }
+        sink.record(&TraceEvent::Call {
+            pc: 0,
+            target: 0,
+            depth: 0,
+            name: "a\"b\\c\u{1}",
+        })
+        .unwrap();
+
+        let text = String::from_utf8(buf).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"type":"inst","pc":2147483648,"raw_inst":5244179,"disasm":"li\ta0,5"}"#,
+                r#"{"type":"mread","addr":4104,"size":4,"value":305419896}"#,
+                r#"{"type":"mwrite","addr":4112,"size":1,"value":255}"#,
+                r#"{"type":"call","pc":2147483652,"target":2147483904,"depth":2,"name":"main"}"#,
+                r#"{"type":"ret","pc":2147483908,"depth":2,"name":"main"}"#,
+                r#"{"type":"call","pc":0,"target":0,"depth":0,"name":"a\"b\\c\u0001"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn escape_json_chars() {
+        assert_eq!(escape_json("plain"), "plain");
+        assert_eq!(escape_json(r#"say "hi""#), r#"say \"hi\""#);
+        assert_eq!(escape_json("a\\b"), r"a\\b");
+        assert_eq!(escape_json("1\n2\t3\r\u{1f}"), r"1\n2\t3\u000d\u001f");
+        assert_eq!(escape_json("ünïcode"), "ünïcode");
+    }
+}
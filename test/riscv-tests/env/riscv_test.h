@@ -0,0 +1,57 @@
+// Bare target environment of riscv-tests for the simulator.
+//
+// The environments of riscv-tests (env/p, env/v) start in machine mode, set
+// up trap handlers through CSRs and end a test with `ecall`. This one needs
+// no CSRs: it runs the test directly from `_start` and writes the result to
+// `tohost` itself: `1` on pass and `(TESTNUM << 1) | 1` on failure of test
+// case TESTNUM.
+
+#ifndef _ENV_BARE_H
+#define _ENV_BARE_H
+
+#define RVTEST_RV64U                                                    \
+  .macro init;                                                          \
+  .endm
+
+#define RVTEST_RV32U RVTEST_RV64U
+
+#define TESTNUM gp
+
+#define RVTEST_CODE_BEGIN                                               \
+        .section .text.init;                                            \
+        .align  6;                                                      \
+        .globl _start;                                                  \
+_start:                                                                 \
+        init;
+
+#define RVTEST_CODE_END                                                 \
+        unimp
+
+#define RVTEST_PASS                                                     \
+        li TESTNUM, 1;                                                  \
+        la t5, tohost;                                                  \
+        sd TESTNUM, 0(t5);                                              \
+1:      j 1b;
+
+#define RVTEST_FAIL                                                     \
+1:      beqz TESTNUM, 1b;                                               \
+        sll TESTNUM, TESTNUM, 1;                                        \
+        or TESTNUM, TESTNUM, 1;                                         \
+        la t5, tohost;                                                  \
+        sd TESTNUM, 0(t5);                                              \
+1:      j 1b;
+
+#define EXTRA_DATA
+
+#define RVTEST_DATA_BEGIN                                               \
+        EXTRA_DATA                                                      \
+        .pushsection .tohost,"aw",@progbits;                            \
+        .align 6; .global tohost; tohost: .dword 0; .size tohost, 8;    \
+        .align 6; .global fromhost; fromhost: .dword 0; .size fromhost, 8; \
+        .popsection;                                                    \
+        .align 4; .global begin_signature; begin_signature:
+
+#define RVTEST_DATA_END                                                 \
+        .align 4; .global end_signature; end_signature:
+
+#endif